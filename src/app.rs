@@ -3,7 +3,7 @@ use std::{
     time::Duration,
 };
 
-use anyhow::{Ok, Result};
+use anyhow::{bail, Ok, Result};
 use futures::future::join_all;
 use tokio::{spawn, time::sleep};
 use tokio_util::sync::CancellationToken;
@@ -12,8 +12,10 @@ use tracing::{debug, error, trace};
 use crate::{
     config,
     database::Database,
-    operations::destination::post,
-    sources::source::{get, retain_all_dst_statuses},
+    http::build_client,
+    operations::{destination::post, unlink::unlink},
+    protocols::check_accounts,
+    sources::source::{get, replay, retain_all_dst_statuses},
     store,
 };
 
@@ -27,12 +29,27 @@ pub async fn do_main_task(
     cancellation_token: &CancellationToken,
     config: &config::Config,
     store: &mut store::Store,
+    database: &dyn Database,
 ) -> Result<()> {
     trace!("do_main_task");
-    let http_client = Arc::new(reqwest::Client::new());
+    let http_client = Arc::new(build_client(&config.http)?);
     let store = Mutex::new(store);
-    let users = config.users.iter();
-    let futures = users.map(|config_user| get(&http_client, config_user, &store));
+    let futures = config.users.iter().flat_map(|config_user| {
+        config_user
+            .srcs
+            .iter()
+            .map(|src| {
+                get(
+                    &http_client,
+                    config_user,
+                    src,
+                    &store,
+                    &config.retry,
+                    config.http.max_media_bytes,
+                )
+            })
+            .collect::<Vec<_>>()
+    });
     for result in join_all(futures).await {
         result?;
     }
@@ -45,11 +62,8 @@ pub async fn do_main_task(
         cancellation_token,
         store,
         http_client.clone(),
-        &config
-            .users
-            .iter()
-            .flat_map(|user| &user.dsts)
-            .collect::<Vec<_>>(),
+        config,
+        database,
     )
     .await?;
     if cancellation_token.is_cancelled() {
@@ -76,9 +90,15 @@ pub async fn app(database: impl Database) -> Result<()> {
     });
     spawn(async move {
         let config = database.config().await?;
+        if let Err(errors) = config.validate() {
+            for err in &errors {
+                error!("{}", err);
+            }
+            bail!("invalid config");
+        }
         let mut store = database.fetch().await.unwrap_or_default();
 
-        let main_result = do_main_task(&cancellation_token, &config, &mut store).await;
+        let main_result = do_main_task(&cancellation_token, &config, &mut store, &database).await;
 
         let commit_result = database.commit(&store).await;
         if let Err(main_error) = main_result {
@@ -92,3 +112,46 @@ pub async fn app(database: impl Database) -> Result<()> {
     })
     .await?
 }
+
+/** 送信先に送ったものを全て削除して、送信先との紐付けを取り除く */
+pub async fn unlink_dst(database: &dyn Database, dst_account_key: &AccountKey) -> Result<()> {
+    let config = database.config().await?;
+    let mut store = database.fetch().await?;
+    let http_client = Arc::new(build_client(&config.http)?);
+    unlink(
+        &CancellationToken::new(),
+        &mut store,
+        http_client,
+        &config,
+        database,
+        dst_account_key,
+    )
+    .await
+}
+
+/** 設定されている全てのアカウントの認証情報を確かめる */
+pub async fn check(database: &dyn Database) -> Result<()> {
+    let config = database.config().await?;
+    let http_client = Arc::new(build_client(&config.http)?);
+    check_accounts(http_client, &config).await
+}
+
+/** src の 1 つの status を送り直す operation を積む。送るのは次の実行で行う */
+pub async fn replay_status(
+    database: &dyn Database,
+    src_account_key: &AccountKey,
+    src_identifier: &str,
+) -> Result<()> {
+    let config = database.config().await?;
+    let mut store = database.fetch().await?;
+    let http_client = Arc::new(build_client(&config.http)?);
+    replay(
+        &mut store,
+        http_client,
+        &config,
+        src_account_key,
+        src_identifier,
+    )
+    .await?;
+    database.commit(&store).await
+}
@@ -1,6 +1,170 @@
-use serde::Deserialize;
+use std::{collections::HashSet, env, fmt};
 
-use crate::{app::AccountKey, protocols::twitter_client};
+use reqwest::Url;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    app::AccountKey,
+    http::HttpConfig,
+    protocols::{discord_client, retry::RetryPolicy, threads_client, twitter_client},
+};
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MastodonVisibility {
+    Public,
+    Unlisted,
+    Private,
+    Direct,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MisskeyVisibility {
+    Public,
+    Home,
+    Followers,
+    Specified,
+}
+
+/** Bluesky を src として使う場合の取得方法 */
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /** 著者のフィードを取得する */
+    #[default]
+    Polling,
+    /**
+     * Jetstream から前回以降のイベントを受け取る。初回は著者のフィードを取得する
+     *
+     * 作成のイベントしか扱わないので、削除や編集は送信先に反映されない
+     */
+    Jetstream,
+}
+
+/** 代替テキストの無い画像の扱い */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RequireAlt {
+    /** その画像だけ送らない */
+    SkipImage,
+    /** 投稿ごと送らずにキューに残す */
+    SkipPost,
+    /** 指定した文字列を代替テキストにする */
+    Placeholder(String),
+}
+
+/** src のいいねを送信先でどう扱うか */
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum LikeAction {
+    /** いいねする。Bluesky のみ対応 */
+    Like,
+    /** リポストする */
+    Repost,
+    /** 送らない */
+    Ignore,
+}
+
+/** 送信先の上限を超える数のメディアが付いた投稿の扱い */
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaOverflow {
+    /** 上限までの先頭のメディアだけ送る */
+    #[default]
+    Truncate,
+    /** 上限ごとに分けて、残りは返信としてスレッドにつなげる */
+    Split,
+}
+
+/** 投稿を送る前に本文やリンクに施す加工。書いた順に適用する */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Transform {
+    /** pattern に一致した部分を replacement に置き換える。$1 などで捕獲したグループを参照できる */
+    #[serde(rename_all = "camelCase")]
+    RegexReplace {
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+    },
+    /** ハッシュタグを本文から取り除く。tags が空の場合は全て取り除く */
+    #[serde(rename_all = "camelCase")]
+    StripHashtags {
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    /** trackingParams に加えて取り除くクエリパラメーター */
+    #[serde(rename_all = "camelCase")]
+    StripTrackingParams { params: Vec<String> },
+}
+
+/** src のリプライのうち転送するもの */
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplyPolicy {
+    /** 全て転送する。他人へのリプライは返信先を外して通常の投稿にする */
+    All,
+    /** 自分の投稿へのリプライだけを転送する */
+    #[default]
+    SelfThreadOnly,
+    /** 転送しない */
+    None,
+}
+
+/** Bluesky のスレッドに返信できる人 */
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Threadgate {
+    Nobody,
+    Mentioned,
+    Following,
+}
+
+/**
+ * 文字列中の ${NAME} を環境変数の値に置き換える
+ *
+ * 設定ファイルに認証情報を直接書かずに済むようにする。$${ と書くと ${ のまま残す
+ */
+fn expand_env(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let Some((name, after)) = rest
+            .strip_prefix("${")
+            .and_then(|variable| variable.split_once('}'))
+        else {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let replacement =
+            env::var(name).map_err(|_| format!("environment variable is not set: {}", name))?;
+        expanded.push_str(&replacement);
+        rest = after;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+pub fn deserialize_env<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    expand_env(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+pub fn deserialize_env_opt<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| expand_env(&value).map_err(de::Error::custom))
+        .transpose()
+}
 
 #[derive(Deserialize)]
 #[serde(tag = "protocol")]
@@ -8,30 +172,97 @@ pub enum Account {
     #[serde(rename = "atproto")]
     #[serde(rename_all = "camelCase")]
     AtProtocol {
+        #[serde(deserialize_with = "deserialize_env")]
         origin: String,
+        #[serde(deserialize_with = "deserialize_env")]
         identifier: String,
+        #[serde(deserialize_with = "deserialize_env")]
         password: String,
+        /** 指定しない場合は誰でも返信できる */
+        #[serde(default)]
+        threadgate: Option<Threadgate>,
+        /** リンクカードが無い投稿では、本文のリンク先のページからリンクカードを生成する */
+        #[serde(default)]
+        link_card: bool,
+        /** リンクカードを生成する際のページ取得のタイムアウト */
+        #[serde(default = "default_link_card_timeout_secs")]
+        link_card_timeout_secs: u64,
+        #[serde(default)]
+        fetch_mode: FetchMode,
     },
     #[serde(rename = "mastodon")]
     #[serde(rename_all = "camelCase")]
     Mastodon {
+        #[serde(deserialize_with = "deserialize_env")]
         origin: String,
+        #[serde(deserialize_with = "deserialize_env")]
         access_token: String,
+        #[serde(default)]
+        visibility: Option<MastodonVisibility>,
+        /** src として使う場合に、ブーストも取得する */
+        #[serde(default = "default_true")]
+        include_reposts: bool,
     },
     #[serde(rename = "misskey")]
     #[serde(rename_all = "camelCase")]
     Misskey {
+        #[serde(deserialize_with = "deserialize_env")]
         origin: String,
+        #[serde(deserialize_with = "deserialize_env")]
         access_token: String,
+        #[serde(default)]
+        visibility: Option<MisskeyVisibility>,
+        /** visibility が specified の場合のみ使われる */
+        #[serde(default)]
+        visible_user_ids: Vec<String>,
+        /** 連合せずにローカルのみに投稿する */
+        #[serde(default)]
+        local_only: bool,
+        /** 末尾のリンクからリンクカードを生成するためにページを取得する */
+        #[serde(default)]
+        link_preview: bool,
+        /** 1 回の取得で遡る最大件数。100 件を超える場合は複数回に分けて取得する */
+        #[serde(default = "default_fetch_limit")]
+        fetch_limit: usize,
+        /** 自分が付けたリアクションを、送信先の Bluesky でいいねとして扱う */
+        #[serde(default)]
+        mirror_reactions: bool,
     },
     #[serde(rename = "twitter")]
     #[serde(rename_all = "camelCase")]
     Twitter {
+        #[serde(deserialize_with = "deserialize_env")]
         api_key: String,
+        #[serde(deserialize_with = "deserialize_env")]
         api_key_secret: String,
+        #[serde(deserialize_with = "deserialize_env")]
         access_token: String,
+        #[serde(deserialize_with = "deserialize_env")]
         access_token_secret: String,
     },
+    #[serde(rename = "threads")]
+    #[serde(rename_all = "camelCase")]
+    Threads {
+        #[serde(deserialize_with = "deserialize_env")]
+        access_token: String,
+        #[serde(deserialize_with = "deserialize_env")]
+        user_id: String,
+    },
+    /** 送信専用 */
+    #[serde(rename = "discord")]
+    #[serde(rename_all = "camelCase")]
+    Discord {
+        #[serde(deserialize_with = "deserialize_env")]
+        webhook_url: String,
+    },
+}
+
+fn default_link_card_timeout_secs() -> u64 {
+    5
+}
+
+fn default_fetch_limit() -> usize {
+    100
 }
 
 impl Account {
@@ -46,6 +277,7 @@ impl Account {
             Account::Mastodon {
                 origin,
                 access_token,
+                ..
             } => AccountKey {
                 origin: origin.clone(),
                 identifier: access_token.clone(),
@@ -53,6 +285,7 @@ impl Account {
             Account::Misskey {
                 origin,
                 access_token,
+                ..
             } => AccountKey {
                 origin: origin.clone(),
                 identifier: access_token.clone(),
@@ -61,17 +294,642 @@ impl Account {
                 origin: twitter_client::ORIGIN.to_string(),
                 identifier: access_token.clone(),
             },
+            Account::Threads { user_id, .. } => AccountKey {
+                origin: threads_client::ORIGIN.to_string(),
+                identifier: user_id.clone(),
+            },
+            Account::Discord { webhook_url } => AccountKey {
+                origin: discord_client::ORIGIN.to_string(),
+                identifier: discord_client::webhook_id(webhook_url).to_owned(),
+            },
+        }
+    }
+
+    /** 1 つの投稿に付けられるメディアの数 */
+    pub fn max_media(&self) -> usize {
+        match self {
+            Account::AtProtocol { .. } | Account::Mastodon { .. } | Account::Twitter { .. } => 4,
+            Account::Misskey { .. } => 16,
+            Account::Threads { .. } => 20,
+            Account::Discord { .. } => 10,
+        }
+    }
+
+    /** 送信先の投稿を引用できるか。できない送信先には引用元へのリンクを付ける */
+    pub fn can_quote(&self) -> bool {
+        matches!(
+            self,
+            Account::AtProtocol { .. } | Account::Misskey { .. } | Account::Twitter { .. }
+        )
+    }
+
+    /** (項目名, 値) */
+    fn required_fields(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            Account::AtProtocol {
+                origin,
+                identifier,
+                password,
+                ..
+            } => vec![
+                ("origin", origin),
+                ("identifier", identifier),
+                ("password", password),
+            ],
+            Account::Mastodon {
+                origin,
+                access_token,
+                ..
+            }
+            | Account::Misskey {
+                origin,
+                access_token,
+                ..
+            } => vec![("origin", origin), ("accessToken", access_token)],
+            Account::Twitter {
+                api_key,
+                api_key_secret,
+                access_token,
+                access_token_secret,
+            } => vec![
+                ("apiKey", api_key),
+                ("apiKeySecret", api_key_secret),
+                ("accessToken", access_token),
+                ("accessTokenSecret", access_token_secret),
+            ],
+            Account::Threads {
+                access_token,
+                user_id,
+            } => vec![("accessToken", access_token), ("userId", user_id)],
+            Account::Discord { webhook_url } => vec![("webhookUrl", webhook_url)],
+        }
+    }
+
+    /** (項目名, 値) */
+    fn url_fields(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            Account::AtProtocol { origin, .. }
+            | Account::Mastodon { origin, .. }
+            | Account::Misskey { origin, .. } => vec![("origin", origin)],
+            Account::Twitter { .. } | Account::Threads { .. } => vec![],
+            Account::Discord { webhook_url } => vec![("webhookUrl", webhook_url)],
+        }
+    }
+
+    fn validate(&self, user: usize, errors: &mut Vec<ConfigError>) {
+        for (field, value) in self.required_fields() {
+            if value.trim().is_empty() {
+                errors.push(ConfigError::EmptyField { user, field });
+            }
         }
+        for (field, value) in self.url_fields() {
+            if value.trim().is_empty() {
+                continue;
+            }
+            let is_valid = Url::parse(value)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+            if !is_valid {
+                errors.push(ConfigError::InvalidUrl {
+                    user,
+                    field,
+                    value: value.to_owned(),
+                });
+            }
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/** 時間が経ってから送る投稿の日時の扱い */
+#[derive(Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backdate {
+    /** 送信時の日時にする */
+    #[default]
+    Now,
+    /** 元の投稿の日時のままにする */
+    Keep,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Destination {
+    #[serde(flatten)]
+    pub account: Account,
+    /** false にすると送信を止める。operation はキューに残して、有効に戻した後に送る */
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /** 無効にしている間の operation を残さずに捨てる */
+    #[serde(default)]
+    pub drop_while_disabled: bool,
+    /** 文字数の上限を超えて省略した場合に、元の投稿へのリンクを付ける */
+    #[serde(default = "default_true")]
+    pub append_src_uri: bool,
+    /** リプライを転送しない */
+    #[serde(default)]
+    pub skip_replies: bool,
+    /** リポストを転送しない */
+    #[serde(default)]
+    pub skip_reposts: bool,
+    /** 本文の前に付ける。{source_origin} と {source_url} は元の投稿のものに置き換える */
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /** 本文の後ろに付ける。置き換えは prefix と同じ */
+    #[serde(default)]
+    pub suffix: Option<String>,
+    /** 本文から空行を空けて付ける。置き換えは prefix と同じ */
+    #[serde(default)]
+    pub footer: Option<String>,
+    /** 日時を指定できるプロトコルで、溜まっていた投稿を後から送る場合の日時 */
+    #[serde(default)]
+    pub backdate: Backdate,
+    /** カスタム絵文字の :shortcode: を残す。指定しない場合は Misskey のみ残す */
+    #[serde(default)]
+    pub keep_custom_emojis: Option<bool>,
+    /** Markdown の強調の記号を残す。指定しない場合は Bluesky と Twitter 以外は残す */
+    #[serde(default)]
+    pub keep_markdown: Option<bool>,
+    /** 代替テキストの無い画像がある場合の扱い。指定しない場合はそのまま送る */
+    #[serde(default)]
+    pub require_alt: Option<RequireAlt>,
+    /** 送るメディアの content-type の前方一致 (image/ など)。空の場合は全て送る */
+    #[serde(default)]
+    pub allowed_media: Vec<String>,
+    /** src のいいねの扱い。指定しない場合は Bluesky ならいいねし、それ以外は送らない */
+    #[serde(default)]
+    pub like_as: Option<LikeAction>,
+    /** 1 つの投稿に付けられる数を超えるメディアの扱い */
+    #[serde(default)]
+    pub media_overflow: MediaOverflow,
+    /**
+     * 元の投稿からこの秒数が経ってから送る。
+     * Mastodon はサーバーの予約投稿を使い、それ以外はその日時まで operation を後回しにする
+     */
+    #[serde(default)]
+    pub schedule_delay_seconds: u64,
+}
+
+impl Destination {
+    pub fn keeps_custom_emojis(&self) -> bool {
+        self.keep_custom_emojis
+            .unwrap_or(matches!(self.account, Account::Misskey { .. }))
+    }
+
+    pub fn keeps_markdown(&self) -> bool {
+        self.keep_markdown.unwrap_or(!matches!(
+            self.account,
+            Account::AtProtocol { .. } | Account::Twitter { .. }
+        ))
+    }
+
+    pub fn like_action(&self) -> LikeAction {
+        self.like_as.unwrap_or(match self.account {
+            Account::AtProtocol { .. } => LikeAction::Like,
+            _ => LikeAction::Ignore,
+        })
     }
 }
 
+fn default_tracking_params() -> Vec<String> {
+    [
+        "utm_*", "fbclid", "gclid", "igshid", "mc_cid", "mc_eid", "yclid", "_hsenc", "_hsmi",
+    ]
+    .into_iter()
+    .map(str::to_owned)
+    .collect()
+}
+
 #[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn deserialize_srcs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Account>, D::Error> {
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(src) => vec![src],
+        OneOrMany::Many(srcs) => srcs,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct User {
-    pub src: Account,
-    pub dsts: Vec<Account>,
+    /** false にすると src からの取得を止める。保存済みの状態は残す */
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /** 1 つでも複数でも良い。以前の src も受け付ける */
+    #[serde(alias = "src", deserialize_with = "deserialize_srcs")]
+    pub srcs: Vec<Account>,
+    /** 各 src から、自分自身を除いた全ての dst に送る */
+    pub dsts: Vec<Destination>,
+    /** リンクから取り除くクエリパラメーター。末尾の * は前方一致 */
+    #[serde(default = "default_tracking_params")]
+    pub tracking_params: Vec<String>,
+    /** 含まれる投稿を転送しないキーワード。# から始まる場合はハッシュタグとして完全一致で判定する */
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+    #[serde(default)]
+    pub reply_policy: ReplyPolicy,
+    /** 本文の加工。trackingParams の除去の後に適用する */
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+    /** 投稿からこの秒数が経つまで転送しない。直後の編集を反映してから送るため */
+    #[serde(default)]
+    pub min_age_seconds: u64,
+    /** 保存しておく src の status の数。最後に取得した分はこれを超えても全て残す */
+    #[serde(default = "default_max_stored_statuses")]
+    pub max_stored_statuses: usize,
+    /** これより古い src の status は保存しない。返信先や削除の解決に使えなくなる */
+    #[serde(default)]
+    pub max_stored_status_age_days: Option<u64>,
+}
+
+fn default_max_stored_statuses() -> usize {
+    100
+}
+
+impl User {
+    fn validate(&self, user: usize, errors: &mut Vec<ConfigError>) {
+        if self.dsts.is_empty() {
+            errors.push(ConfigError::NoDestinations { user });
+        }
+        for src in &self.srcs {
+            src.validate(user, errors);
+        }
+        for transform in &self.transforms {
+            if let Transform::RegexReplace { pattern, .. } = transform {
+                if let Err(err) = regex::Regex::new(pattern) {
+                    errors.push(ConfigError::InvalidPattern {
+                        user,
+                        pattern: pattern.clone(),
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+        let mut account_keys = HashSet::new();
+        for dst in &self.dsts {
+            dst.account.validate(user, errors);
+            let account_key = dst.account.to_account_key();
+            if dst.like_action() == LikeAction::Like
+                && !matches!(dst.account, Account::AtProtocol { .. })
+            {
+                errors.push(ConfigError::UnsupportedLikeAction {
+                    user,
+                    origin: account_key.origin.clone(),
+                });
+            }
+            if !account_keys.insert(account_key.clone()) {
+                errors.push(ConfigError::DuplicateDestination {
+                    user,
+                    origin: account_key.origin,
+                });
+            }
+        }
+    }
+
+    pub fn dsts_for(&self, src: &Account) -> Vec<&Destination> {
+        let src_account_key = src.to_account_key();
+        self.dsts
+            .iter()
+            .filter(|dst| dst.account.to_account_key() != src_account_key)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub requests_per_minute: f64,
+    /** 連続して送信できる最大数 */
+    pub burst: f64,
+}
+
+/** 送信先のプロトコルごとの書き込みの頻度 */
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimits {
+    #[serde(default = "default_rate_limit")]
+    pub atproto: RateLimit,
+    #[serde(default = "default_rate_limit")]
+    pub mastodon: RateLimit,
+    #[serde(default = "default_rate_limit")]
+    pub misskey: RateLimit,
+    #[serde(default = "default_twitter_rate_limit")]
+    pub twitter: RateLimit,
+    #[serde(default = "default_rate_limit")]
+    pub threads: RateLimit,
+    #[serde(default = "default_rate_limit")]
+    pub discord: RateLimit,
+}
+
+fn default_rate_limit() -> RateLimit {
+    RateLimit {
+        requests_per_minute: 30.0,
+        burst: 10.0,
+    }
+}
+
+fn default_twitter_rate_limit() -> RateLimit {
+    RateLimit {
+        requests_per_minute: 5.0,
+        burst: 2.0,
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            atproto: default_rate_limit(),
+            mastodon: default_rate_limit(),
+            misskey: default_rate_limit(),
+            twitter: default_twitter_rate_limit(),
+            threads: default_rate_limit(),
+            discord: default_rate_limit(),
+        }
+    }
+}
+
+impl RateLimit {
+    /** 0 以下では補充されず、1 未満では 1 件も送れないので、どちらも永久に待ち続けてしまう */
+    fn is_valid(&self) -> bool {
+        self.requests_per_minute > 0.0 && self.burst >= 1.0
+    }
+}
+
+impl RateLimits {
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        for (protocol, rate_limit) in [
+            ("atproto", self.atproto),
+            ("mastodon", self.mastodon),
+            ("misskey", self.misskey),
+            ("twitter", self.twitter),
+            ("threads", self.threads),
+            ("discord", self.discord),
+        ] {
+            if !rate_limit.is_valid() {
+                errors.push(ConfigError::InvalidRateLimit { protocol });
+            }
+        }
+    }
+
+    pub fn get(&self, account: &Account) -> RateLimit {
+        match account {
+            Account::AtProtocol { .. } => self.atproto,
+            Account::Mastodon { .. } => self.mastodon,
+            Account::Misskey { .. } => self.misskey,
+            Account::Twitter { .. } => self.twitter,
+            Account::Threads { .. } => self.threads,
+            Account::Discord { .. } => self.discord,
+        }
+    }
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Config {
     pub users: Vec<User>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub http: HttpConfig,
+    /** 送信せずに、送信する予定の内容をログに出すだけにする */
+    #[serde(default)]
+    pub dry_run: bool,
+    /**
+     * 1 回の実行で送信先に送る operation の上限。残りは次回の実行に持ち越す
+     *
+     * 指定しない場合は全て消化する。DynamoDB に続けて書き込むと失敗する場合は 2 程度に抑える
+     */
+    #[serde(default)]
+    pub max_operations_per_run: Option<usize>,
+}
+
+impl Config {
+    /** 実行してから失敗する前に、設定の誤りをまとめて見つける */
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        for (user, config_user) in self.users.iter().enumerate() {
+            config_user.validate(user, &mut errors);
+        }
+        self.rate_limits.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/** user は users の中の位置 */
+#[derive(Debug)]
+pub enum ConfigError {
+    EmptyField {
+        user: usize,
+        field: &'static str,
+    },
+    InvalidUrl {
+        user: usize,
+        field: &'static str,
+        value: String,
+    },
+    NoDestinations {
+        user: usize,
+    },
+    /** 認証情報は出さずに origin だけ示す */
+    DuplicateDestination {
+        user: usize,
+        origin: String,
+    },
+    UnsupportedLikeAction {
+        user: usize,
+        origin: String,
+    },
+    InvalidPattern {
+        user: usize,
+        pattern: String,
+        reason: String,
+    },
+    InvalidRateLimit {
+        protocol: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyField { user, field } => write!(f, "users[{}]: {} is empty", user, field),
+            Self::InvalidUrl { user, field, value } => {
+                write!(
+                    f,
+                    "users[{}]: {} is not a valid url: {}",
+                    user, field, value
+                )
+            }
+            Self::NoDestinations { user } => write!(f, "users[{}]: dsts is empty", user),
+            Self::DuplicateDestination { user, origin } => {
+                write!(f, "users[{}]: duplicate dst: {}", user, origin)
+            }
+            Self::UnsupportedLikeAction { user, origin } => {
+                write!(
+                    f,
+                    "users[{}]: likeAs like is not supported: {}",
+                    user, origin
+                )
+            }
+            Self::InvalidPattern {
+                user,
+                pattern,
+                reason,
+            } => write!(
+                f,
+                "users[{}]: invalid pattern: {}: {}",
+                user, pattern, reason
+            ),
+            Self::InvalidRateLimit { protocol } => write!(
+                f,
+                "rateLimits.{}: requestsPerMinute must be positive and burst must be at least 1",
+                protocol
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn invalid_rate_limits_are_rejected() {
+        let config: Config = serde_json::from_value(json!({
+            "users": [],
+            "rateLimits": {
+                "mastodon": { "requestsPerMinute": 0, "burst": 1 },
+                "twitter": { "requestsPerMinute": 5, "burst": 0.5 },
+                "misskey": { "requestsPerMinute": 1, "burst": 1 },
+            },
+        }))
+        .unwrap();
+
+        let errors = config.validate().unwrap_err();
+
+        let protocols: Vec<_> = errors
+            .iter()
+            .map(|err| match err {
+                ConfigError::InvalidRateLimit { protocol } => *protocol,
+                err => panic!("unexpected error: {}", err),
+            })
+            .collect();
+        assert_eq!(protocols, ["mastodon", "twitter"]);
+    }
+
+    #[test]
+    fn each_failure_category_is_reported() {
+        let dst = json!({ "protocol": "mastodon", "origin": "https://dst.example.com", "accessToken": "dst" });
+        let config: Config = serde_json::from_value(json!({
+            "users": [
+                {
+                    "src": { "protocol": "mastodon", "origin": "example.com", "accessToken": " " },
+                    "dsts": [],
+                },
+                {
+                    "src": { "protocol": "mastodon", "origin": "https://src.example.com", "accessToken": "src" },
+                    "dsts": [dst, dst, {
+                        "protocol": "misskey",
+                        "origin": "https://misskey.example.com",
+                        "accessToken": "misskey",
+                        "likeAs": "like",
+                    }],
+                    "transforms": [{ "type": "regexReplace", "pattern": "(" }],
+                },
+            ],
+        }))
+        .unwrap();
+
+        let errors: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(|err| match err {
+                ConfigError::EmptyField { .. } => "EmptyField",
+                ConfigError::InvalidUrl { .. } => "InvalidUrl",
+                ConfigError::NoDestinations { .. } => "NoDestinations",
+                ConfigError::DuplicateDestination { .. } => "DuplicateDestination",
+                ConfigError::UnsupportedLikeAction { .. } => "UnsupportedLikeAction",
+                ConfigError::InvalidPattern { .. } => "InvalidPattern",
+                ConfigError::InvalidRateLimit { .. } => "InvalidRateLimit",
+            })
+            .collect();
+
+        assert_eq!(
+            errors,
+            [
+                "NoDestinations",
+                "EmptyField",
+                "InvalidUrl",
+                "InvalidPattern",
+                "DuplicateDestination",
+                "UnsupportedLikeAction",
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_point_at_user_and_field_without_secrets() {
+        let config: Config = serde_json::from_value(json!({
+            "users": [{
+                "src": { "protocol": "mastodon", "origin": "example.com", "accessToken": "secret" },
+                "dsts": [{ "protocol": "mastodon", "origin": "https://dst.example.com", "accessToken": "" }],
+            }],
+        }))
+        .unwrap();
+
+        let errors: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            errors,
+            [
+                "users[0]: origin is not a valid url: example.com",
+                "users[0]: accessToken is empty",
+            ]
+        );
+    }
+
+    #[test]
+    fn env_is_interpolated_unless_escaped() {
+        env::set_var("TIMELINEECHO_TEST_TOKEN", "secret");
+
+        assert_eq!(
+            expand_env("Bearer ${TIMELINEECHO_TEST_TOKEN}!").unwrap(),
+            "Bearer secret!"
+        );
+        assert_eq!(
+            expand_env("$${TIMELINEECHO_TEST_TOKEN}").unwrap(),
+            "${TIMELINEECHO_TEST_TOKEN}"
+        );
+        assert_eq!(
+            expand_env("pa$$word ${unclosed").unwrap(),
+            "pa$$word ${unclosed"
+        );
+        assert_eq!(
+            expand_env("${TIMELINEECHO_TEST_MISSING}").unwrap_err(),
+            "environment variable is not set: TIMELINEECHO_TEST_MISSING"
+        );
+    }
 }
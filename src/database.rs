@@ -1,4 +1,8 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
@@ -11,6 +15,10 @@ use tracing::{error, info};
 
 use crate::{config::Config, store};
 
+mod sqlite;
+
+pub use sqlite::Sqlite;
+
 #[async_trait]
 pub trait Database: Send + Sync + 'static {
     async fn config(&self) -> Result<Config>;
@@ -30,8 +38,9 @@ impl Database for File {
     }
 
     async fn fetch(&self) -> Result<store::Store> {
-        let store = serde_json::from_str(&fs::read_to_string("store.json").await?)?;
-        Ok(store)
+        store::migrate(serde_json::from_str(
+            &fs::read_to_string("store.json").await?,
+        )?)
     }
 
     async fn commit(&self, store: &store::Store) -> Result<()> {
@@ -39,6 +48,51 @@ impl Database for File {
     }
 }
 
+/** ファイルやネットワークを使わずにメモリー上で完結する。テストや動作確認用 */
+#[derive(Clone)]
+pub struct InMemory {
+    /** Config は Clone できないので JSON で持っておいて毎回読み直す */
+    config: serde_json::Value,
+    store: Arc<Mutex<store::Store>>,
+    commit_count: Arc<Mutex<usize>>,
+}
+
+impl InMemory {
+    pub fn new(config: serde_json::Value, store: store::Store) -> Self {
+        Self {
+            config,
+            store: Arc::new(Mutex::new(store)),
+            commit_count: Arc::default(),
+        }
+    }
+
+    /** 最後に commit された Store */
+    pub fn last_committed(&self) -> store::Store {
+        self.store.lock().unwrap().clone()
+    }
+
+    pub fn commit_count(&self) -> usize {
+        *self.commit_count.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl Database for InMemory {
+    async fn config(&self) -> Result<Config> {
+        Ok(serde_json::from_value(self.config.clone())?)
+    }
+
+    async fn fetch(&self) -> Result<store::Store> {
+        Ok(self.last_committed())
+    }
+
+    async fn commit(&self, store: &store::Store) -> Result<()> {
+        *self.store.lock().unwrap() = store.clone();
+        *self.commit_count.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DynamoDBConfig {
@@ -92,7 +146,7 @@ impl Database for DynamoDB {
             .await?;
         let item = output.item().ok_or_else(|| anyhow!("object not found"))?;
         let root: DynamoDBStore = from_item(item.clone())?;
-        Ok(serde_json::from_str(&root.store)?)
+        store::migrate(serde_json::from_str(&root.store)?)
     }
 
     #[tracing::instrument(name = "dynamodb::Database::commit", skip_all)]
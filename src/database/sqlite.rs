@@ -0,0 +1,544 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{config::Config, store};
+
+use super::{Database, File};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    key TEXT PRIMARY KEY,
+    position INTEGER NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS src_statuses (
+    key TEXT PRIMARY KEY,
+    user_key TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS dst_statuses (
+    key TEXT PRIMARY KEY,
+    dst_key TEXT NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS operations (
+    position INTEGER PRIMARY KEY,
+    json TEXT NOT NULL
+);
+";
+
+fn to_key(parts: &[&str]) -> String {
+    serde_json::to_string(parts).unwrap()
+}
+
+fn to_user_key(user: &store::user::User) -> String {
+    to_key(&[&user.src.origin, &user.src.identifier])
+}
+
+fn to_dst_key(user: &store::user::User, dst: &store::user::Destination) -> String {
+    to_key(&[
+        &user.src.origin,
+        &user.src.identifier,
+        &dst.origin,
+        &dst.identifier,
+    ])
+}
+
+fn to_src_status_key(user: &store::user::User, status: &store::user::SourceStatus) -> String {
+    let identifier = match status {
+        store::user::SourceStatus::Post(post) => &post.identifier,
+        store::user::SourceStatus::Repost(repost) => &repost.identifier,
+        store::user::SourceStatus::Like(like) => &like.identifier,
+    };
+    to_key(&[&user.src.origin, &user.src.identifier, identifier])
+}
+
+fn to_dst_status_key(dst_key: &str, status: &store::user::DestinationStatus) -> String {
+    let (kind, src_identifier) = match status {
+        store::user::DestinationStatus::Post(post) => ("post", &post.src_identifier),
+        store::user::DestinationStatus::Repost(repost) => ("repost", &repost.src_identifier),
+        store::user::DestinationStatus::Like(like) => ("like", &like.src_identifier),
+    };
+    to_key(&[dst_key, kind, src_identifier])
+}
+
+/** テーブルの行に分解した Store */
+#[derive(Default)]
+struct Rows {
+    /** PRAGMA user_version に入れる */
+    version: u32,
+    /** (key, json) */
+    users: Vec<(String, String)>,
+    /** (key, user_key, created_at, json) */
+    src_statuses: Vec<(String, String, i64, String)>,
+    /** (key, dst_key, json) 新しい順 */
+    dst_statuses: Vec<(String, String, String)>,
+    operations: Vec<String>,
+}
+
+impl Rows {
+    fn new(store: &store::Store) -> Result<Self> {
+        let mut rows = Self {
+            version: store.version,
+            ..Self::default()
+        };
+        for user in &store.users {
+            let user_key = to_user_key(user);
+            // NOTE: statuses は別のテーブルに入れるので空にしておく
+            let mut user_without_statuses = user.clone();
+            user_without_statuses.src.statuses.clear();
+            user_without_statuses
+                .dsts
+                .iter_mut()
+                .for_each(|dst| dst.statuses.clear());
+            rows.users.push((
+                user_key.clone(),
+                serde_json::to_string(&user_without_statuses)?,
+            ));
+            for status in &user.src.statuses {
+                rows.src_statuses.push((
+                    to_src_status_key(user, status),
+                    user_key.clone(),
+                    status.created_at().timestamp_micros(),
+                    serde_json::to_string(status)?,
+                ));
+            }
+            for dst in &user.dsts {
+                let dst_key = to_dst_key(user, dst);
+                for status in &dst.statuses {
+                    rows.dst_statuses.push((
+                        to_dst_status_key(&dst_key, status),
+                        dst_key.clone(),
+                        serde_json::to_string(status)?,
+                    ));
+                }
+            }
+        }
+        for operation in &store.operations {
+            rows.operations.push(serde_json::to_string(operation)?);
+        }
+        Ok(rows)
+    }
+}
+
+/** 最後に書き込んだ内容。差分だけを書き込むために使う */
+#[derive(Default)]
+struct Committed {
+    version: u32,
+    users: HashMap<String, (usize, String)>,
+    src_statuses: HashMap<String, String>,
+    dst_statuses: HashMap<String, String>,
+    operations: Vec<String>,
+}
+
+impl From<Rows> for Committed {
+    fn from(rows: Rows) -> Self {
+        Self {
+            version: rows.version,
+            users: rows
+                .users
+                .into_iter()
+                .enumerate()
+                .map(|(position, (key, json))| (key, (position, json)))
+                .collect(),
+            src_statuses: rows
+                .src_statuses
+                .into_iter()
+                .map(|(key, _, _, json)| (key, json))
+                .collect(),
+            dst_statuses: rows
+                .dst_statuses
+                .into_iter()
+                .map(|(key, _, json)| (key, json))
+                .collect(),
+            operations: rows.operations,
+        }
+    }
+}
+
+fn commit_rows(connection: &mut Connection, committed: &Committed, rows: &Rows) -> Result<()> {
+    let tx = connection.transaction()?;
+    if committed.version != rows.version {
+        tx.pragma_update(None, "user_version", rows.version)?;
+    }
+    for (position, (key, json)) in rows.users.iter().enumerate() {
+        if committed.users.get(key) == Some(&(position, json.clone())) {
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO users (key, position, json) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET position = excluded.position, json = excluded.json",
+            params![key, position as i64, json],
+        )?;
+    }
+    for (key, user_key, created_at, json) in &rows.src_statuses {
+        if committed.src_statuses.get(key) == Some(json) {
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO src_statuses (key, user_key, created_at, json) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (key) DO UPDATE SET created_at = excluded.created_at, json = excluded.json",
+            params![key, user_key, created_at, json],
+        )?;
+    }
+    // NOTE: rowid の降順で読み出すので、古いものから追加する
+    for (key, dst_key, json) in rows.dst_statuses.iter().rev() {
+        if committed.dst_statuses.get(key) == Some(json) {
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO dst_statuses (key, dst_key, json) VALUES (?1, ?2, ?3)
+             ON CONFLICT (key) DO UPDATE SET json = excluded.json",
+            params![key, dst_key, json],
+        )?;
+    }
+
+    let user_keys: HashSet<_> = rows.users.iter().map(|(key, _)| key).collect();
+    for key in committed
+        .users
+        .keys()
+        .filter(|key| !user_keys.contains(key))
+    {
+        tx.execute("DELETE FROM users WHERE key = ?1", params![key])?;
+    }
+    let src_status_keys: HashSet<_> = rows.src_statuses.iter().map(|(key, ..)| key).collect();
+    for key in committed
+        .src_statuses
+        .keys()
+        .filter(|key| !src_status_keys.contains(key))
+    {
+        tx.execute("DELETE FROM src_statuses WHERE key = ?1", params![key])?;
+    }
+    let dst_status_keys: HashSet<_> = rows.dst_statuses.iter().map(|(key, ..)| key).collect();
+    for key in committed
+        .dst_statuses
+        .keys()
+        .filter(|key| !dst_status_keys.contains(key))
+    {
+        tx.execute("DELETE FROM dst_statuses WHERE key = ?1", params![key])?;
+    }
+
+    // NOTE: キューはマージの度に並び替えられるので、変わっていれば丸ごと書き直す
+    if committed.operations != rows.operations {
+        tx.execute("DELETE FROM operations", [])?;
+        for (position, json) in rows.operations.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO operations (position, json) VALUES (?1, ?2)",
+                params![position as i64, json],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/**
+ * 行ごとの JSON を組み立ててから migrate で読み、保存されていた版と合わせて返す
+ *
+ * 行は古い版の形式のまま残っていることがあるので、先に Store として読まない
+ */
+fn fetch_store(connection: &Connection) -> Result<(store::Store, u32)> {
+    let mut users: Vec<Value> = Vec::new();
+    let mut user_indices = HashMap::new();
+    let mut dst_indices = HashMap::new();
+
+    let mut stmt = connection.prepare("SELECT key, json FROM users ORDER BY position")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let key: String = row.get(0)?;
+        let user: Value = serde_json::from_str(&row.get::<_, String>(1)?)?;
+        let src = &user["src"];
+        for (dst_idx, dst) in user["dsts"].as_array().into_iter().flatten().enumerate() {
+            let dst_key = to_key(&[
+                src["origin"].as_str().unwrap_or_default(),
+                src["identifier"].as_str().unwrap_or_default(),
+                dst["origin"].as_str().unwrap_or_default(),
+                dst["identifier"].as_str().unwrap_or_default(),
+            ]);
+            dst_indices.insert(dst_key, (users.len(), dst_idx));
+        }
+        user_indices.insert(key, users.len());
+        users.push(user);
+    }
+
+    let mut stmt = connection
+        .prepare("SELECT user_key, json FROM src_statuses ORDER BY created_at DESC, rowid")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let user_key: String = row.get(0)?;
+        let idx = *user_indices
+            .get(&user_key)
+            .ok_or_else(|| anyhow!("user not found: {}", user_key))?;
+        users[idx]["src"]["statuses"]
+            .as_array_mut()
+            .ok_or_else(|| anyhow!("statuses not found: {}", user_key))?
+            .push(serde_json::from_str(&row.get::<_, String>(1)?)?);
+    }
+
+    let mut stmt =
+        connection.prepare("SELECT dst_key, json FROM dst_statuses ORDER BY rowid DESC")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let dst_key: String = row.get(0)?;
+        let &(user_idx, dst_idx) = dst_indices
+            .get(&dst_key)
+            .ok_or_else(|| anyhow!("dst not found: {}", dst_key))?;
+        users[user_idx]["dsts"][dst_idx]["statuses"]
+            .as_array_mut()
+            .ok_or_else(|| anyhow!("statuses not found: {}", dst_key))?
+            .push(serde_json::from_str(&row.get::<_, String>(1)?)?);
+    }
+
+    let mut operations = Vec::new();
+    let mut stmt = connection.prepare("SELECT json FROM operations ORDER BY position")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        operations.push(serde_json::from_str::<Value>(&row.get::<_, String>(0)?)?);
+    }
+
+    let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let store = store::migrate(json!({
+        "version": version,
+        "users": users,
+        "operations": operations,
+    }))?;
+    Ok((store, version))
+}
+
+struct Inner {
+    connection: Mutex<Connection>,
+    committed: Mutex<Committed>,
+}
+
+/**
+ * Store をテーブルに分けて保存する
+ *
+ * commit では前回から変わった行だけを書き込む。設定は File と同じく config.json から読む。
+ * rusqlite は同期 API なので、読み書きは spawn_blocking で tokio のワーカーの外で行う
+ */
+pub struct Sqlite(Arc<Inner>);
+
+impl Sqlite {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self(Arc::new(Inner {
+            connection: Mutex::new(connection),
+            committed: Mutex::default(),
+        })))
+    }
+
+    async fn is_empty(&self) -> Result<bool> {
+        let inner = self.0.clone();
+        spawn_blocking(move || {
+            let count: i64 = inner.connection.lock().unwrap().query_row(
+                "SELECT (SELECT COUNT(*) FROM users) + (SELECT COUNT(*) FROM operations)",
+                [],
+                |row| row.get(0),
+            )?;
+            Ok(count == 0)
+        })
+        .await?
+    }
+
+    /**
+     * 他の Database の内容を丸ごと書き込む
+     *
+     * File は初回の fetch で自動的に取り込む。DynamoDB からは migrate-dynamodb コマンドで取り込む
+     */
+    pub async fn migrate_from(&self, database: &impl Database) -> Result<()> {
+        let store = database.fetch().await?;
+        self.commit(&store).await
+    }
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    async fn config(&self) -> Result<Config> {
+        File.config().await
+    }
+
+    #[tracing::instrument(name = "sqlite::Database::fetch", skip_all)]
+    async fn fetch(&self) -> Result<store::Store> {
+        // NOTE: 初回は store.json があれば取り込む
+        if self.is_empty().await? && Path::new("store.json").exists() {
+            info!("migrate from store.json...");
+            self.migrate_from(&File).await?;
+        }
+        let inner = self.0.clone();
+        spawn_blocking(move || {
+            let (store, version) = fetch_store(&inner.connection.lock().unwrap())?;
+            // NOTE: 古い版の行が残らないように、次の commit で全て書き直す
+            *inner.committed.lock().unwrap() = if version < store::CURRENT_VERSION {
+                Committed {
+                    version,
+                    ..Committed::default()
+                }
+            } else {
+                Rows::new(&store)?.into()
+            };
+            Ok(store)
+        })
+        .await?
+    }
+
+    #[tracing::instrument(name = "sqlite::Database::commit", skip_all)]
+    async fn commit(&self, store: &store::Store) -> Result<()> {
+        let rows = Rows::new(store)?;
+        let inner = self.0.clone();
+        spawn_blocking(move || {
+            let mut committed = inner.committed.lock().unwrap();
+            commit_rows(&mut inner.connection.lock().unwrap(), &committed, &rows)?;
+            *committed = rows.into();
+            Ok(())
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "timelineecho-{}-{}.sqlite3",
+                name,
+                std::process::id()
+            )))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn store() -> store::Store {
+        let mut store = store::Store::default();
+        let account_pair = store::operations::AccountPair::test();
+        store.get_or_create_dst_mut(&account_pair).statuses.push(
+            store::user::DestinationStatus::Post(store::user::DestinationPost {
+                identifier: "dst-1".into(),
+                src_identifier: "1".into(),
+                src_uri: "https://src.example.com/1".into(),
+                follow_up_identifiers: Vec::new(),
+            }),
+        );
+        for src_identifier in ["2", "3"] {
+            store
+                .operations
+                .push_back(store::operations::Operation::CreatePost(
+                    store::operations::CreatePostOperation::test(src_identifier, "hello"),
+                ));
+        }
+        store
+    }
+
+    fn src_identifiers(store: &store::Store) -> Vec<&str> {
+        store
+            .operations
+            .iter()
+            .map(|operation| operation.src_identifier())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn committed_store_is_reloaded() {
+        let path = TempPath::new("reload");
+        Sqlite::open(&path.0)
+            .unwrap()
+            .commit(&store())
+            .await
+            .unwrap();
+
+        let reloaded = Sqlite::open(&path.0).unwrap().fetch().await.unwrap();
+
+        assert_eq!(src_identifiers(&reloaded), ["2", "3"]);
+        let dst = &reloaded.users[0].dsts[0];
+        assert_eq!(dst.origin, "https://dst.example.com");
+        let [store::user::DestinationStatus::Post(post)] = dst.statuses.as_slice() else {
+            panic!("unexpected statuses");
+        };
+        assert_eq!(post.identifier, "dst-1");
+    }
+
+    #[tokio::test]
+    async fn dequeued_operation_is_persisted() {
+        let path = TempPath::new("dequeue");
+        let database = Sqlite::open(&path.0).unwrap();
+        database.commit(&store()).await.unwrap();
+        let mut store = database.fetch().await.unwrap();
+
+        store.operations.pop_front();
+        database.commit(&store).await.unwrap();
+
+        let reloaded = Sqlite::open(&path.0).unwrap().fetch().await.unwrap();
+        assert_eq!(src_identifiers(&reloaded), ["3"]);
+    }
+
+    #[tokio::test]
+    async fn v0_rows_are_migrated_and_rewritten() {
+        let path = TempPath::new("v0");
+        let database = Sqlite::open(&path.0).unwrap();
+        // NOTE: 版を持つ前の行は media に sensitive が無い
+        database
+            .0
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO operations (position, json) VALUES (0, ?1)",
+                params![json!({
+                    "operation": "createPost",
+                    "srcOrigin": "https://src.example.com",
+                    "srcAccountIdentifier": "src",
+                    "dstOrigin": "https://dst.example.com",
+                    "dstAccountIdentifier": "dst",
+                    "srcIdentifier": "1",
+                    "srcUri": "https://src.example.com/1",
+                    "content": "hello",
+                    "replySrcIdentifier": null,
+                    "media": [{ "url": "https://src.example.com/1.png", "alt": "" }],
+                    "createdAt": "2024-01-01T00:00:00Z"
+                })
+                .to_string()],
+            )
+            .unwrap();
+
+        let store = database.fetch().await.unwrap();
+        let Some(store::operations::Operation::CreatePost(operation)) = store.operations.front()
+        else {
+            panic!("unexpected operations");
+        };
+        assert!(!operation.status.media[0].sensitive);
+
+        database.commit(&store).await.unwrap();
+        let connection = database.0.connection.lock().unwrap();
+        let version: u32 = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, store::CURRENT_VERSION);
+        let json: String = connection
+            .query_row("SELECT json FROM operations", [], |row| row.get(0))
+            .unwrap();
+        assert!(json.contains("\"sensitive\":false"));
+    }
+}
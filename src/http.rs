@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::config::{deserialize_env, deserialize_env_opt};
+
+pub const USER_AGENT: &str = concat!("mbcp/", env!("CARGO_PKG_VERSION"));
+
+/** 外部へのリクエストを中継させるプロキシ */
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyConfig {
+    /** http://、https://、socks5:// のいずれか */
+    #[serde(deserialize_with = "deserialize_env")]
+    pub url: String,
+    #[serde(default, deserialize_with = "deserialize_env_opt")]
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_env_opt")]
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpConfig {
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /** 接続からレスポンスを読み終えるまでの上限。応答しないインスタンスで実行全体が止まらないようにする */
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /** 画像や動画をダウンロードする際のサイズの上限 */
+    #[serde(default = "default_max_media_bytes")]
+    pub max_media_bytes: usize,
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_media_bytes() -> usize {
+    100 * 1024 * 1024
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: default_connect_timeout_secs(),
+            timeout_secs: default_timeout_secs(),
+            max_media_bytes: default_max_media_bytes(),
+            proxy: None,
+        }
+    }
+}
+
+fn to_proxy(config: &ProxyConfig) -> Result<reqwest::Proxy> {
+    let proxy = reqwest::Proxy::all(&config.url)?;
+    Ok(match &config.username {
+        Some(username) => {
+            proxy.basic_auth(username, config.password.as_deref().unwrap_or_default())
+        }
+        None => proxy,
+    })
+}
+
+/**
+ * 外部へのリクエストは全てこれで作った Client を使う
+ *
+ * atrium の agent と megalodon はそれぞれ別の Client を作るので proxy は効かず、環境変数の HTTPS_PROXY などが使われる
+ */
+pub fn build_client(config: &HttpConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .timeout(Duration::from_secs(config.timeout_secs));
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(to_proxy(proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header, method},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_endpoint_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(10)))
+            .mount(&server)
+            .await;
+        let client = build_client(&HttpConfig {
+            timeout_secs: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let err = client.get(server.uri()).send().await.unwrap_err();
+
+        assert!(err.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn request_is_sent_through_proxy() {
+        // NOTE: プロキシには絶対 URI でリクエストが来るので、どのパスでも応答する
+        let proxy = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("proxy-authorization", "Basic dXNlcjpwYXNz"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("proxied"))
+            .expect(1)
+            .mount(&proxy)
+            .await;
+        let client = build_client(&HttpConfig {
+            proxy: Some(ProxyConfig {
+                url: proxy.uri(),
+                username: Some("user".into()),
+                password: Some("pass".into()),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let resp = client
+            .get("http://unreachable.invalid/")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.text().await.unwrap(), "proxied");
+        assert_eq!(
+            proxy.received_requests().await.unwrap()[0]
+                .headers
+                .get("user-agent")
+                .unwrap(),
+            USER_AGENT
+        );
+    }
+}
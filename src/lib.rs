@@ -0,0 +1,11 @@
+pub mod app;
+pub mod config;
+pub mod database;
+pub mod http;
+pub mod metrics;
+pub mod operations;
+pub mod protocols;
+pub mod rate_limit;
+pub mod sources;
+pub mod store;
+pub mod utils;
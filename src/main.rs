@@ -1,12 +1,3 @@
-mod app;
-mod config;
-mod database;
-mod operations;
-mod protocols;
-mod sources;
-mod store;
-mod utils;
-
 use tracing_subscriber::{
     fmt::{
         format::{DefaultFields, FmtSpan, Format, Full, Writer},
@@ -39,14 +30,20 @@ fn default_subscriber_builder(
 mod local {
     use std::num::NonZeroU8;
 
-    use anyhow::Result;
+    use anyhow::{bail, Result};
     use time::format_description::well_known::{
         iso8601::{self, EncodedConfig},
         Iso8601,
     };
     use tracing_subscriber::fmt::time::LocalTime;
 
-    use crate::{app::app, database, default_subscriber_builder};
+    use timelineecho::{
+        app::{app, check, replay_status, unlink_dst, AccountKey},
+        database::{self, Database},
+        store::summary::summarize,
+    };
+
+    use crate::default_subscriber_builder;
 
     pub fn init_tracing() {
         const MY_CONFIG: EncodedConfig = iso8601::Config::DEFAULT
@@ -60,10 +57,66 @@ mod local {
             .init();
     }
 
+    /** store.sqlite3 があれば SQLite を使う */
+    fn open_database() -> Result<Box<dyn Database>> {
+        if std::path::Path::new("store.sqlite3").exists() {
+            Ok(Box::new(database::Sqlite::open("store.sqlite3")?))
+        } else {
+            Ok(Box::new(database::File))
+        }
+    }
+
     pub async fn main() -> Result<()> {
         init_tracing();
 
-        app(database::File).await
+        // NOTE: DynamoDB の Store を SQLite に取り込む
+        if std::env::args().nth(1).as_deref() == Some("migrate-dynamodb") {
+            let sqlite = database::Sqlite::open("store.sqlite3")?;
+            return sqlite.migrate_from(&database::DynamoDB::new().await).await;
+        }
+        // NOTE: 監視用に Store の状態を JSON で書き出す
+        if std::env::args().nth(1).as_deref() == Some("status") {
+            let store = open_database()?.fetch().await?;
+            println!("{}", serde_json::to_string_pretty(&summarize(&store))?);
+            return Ok(());
+        }
+        // NOTE: 投稿を始める前に、期限切れのトークンなどが無いかを確かめる
+        if std::env::args().nth(1).as_deref() == Some("check") {
+            return check(open_database()?.as_ref()).await;
+        }
+        // NOTE: unlink <origin> <identifier> で送信先に送ったものを全て削除する
+        if std::env::args().nth(1).as_deref() == Some("unlink") {
+            let (Some(origin), Some(identifier)) =
+                (std::env::args().nth(2), std::env::args().nth(3))
+            else {
+                bail!("usage: unlink <origin> <identifier>");
+            };
+            let dst_account_key = AccountKey { origin, identifier };
+            return unlink_dst(open_database()?.as_ref(), &dst_account_key).await;
+        }
+        // NOTE: replay <origin> <identifier> <status identifier> で src の status を送り直す
+        if std::env::args().nth(1).as_deref() == Some("replay") {
+            let (Some(origin), Some(identifier), Some(src_identifier)) = (
+                std::env::args().nth(2),
+                std::env::args().nth(3),
+                std::env::args().nth(4),
+            ) else {
+                bail!("usage: replay <origin> <identifier> <status identifier>");
+            };
+            let src_account_key = AccountKey { origin, identifier };
+            return replay_status(open_database()?.as_ref(), &src_account_key, &src_identifier)
+                .await;
+        }
+        // NOTE: store.sqlite3 があれば SQLite を使う
+        let result = if std::path::Path::new("store.sqlite3").exists() {
+            app(database::Sqlite::open("store.sqlite3")?).await
+        } else {
+            app(database::File).await
+        };
+        // NOTE: node_exporter の textfile collector で読めるように書き出す
+        #[cfg(feature = "metrics")]
+        std::fs::write("metrics.prom", timelineecho::metrics::render())?;
+        result
     }
 }
 
@@ -71,7 +124,9 @@ mod lambda {
     use aws_lambda_events::event::cloudwatch_events::CloudWatchEvent;
     use lambda_runtime::{run, service_fn, LambdaEvent};
 
-    use crate::{app::app, database, default_subscriber_builder};
+    use timelineecho::{app::app, database};
+
+    use crate::default_subscriber_builder;
 
     pub fn init_tracing() {
         default_subscriber_builder("debug")
@@ -83,7 +138,10 @@ mod lambda {
     pub async fn function_handler(
         _event: LambdaEvent<CloudWatchEvent>,
     ) -> Result<(), lambda_runtime::Error> {
-        if let Err(err) = app(database::DynamoDB::new().await).await {
+        let result = app(database::DynamoDB::new().await).await;
+        #[cfg(feature = "metrics")]
+        tracing::info!("metrics:\n{}", timelineecho::metrics::render());
+        if let Err(err) = result {
             tracing::error!("{:?}", err);
             return Err(err.into());
         }
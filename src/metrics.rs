@@ -0,0 +1,70 @@
+#[cfg(feature = "metrics")]
+use std::{collections::BTreeMap, sync::Mutex};
+
+/** (名前, ラベル) ごとの値 */
+#[cfg(feature = "metrics")]
+static COUNTERS: Mutex<BTreeMap<(&'static str, String), u64>> = Mutex::new(BTreeMap::new());
+
+#[cfg(feature = "metrics")]
+fn to_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/** metrics の feature が無い場合は何もしない */
+#[inline]
+fn add(name: &'static str, labels: &[(&str, &str)], value: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        *COUNTERS
+            .lock()
+            .unwrap()
+            .entry((name, to_labels(labels)))
+            .or_default() += value;
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (name, labels, value);
+}
+
+/** result は succeeded、deferred、not_found、retry、failed のいずれか */
+pub fn operation(dst_origin: &str, kind: &str, result: &str) {
+    add(
+        "timelineecho_operations_total",
+        &[("origin", dst_origin), ("kind", kind), ("result", result)],
+        1,
+    );
+}
+
+pub fn uploaded_bytes(origin: &str, bytes: usize) {
+    add(
+        "timelineecho_uploaded_bytes_total",
+        &[("origin", origin)],
+        bytes as u64,
+    );
+}
+
+pub fn rate_limited(origin: &str) {
+    add("timelineecho_rate_limited_total", &[("origin", origin)], 1);
+}
+
+/** Prometheus のテキスト形式にする */
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    let counters = COUNTERS.lock().unwrap();
+    let mut text = String::new();
+    let mut last_name = None;
+    for ((name, labels), value) in counters.iter() {
+        if last_name != Some(name) {
+            text.push_str(&format!("# TYPE {} counter\n", name));
+            last_name = Some(name);
+        }
+        text.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+    text
+}
@@ -1,6 +1,11 @@
+mod create_like;
 mod create_post;
 mod create_repost;
+pub mod crosspost;
+mod delete_like;
 mod delete_post;
 mod delete_repost;
 pub mod destination;
+pub mod unlink;
+mod update_post;
 mod utils;
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use tracing::warn;
+
+use crate::{
+    config,
+    protocols::{at_proto_client::to_repost_target_identifier, Client},
+    store,
+};
+
+use super::utils::{insert_dst_status, resolve_created_at, DestinationIndex};
+
+pub async fn create_like(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    dst_client: &mut dyn Client,
+    operation: store::operations::CreateLikeOperation,
+    dst: &config::Destination,
+) -> Result<()> {
+    let target_dst_identifier = index
+        .find_post_dst_identifier(
+            &operation.account_pair,
+            &operation.status.target_src_identifier,
+        )
+        .or_else(|| {
+            index.find_post_dst_identifier_by_uri(
+                &operation.status.target_src_uri,
+                &operation.account_pair.dst_origin,
+            )
+        })
+        .map(str::to_owned)
+        // NOTE: Bluesky の投稿の URL なら、送っていない投稿も直接いいねできる
+        .or_else(|| to_repost_target_identifier(None, &operation.status.target_src_uri, None));
+    // NOTE: リポストと違い、リンクの投稿で代わりにはしない
+    let Some(target_dst_identifier) = target_dst_identifier else {
+        warn!(
+            "target_dst_identifier not found (target_src_identifier={})",
+            operation.status.target_src_identifier
+        );
+        return Ok(());
+    };
+    let dst_identifier = dst_client
+        .like(
+            &target_dst_identifier,
+            &resolve_created_at(
+                &operation.status.created_at,
+                operation.backfill,
+                dst.backdate,
+            ),
+        )
+        .await?;
+    insert_dst_status(
+        store,
+        index,
+        &operation.account_pair,
+        store::user::DestinationStatus::Like(store::user::DestinationLike {
+            identifier: dst_identifier,
+            src_identifier: operation.status.src_identifier,
+        }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::protocols::mock_client::MockClient;
+
+    use super::*;
+
+    fn bluesky() -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "atproto",
+            "origin": "https://bsky.social",
+            "identifier": "dst.bsky.social",
+            "password": "dst",
+        }))
+        .unwrap()
+    }
+
+    /** 送ったいいねの identifier を返す */
+    async fn like_on_bluesky(
+        store: &mut store::Store,
+        operation: store::operations::CreateLikeOperation,
+    ) -> Option<String> {
+        let mut index = DestinationIndex::new(&store.users);
+        create_like(
+            store,
+            &mut index,
+            &mut MockClient::default(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+        store.users[0].dsts[0]
+            .statuses
+            .iter()
+            .find_map(|status| match status {
+                store::user::DestinationStatus::Like(like) => {
+                    assert_eq!(like.src_identifier, "1");
+                    Some(like.identifier.clone())
+                }
+                _ => None,
+            })
+    }
+
+    #[tokio::test]
+    async fn mirrored_target_is_liked_by_its_dst_identifier() {
+        let mut store = store::Store::default();
+        insert_dst_status(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &store::operations::AccountPair::test(),
+            store::user::DestinationStatus::Post(store::user::DestinationPost {
+                identifier: "dst-target".into(),
+                src_identifier: "target".into(),
+                src_uri: "https://src.example.com/target".into(),
+                follow_up_identifiers: Vec::new(),
+            }),
+        );
+        let operation =
+            store::operations::CreateLikeOperation::test("1", "https://src.example.com/target");
+
+        let identifier = like_on_bluesky(&mut store, operation).await;
+
+        assert_eq!(identifier.as_deref(), Some("like-dst-target"));
+    }
+
+    #[tokio::test]
+    async fn bluesky_target_is_liked_by_uri() {
+        let mut store = store::Store::default();
+        let operation = store::operations::CreateLikeOperation::test(
+            "1",
+            "https://bsky.app/profile/did:plc:other/post/a",
+        );
+
+        let identifier = like_on_bluesky(&mut store, operation).await.unwrap();
+
+        let target: Value =
+            serde_json::from_str(identifier.strip_prefix("like-").unwrap()).unwrap();
+        assert_eq!(
+            target,
+            json!({ "uri": "at://did:plc:other/app.bsky.feed.post/a" })
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_target_is_not_liked() {
+        let mut store = store::Store::default();
+        let operation =
+            store::operations::CreateLikeOperation::test("1", "https://misskey.example/notes/a");
+        let client = MockClient::default();
+
+        create_like(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        // NOTE: リポストと違い、リンクの投稿で代わりにもしない
+        assert!(store.users.is_empty());
+        assert!(client.posts().is_empty());
+    }
+}
@@ -1,42 +1,1046 @@
 use anyhow::Result;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use tracing::{debug, trace, warn};
 
-use crate::{protocols::Client, store};
+use crate::{
+    config,
+    protocols::{
+        media::guess_content_type,
+        text::{append_link, strip_custom_emojis, strip_markdown},
+        Client, NewPost,
+    },
+    store::{self, operations::Facet::Link},
+};
 
-use super::utils::find_post_dst_identifier;
+use super::utils::{
+    insert_dst_status, resolve_created_at, update_dst_post_follow_ups, DestinationIndex,
+};
 
+/** 返信先が見つからないまま、これだけ後回しにしたら単独の投稿として送る */
+const MAX_REPLY_DEFERRALS: u32 = 5;
+/** Mastodon はこれより先の日時でないと予約できない */
+const MIN_SCHEDULE_AHEAD_MINUTES: i64 = 5;
+
+enum Schedule {
+    Now,
+    /** サーバーに予約させる */
+    Server(DateTime<FixedOffset>),
+    /** 日時になるまで後回しにする */
+    Defer,
+}
+
+/**
+ * schedule_delay_seconds から送る日時を決める
+ *
+ * 分けて送る投稿は予約した投稿に返信できないので、サーバーには予約させない
+ */
+fn to_schedule(
+    dst: &config::Destination,
+    status: &store::operations::CreatePostOperationStatus,
+    media_len: usize,
+) -> Schedule {
+    if dst.schedule_delay_seconds == 0 {
+        return Schedule::Now;
+    }
+    let scheduled_at = status.created_at + Duration::seconds(dst.schedule_delay_seconds as i64);
+    let now = Utc::now();
+    if scheduled_at.with_timezone(&Utc) <= now {
+        return Schedule::Now;
+    }
+    let can_schedule = matches!(dst.account, config::Account::Mastodon { .. })
+        && media_len <= dst.account.max_media()
+        && scheduled_at.with_timezone(&Utc) - now > Duration::minutes(MIN_SCHEDULE_AHEAD_MINUTES);
+    if can_schedule {
+        Schedule::Server(scheduled_at)
+    } else {
+        Schedule::Defer
+    }
+}
+
+/**
+ * テンプレートの {source_origin} と {source_url} を置き換える
+ *
+ * {source_url} の位置にはリンクの facet を付ける
+ */
+fn render_template(
+    template: &str,
+    src_origin: &str,
+    src_uri: &str,
+) -> (String, Vec<store::operations::Facet>) {
+    let mut text = String::new();
+    let mut facets = Vec::new();
+    for (i, part) in template.split("{source_url}").enumerate() {
+        if i > 0 {
+            let start = text.len();
+            text.push_str(src_uri);
+            facets.push(Link {
+                byte_slice: start as u32..text.len() as u32,
+                uri: src_uri.to_owned(),
+            });
+        }
+        text.push_str(&part.replace("{source_origin}", src_origin));
+    }
+    (text, facets)
+}
+
+fn shift_facets(
+    facets: &[store::operations::Facet],
+    offset: usize,
+) -> impl Iterator<Item = store::operations::Facet> + '_ {
+    let offset = offset as u32;
+    facets.iter().map(move |facet| match facet {
+        Link { byte_slice, uri } => Link {
+            byte_slice: byte_slice.start + offset..byte_slice.end + offset,
+            uri: uri.clone(),
+        },
+    })
+}
+
+fn to_body(
+    dst: &config::Destination,
+    content: &str,
+    facets: &[store::operations::Facet],
+    custom_emojis: &[String],
+    quote_uri: Option<&str>,
+) -> (String, Vec<store::operations::Facet>) {
+    let (content, facets) = if dst.keeps_custom_emojis() {
+        (content.to_owned(), facets.to_vec())
+    } else {
+        strip_custom_emojis(content, facets, custom_emojis)
+    };
+    let (content, facets) = match quote_uri {
+        Some(quote_uri) => append_link(&content, &facets, quote_uri),
+        None => (content, facets),
+    };
+    if dst.keeps_markdown() {
+        return (content, facets);
+    }
+    strip_markdown(&content, &facets)
+}
+
+/**
+ * 本文の前後に送信先ごとのテンプレートを付け、facet の位置をずらす
+ *
+ * quote_uri があれば、本文の末尾に引用元へのリンクとして付ける。
+ * 更新でも同じ本文になるように、投稿と更新のどちらもこれを通す
+ */
+pub fn apply_templates(
+    dst: &config::Destination,
+    src_origin: &str,
+    src_uri: &str,
+    content: &str,
+    facets: &[store::operations::Facet],
+    custom_emojis: &[String],
+    quote_uri: Option<&str>,
+) -> (String, Vec<store::operations::Facet>) {
+    let footer = dst.footer.as_ref().map(|footer| format!("\n\n{}", footer));
+    let render = |template: Option<&str>| {
+        template
+            .map(|template| render_template(template, src_origin, src_uri))
+            .unwrap_or_default()
+    };
+    let mut rendered = String::new();
+    let mut rendered_facets = Vec::new();
+    for (text, text_facets) in [
+        render(dst.prefix.as_deref()),
+        to_body(dst, content, facets, custom_emojis, quote_uri),
+        render(dst.suffix.as_deref()),
+        render(footer.as_deref()),
+    ] {
+        rendered_facets.extend(shift_facets(&text_facets, rendered.len()));
+        rendered.push_str(&text);
+    }
+    (rendered, rendered_facets)
+}
+
+/**
+ * 返信先の src の identifier を、送信先に送った投稿の identifier に変換する
+ *
+ * 送信先のプロトコルに関わらずここで変換するので、返信先の投稿を送ってあれば
+ * Mastodon の返信も Bluesky などの送信先でスレッドになる。
+ * 返信先を分けて送っていた場合は、つなげた最後の投稿に返信する
+ */
+fn to_reply_identifier<'a>(
+    index: &'a DestinationIndex,
+    operation: &store::operations::CreatePostOperation,
+) -> Option<&'a str> {
+    let reply = operation.status.reply_src_identifier.as_deref()?;
+    let account_pair = &operation.account_pair;
+    let dst_identifier = index.find_post_dst_identifier(account_pair, reply)?;
+    let follow_up_identifiers = index.find_post_follow_up_identifiers(account_pair, reply);
+    Some(
+        follow_up_identifiers
+            .last()
+            .map_or(dst_identifier, String::as_str),
+    )
+}
+
+/** 引用元を送ってあり、送信先で引用できる場合は、引用する送信先の投稿の identifier を返す */
+pub fn to_quote_identifier<'a>(
+    index: &'a DestinationIndex,
+    account_pair: &store::operations::AccountPair,
+    quote: Option<&store::operations::Quote>,
+    dst: &config::Destination,
+) -> Option<&'a str> {
+    let quote = quote?;
+    if !dst.account.can_quote() {
+        return None;
+    }
+    index.find_post_dst_identifier(account_pair, &quote.src_identifier)
+}
+
+/** 引用できない場合に、代わりに本文の末尾に付ける引用元の URL */
+pub fn to_quote_uri<'a>(
+    quote: Option<&'a store::operations::Quote>,
+    quote_identifier: Option<&str>,
+) -> Option<&'a str> {
+    match (quote, quote_identifier) {
+        (Some(quote), None) => Some(quote.src_uri.as_str()),
+        _ => None,
+    }
+}
+
+/**
+ * allowed_media に当てはまらないメディアを取り除く
+ *
+ * 取得元の content-type を使い、無ければ URL から推測する。
+ * どちらでも分からないものは、許可していない種類かもしれないので送らない
+ */
+fn filter_allowed_media(
+    allowed_media: &[String],
+    media: Vec<store::operations::Medium>,
+) -> Vec<store::operations::Medium> {
+    if allowed_media.is_empty() {
+        return media;
+    }
+    media
+        .into_iter()
+        .filter(|medium| {
+            let Some(content_type) = medium
+                .content_type
+                .as_deref()
+                .or_else(|| guess_content_type(&medium.url))
+            else {
+                debug!("media type is unknown, skipped: {}", medium.url);
+                return false;
+            };
+            let allowed = allowed_media
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix.as_str()));
+            if !allowed {
+                debug!("media not allowed, skipped: {}", medium.url);
+            }
+            allowed
+        })
+        .collect()
+}
+
+/** 投稿ごと送らない場合は None を返す */
+fn apply_require_alt(
+    require_alt: Option<&config::RequireAlt>,
+    media: Vec<store::operations::Medium>,
+) -> Option<Vec<store::operations::Medium>> {
+    let Some(require_alt) = require_alt else {
+        return Some(media);
+    };
+    if media.iter().all(|medium| !medium.alt.is_empty()) {
+        return Some(media);
+    }
+    match require_alt {
+        config::RequireAlt::SkipImage => Some(
+            media
+                .into_iter()
+                .filter(|medium| !medium.alt.is_empty())
+                .collect(),
+        ),
+        config::RequireAlt::SkipPost => None,
+        config::RequireAlt::Placeholder(placeholder) => Some(
+            media
+                .into_iter()
+                .map(|mut medium| {
+                    if medium.alt.is_empty() {
+                        medium.alt = placeholder.clone();
+                    }
+                    medium
+                })
+                .collect(),
+        ),
+    }
+}
+
+/**
+ * 送信先に付けられる数ごとにメディアを分ける
+ *
+ * 先頭は元の投稿に、残りは返信としてつなげる投稿に付ける。メディアが無くても 1 つは返す
+ */
+fn split_media(
+    dst: &config::Destination,
+    mut media: Vec<store::operations::Medium>,
+) -> Vec<Vec<store::operations::Medium>> {
+    let max_media = dst.account.max_media();
+    if media.len() <= max_media {
+        return vec![media];
+    }
+    match dst.media_overflow {
+        config::MediaOverflow::Truncate => {
+            debug!(
+                "too many media, truncated: {} -> {}",
+                media.len(),
+                max_media
+            );
+            media.truncate(max_media);
+            vec![media]
+        }
+        config::MediaOverflow::Split => media
+            .chunks(max_media)
+            .map(|chunk| chunk.to_vec())
+            .collect(),
+    }
+}
+
+/**
+ * 返信先がまだ送られていない場合や、代替テキストの無い画像があって送らない場合は、
+ * 送らずに後回しにした operation を返す
+ */
 pub async fn create_post(
     store: &mut store::Store,
+    index: &mut DestinationIndex,
     dst_client: &mut dyn Client,
-    operation: store::operations::CreatePostOperation,
-) -> Result<()> {
-    let reply_identifier = operation.status.reply_src_identifier.and_then(|reply| {
-        find_post_dst_identifier(
-            &store.users,
-            &operation.account_pair.src_origin,
-            &reply,
-            &operation.account_pair.dst_origin,
-        )
-    });
-    let dst_identifier = dst_client
-        .post(
-            &operation.status.content,
-            &operation.status.facets,
-            reply_identifier,
-            operation.status.media,
-            operation.status.external,
-            &operation.status.created_at,
-        )
-        .await?;
-    store
-        .get_or_create_dst_mut(&operation.account_pair)
-        .statuses
-        .insert(
-            0,
-            store::user::DestinationStatus::Post(store::user::DestinationPost {
-                identifier: dst_identifier,
-                src_identifier: operation.status.src_identifier,
-                src_uri: operation.status.src_uri,
-            }),
-        );
-    Ok(())
+    mut operation: store::operations::CreatePostOperation,
+    dst: &config::Destination,
+) -> Result<Option<store::operations::CreatePostOperation>> {
+    let reply_identifier = to_reply_identifier(index, &operation);
+    if let (Some(reply), None) = (&operation.status.reply_src_identifier, reply_identifier) {
+        if operation.status.reply_deferrals < MAX_REPLY_DEFERRALS {
+            debug!("reply target is not posted yet, deferred: {}", reply);
+            operation.status.reply_deferrals += 1;
+            return Ok(Some(operation));
+        }
+        warn!("reply target not found, post without reply: {}", reply);
+    }
+    // NOTE: 代替テキストを付けてもらうまで送らない。毎回後回しになる
+    let media = filter_allowed_media(&dst.allowed_media, operation.status.media.clone());
+    let Some(images) = apply_require_alt(dst.require_alt.as_ref(), media) else {
+        warn!(
+            "image without alt text, deferred: {}",
+            operation.status.src_uri
+        );
+        return Ok(Some(operation));
+    };
+    let scheduled_at = match to_schedule(dst, &operation.status, images.len()) {
+        Schedule::Now => None,
+        Schedule::Server(scheduled_at) => Some(scheduled_at),
+        Schedule::Defer => {
+            trace!(
+                "not scheduled time yet, deferred: {}",
+                operation.status.src_uri
+            );
+            return Ok(Some(operation));
+        }
+    };
+    // NOTE: 引用できない場合は、引用元へのリンクを付けて普通の投稿にする
+    let quote_identifier = to_quote_identifier(
+        index,
+        &operation.account_pair,
+        operation.status.quote.as_ref(),
+        dst,
+    );
+    let quote_uri = to_quote_uri(operation.status.quote.as_ref(), quote_identifier);
+    let (content, facets) = apply_templates(
+        dst,
+        &operation.account_pair.src_origin,
+        &operation.status.src_uri,
+        &operation.status.content,
+        &operation.status.facets,
+        &operation.status.custom_emojis,
+        quote_uri,
+    );
+    let created_at = resolve_created_at(
+        &operation.status.created_at,
+        operation.backfill,
+        dst.backdate,
+    );
+    let mut texts = dst_client.split_content(&content, &facets).into_iter();
+    let mut media_chunks = split_media(dst, images).into_iter();
+    let (first_content, first_facets) = texts.next().unwrap_or_default();
+    let first_images = media_chunks.next().unwrap_or_default();
+    let account_pair = operation.account_pair.clone();
+    let src_identifier = operation.status.src_identifier.clone();
+    // NOTE: 前回分けて送る途中で失敗していれば、送れた分は飛ばして続きから送る
+    let sent = index
+        .find_post_dst_identifier(&account_pair, &src_identifier)
+        .map(|dst_identifier| {
+            (
+                dst_identifier.to_owned(),
+                index
+                    .find_post_follow_up_identifiers(&account_pair, &src_identifier)
+                    .to_vec(),
+            )
+        });
+    let (dst_identifier, mut follow_up_identifiers) = match sent {
+        Some(sent) => {
+            debug!(
+                "already posted, resume follow-ups: {}",
+                operation.status.src_uri
+            );
+            sent
+        }
+        None => {
+            let dst_identifier = dst_client
+                .post(NewPost {
+                    content: &first_content,
+                    facets: &first_facets,
+                    reply_identifier,
+                    images: first_images,
+                    external: operation.status.external,
+                    content_warning: operation.status.content_warning.as_deref(),
+                    poll: operation.status.poll.as_ref(),
+                    quote_identifier,
+                    src_uri: dst
+                        .append_src_uri
+                        .then_some(operation.status.src_uri.as_str()),
+                    idempotency_key: &operation.status.src_uri,
+                    created_at: &created_at,
+                    scheduled_at: scheduled_at.as_ref(),
+                })
+                .await?;
+            insert_dst_status(
+                store,
+                index,
+                &account_pair,
+                store::user::DestinationStatus::Post(store::user::DestinationPost {
+                    identifier: dst_identifier.clone(),
+                    src_identifier: src_identifier.clone(),
+                    src_uri: operation.status.src_uri.clone(),
+                    follow_up_identifiers: Vec::new(),
+                }),
+            );
+            (dst_identifier, Vec::new())
+        }
+    };
+    // NOTE: 残りの本文とメディアは、直前の投稿への返信として送る
+    let follow_ups: Vec<_> = texts
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(media_chunks.map(Some).chain(std::iter::repeat(None)))
+        .take_while(|follow_up| !matches!(follow_up, (None, None)))
+        .collect();
+    for (i, (text, images)) in follow_ups
+        .into_iter()
+        .enumerate()
+        .skip(follow_up_identifiers.len())
+    {
+        let (content, facets) = text.unwrap_or_default();
+        let reply_identifier = follow_up_identifiers.last().unwrap_or(&dst_identifier);
+        let follow_up_identifier = dst_client
+            .post(NewPost {
+                content: &content,
+                facets: &facets,
+                reply_identifier: Some(reply_identifier.as_str()),
+                images: images.unwrap_or_default(),
+                external: None,
+                content_warning: operation.status.content_warning.as_deref(),
+                poll: None,
+                quote_identifier: None,
+                src_uri: None,
+                idempotency_key: &format!("{}#{}", operation.status.src_uri, i + 1),
+                created_at: &created_at,
+                scheduled_at: None,
+            })
+            .await?;
+        follow_up_identifiers.push(follow_up_identifier);
+        update_dst_post_follow_ups(
+            store,
+            index,
+            &account_pair,
+            &src_identifier,
+            &follow_up_identifiers,
+        );
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::protocols::{
+        mock_client::{MockClient, MockPost},
+        twitter_client::split_into_thread,
+    };
+
+    use super::*;
+
+    fn twitter() -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "twitter",
+            "apiKey": "",
+            "apiKeySecret": "",
+            "accessToken": "",
+            "accessTokenSecret": "",
+        }))
+        .unwrap()
+    }
+
+    fn dst_post(store: &store::Store) -> store::user::DestinationPost {
+        match &store.users[0].dsts[0].statuses[..] {
+            [store::user::DestinationStatus::Post(post)] => post.clone(),
+            _ => panic!("dst post not found"),
+        }
+    }
+
+    fn replies(posts: &[MockPost]) -> Vec<Option<&str>> {
+        posts
+            .iter()
+            .map(|post| post.reply_identifier.as_deref())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn long_post_is_chained_as_thread() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        client.state.lock().unwrap().split_content = Some(split_into_thread);
+        let content = "word ".repeat(120);
+        let operation = store::operations::CreatePostOperation::test("1", content.trim_end());
+
+        let deferred = create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_none());
+        let posts = client.posts();
+        assert_eq!(posts.len(), 3);
+        assert!(posts[0].content.ends_with(" (1/3)"));
+        assert!(posts[2].content.ends_with(" (3/3)"));
+        assert_eq!(replies(&posts), [None, Some("post-1"), Some("post-2")]);
+        let dst_post = dst_post(&store);
+        assert_eq!(dst_post.identifier, "post-1");
+        assert_eq!(dst_post.follow_up_identifiers, ["post-2", "post-3"]);
+    }
+
+    #[tokio::test]
+    async fn reply_is_deferred_until_parent_is_posted() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        let parent = store::operations::CreatePostOperation::test("1", "parent");
+        let mut reply = store::operations::CreatePostOperation::test("2", "reply");
+        reply.status.reply_src_identifier = Some("1".into());
+
+        let deferred = create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            reply,
+            &twitter(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(deferred.status.reply_deferrals, 1);
+        assert!(client.posts().is_empty());
+
+        create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            parent,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+        let deferred = create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            deferred,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_none());
+        assert_eq!(replies(&client.posts()), [None, Some("post-1")]);
+    }
+
+    #[tokio::test]
+    async fn reply_is_posted_alone_after_max_deferrals() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        let mut reply = store::operations::CreatePostOperation::test("2", "reply");
+        reply.status.reply_src_identifier = Some("1".into());
+        reply.status.reply_deferrals = MAX_REPLY_DEFERRALS;
+
+        let deferred = create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            reply,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_none());
+        assert_eq!(replies(&client.posts()), [None]);
+    }
+
+    #[tokio::test]
+    async fn failed_thread_is_resumed() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        client.state.lock().unwrap().split_content = Some(split_into_thread);
+        client.state.lock().unwrap().fail_after = Some(2);
+        let content = "word ".repeat(120);
+        let operation = store::operations::CreatePostOperation::test("1", content.trim_end());
+
+        let result = create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation.clone(),
+            &twitter(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(dst_post(&store).follow_up_identifiers, ["post-2"]);
+
+        client.state.lock().unwrap().fail_after = None;
+        create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+
+        let posts = client.posts();
+        assert_eq!(posts.len(), 3);
+        assert!(posts[2].content.ends_with(" (3/3)"));
+        assert_eq!(posts[2].reply_identifier.as_deref(), Some("post-2"));
+        assert_eq!(dst_post(&store).follow_up_identifiers, ["post-2", "post-3"]);
+    }
+
+    #[tokio::test]
+    async fn reply_is_attached_to_last_follow_up() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        client.state.lock().unwrap().split_content = Some(split_into_thread);
+        let content = "word ".repeat(120);
+        let operation = store::operations::CreatePostOperation::test("1", content.trim_end());
+        create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+        let mut reply = store::operations::CreatePostOperation::test("2", "reply");
+        reply.status.reply_src_identifier = Some("1".into());
+
+        create_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            reply,
+            &twitter(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            client.posts()[3].reply_identifier.as_deref(),
+            Some("post-3")
+        );
+    }
+
+    #[test]
+    fn prefix_shifts_link_facets() {
+        let dst: config::Destination = serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "prefix": "[{source_origin}] ",
+            "footer": "via {source_url}",
+        }))
+        .unwrap();
+        let content = "日本語 https://example.com/";
+        let facets = [Link {
+            byte_slice: 10..30,
+            uri: "https://example.com/".into(),
+        }];
+
+        let (text, facets) = apply_templates(
+            &dst,
+            "https://src.example.com",
+            "https://src.example.com/1",
+            content,
+            &facets,
+            &[],
+            None,
+        );
+
+        assert_eq!(
+            text,
+            "[https://src.example.com] 日本語 https://example.com/\n\nvia https://src.example.com/1"
+        );
+        let uris: Vec<_> = facets
+            .iter()
+            .map(|facet| match facet {
+                Link { byte_slice, uri } => {
+                    assert_eq!(
+                        &text[byte_slice.start as usize..byte_slice.end as usize],
+                        uri
+                    );
+                    uri.as_str()
+                }
+            })
+            .collect();
+        assert_eq!(uris, ["https://example.com/", "https://src.example.com/1"]);
+    }
+
+    fn dst(protocol: &str, keep_custom_emojis: Option<bool>) -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": protocol,
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "keepCustomEmojis": keep_custom_emojis,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn custom_emojis_are_stripped_or_passed_through() {
+        let content = ":blobcat: https://example.com/";
+        let facets = [Link {
+            byte_slice: 10..30,
+            uri: "https://example.com/".into(),
+        }];
+        let custom_emojis = ["blobcat".to_owned()];
+
+        let (text, stripped_facets) = to_body(
+            &dst("mastodon", None),
+            content,
+            &facets,
+            &custom_emojis,
+            None,
+        );
+        assert_eq!(text, " https://example.com/");
+        let [Link { byte_slice, .. }] = stripped_facets.as_slice() else {
+            panic!("unexpected facets");
+        };
+        assert_eq!(byte_slice, &(1..21));
+
+        let (text, kept_facets) = to_body(
+            &dst("misskey", None),
+            content,
+            &facets,
+            &custom_emojis,
+            None,
+        );
+        assert_eq!(text, content);
+        let [Link { byte_slice, .. }] = kept_facets.as_slice() else {
+            panic!("unexpected facets");
+        };
+        assert_eq!(byte_slice, &(10..30));
+
+        let (text, _) = to_body(
+            &dst("mastodon", Some(true)),
+            content,
+            &facets,
+            &custom_emojis,
+            None,
+        );
+        assert_eq!(text, content);
+    }
+
+    fn bluesky_dst(keep_markdown: Option<bool>) -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "atproto",
+            "origin": "https://bsky.social",
+            "identifier": "dst.bsky.social",
+            "password": "dst",
+            "keepMarkdown": keep_markdown,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn bold_is_stripped_for_bluesky_with_shifted_link() {
+        let content = "**bold** https://example.com/";
+        let facets = [Link {
+            byte_slice: 9..29,
+            uri: "https://example.com/".into(),
+        }];
+
+        let (text, stripped_facets) = to_body(&bluesky_dst(None), content, &facets, &[], None);
+        assert_eq!(text, "bold https://example.com/");
+        let [Link { byte_slice, .. }] = stripped_facets.as_slice() else {
+            panic!("unexpected facets");
+        };
+        assert_eq!(byte_slice, &(5..25));
+
+        let (text, _) = to_body(&dst("mastodon", None), content, &facets, &[], None);
+        assert_eq!(text, content);
+        let (text, _) = to_body(&bluesky_dst(Some(true)), content, &facets, &[], None);
+        assert_eq!(text, content);
+    }
+
+    fn medium(url: &str, alt: &str) -> store::operations::Medium {
+        store::operations::Medium {
+            url: url.into(),
+            alt: alt.into(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }
+    }
+
+    fn alts(media: &[store::operations::Medium]) -> Vec<&str> {
+        media.iter().map(|medium| medium.alt.as_str()).collect()
+    }
+
+    #[test]
+    fn each_require_alt_mode_handles_uncaptioned_media() {
+        let media = vec![
+            medium("https://src.example.com/1.png", "captioned"),
+            medium("https://src.example.com/2.png", ""),
+        ];
+
+        let kept = apply_require_alt(None, media.clone()).unwrap();
+        assert_eq!(alts(&kept), ["captioned", ""]);
+        let skipped = apply_require_alt(Some(&config::RequireAlt::SkipImage), media.clone());
+        assert_eq!(alts(&skipped.unwrap()), ["captioned"]);
+        assert!(apply_require_alt(Some(&config::RequireAlt::SkipPost), media.clone()).is_none());
+        let filled = apply_require_alt(
+            Some(&config::RequireAlt::Placeholder("no alt".into())),
+            media,
+        );
+        assert_eq!(alts(&filled.unwrap()), ["captioned", "no alt"]);
+    }
+
+    #[tokio::test]
+    async fn post_with_uncaptioned_image_is_deferred_by_skip_post() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        let mut operation = store::operations::CreatePostOperation::test("1", "hello");
+        operation.status.media = vec![
+            medium("https://src.example.com/1.png", "captioned"),
+            medium("https://src.example.com/2.png", ""),
+        ];
+        let dst: config::Destination = serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "requireAlt": "skipPost",
+        }))
+        .unwrap();
+
+        let deferred = create_post(&mut store, &mut index, &mut client.clone(), operation, &dst)
+            .await
+            .unwrap();
+
+        assert!(deferred.is_some());
+        assert!(client.posts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mastodon_reply_threads_onto_mirrored_bluesky_parent() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        let parent = store::operations::CreatePostOperation::test("1", "parent");
+        let mut reply = store::operations::CreatePostOperation::test("2", "reply");
+        reply.status.reply_src_identifier = Some("1".into());
+
+        for operation in [parent, reply] {
+            create_post(
+                &mut store,
+                &mut index,
+                &mut client.clone(),
+                operation,
+                &bluesky_dst(None),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(replies(&client.posts()), [None, Some("post-1")]);
+    }
+
+    #[tokio::test]
+    async fn image_only_allowlist_drops_video_and_posts_the_rest() {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let client = MockClient::default();
+        let mut image_and_video = store::operations::CreatePostOperation::test("1", "hello");
+        image_and_video.status.media = vec![
+            medium("https://src.example.com/1.png", "image"),
+            medium("https://src.example.com/2.mp4", "video"),
+        ];
+        let mut video_only = store::operations::CreatePostOperation::test("2", "hello");
+        video_only.status.media = vec![medium("https://src.example.com/3.mp4", "video")];
+        let dst: config::Destination = serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "allowedMedia": ["image/"],
+        }))
+        .unwrap();
+
+        for operation in [image_and_video, video_only] {
+            let deferred =
+                create_post(&mut store, &mut index, &mut client.clone(), operation, &dst)
+                    .await
+                    .unwrap();
+            assert!(deferred.is_none());
+        }
+
+        let media_lens: Vec<_> = client.posts().iter().map(|post| post.media_len).collect();
+        assert_eq!(media_lens, [1, 0]);
+    }
+
+    #[test]
+    fn allowlist_prefers_source_content_type_and_drops_unknown() {
+        let allowed_media = vec!["image/".to_owned()];
+        let mut video_with_image_extension = medium("https://src.example.com/1.png", "video");
+        video_with_image_extension.content_type = Some("video/mp4".into());
+        let mut image_without_extension = medium("https://src.example.com/2", "image");
+        image_without_extension.content_type = Some("image/".into());
+        let unknown = medium("https://src.example.com/3", "unknown");
+
+        let media = filter_allowed_media(
+            &allowed_media,
+            vec![video_with_image_extension, image_without_extension, unknown],
+        );
+
+        assert_eq!(alts(&media), ["image"]);
+    }
+
+    /** Mastodon は 1 つの投稿に 4 つまで */
+    async fn post_six_images(media_overflow: &str) -> (store::Store, Vec<MockPost>) {
+        let mut store = store::Store::default();
+        let client = MockClient::default();
+        let mut operation = store::operations::CreatePostOperation::test("1", "hello");
+        operation.status.media = (1..=6)
+            .map(|i| medium(&format!("https://src.example.com/{}.png", i), "alt"))
+            .collect();
+        let dst: config::Destination = serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "mediaOverflow": media_overflow,
+        }))
+        .unwrap();
+
+        let deferred = create_post(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &mut client.clone(),
+            operation,
+            &dst,
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_none());
+        (store, client.posts())
+    }
+
+    #[tokio::test]
+    async fn media_over_limit_are_truncated() {
+        let (store, posts) = post_six_images("truncate").await;
+
+        let media_lens: Vec<_> = posts.iter().map(|post| post.media_len).collect();
+        assert_eq!(media_lens, [4]);
+        assert!(dst_post(&store).follow_up_identifiers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn media_over_limit_are_split_into_thread() {
+        let (store, posts) = post_six_images("split").await;
+
+        let media_lens: Vec<_> = posts.iter().map(|post| post.media_len).collect();
+        assert_eq!(media_lens, [4, 2]);
+        assert_eq!(posts[0].content, "hello");
+        assert_eq!(posts[1].content, "");
+        assert_eq!(replies(&posts), [None, Some("post-1")]);
+        assert_eq!(dst_post(&store).follow_up_identifiers, ["post-2"]);
+    }
+
+    fn delayed_dst(protocol: &str) -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": protocol,
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "identifier": "dst",
+            "password": "dst",
+            "scheduleDelaySeconds": 3600,
+        }))
+        .unwrap()
+    }
+
+    fn just_posted() -> store::operations::CreatePostOperation {
+        let mut operation = store::operations::CreatePostOperation::test("1", "hello");
+        operation.status.created_at = Utc::now().fixed_offset();
+        operation
+    }
+
+    #[tokio::test]
+    async fn delayed_mastodon_post_is_scheduled_on_server() {
+        let mut store = store::Store::default();
+        let client = MockClient::default();
+        let operation = just_posted();
+        let expected = operation.status.created_at + Duration::seconds(3600);
+
+        let deferred = create_post(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &mut client.clone(),
+            operation,
+            &delayed_dst("mastodon"),
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_none());
+        let posts = client.posts();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].scheduled_at, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn delayed_post_is_deferred_elsewhere() {
+        let mut store = store::Store::default();
+        let client = MockClient::default();
+
+        let deferred = create_post(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &mut client.clone(),
+            just_posted(),
+            &delayed_dst("atproto"),
+        )
+        .await
+        .unwrap();
+
+        assert!(deferred.is_some());
+        assert!(client.posts().is_empty());
+    }
 }
@@ -1,44 +1,212 @@
 use anyhow::Result;
 use tracing::warn;
 
-use crate::{protocols::Client, store};
+use crate::{
+    config,
+    protocols::{at_proto_client::to_repost_target_identifier, Client, NewPost},
+    store::{self, operations::Facet::Link},
+};
 
-use super::utils::{find_post_dst_identifier, find_post_dst_identifier_by_uri};
+use super::utils::{insert_dst_status, resolve_created_at, DestinationIndex};
+
+async fn post_link(
+    dst_client: &mut dyn Client,
+    operation: &store::operations::CreateRepostOperation,
+    dst: &config::Destination,
+) -> Result<String> {
+    let uri = &operation.status.target_src_uri;
+    Ok(dst_client
+        .post(NewPost {
+            content: uri,
+            facets: &[Link {
+                byte_slice: 0..uri.len() as u32,
+                uri: uri.clone(),
+            }],
+            reply_identifier: None,
+            images: Vec::new(),
+            external: None,
+            content_warning: None,
+            poll: None,
+            quote_identifier: None,
+            src_uri: None,
+            idempotency_key: uri,
+            created_at: &resolve_created_at(
+                &operation.status.created_at,
+                operation.backfill,
+                dst.backdate,
+            ),
+            scheduled_at: None,
+        })
+        .await?)
+}
 
 pub async fn create_repost(
     store: &mut store::Store,
+    index: &mut DestinationIndex,
     dst_client: &mut dyn Client,
     operation: store::operations::CreateRepostOperation,
+    dst: &config::Destination,
 ) -> Result<()> {
-    let target_dst_identifier = find_post_dst_identifier(
-        &store.users,
-        &operation.account_pair.src_origin,
-        &operation.status.target_src_identifier,
-        &operation.account_pair.dst_origin,
-    )
-    .or_else(|| {
-        find_post_dst_identifier_by_uri(
-            &store.users,
-            &operation.status.target_src_uri,
-            &operation.account_pair.dst_origin,
+    let target_dst_identifier = index
+        .find_post_dst_identifier(
+            &operation.account_pair,
+            &operation.status.target_src_identifier,
         )
-    });
-    let Some(target_dst_identifier) = target_dst_identifier else {
-        warn!("target_dst_identifier not found (target_src_identifier={})", operation.status.target_src_identifier);
-        return Ok(());
-    };
-    let dst_identifier = dst_client
-        .repost(target_dst_identifier, &operation.status.created_at)
-        .await?;
-    store
-        .get_or_create_dst_mut(&operation.account_pair)
-        .statuses
-        .insert(
-            0,
+        .or_else(|| {
+            index.find_post_dst_identifier_by_uri(
+                &operation.status.target_src_uri,
+                &operation.account_pair.dst_origin,
+            )
+        })
+        .map(str::to_owned)
+        .or_else(|| {
+            // NOTE: Bluesky の投稿なら、送っていない他人の投稿も直接リポストできる
+            if !matches!(dst.account, config::Account::AtProtocol { .. }) {
+                return None;
+            }
+            to_repost_target_identifier(
+                operation.status.target_src_at_uri.as_deref(),
+                &operation.status.target_src_uri,
+                operation.status.target_src_cid.as_deref(),
+            )
+        });
+    // NOTE: Bluesky に無い投稿はリポストできないので、リンクの投稿で代わりにする
+    if target_dst_identifier.is_none() && matches!(dst.account, config::Account::AtProtocol { .. })
+    {
+        let dst_identifier = post_link(dst_client, &operation, dst).await?;
+        insert_dst_status(
+            store,
+            index,
+            &operation.account_pair,
             store::user::DestinationStatus::Repost(store::user::DestinationRepost {
                 identifier: dst_identifier,
                 src_identifier: operation.status.src_identifier,
             }),
         );
+        return Ok(());
+    }
+    let Some(target_dst_identifier) = target_dst_identifier else {
+        warn!(
+            "target_dst_identifier not found (target_src_identifier={})",
+            operation.status.target_src_identifier
+        );
+        return Ok(());
+    };
+    let dst_identifier = dst_client
+        .repost(
+            &target_dst_identifier,
+            &resolve_created_at(
+                &operation.status.created_at,
+                operation.backfill,
+                dst.backdate,
+            ),
+        )
+        .await?;
+    insert_dst_status(
+        store,
+        index,
+        &operation.account_pair,
+        store::user::DestinationStatus::Repost(store::user::DestinationRepost {
+            identifier: dst_identifier,
+            src_identifier: operation.status.src_identifier,
+        }),
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::protocols::mock_client::MockClient;
+
+    use super::*;
+
+    fn bluesky() -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "atproto",
+            "origin": "https://bsky.social",
+            "identifier": "dst.bsky.social",
+            "password": "dst",
+        }))
+        .unwrap()
+    }
+
+    async fn repost_to_bluesky(
+        client: &MockClient,
+        operation: store::operations::CreateRepostOperation,
+    ) -> store::user::DestinationRepost {
+        let mut store = store::Store::default();
+        create_repost(
+            &mut store,
+            &mut DestinationIndex::default(),
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+        match &store.users[0].dsts[0].statuses[..] {
+            [store::user::DestinationStatus::Repost(repost)] => repost.clone(),
+            _ => panic!("dst repost not found"),
+        }
+    }
+
+    /** MockClient の repost が返す identifier から、渡された対象を取り出す */
+    fn repost_target(repost: &store::user::DestinationRepost) -> Value {
+        serde_json::from_str(repost.identifier.strip_prefix("repost-").unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn bluesky_target_with_cid_is_reposted_directly() {
+        let client = MockClient::default();
+        let mut operation = store::operations::CreateRepostOperation::test(
+            "1",
+            "https://bsky.app/profile/did:plc:other/post/a",
+        );
+        operation.status.target_src_at_uri = Some("at://did:plc:other/app.bsky.feed.post/a".into());
+        operation.status.target_src_cid = Some("cid".into());
+
+        let repost = repost_to_bluesky(&client, operation).await;
+
+        assert_eq!(
+            repost_target(&repost),
+            json!({ "uri": "at://did:plc:other/app.bsky.feed.post/a", "cid": "cid" })
+        );
+        assert!(client.posts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bluesky_target_without_cid_is_reposted_by_uri() {
+        let client = MockClient::default();
+        let operation = store::operations::CreateRepostOperation::test(
+            "1",
+            "https://bsky.app/profile/other.bsky.social/post/a",
+        );
+
+        let repost = repost_to_bluesky(&client, operation).await;
+
+        // NOTE: cid は Client の repost で引く
+        assert_eq!(
+            repost_target(&repost),
+            json!({ "uri": "at://other.bsky.social/app.bsky.feed.post/a" })
+        );
+        assert!(client.posts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn target_outside_bluesky_falls_back_to_link_post() {
+        let client = MockClient::default();
+        let operation =
+            store::operations::CreateRepostOperation::test("1", "https://misskey.example/notes/a");
+
+        let repost = repost_to_bluesky(&client, operation).await;
+
+        assert_eq!(repost.identifier, "post-1");
+        let posts = client.posts();
+        let [post] = posts.as_slice() else {
+            panic!("unexpected posts");
+        };
+        assert_eq!(post.content, "https://misskey.example/notes/a");
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::{
+    config,
+    http::HttpConfig,
+    protocols::{create_client, retry::RetryPolicy, text::append_link, Client, NewPost},
+    rate_limit::Budget,
+    sources::source::{LiveExternal, LivePost},
+    store,
+};
+
+/**
+ * 1 つの投稿を各アカウントに送り、送信先ごとの identifier を返す
+ *
+ * Store を使わないので、返信先の解決や後からの更新・削除はできない
+ */
+pub async fn crosspost_one(
+    http_client: Arc<reqwest::Client>,
+    src_post: LivePost,
+    dsts: &[config::Account],
+) -> Result<Vec<String>> {
+    // NOTE: 認証に失敗するアカウントがあれば、どこにも送らずに終わる
+    let mut dst_clients = Vec::new();
+    for dst in dsts {
+        dst_clients.push(
+            create_client(
+                http_client.clone(),
+                dst,
+                None,
+                &RetryPolicy::default(),
+                HttpConfig::default().max_media_bytes,
+                Budget::default(),
+            )
+            .await?,
+        );
+    }
+    post_to_all(&src_post, dst_clients).await
+}
+
+/**
+ * crosspost_one で各アカウントに送る内容を、送らずに返す
+ *
+ * 認証はするので、アカウントの設定が正しいかも確かめられる
+ */
+pub async fn preview_one(
+    http_client: Arc<reqwest::Client>,
+    src_post: LivePost,
+    dsts: &[config::Account],
+) -> Result<Vec<Value>> {
+    let mut dst_clients = Vec::new();
+    for dst in dsts {
+        dst_clients.push(
+            create_client(
+                http_client.clone(),
+                dst,
+                None,
+                &RetryPolicy::default(),
+                HttpConfig::default().max_media_bytes,
+                Budget::default(),
+            )
+            .await?,
+        );
+    }
+    preview_to_all(&src_post, dst_clients).await
+}
+
+// NOTE: 送信先の投稿を探せないので、引用は引用元へのリンクにする
+fn to_content(src_post: &LivePost) -> (String, Vec<store::operations::Facet>) {
+    match &src_post.quote {
+        Some(quote) => append_link(&src_post.content, &src_post.facets, &quote.src_uri),
+        None => (src_post.content.clone(), src_post.facets.clone()),
+    }
+}
+
+fn to_new_post<'a>(
+    src_post: &'a LivePost,
+    content: &'a str,
+    facets: &'a [store::operations::Facet],
+) -> NewPost<'a> {
+    let external = match &src_post.external {
+        LiveExternal::Some(external) => Some(external.clone()),
+        LiveExternal::None | LiveExternal::Unknown => None,
+    };
+    NewPost {
+        content,
+        facets,
+        reply_identifier: None,
+        images: src_post.media.clone(),
+        external,
+        content_warning: src_post.content_warning.as_deref(),
+        poll: src_post.poll.as_ref(),
+        quote_identifier: None,
+        src_uri: Some(&src_post.uri),
+        idempotency_key: &src_post.uri,
+        created_at: &src_post.created_at,
+        scheduled_at: None,
+    }
+}
+
+async fn post_to_all(
+    src_post: &LivePost,
+    dst_clients: Vec<Box<dyn Client>>,
+) -> Result<Vec<String>> {
+    let (content, facets) = to_content(src_post);
+    let mut dst_identifiers = Vec::new();
+    for mut dst_client in dst_clients {
+        let dst_identifier = dst_client
+            .post(to_new_post(src_post, &content, &facets))
+            .await?;
+        dst_identifiers.push(dst_identifier);
+    }
+    Ok(dst_identifiers)
+}
+
+async fn preview_to_all(
+    src_post: &LivePost,
+    dst_clients: Vec<Box<dyn Client>>,
+) -> Result<Vec<Value>> {
+    let (content, facets) = to_content(src_post);
+    let mut previews = Vec::new();
+    for mut dst_client in dst_clients {
+        previews.push(
+            dst_client
+                .preview(to_new_post(src_post, &content, &facets))
+                .await?,
+        );
+    }
+    Ok(previews)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::protocols::mock_client::MockClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn post_is_sent_to_each_dst_with_quote_link() {
+        let mut src_post = LivePost::test("1", "hello", "2024-01-01T00:00:00Z");
+        src_post.quote = Some(store::operations::Quote {
+            src_identifier: "0".into(),
+            src_uri: "https://src.example.com/0".into(),
+        });
+        let a = MockClient::default();
+        let b = MockClient::default();
+
+        let dst_identifiers =
+            post_to_all(&src_post, vec![Box::new(a.clone()), Box::new(b.clone())])
+                .await
+                .unwrap();
+
+        assert_eq!(dst_identifiers, ["post-1", "post-1"]);
+        for client in [a, b] {
+            let posts = client.posts();
+            let [post] = posts.as_slice() else {
+                panic!("unexpected posts");
+            };
+            assert_eq!(post.content, "hello\n\nhttps://src.example.com/0");
+            assert_eq!(post.reply_identifier, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn preview_is_built_without_sending() {
+        let mut src_post = LivePost::test("1", "hello", "2024-01-01T00:00:00Z");
+        src_post.quote = Some(store::operations::Quote {
+            src_identifier: "0".into(),
+            src_uri: "https://src.example.com/0".into(),
+        });
+        let client = MockClient::default();
+
+        let previews = preview_to_all(&src_post, vec![Box::new(client.clone())])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            previews,
+            [json!({
+                "content": "hello\n\nhttps://src.example.com/0",
+                "mediaLen": 0,
+            })]
+        );
+        assert!(client.posts().is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+use anyhow::Result;
+use tracing::warn;
+
+use crate::{
+    protocols::{error::ignore_not_found, Client},
+    store,
+};
+
+use super::utils::{remove_dst_status, DestinationIndex};
+
+/** 送信先で既に消されていた場合も、消せたものとして Store から取り除く */
+pub async fn delete_like(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    dst_client: &mut dyn Client,
+    operation: store::operations::DeleteLikeOperation,
+) -> Result<()> {
+    let dst_identifier =
+        index.find_like_dst_identifier(&operation.account_pair, &operation.status.src_identifier);
+    let Some(dst_identifier) = dst_identifier.map(str::to_owned) else {
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
+        return Ok(());
+    };
+    let result = dst_client.unlike(&dst_identifier).await;
+    ignore_not_found(result, &dst_identifier)?;
+    remove_dst_status(store, index, &operation.account_pair, &dst_identifier);
+    Ok(())
+}
@@ -1,25 +1,39 @@
 use anyhow::Result;
 use tracing::warn;
 
-use crate::{protocols::Client, store};
+use crate::{
+    protocols::{error::ignore_not_found, Client},
+    store,
+};
 
-use super::utils::find_post_dst_identifier;
+use super::utils::{remove_dst_status, DestinationIndex};
 
+/** 送信先で既に消されていた場合も、消せたものとして Store から取り除く */
 pub async fn delete_post(
     store: &mut store::Store,
+    index: &mut DestinationIndex,
     dst_client: &mut dyn Client,
     operation: store::operations::DeletePostOperation,
 ) -> Result<()> {
-    let dst_identifier = find_post_dst_identifier(
-        &store.users,
-        &operation.account_pair.src_origin,
-        &operation.status.src_identifier,
-        &operation.account_pair.dst_origin,
-    );
-    let Some(dst_identifier) = dst_identifier else {
-        warn!("dst_identifier not found (src_identifier={})", operation.status.src_identifier);
+    let dst_identifier =
+        index.find_post_dst_identifier(&operation.account_pair, &operation.status.src_identifier);
+    let Some(dst_identifier) = dst_identifier.map(str::to_owned) else {
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
         return Ok(());
     };
-    dst_client.delete_post(dst_identifier).await?;
+    // NOTE: 返信としてつなげた投稿から消す
+    let follow_up_identifiers = index
+        .find_post_follow_up_identifiers(&operation.account_pair, &operation.status.src_identifier)
+        .to_vec();
+    for follow_up_identifier in follow_up_identifiers.iter().rev() {
+        let result = dst_client.delete_post(follow_up_identifier).await;
+        ignore_not_found(result, follow_up_identifier)?;
+    }
+    let result = dst_client.delete_post(&dst_identifier).await;
+    ignore_not_found(result, &dst_identifier)?;
+    remove_dst_status(store, index, &operation.account_pair, &dst_identifier);
     Ok(())
 }
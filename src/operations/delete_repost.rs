@@ -1,25 +1,31 @@
 use anyhow::Result;
 use tracing::warn;
 
-use crate::{protocols::Client, store};
+use crate::{
+    protocols::{error::ignore_not_found, Client},
+    store,
+};
 
-use super::utils::find_repost_dst_identifier;
+use super::utils::{remove_dst_status, DestinationIndex};
 
+/** 送信先で既に消されていた場合も、消せたものとして Store から取り除く */
 pub async fn delete_repost(
     store: &mut store::Store,
+    index: &mut DestinationIndex,
     dst_client: &mut dyn Client,
     operation: store::operations::DeleteRepostOperation,
 ) -> Result<()> {
-    let dst_identifier = find_repost_dst_identifier(
-        &store.users,
-        &operation.account_pair.src_origin,
-        &operation.status.src_identifier,
-        &operation.account_pair.dst_origin,
-    );
-    let Some(dst_identifier) = dst_identifier else {
-        warn!("dst_identifier not found (src_identifier={})", operation.status.src_identifier);
+    let dst_identifier =
+        index.find_repost_dst_identifier(&operation.account_pair, &operation.status.src_identifier);
+    let Some(dst_identifier) = dst_identifier.map(str::to_owned) else {
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
         return Ok(());
     };
-    dst_client.delete_repost(dst_identifier).await?;
+    let result = dst_client.delete_repost(&dst_identifier).await;
+    ignore_not_found(result, &dst_identifier)?;
+    remove_dst_status(store, index, &operation.account_pair, &dst_identifier);
     Ok(())
 }
@@ -2,59 +2,633 @@ use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    config::Account,
-    protocols::create_client,
+    config,
+    database::Database,
+    metrics,
+    protocols::{create_client, error::ClientError},
+    rate_limit::RateLimiter,
     store::{
         self,
-        operations::Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
+        operations::Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, UpdatePost,
+        },
     },
 };
 
 use super::{
-    create_post::create_post, create_repost::create_repost, delete_post::delete_post,
-    delete_repost::delete_repost,
+    create_like::create_like, create_post::create_post, create_repost::create_repost,
+    delete_like::delete_like, delete_post::delete_post, delete_repost::delete_repost,
+    update_post::update_post, utils::DestinationIndex,
 };
 
+fn log_dry_run(operation: &store::operations::Operation) {
+    let dst_origin = &operation.account_pair().dst_origin;
+    match operation {
+        CreatePost(operation) => info!(
+            "[dry run] post to {}: {:?} (media={})",
+            dst_origin,
+            operation.status.content,
+            operation.status.media.len()
+        ),
+        CreateRepost(operation) => info!(
+            "[dry run] repost to {}: {}",
+            dst_origin, operation.status.target_src_uri
+        ),
+        UpdatePost(operation) => info!(
+            "[dry run] update on {}: {} -> {:?}",
+            dst_origin, operation.status.src_identifier, operation.status.content
+        ),
+        DeletePost(operation) => info!(
+            "[dry run] delete post on {}: {}",
+            dst_origin, operation.status.src_identifier
+        ),
+        DeleteRepost(operation) => info!(
+            "[dry run] delete repost on {}: {}",
+            dst_origin, operation.status.src_identifier
+        ),
+        CreateLike(operation) => info!(
+            "[dry run] like on {}: {}",
+            dst_origin, operation.status.target_src_uri
+        ),
+        DeleteLike(operation) => info!(
+            "[dry run] unlike on {}: {}",
+            dst_origin, operation.status.src_identifier
+        ),
+    }
+}
+
+/**
+ * 先頭から operation を消化する
+ *
+ * 途中で止まっても送った分を二重に送らないように、operation を 1 つ消化するごとに commit する
+ */
 pub async fn post(
     cancellation_token: &CancellationToken,
     store: &mut store::Store,
     http_client: Arc<reqwest::Client>,
-    dsts: &[&Account],
+    config: &config::Config,
+    database: &dyn Database,
 ) -> Result<()> {
     trace!("post");
+    let dsts: Vec<_> = config.users.iter().flat_map(|user| &user.dsts).collect();
+    let mut rate_limiter = RateLimiter::new(&config.rate_limits);
+    let mut index = DestinationIndex::new(&store.users);
+    let mut processed = 0;
+    // NOTE: 後回しにした operation や無効な送信先の operation は末尾に積むので、
+    //       それだけが残ったら次回の実行に回す
+    let mut deferred = 0;
+    // NOTE: 以前は末尾から消化していたので、保存済みの順番を並べ直しておく
+    store.sort_operations();
     loop {
         trace!("post loop");
         if cancellation_token.is_cancelled() {
             debug!("cancel accepted");
             return Ok(());
         }
-        let Some(operation) = store.operations.pop() else {
+        if config
+            .max_operations_per_run
+            .is_some_and(|max| processed >= max)
+        {
+            debug!("max operations per run reached");
+            return Ok(());
+        }
+        if store.operations.len() <= deferred {
             trace!("post completed");
             return Ok(());
-        };
+        }
+        let operation = store.operations.front().unwrap();
 
         let dst = dsts
             .iter()
-            .find(|dst| dst.to_account_key() == operation.account_pair().to_dst_key())
+            .find(|dst| dst.account.to_account_key() == operation.account_pair().to_dst_key())
             .ok_or_else(|| anyhow!("dst not found"))?;
-        let mut dst_client = create_client(http_client.clone(), dst, None).await?;
+        if !dst.enabled {
+            let mut operation = store.operations.pop_front().unwrap();
+            if dst.drop_while_disabled {
+                debug!("dst is disabled, operation dropped");
+            } else {
+                trace!("dst is disabled, operation skipped");
+                // NOTE: 有効に戻すまで送らないので、溜まっていたものとして扱う
+                operation.mark_backfill();
+                store.operations.push_back(operation);
+                deferred += 1;
+            }
+            continue;
+        }
+        // NOTE: 送信先の状態は変えずに operation だけ消化する
+        if config.dry_run {
+            log_dry_run(operation);
+            store.operations.pop_front();
+            continue;
+        }
+        // NOTE: 待っている間に中断された場合や、待ちきれない場合は operation を残したまま終わる
+        let acquired = tokio::select! {
+            acquired = rate_limiter.acquire(&dst.account) => acquired,
+            _ = cancellation_token.cancelled() => {
+                debug!("cancel accepted");
+                return Ok(());
+            }
+        };
+        if let Err(err) = acquired {
+            metrics::rate_limited(&operation.account_pair().dst_origin);
+            warn!("{}, retry on next run", err);
+            return Ok(());
+        }
+        // NOTE: ログインに失敗しても operation を失わないように、取り出す前にクライアントを作る
+        let dst_origin = operation.account_pair().dst_origin.clone();
+        let kind = operation.kind();
+        let mut dst_client = match create_client(
+            http_client.clone(),
+            &dst.account,
+            None,
+            &config.retry,
+            config.http.max_media_bytes,
+            rate_limiter.budget(&dst.account),
+        )
+        .await
+        {
+            Ok(dst_client) => dst_client,
+            Err(err) => {
+                error!("{:?}", err);
+                match ClientError::classify(err) {
+                    err if err.is_retryable() => {
+                        if matches!(err, ClientError::RateLimited { .. }) {
+                            metrics::rate_limited(&dst_origin);
+                        }
+                        metrics::operation(&dst_origin, kind, "retry");
+                        warn!("{}, retry on next run", err);
+                        return Ok(());
+                    }
+                    ClientError::Auth => {
+                        metrics::operation(&dst_origin, kind, "failed");
+                        bail!("authentication failed");
+                    }
+                    _ => {
+                        metrics::operation(&dst_origin, kind, "failed");
+                        bail!("client creation failed")
+                    }
+                }
+            }
+        };
+        let operation = store.operations.pop_front().unwrap();
+        processed += 1;
 
-        let result = match operation {
-            CreatePost(operation) => create_post(store, dst_client.as_mut(), operation).await,
-            CreateRepost(operation) => create_repost(store, dst_client.as_mut(), operation).await,
-            UpdatePost(_) => {
-                warn!("Update is not supported yet");
-                Ok(())
+        let result = match operation.clone() {
+            CreatePost(operation) => {
+                create_post(store, &mut index, dst_client.as_mut(), operation, dst)
+                    .await
+                    .map(|deferred| deferred.map(CreatePost))
+            }
+            CreateRepost(operation) => {
+                create_repost(store, &mut index, dst_client.as_mut(), operation, dst)
+                    .await
+                    .map(|_| None)
+            }
+            UpdatePost(operation) => {
+                update_post(store, &mut index, dst_client.as_mut(), operation, dst)
+                    .await
+                    .map(|_| None)
+            }
+            DeletePost(operation) => delete_post(store, &mut index, dst_client.as_mut(), operation)
+                .await
+                .map(|_| None),
+            DeleteRepost(operation) => {
+                delete_repost(store, &mut index, dst_client.as_mut(), operation)
+                    .await
+                    .map(|_| None)
+            }
+            CreateLike(operation) => {
+                create_like(store, &mut index, dst_client.as_mut(), operation, dst)
+                    .await
+                    .map(|_| None)
+            }
+            DeleteLike(operation) => delete_like(store, &mut index, dst_client.as_mut(), operation)
+                .await
+                .map(|_| None),
+        };
+        let err = match result {
+            Ok(None) => {
+                metrics::operation(&dst_origin, kind, "succeeded");
+                database.commit(store).await?;
+                continue;
+            }
+            Ok(Some(operation)) => {
+                metrics::operation(&dst_origin, kind, "deferred");
+                store.operations.push_back(operation);
+                deferred += 1;
+                database.commit(store).await?;
+                continue;
             }
-            DeletePost(operation) => delete_post(store, dst_client.as_mut(), operation).await,
-            DeleteRepost(operation) => delete_repost(store, dst_client.as_mut(), operation).await,
+            Err(err) => err,
+        };
+        error!("{:?}", err);
+        match ClientError::classify(err) {
+            // NOTE: 次回の実行で同じ operation からやり直す
+            err if err.is_retryable() => {
+                if matches!(err, ClientError::RateLimited { .. }) {
+                    metrics::rate_limited(&dst_origin);
+                }
+                metrics::operation(&dst_origin, kind, "retry");
+                warn!("{}, retry on next run", err);
+                store.operations.push_front(operation);
+                return Ok(());
+            }
+            ClientError::NotFound => {
+                metrics::operation(&dst_origin, kind, "not_found");
+                warn!("target not found, operation skipped");
+                database.commit(store).await?;
+            }
+            // NOTE: 認証情報を直すまで何度やっても失敗するので、operation を残して止める
+            ClientError::Auth => {
+                metrics::operation(&dst_origin, kind, "failed");
+                store.operations.push_front(operation);
+                bail!("authentication failed");
+            }
+            _ => {
+                metrics::operation(&dst_origin, kind, "failed");
+                bail!("post failed")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        database::InMemory,
+        protocols::megalodon_client::{test_account, test_status},
+    };
+
+    use super::*;
+
+    fn config(dry_run: bool) -> config::Config {
+        serde_json::from_value(json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": "https://src.example.com",
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": "https://dst.example.com",
+                    "accessToken": "dst",
+                }],
+            }],
+            "dryRun": dry_run,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn dry_run_drains_operations_without_dst_statuses() {
+        let mut store = store::Store::default();
+        for src_identifier in ["1", "2"] {
+            store
+                .operations
+                .push_back(CreatePost(store::operations::CreatePostOperation::test(
+                    src_identifier,
+                    "hello",
+                )));
+        }
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config(true),
+            &InMemory::new(json!({}), store::Store::default()),
+        )
+        .await
+        .unwrap();
+
+        assert!(store.operations.is_empty());
+        assert!(store
+            .users
+            .iter()
+            .flat_map(|user| &user.dsts)
+            .all(|dst| dst.statuses.is_empty()));
+    }
+
+    async fn mastodon_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_account("1", "dst")))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    async fn mount_status(server: &MockServer, body: Value, id: &str) {
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(body_partial_json(body))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_status(id)))
+            .mount(server)
+            .await;
+    }
+
+    fn config_to(server: &MockServer, max_operations_per_run: Value) -> config::Config {
+        let mut config = json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": "https://src.example.com",
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": server.uri(),
+                    "accessToken": "dst",
+                }],
+            }],
+        });
+        if !max_operations_per_run.is_null() {
+            config["maxOperationsPerRun"] = max_operations_per_run;
+        }
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn operation_to(
+        server: &MockServer,
+        src_identifier: &str,
+        content: &str,
+    ) -> store::operations::CreatePostOperation {
+        let mut operation = store::operations::CreatePostOperation::test(src_identifier, content);
+        operation.account_pair.dst_origin = server.uri();
+        operation
+    }
+
+    async fn remaining_operations(server: &MockServer, max_operations_per_run: Value) -> usize {
+        let config = config_to(server, max_operations_per_run);
+        let mut store = store::Store::default();
+        for src_identifier in ["1", "2", "3", "4", "5"] {
+            store
+                .operations
+                .push_back(CreatePost(operation_to(server, src_identifier, "hello")));
+        }
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config,
+            &InMemory::new(json!({}), store::Store::default()),
+        )
+        .await
+        .unwrap();
+
+        store.operations.len()
+    }
+
+    #[tokio::test]
+    async fn at_most_max_operations_are_processed_per_run() {
+        let server = mastodon_server().await;
+        mount_status(&server, json!({}), "10").await;
+        assert_eq!(remaining_operations(&server, json!(3)).await, 2);
+        assert_eq!(remaining_operations(&server, json!(10)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn all_operations_are_processed_per_run_by_default() {
+        let server = mastodon_server().await;
+        mount_status(&server, json!({}), "10").await;
+        assert_eq!(remaining_operations(&server, Value::Null).await, 0);
+    }
+
+    #[tokio::test]
+    async fn operations_are_left_when_budget_resets_too_late() {
+        let server = mastodon_server().await;
+        let reset_at = chrono::Utc::now() + chrono::Duration::hours(1);
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(test_status("10"))
+                    .insert_header("x-ratelimit-remaining", "0")
+                    .insert_header("x-ratelimit-reset", reset_at.to_rfc3339().as_str()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        assert_eq!(remaining_operations(&server, json!(10)).await, 4);
+    }
+
+    /** 送信先を無効にして、operation を 1 つ積んで送る */
+    async fn remaining_while_disabled(
+        drop_while_disabled: bool,
+    ) -> Vec<store::operations::Operation> {
+        let config: config::Config = serde_json::from_value(json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": "https://src.example.com",
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": "https://dst.example.com",
+                    "accessToken": "dst",
+                    "enabled": false,
+                    "dropWhileDisabled": drop_while_disabled,
+                }],
+            }],
+        }))
+        .unwrap();
+        let mut store = store::Store::default();
+        store
+            .operations
+            .push_back(CreatePost(store::operations::CreatePostOperation::test(
+                "1", "hello",
+            )));
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config,
+            &InMemory::new(json!({}), store::Store::default()),
+        )
+        .await
+        .unwrap();
+
+        store.operations.into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn operations_are_requeued_while_dst_is_disabled() {
+        let operations = remaining_while_disabled(false).await;
+
+        let [CreatePost(operation)] = operations.as_slice() else {
+            panic!("unexpected operations");
         };
-        if let Err(err) = result {
-            error!("{:?}", err);
-            bail!("post failed");
+        // NOTE: 有効に戻した後は溜まっていたものとして送る
+        assert!(operation.backfill);
+    }
+
+    #[tokio::test]
+    async fn operations_are_dropped_while_dst_is_disabled_if_configured() {
+        assert!(remaining_while_disabled(true).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reply_is_sent_after_its_parent() {
+        let server = mastodon_server().await;
+        mount_status(&server, json!({ "status": "parent" }), "10").await;
+        // NOTE: 親より先に送ると in_reply_to_id が付かず、どのモックにも一致しない
+        mount_status(
+            &server,
+            json!({ "status": "reply", "in_reply_to_id": "10" }),
+            "11",
+        )
+        .await;
+        let mut store = store::Store::default();
+        let parent = operation_to(&server, "1", "parent");
+        let mut reply = operation_to(&server, "2", "reply");
+        reply.status.reply_src_identifier = Some("1".into());
+        store.operations.push_back(CreatePost(parent));
+        store.operations.push_back(CreatePost(reply));
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config_to(&server, Value::Null),
+            &InMemory::new(json!({}), store::Store::default()),
+        )
+        .await
+        .unwrap();
+
+        assert!(store.operations.is_empty());
+        let identifiers: Vec<_> = store
+            .users
+            .iter()
+            .flat_map(|user| &user.dsts)
+            .flat_map(|dst| &dst.statuses)
+            .map(|status| match status {
+                store::user::DestinationStatus::Post(post) => {
+                    (post.src_identifier.as_str(), post.identifier.as_str())
+                }
+                _ => panic!("unexpected status"),
+            })
+            .collect();
+        assert_eq!(identifiers, [("2", "11"), ("1", "10")]);
+    }
+
+    #[tokio::test]
+    async fn store_is_committed_after_each_operation() {
+        let server = mastodon_server().await;
+        mount_status(&server, json!({}), "10").await;
+        let mut store = store::Store::default();
+        for src_identifier in ["1", "2"] {
+            store
+                .operations
+                .push_back(CreatePost(operation_to(&server, src_identifier, "hello")));
+        }
+        let database = InMemory::new(json!({}), store::Store::default());
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config_to(&server, Value::Null),
+            &database,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(database.commit_count(), 2);
+        let committed = database.last_committed();
+        assert!(committed.operations.is_empty());
+        assert_eq!(
+            committed
+                .users
+                .iter()
+                .flat_map(|user| &user.dsts)
+                .map(|dst| dst.statuses.len())
+                .sum::<usize>(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn operation_is_kept_when_client_creation_fails() {
+        for (status, ok) in [(503, true), (429, true), (401, false)] {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/api/v1/accounts/verify_credentials"))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+            let mut store = store::Store::default();
+            store
+                .operations
+                .push_back(CreatePost(operation_to(&server, "1", "hello")));
+
+            let posted = post(
+                &CancellationToken::new(),
+                &mut store,
+                Arc::new(reqwest::Client::new()),
+                &config_to(&server, Value::Null),
+                &InMemory::new(json!({}), store::Store::default()),
+            )
+            .await;
+
+            assert_eq!(posted.is_ok(), ok, "{}", status);
+            assert_eq!(store.operations.len(), 1, "{}", status);
+        }
+    }
+
+    /** metrics の feature が無い場合も、数えないだけで同じように処理する */
+    #[tokio::test]
+    async fn operation_results_are_counted() {
+        for (status, result) in [(200, "succeeded"), (429, "retry"), (422, "failed")] {
+            let server = mastodon_server().await;
+            Mock::given(method("POST"))
+                .and(path("/api/v1/statuses"))
+                .respond_with(ResponseTemplate::new(status).set_body_json(test_status("10")))
+                .mount(&server)
+                .await;
+            let mut store = store::Store::default();
+            store
+                .operations
+                .push_back(CreatePost(operation_to(&server, "1", "hello")));
+
+            let posted = post(
+                &CancellationToken::new(),
+                &mut store,
+                Arc::new(reqwest::Client::new()),
+                &config_to(&server, Value::Null),
+                &InMemory::new(json!({}), store::Store::default()),
+            )
+            .await;
+
+            assert_eq!(posted.is_ok(), result != "failed");
+            #[cfg(feature = "metrics")]
+            {
+                let rendered = metrics::render();
+                let line = format!(
+                    "timelineecho_operations_total{{origin=\"{}\",kind=\"create_post\",result=\"{}\"}} 1\n",
+                    server.uri(),
+                    result
+                );
+                assert!(rendered.contains(&line), "{}", rendered);
+                let rate_limited = format!(
+                    "timelineecho_rate_limited_total{{origin=\"{}\"}} 1\n",
+                    server.uri()
+                );
+                assert_eq!(rendered.contains(&rate_limited), result == "retry");
+            }
         }
     }
 }
@@ -0,0 +1,306 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    app::AccountKey,
+    config,
+    database::Database,
+    protocols::{
+        create_client,
+        error::{ignore_not_found, ClientError},
+        Client,
+    },
+    rate_limit::RateLimiter,
+    store,
+};
+
+/** メディアを分けて送った投稿は、返信としてつなげた投稿から消す */
+async fn delete_post(
+    dst_client: &mut dyn Client,
+    post: &store::user::DestinationPost,
+) -> Result<(), ClientError> {
+    for follow_up_identifier in post.follow_up_identifiers.iter().rev() {
+        let result = dst_client.delete_post(follow_up_identifier).await;
+        ignore_not_found(result, follow_up_identifier)?;
+    }
+    dst_client.delete_post(&post.identifier).await
+}
+
+fn next_status(
+    store: &store::Store,
+    dst_account_key: &AccountKey,
+) -> Option<store::user::DestinationStatus> {
+    store
+        .users
+        .iter()
+        .flat_map(|user| &user.dsts)
+        .filter(|dst| dst.origin == dst_account_key.origin)
+        .filter(|dst| dst.identifier == dst_account_key.identifier)
+        .find_map(|dst| dst.statuses.last().cloned())
+}
+
+fn remove_status(store: &mut store::Store, dst_account_key: &AccountKey, identifier: &str) {
+    store
+        .users
+        .iter_mut()
+        .flat_map(|user| &mut user.dsts)
+        .filter(|dst| dst.origin == dst_account_key.origin)
+        .filter(|dst| dst.identifier == dst_account_key.identifier)
+        .for_each(|dst| {
+            dst.statuses.retain(|status| match status {
+                store::user::DestinationStatus::Post(post) => post.identifier != identifier,
+                store::user::DestinationStatus::Repost(repost) => repost.identifier != identifier,
+                store::user::DestinationStatus::Like(like) => like.identifier != identifier,
+            })
+        });
+}
+
+/**
+ * 送信先の status を 1 件ずつ削除して Store から取り除く
+ *
+ * 全て取り除けた場合は true を返す。レート制限や一時的なエラーで止めた場合は次の実行で続きから削除する
+ */
+async fn delete_statuses(
+    cancellation_token: &CancellationToken,
+    store: &mut store::Store,
+    dst_client: &mut dyn Client,
+    rate_limiter: &mut RateLimiter<'_>,
+    config: &config::Config,
+    dst: &config::Destination,
+    database: &dyn Database,
+) -> Result<bool> {
+    let dst_account_key = dst.account.to_account_key();
+    let mut processed = 0;
+    let mut failed = 0;
+    while let Some(status) = next_status(store, &dst_account_key) {
+        if config
+            .max_operations_per_run
+            .is_some_and(|max| processed >= max)
+        {
+            debug!("max operations per run reached");
+            return Ok(false);
+        }
+        let acquired = tokio::select! {
+            acquired = rate_limiter.acquire(&dst.account) => acquired,
+            _ = cancellation_token.cancelled() => {
+                debug!("cancel accepted");
+                return Ok(false);
+            }
+        };
+        if let Err(err) = acquired {
+            warn!("{}, retry on next run", err);
+            return Ok(false);
+        }
+        processed += 1;
+        let (identifier, result) = match &status {
+            store::user::DestinationStatus::Post(post) => {
+                (&post.identifier, delete_post(dst_client, post).await)
+            }
+            store::user::DestinationStatus::Repost(repost) => (
+                &repost.identifier,
+                dst_client.delete_repost(&repost.identifier).await,
+            ),
+            store::user::DestinationStatus::Like(like) => {
+                (&like.identifier, dst_client.unlike(&like.identifier).await)
+            }
+        };
+        if let Err(err) = result {
+            match err {
+                // NOTE: 既に消えているものは削除できたものとして扱う
+                ClientError::NotFound => warn!("already deleted: {}", identifier),
+                err if err.is_retryable() => {
+                    warn!("{}, retry on next run", err);
+                    return Ok(false);
+                }
+                // NOTE: 何度やっても失敗するので、削除を諦めて紐付けだけを取り除く
+                err => {
+                    error!("unlink failed, skipped: {}: {:?}", identifier, err);
+                    failed += 1;
+                }
+            }
+        }
+        remove_status(store, &dst_account_key, identifier);
+        database.commit(store).await?;
+    }
+    if failed > 0 {
+        warn!("{} statuses could not be deleted", failed);
+    }
+    Ok(true)
+}
+
+/**
+ * 送信先に送った投稿とリポストといいねを全て削除し、送信先との紐付けを Store から取り除く
+ *
+ * 1 件削除するごとに commit するので、途中で止まっても次の実行で続きから削除できる
+ */
+pub async fn unlink(
+    cancellation_token: &CancellationToken,
+    store: &mut store::Store,
+    http_client: Arc<reqwest::Client>,
+    config: &config::Config,
+    database: &dyn Database,
+    dst_account_key: &AccountKey,
+) -> Result<()> {
+    trace!("unlink");
+    let dst = config
+        .users
+        .iter()
+        .flat_map(|user| &user.dsts)
+        .find(|dst| dst.account.to_account_key() == *dst_account_key)
+        .ok_or_else(|| anyhow!("dst not found"))?;
+    // NOTE: 送っていない operation が残っていると、削除した後に送られてしまう
+    store
+        .operations
+        .retain(|operation| operation.account_pair().to_dst_key() != *dst_account_key);
+    let mut rate_limiter = RateLimiter::new(&config.rate_limits);
+    let mut dst_client = create_client(
+        http_client,
+        &dst.account,
+        None,
+        &config.retry,
+        config.http.max_media_bytes,
+        rate_limiter.budget(&dst.account),
+    )
+    .await?;
+    let completed = delete_statuses(
+        cancellation_token,
+        store,
+        dst_client.as_mut(),
+        &mut rate_limiter,
+        config,
+        dst,
+        database,
+    )
+    .await?;
+    if !completed {
+        return Ok(());
+    }
+    store.users.iter_mut().for_each(|user| {
+        user.dsts.retain(|dst| {
+            dst.origin != dst_account_key.origin || dst.identifier != dst_account_key.identifier
+        })
+    });
+    database.commit(store).await?;
+    info!("unlink completed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{database::InMemory, protocols::mock_client::MockClient};
+
+    use super::*;
+
+    fn config(max_operations_per_run: Option<usize>) -> config::Config {
+        serde_json::from_value(json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": "https://src.example.com",
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": "https://dst.example.com",
+                    "accessToken": "dst",
+                }],
+            }],
+            "maxOperationsPerRun": max_operations_per_run,
+        }))
+        .unwrap()
+    }
+
+    /** 古い順に投稿、リポスト、いいね。いいねは MockClient では取り消せない */
+    fn store() -> store::Store {
+        let mut store = store::Store::default();
+        let dst = store.get_or_create_dst_mut(&store::operations::AccountPair::test());
+        dst.statuses = vec![
+            store::user::DestinationStatus::Like(store::user::DestinationLike {
+                identifier: "like-1".into(),
+                src_identifier: "3".into(),
+            }),
+            store::user::DestinationStatus::Repost(store::user::DestinationRepost {
+                identifier: "repost-1".into(),
+                src_identifier: "2".into(),
+            }),
+            store::user::DestinationStatus::Post(store::user::DestinationPost {
+                identifier: "post-1".into(),
+                src_identifier: "1".into(),
+                src_uri: "https://src.example.com/1".into(),
+                follow_up_identifiers: vec!["post-2".into()],
+            }),
+        ];
+        store
+    }
+
+    async fn delete(
+        store: &mut store::Store,
+        client: &mut MockClient,
+        config: &config::Config,
+        database: &InMemory,
+    ) -> bool {
+        delete_statuses(
+            &CancellationToken::new(),
+            store,
+            client,
+            &mut RateLimiter::new(&config.rate_limits),
+            config,
+            &config.users[0].dsts[0],
+            database,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn dst_statuses(store: &store::Store) -> usize {
+        store
+            .users
+            .iter()
+            .flat_map(|user| &user.dsts)
+            .map(|dst| dst.statuses.len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn all_statuses_are_deleted_past_permanent_failures() {
+        let config = config(None);
+        let database = InMemory::new(json!({}), store::Store::default());
+        let mut client = MockClient::default();
+        let mut store = store();
+
+        let completed = delete(&mut store, &mut client, &config, &database).await;
+
+        assert!(completed);
+        assert_eq!(dst_statuses(&store), 0);
+        assert_eq!(
+            client.state.lock().unwrap().deleted,
+            ["post-2", "post-1", "repost-1"]
+        );
+        assert_eq!(database.commit_count(), 3);
+        assert_eq!(dst_statuses(&database.last_committed()), 0);
+    }
+
+    #[tokio::test]
+    async fn capped_run_is_resumed() {
+        let config = config(Some(1));
+        let database = InMemory::new(json!({}), store::Store::default());
+        let mut client = MockClient::default();
+        let mut store = store();
+
+        assert!(!delete(&mut store, &mut client, &config, &database).await);
+        assert_eq!(dst_statuses(&database.last_committed()), 2);
+
+        let mut store = database.last_committed();
+        assert!(!delete(&mut store, &mut client, &config, &database).await);
+        assert_eq!(dst_statuses(&database.last_committed()), 1);
+        assert_eq!(
+            client.state.lock().unwrap().deleted,
+            ["post-2", "post-1", "repost-1"]
+        );
+    }
+}
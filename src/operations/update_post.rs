@@ -0,0 +1,242 @@
+use anyhow::Result;
+use tracing::warn;
+
+use crate::{config, protocols::Client, store};
+
+use super::{
+    create_post::{apply_templates, to_quote_identifier, to_quote_uri},
+    utils::{update_dst_post_identifier, DestinationIndex},
+};
+
+/**
+ * 投稿と同じくテンプレートなどを付けてから更新する。文字数の上限は各 Client で収める
+ *
+ * 分けて送った投稿は、先頭だけ更新すると続きの投稿と食い違うので更新しない
+ */
+pub async fn update_post(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    dst_client: &mut dyn Client,
+    operation: store::operations::UpdatePostOperation,
+    dst: &config::Destination,
+) -> Result<()> {
+    let dst_identifier = index
+        .find_post_dst_identifier(&operation.account_pair, &operation.status.src_identifier)
+        .map(str::to_owned);
+    let Some(dst_identifier) = dst_identifier else {
+        warn!(
+            "dst_identifier not found (src_identifier={})",
+            operation.status.src_identifier
+        );
+        return Ok(());
+    };
+    if !index
+        .find_post_follow_up_identifiers(&operation.account_pair, &operation.status.src_identifier)
+        .is_empty()
+    {
+        warn!(
+            "split post cannot be updated (src_identifier={})",
+            operation.status.src_identifier
+        );
+        return Ok(());
+    }
+    let quote_identifier = to_quote_identifier(
+        index,
+        &operation.account_pair,
+        operation.status.quote.as_ref(),
+        dst,
+    );
+    let quote_uri = to_quote_uri(operation.status.quote.as_ref(), quote_identifier);
+    let (content, facets) = apply_templates(
+        dst,
+        &operation.account_pair.src_origin,
+        &operation.status.src_uri,
+        &operation.status.content,
+        &operation.status.facets,
+        &operation.status.custom_emojis,
+        quote_uri,
+    );
+    let new_dst_identifier = match &operation.status.media_alts {
+        Some(media_alts) => {
+            dst_client
+                .update_post_with_media_alts(&dst_identifier, &content, &facets, media_alts)
+                .await?
+        }
+        None => {
+            dst_client
+                .update_post(&dst_identifier, &content, &facets)
+                .await?
+        }
+    };
+    update_dst_post_identifier(
+        store,
+        index,
+        &operation.account_pair,
+        &operation.status.src_identifier,
+        &new_dst_identifier,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::protocols::mock_client::MockClient;
+
+    use super::{
+        super::utils::{insert_dst_status, update_dst_post_follow_ups},
+        *,
+    };
+
+    fn bluesky() -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "atproto",
+            "origin": "https://bsky.social",
+            "identifier": "",
+            "password": "",
+            "prefix": "[{source_origin}] ",
+        }))
+        .unwrap()
+    }
+
+    fn setup(
+        media_alts: Option<Vec<String>>,
+    ) -> (
+        store::Store,
+        DestinationIndex,
+        store::operations::UpdatePostOperation,
+    ) {
+        let mut store = store::Store::default();
+        let mut index = DestinationIndex::default();
+        let account_pair = store::operations::AccountPair::test();
+        insert_dst_status(
+            &mut store,
+            &mut index,
+            &account_pair,
+            store::user::DestinationStatus::Post(store::user::DestinationPost {
+                identifier: "post-1".into(),
+                src_identifier: "1".into(),
+                src_uri: "https://src.example.com/1".into(),
+                follow_up_identifiers: Vec::new(),
+            }),
+        );
+        let mut operation = store::operations::UpdatePostOperation::test("1", "**edited**:emoji:");
+        operation.status.media_alts = media_alts;
+        operation.status.custom_emojis = vec!["emoji".into()];
+        (store, index, operation)
+    }
+
+    #[tokio::test]
+    async fn content_is_updated_through_create_pipeline() {
+        let (mut store, mut index, operation) = setup(None);
+        let client = MockClient::default();
+
+        update_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        let updates = client.updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].identifier, "post-1");
+        assert_eq!(updates[0].content, "[https://src.example.com] edited");
+        assert_eq!(updates[0].media_alts, None);
+    }
+
+    #[tokio::test]
+    async fn media_alts_are_updated_with_content() {
+        let (mut store, mut index, operation) = setup(Some(vec!["alt".into()]));
+        let client = MockClient::default();
+
+        update_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        let updates = client.updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].media_alts, Some(vec!["alt".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn quote_link_is_kept_on_update() {
+        let (mut store, mut index, mut operation) = setup(None);
+        operation.status.quote = Some(store::operations::Quote {
+            src_identifier: "0".into(),
+            src_uri: "https://src.example.com/0".into(),
+        });
+        let client = MockClient::default();
+
+        update_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        let updates = client.updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            updates[0].content,
+            "[https://src.example.com] edited\n\nhttps://src.example.com/0"
+        );
+    }
+
+    #[tokio::test]
+    async fn split_post_is_not_updated() {
+        let (mut store, mut index, operation) = setup(None);
+        update_dst_post_follow_ups(
+            &mut store,
+            &mut index,
+            &operation.account_pair,
+            "1",
+            &["post-2".to_owned()],
+        );
+        let client = MockClient::default();
+
+        update_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        assert!(client.updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_post_is_not_updated() {
+        let (mut store, mut index, mut operation) = setup(None);
+        operation.status.src_identifier = "2".into();
+        let client = MockClient::default();
+
+        update_post(
+            &mut store,
+            &mut index,
+            &mut client.clone(),
+            operation,
+            &bluesky(),
+        )
+        .await
+        .unwrap();
+
+        assert!(client.updates().is_empty());
+    }
+}
@@ -1,74 +1,469 @@
-use crate::store;
-
-fn destination_statuses<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
-    dst_origin: &str,
-) -> Vec<&'a store::user::DestinationStatus> {
-    users
-        .iter()
-        .filter(|user| user.src.origin == src_origin)
-        .flat_map(|user| &user.dsts)
-        .filter(|dst| dst.origin == dst_origin)
-        .flat_map(|dst| &dst.statuses)
-        .collect()
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::{config, store};
+
+/**
+ * 送信先に渡す日時を決める
+ *
+ * 溜まっていた投稿を元の日時で送ると、送信先のタイムラインで埋もれたり順番が前後したりする。
+ * 取得してすぐに送るものは元の日時のままにする
+ */
+pub fn resolve_created_at(
+    created_at: &DateTime<FixedOffset>,
+    backfill: bool,
+    backdate: config::Backdate,
+) -> DateTime<FixedOffset> {
+    if backfill && backdate == config::Backdate::Now {
+        return Utc::now().into();
+    }
+    *created_at
 }
 
-pub fn find_post_dst_identifier<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
-    src_identifier: &str,
-    dst_origin: &str,
-) -> Option<&'a str> {
-    Some(
-        destination_statuses(users, src_origin, dst_origin)
+/**
+ * (account_pair, src_identifier)
+ *
+ * 同じ origin に複数のアカウントがあっても混ざらないように、アカウントまで含めて引く
+ */
+type IdentifierKey = (store::operations::AccountPair, String);
+
+/** (dst_origin, src_uri) */
+type UriKey = (String, String);
+
+/**
+ * src の identifier から dst の identifier を引くための索引
+ *
+ * operation ごとに全 status を走査しないように、post の実行前に一度だけ作って使い回す
+ */
+#[derive(Default)]
+pub struct DestinationIndex {
+    posts: HashMap<IdentifierKey, String>,
+    posts_by_uri: HashMap<UriKey, String>,
+    post_follow_ups: HashMap<IdentifierKey, Vec<String>>,
+    reposts: HashMap<IdentifierKey, String>,
+    likes: HashMap<IdentifierKey, String>,
+}
+
+impl DestinationIndex {
+    pub fn new(users: &[store::user::User]) -> Self {
+        let mut index = Self::default();
+        for user in users {
+            for dst in &user.dsts {
+                let account_pair = store::operations::AccountPair {
+                    src_origin: user.src.origin.clone(),
+                    src_account_identifier: user.src.identifier.clone(),
+                    dst_origin: dst.origin.clone(),
+                    dst_account_identifier: dst.identifier.clone(),
+                };
+                for dst_status in &dst.statuses {
+                    // NOTE: 線形探索と同じく先に見つかったものを優先する
+                    index.insert(&account_pair, dst_status, false);
+                }
+            }
+        }
+        index
+    }
+
+    fn insert(
+        &mut self,
+        account_pair: &store::operations::AccountPair,
+        dst_status: &store::user::DestinationStatus,
+        overwrite: bool,
+    ) {
+        fn put<K: std::hash::Hash + Eq, V: Clone>(
+            map: &mut HashMap<K, V>,
+            key: K,
+            value: &V,
+            overwrite: bool,
+        ) {
+            if overwrite || !map.contains_key(&key) {
+                map.insert(key, value.clone());
+            }
+        }
+        let key = |src_identifier: &str| (account_pair.clone(), src_identifier.to_owned());
+        match dst_status {
+            store::user::DestinationStatus::Post(post) => {
+                put(
+                    &mut self.posts,
+                    key(&post.src_identifier),
+                    &post.identifier,
+                    overwrite,
+                );
+                put(
+                    &mut self.posts_by_uri,
+                    (account_pair.dst_origin.clone(), post.src_uri.clone()),
+                    &post.identifier,
+                    overwrite,
+                );
+                put(
+                    &mut self.post_follow_ups,
+                    key(&post.src_identifier),
+                    &post.follow_up_identifiers,
+                    overwrite,
+                );
+            }
+            store::user::DestinationStatus::Repost(repost) => {
+                put(
+                    &mut self.reposts,
+                    key(&repost.src_identifier),
+                    &repost.identifier,
+                    overwrite,
+                );
+            }
+            store::user::DestinationStatus::Like(like) => {
+                put(
+                    &mut self.likes,
+                    key(&like.src_identifier),
+                    &like.identifier,
+                    overwrite,
+                );
+            }
+        }
+    }
+
+    fn remove(&mut self, account_pair: &store::operations::AccountPair, dst_identifier: &str) {
+        let is_removed = |(pair, _): &IdentifierKey, identifier: &String| {
+            pair == account_pair && identifier == dst_identifier
+        };
+        let removed_posts: Vec<_> = self
+            .posts
             .iter()
-            .filter_map(|dst_status| match dst_status {
-                store::user::DestinationStatus::Post(post) => Some(post),
-                store::user::DestinationStatus::Repost(_) => None,
-            })
-            .find(|dst_post| dst_post.src_identifier == src_identifier)?
-            .identifier
-            .as_str(),
-    )
+            .filter(|(key, identifier)| is_removed(key, identifier))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed_posts {
+            self.posts.remove(&key);
+            self.post_follow_ups.remove(&key);
+        }
+        self.posts_by_uri.retain(|(dst, _), identifier| {
+            !(dst == &account_pair.dst_origin && identifier == dst_identifier)
+        });
+        self.reposts
+            .retain(|key, identifier| !is_removed(key, identifier));
+        self.likes
+            .retain(|key, identifier| !is_removed(key, identifier));
+    }
+
+    pub fn find_post_dst_identifier(
+        &self,
+        account_pair: &store::operations::AccountPair,
+        src_identifier: &str,
+    ) -> Option<&str> {
+        self.posts
+            .get(&(account_pair.clone(), src_identifier.to_owned()))
+            .map(String::as_str)
+    }
+
+    /** メディアを分けて送った場合の、返信としてつなげた投稿の identifier */
+    pub fn find_post_follow_up_identifiers(
+        &self,
+        account_pair: &store::operations::AccountPair,
+        src_identifier: &str,
+    ) -> &[String] {
+        self.post_follow_ups
+            .get(&(account_pair.clone(), src_identifier.to_owned()))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn find_post_dst_identifier_by_uri(&self, src_uri: &str, dst_origin: &str) -> Option<&str> {
+        self.posts_by_uri
+            .get(&(dst_origin.to_owned(), src_uri.to_owned()))
+            .map(String::as_str)
+    }
+
+    pub fn find_repost_dst_identifier(
+        &self,
+        account_pair: &store::operations::AccountPair,
+        src_identifier: &str,
+    ) -> Option<&str> {
+        self.reposts
+            .get(&(account_pair.clone(), src_identifier.to_owned()))
+            .map(String::as_str)
+    }
+
+    pub fn find_like_dst_identifier(
+        &self,
+        account_pair: &store::operations::AccountPair,
+        src_identifier: &str,
+    ) -> Option<&str> {
+        self.likes
+            .get(&(account_pair.clone(), src_identifier.to_owned()))
+            .map(String::as_str)
+    }
+}
+
+/**
+ * store と索引の両方に dst の status を追加する
+ */
+pub fn insert_dst_status(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    account_pair: &store::operations::AccountPair,
+    dst_status: store::user::DestinationStatus,
+) {
+    index.insert(account_pair, &dst_status, true);
+    store
+        .get_or_create_dst_mut(account_pair)
+        .statuses
+        .insert(0, dst_status);
+}
+
+/**
+ * store と索引の両方から dst の status を取り除く
+ */
+pub fn remove_dst_status(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    account_pair: &store::operations::AccountPair,
+    dst_identifier: &str,
+) {
+    index.remove(account_pair, dst_identifier);
+    store
+        .get_or_create_dst_mut(account_pair)
+        .statuses
+        .retain(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(post) => post.identifier != dst_identifier,
+            store::user::DestinationStatus::Repost(repost) => repost.identifier != dst_identifier,
+            store::user::DestinationStatus::Like(like) => like.identifier != dst_identifier,
+        });
+}
+
+/**
+ * store と索引の両方で dst の post の identifier を差し替える
+ */
+pub fn update_dst_post_identifier(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    account_pair: &store::operations::AccountPair,
+    src_identifier: &str,
+    dst_identifier: &str,
+) {
+    store
+        .get_or_create_dst_mut(account_pair)
+        .statuses
+        .iter_mut()
+        .filter_map(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(post) => Some(post),
+            store::user::DestinationStatus::Repost(_) | store::user::DestinationStatus::Like(_) => {
+                None
+            }
+        })
+        .filter(|dst_post| dst_post.src_identifier == src_identifier)
+        .for_each(|dst_post| {
+            dst_post.identifier = dst_identifier.to_owned();
+            index.insert(
+                account_pair,
+                &store::user::DestinationStatus::Post(dst_post.clone()),
+                true,
+            );
+        });
+}
+
+/**
+ * store と索引の両方で dst の post の follow_up_identifiers を差し替える
+ *
+ * 分けて送る途中で失敗しても、送れた分を残しておけば次の実行で続きから送れる
+ */
+pub fn update_dst_post_follow_ups(
+    store: &mut store::Store,
+    index: &mut DestinationIndex,
+    account_pair: &store::operations::AccountPair,
+    src_identifier: &str,
+    follow_up_identifiers: &[String],
+) {
+    store
+        .get_or_create_dst_mut(account_pair)
+        .statuses
+        .iter_mut()
+        .filter_map(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(post) => Some(post),
+            store::user::DestinationStatus::Repost(_) | store::user::DestinationStatus::Like(_) => {
+                None
+            }
+        })
+        .filter(|dst_post| dst_post.src_identifier == src_identifier)
+        .for_each(|dst_post| {
+            dst_post.follow_up_identifiers = follow_up_identifiers.to_vec();
+            index.insert(
+                account_pair,
+                &store::user::DestinationStatus::Post(dst_post.clone()),
+                true,
+            );
+        });
 }
 
-pub fn find_post_dst_identifier_by_uri<'a>(
-    users: &'a [store::user::User],
-    src_uri: &str,
-    dst_origin: &str,
-) -> Option<&'a str> {
-    Some(
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn live_operation_keeps_created_at() {
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        assert_eq!(
+            resolve_created_at(&created_at, false, config::Backdate::Now),
+            created_at
+        );
+    }
+
+    #[test]
+    fn backfill_operation_is_sent_now_unless_kept() {
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let resolved = resolve_created_at(&created_at, true, config::Backdate::Now);
+        assert!(Utc::now() - resolved.with_timezone(&Utc) < Duration::minutes(1));
+        assert_eq!(
+            resolve_created_at(&created_at, true, config::Backdate::Keep),
+            created_at
+        );
+    }
+
+    /** 索引を作る前の線形探索 */
+    fn find_post_dst_identifier_linearly<'a>(
+        users: &'a [store::user::User],
+        account_pair: &store::operations::AccountPair,
+        src_identifier: &str,
+    ) -> Option<&'a str> {
         users
             .iter()
+            .filter(|user| {
+                user.src.origin == account_pair.src_origin
+                    && user.src.identifier == account_pair.src_account_identifier
+            })
             .flat_map(|user| &user.dsts)
-            .filter(|dst| dst.origin == dst_origin)
+            .filter(|dst| {
+                dst.origin == account_pair.dst_origin
+                    && dst.identifier == account_pair.dst_account_identifier
+            })
             .flat_map(|dst| &dst.statuses)
-            .filter_map(|dst_status| match dst_status {
-                store::user::DestinationStatus::Post(post) => Some(post),
-                store::user::DestinationStatus::Repost(_) => None,
+            .find_map(|dst_status| match dst_status {
+                store::user::DestinationStatus::Post(post)
+                    if post.src_identifier == src_identifier =>
+                {
+                    Some(post.identifier.as_str())
+                }
+                _ => None,
             })
-            .find(|dst_post| dst_post.src_uri == src_uri)?
-            .identifier
-            .as_str(),
-    )
-}
+    }
 
-pub fn find_repost_dst_identifier<'a>(
-    users: &'a [store::user::User],
-    src_origin: &str,
-    src_identifier: &str,
-    dst_origin: &str,
-) -> Option<&'a str> {
-    Some(
-        destination_statuses(users, src_origin, dst_origin)
-            .iter()
-            .filter_map(|dst_status| match dst_status {
-                store::user::DestinationStatus::Post(_) => None,
-                store::user::DestinationStatus::Repost(repost) => Some(repost),
-            })
-            .find(|dst_post| dst_post.src_identifier == src_identifier)?
-            .identifier
-            .as_str(),
-    )
+    fn dst(origin: &str, identifier: &str, src_identifiers: &[&str]) -> store::user::Destination {
+        store::user::Destination {
+            origin: origin.into(),
+            identifier: identifier.into(),
+            session: None,
+            statuses: src_identifiers
+                .iter()
+                .map(|src_identifier| {
+                    store::user::DestinationStatus::Post(store::user::DestinationPost {
+                        identifier: format!("{}-{}-{}", origin, identifier, src_identifier),
+                        src_identifier: (*src_identifier).into(),
+                        src_uri: format!("https://src.example.com/{}", src_identifier),
+                        follow_up_identifiers: Vec::new(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    fn user(src_origin: &str, dsts: Vec<store::user::Destination>) -> store::user::User {
+        store::user::User {
+            src: store::user::Source {
+                origin: src_origin.into(),
+                identifier: "src".into(),
+                session: None,
+                cursor: None,
+                statuses: Vec::new(),
+            },
+            dsts,
+        }
+    }
+
+    fn account_pair(
+        src_origin: &str,
+        dst_origin: &str,
+        dst_account_identifier: &str,
+    ) -> store::operations::AccountPair {
+        store::operations::AccountPair {
+            src_origin: src_origin.into(),
+            src_account_identifier: "src".into(),
+            dst_origin: dst_origin.into(),
+            dst_account_identifier: dst_account_identifier.into(),
+        }
+    }
+
+    #[test]
+    fn index_lookup_matches_linear_scan() {
+        // NOTE: 同じ src の identifier を複数の送信先に送っている
+        let users = vec![
+            user(
+                "https://a.example.com",
+                vec![
+                    dst("https://x.example.com", "1", &["1", "2"]),
+                    dst("https://x.example.com", "2", &["2", "3"]),
+                    dst("https://y.example.com", "1", &["1", "3"]),
+                ],
+            ),
+            user(
+                "https://b.example.com",
+                vec![dst("https://x.example.com", "1", &["1", "4"])],
+            ),
+        ];
+        let index = DestinationIndex::new(&users);
+
+        for src_origin in ["https://a.example.com", "https://b.example.com"] {
+            for dst_origin in ["https://x.example.com", "https://y.example.com"] {
+                for dst_account_identifier in ["1", "2"] {
+                    let account_pair = account_pair(src_origin, dst_origin, dst_account_identifier);
+                    for src_identifier in ["1", "2", "3", "4", "5"] {
+                        assert_eq!(
+                            index.find_post_dst_identifier(&account_pair, src_identifier),
+                            find_post_dst_identifier_linearly(
+                                &users,
+                                &account_pair,
+                                src_identifier
+                            ),
+                            "{} {} {} {}",
+                            src_origin,
+                            dst_origin,
+                            dst_account_identifier,
+                            src_identifier
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn accounts_on_same_dst_origin_are_not_mixed() {
+        let users = vec![user(
+            "https://a.example.com",
+            vec![
+                dst("https://x.example.com", "1", &["1"]),
+                dst("https://x.example.com", "2", &["1"]),
+            ],
+        )];
+        let mut index = DestinationIndex::new(&users);
+        let first = account_pair("https://a.example.com", "https://x.example.com", "1");
+        let second = account_pair("https://a.example.com", "https://x.example.com", "2");
+
+        assert_eq!(
+            index.find_post_dst_identifier(&first, "1"),
+            Some("https://x.example.com-1-1")
+        );
+        assert_eq!(
+            index.find_post_dst_identifier(&second, "1"),
+            Some("https://x.example.com-2-1")
+        );
+
+        index.remove(&first, "https://x.example.com-1-1");
+
+        assert_eq!(index.find_post_dst_identifier(&first, "1"), None);
+        assert_eq!(
+            index.find_post_dst_identifier(&second, "1"),
+            Some("https://x.example.com-2-1")
+        );
+    }
 }
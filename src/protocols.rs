@@ -1,56 +1,233 @@
 mod at_proto;
 pub mod at_proto_client;
+pub mod discord_client;
+pub mod error;
 mod from_megalodon;
+pub mod media;
 pub mod megalodon_client;
 mod misskey_client;
+#[cfg(test)]
+pub mod mock_client;
+pub mod ogp;
+mod redact;
+pub mod retry;
+pub mod text;
+pub mod threads_client;
 mod twitter_api;
 pub mod twitter_client;
 
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, FixedOffset};
+use futures::future::join_all;
+use serde_json::Value;
+use tracing::{error, info};
 
-use crate::{config, sources::source, store};
+use crate::{config, rate_limit::Budget, sources::source, store};
+
+use self::error::ClientError;
+
+/** fetch_statuses で cursor まで遡る際の最大ページ数 */
+const MAX_CATCH_UP_PAGES: usize = 5;
+
+/**
+ * 前回の取得で最新だった status
+ *
+ * 投稿日時は backdate や時計のずれで前後するので、遡るのは identifier が見つかるまでにする。
+ * 日時は時刻で再開する Jetstream だけが使う
+ */
+pub struct Cursor<'a> {
+    pub identifier: &'a str,
+    pub created_at: &'a DateTime<FixedOffset>,
+}
+
+/** 取得したページが cursor まで届いているか。cursor が無い場合は最新のページだけで良い */
+fn is_caught_up(page: &[source::LiveStatus], cursor: Option<&Cursor>) -> bool {
+    let Some(cursor) = cursor else {
+        return true;
+    };
+    page.iter()
+        .any(|live| live.identifier() == cursor.identifier)
+}
+
+pub struct NewPost<'a> {
+    pub content: &'a str,
+    pub facets: &'a [store::operations::Facet],
+    pub reply_identifier: Option<&'a str>,
+    pub images: Vec<store::operations::Medium>,
+    pub external: Option<store::operations::External>,
+    pub content_warning: Option<&'a str>,
+    pub poll: Option<&'a store::operations::Poll>,
+    /** 引用する送信先の投稿の identifier。引用できない送信先では None */
+    pub quote_identifier: Option<&'a str>,
+    /** 文字数の上限を超えて省略した場合に末尾に付けるリンク */
+    pub src_uri: Option<&'a str>,
+    /** 再送しても重複して投稿されないように、送信先で投稿を特定するキー。元の投稿の URI を使う */
+    pub idempotency_key: &'a str,
+    pub created_at: &'a DateTime<FixedOffset>,
+    /** 予約投稿の日時。Mastodon 以外は無視する */
+    pub scheduled_at: Option<&'a DateTime<FixedOffset>>,
+}
+
+/** テスト用の投稿が借用する値を持っておくもの */
+#[cfg(test)]
+pub struct NewPostFixture {
+    pub created_at: DateTime<FixedOffset>,
+}
+
+#[cfg(test)]
+impl Default for NewPostFixture {
+    fn default() -> Self {
+        Self {
+            created_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl NewPostFixture {
+    /** 本文だけの投稿 */
+    pub fn post<'a>(&'a self, content: &'a str) -> NewPost<'a> {
+        NewPost {
+            content,
+            facets: &[],
+            reply_identifier: None,
+            images: Vec::new(),
+            external: None,
+            content_warning: None,
+            poll: None,
+            quote_identifier: None,
+            src_uri: None,
+            idempotency_key: "https://example.com/1",
+            created_at: &self.created_at,
+            scheduled_at: None,
+        }
+    }
+}
+
+/** verify で確かめたアカウント */
+pub struct AccountInfo {
+    pub id: String,
+    pub handle: String,
+}
 
 #[async_trait]
 pub trait Client: Send + Sync {
     fn to_session(&self) -> Option<String>;
 
-    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>>;
+    /** 認証情報が有効かを確かめて、アカウントを返す */
+    async fn verify(&self) -> Result<AccountInfo, ClientError>;
 
-    async fn post(
+    /**
+     * 新しい順に status を取得する
+     *
+     * 前回以降の投稿が 1 ページに収まらない場合は、cursor まで遡って取得する
+     */
+    async fn fetch_statuses(
         &mut self,
+        cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError>;
+
+    /** 自分の status を 1 件取得する。見つからない場合や他人の status の場合は None */
+    async fn get_status(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError>;
+
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError>;
+
+    /**
+     * 文字数の上限を超える本文を、スレッドにつなげて送る投稿ごとに分ける
+     *
+     * 分けない送信先は post で省略するので、そのまま 1 つにして返す
+     */
+    fn split_content(
+        &self,
         content: &str,
         facets: &[store::operations::Facet],
-        reply_identifier: Option<&str>,
-        images: Vec<store::operations::Medium>,
-        external: Option<store::operations::External>,
-        created_at: &DateTime<FixedOffset>,
-    ) -> Result<String>;
+    ) -> Vec<(String, Vec<store::operations::Facet>)> {
+        vec![(content.to_owned(), facets.to_vec())]
+    }
+
+    /**
+     * post で送る内容を、送らずに返す
+     *
+     * 画像などアップロードしないと作れないものは、元の URL で代わりにする
+     */
+    async fn preview(&mut self, _post: NewPost<'_>) -> Result<Value, ClientError> {
+        Err(anyhow!("preview is not supported").into())
+    }
+
+    /** 更新後の identifier を返す */
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError>;
+
+    /**
+     * 本文と合わせて、送ったメディアの代替テキストを先頭から順に差し替える
+     *
+     * 更新後の identifier を返す。代替テキストを編集できない送信先では本文だけ更新する
+     */
+    async fn update_post_with_media_alts(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+        _media_alts: &[String],
+    ) -> Result<String, ClientError> {
+        self.update_post(identifier, content, facets).await
+    }
 
     async fn repost(
         &mut self,
         target_identifier: &str,
         created_at: &DateTime<FixedOffset>,
-    ) -> Result<String>;
+    ) -> Result<String, ClientError>;
+
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError>;
+
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError>;
 
-    async fn delete_post(&mut self, identifier: &str) -> Result<()>;
+    /** いいねの identifier を返す */
+    async fn like(
+        &mut self,
+        _target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        Err(anyhow!("like is not supported").into())
+    }
 
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()>;
+    async fn unlike(&mut self, _identifier: &str) -> Result<(), ClientError> {
+        Err(anyhow!("unlike is not supported").into())
+    }
 }
 
 pub async fn create_client(
     http_client: Arc<reqwest::Client>,
     account: &config::Account,
     initial_session: Option<String>,
+    retry_policy: &retry::RetryPolicy,
+    max_media_bytes: usize,
+    budget: Budget,
 ) -> Result<Box<dyn Client>> {
+    let media = media::DownloadOptions {
+        retry_policy: *retry_policy,
+        max_bytes: max_media_bytes,
+    };
     match account {
         config::Account::AtProtocol {
             origin,
             identifier,
             password,
+            threadgate,
+            link_card,
+            link_card_timeout_secs,
+            fetch_mode,
         } => Ok(Box::new(
             at_proto_client::Client::new(
                 origin.into(),
@@ -58,20 +235,62 @@ pub async fn create_client(
                 identifier.into(),
                 password.into(),
                 initial_session,
+                *retry_policy,
+                at_proto_client::Options {
+                    threadgate: *threadgate,
+                    link_card_timeout: link_card
+                        .then(|| Duration::from_secs(*link_card_timeout_secs)),
+                    fetch_mode: *fetch_mode,
+                    budget,
+                    media,
+                },
             )
             .await?,
         )),
         config::Account::Mastodon {
             origin,
             access_token,
+            visibility,
+            include_reposts,
         } => Ok(Box::new(
-            megalodon_client::Client::new_mastodon(origin.clone(), access_token.clone()).await?,
+            megalodon_client::Client::new_mastodon(
+                http_client,
+                origin.clone(),
+                access_token.clone(),
+                *visibility,
+                *include_reposts,
+                budget,
+                media,
+            )
+            .await?,
         )),
         config::Account::Misskey {
             origin,
             access_token,
+            visibility,
+            visible_user_ids,
+            local_only,
+            link_preview,
+            fetch_limit,
+            mirror_reactions,
         } => Ok(Box::new(
-            misskey_client::Client::new(http_client, origin.clone(), access_token.clone()).await?,
+            misskey_client::Client::new(
+                http_client,
+                origin.clone(),
+                access_token.clone(),
+                initial_session,
+                misskey_client::Options {
+                    visibility: *visibility,
+                    visible_user_ids: visible_user_ids.clone(),
+                    local_only: *local_only,
+                    link_preview: *link_preview,
+                    fetch_limit: *fetch_limit,
+                    mirror_reactions: *mirror_reactions,
+                    media,
+                },
+                *retry_policy,
+            )
+            .await?,
         )),
         config::Account::Twitter {
             api_key,
@@ -85,8 +304,138 @@ pub async fn create_client(
                 api_key_secret.clone(),
                 access_token.clone(),
                 access_token_secret.clone(),
+                media,
             )
             .await?,
         )),
+        config::Account::Threads {
+            access_token,
+            user_id,
+        } => Ok(Box::new(
+            threads_client::Client::new(http_client, access_token.clone(), user_id.clone()).await?,
+        )),
+        config::Account::Discord { webhook_url } => Ok(Box::new(
+            discord_client::Client::new(http_client, webhook_url.clone()).await?,
+        )),
+    }
+}
+
+/**
+ * 設定されている全てのアカウントの認証情報を並行して確かめる
+ *
+ * 失敗したアカウントはログに出し、1 つでも失敗していればエラーにする
+ */
+pub async fn check_accounts(
+    http_client: Arc<reqwest::Client>,
+    config: &config::Config,
+) -> Result<()> {
+    let mut account_keys = HashSet::new();
+    let accounts: Vec<_> = config
+        .users
+        .iter()
+        .flat_map(|user| {
+            user.srcs
+                .iter()
+                .chain(user.dsts.iter().map(|dst| &dst.account))
+        })
+        .filter(|account| account_keys.insert(account.to_account_key()))
+        .collect();
+    let futures = accounts.iter().map(|account| {
+        let http_client = http_client.clone();
+        async move {
+            let client = create_client(
+                http_client,
+                account,
+                None,
+                &config.retry,
+                config.http.max_media_bytes,
+                Budget::default(),
+            )
+            .await
+            .map_err(ClientError::classify)?;
+            client.verify().await
+        }
+    });
+    let mut failed = 0;
+    for (account, result) in accounts.iter().zip(join_all(futures).await) {
+        let origin = account.to_account_key().origin;
+        match result {
+            Ok(account_info) => info!(
+                "verified: {} {} ({})",
+                origin, account_info.handle, account_info.id
+            ),
+            Err(err) => {
+                error!("verification failed: {}: {}", origin, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} account(s) failed verification", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::{megalodon_client::test_account, *};
+
+    async fn mastodon_server(status: u16) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .respond_with(ResponseTemplate::new(status).set_body_json(match status {
+                200 => test_account("1", "test"),
+                _ => json!({ "error": "The access token is invalid" }),
+            }))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn config(src: &MockServer, dst: &MockServer) -> config::Config {
+        serde_json::from_value(json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": src.uri(),
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": dst.uri(),
+                    "accessToken": "dst",
+                }],
+            }],
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_accounts_are_verified() {
+        let src = mastodon_server(200).await;
+        let dst = mastodon_server(200).await;
+
+        check_accounts(Arc::new(reqwest::Client::new()), &config(&src, &dst))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unauthorized_account_fails_verification() {
+        let src = mastodon_server(200).await;
+        let dst = mastodon_server(401).await;
+
+        let err = check_accounts(Arc::new(reqwest::Client::new()), &config(&src, &dst))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.to_string(), "1 account(s) failed verification");
     }
 }
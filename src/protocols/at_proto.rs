@@ -3,72 +3,176 @@ use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use tracing::error;
 
-use self::repo::Repo;
+use self::{identity::Identity, repo::Repo};
+
+use crate::rate_limit::Budget;
+
+use super::{
+    error::ClientError,
+    redact::redact_json,
+    retry::{send_with_retry, RetryPolicy},
+};
 
 pub mod from_atrium;
+pub mod identity;
+pub mod jetstream;
 pub mod repo;
 pub mod utils;
 
 pub struct Api {
+    pub identity: Identity,
     pub repo: Repo,
 }
 
 impl Api {
-    pub fn new(origin: String) -> Self {
+    pub fn new(origin: String, retry_policy: RetryPolicy, budget: Budget) -> Self {
         Self {
-            repo: Repo::new(origin.clone()),
+            identity: Identity::new(origin.clone(), retry_policy, budget.clone()),
+            repo: Repo::new(origin.clone(), retry_policy, budget),
         }
     }
 }
 
+/** テスト用のログイン済みのセッション */
+#[cfg(test)]
+pub fn test_session() -> atrium_api::com::atproto::server::create_session::Output {
+    serde_json::from_value(serde_json::json!({
+        "accessJwt": "access",
+        "refreshJwt": "refresh",
+        "did": "did:plc:test",
+        "handle": "test.bsky.social",
+    }))
+    .unwrap()
+}
+
 async fn query<T: DeserializeOwned, U: Serialize + ?Sized>(
     client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    budget: &Budget,
     origin: &str,
     token: &str,
     lexicon_id: &str,
     query_params: &U,
 ) -> Result<T> {
-    let resp = client
-        .get(format!("{}/xrpc/{}", origin, lexicon_id))
-        .query(query_params)
-        .bearer_auth(token)
-        .send()
-        .await?;
-    if let Err(err) = resp.error_for_status_ref() {
-        let json: Value = resp.json().await?;
+    let resp = send_with_retry(retry_policy, || {
+        client
+            .get(format!("{}/xrpc/{}", origin, lexicon_id))
+            .query(query_params)
+            .bearer_auth(token)
+    })
+    .await?;
+    budget.update(resp.headers());
+    if let Some(client_error) = ClientError::from_response(&resp) {
+        let url = resp.url().to_string();
+        let status = resp.status();
+        let json: Value = resp.json().await.unwrap_or_default();
         error!(
             "url={:?}, status-code={:?}, body={}",
-            err.url().map(ToString::to_string),
-            err.status(),
-            json
+            url,
+            status,
+            redact_json(&json)
         );
-        return Err(err.into());
+        return Err(client_error.into());
     }
     Ok(resp.json().await?)
 }
 
 async fn procedure<T: DeserializeOwned>(
     client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    budget: &Budget,
     origin: &str,
     token: &str,
     lexicon_id: &str,
     properties: &Value,
 ) -> Result<T> {
-    let resp = client
-        .post(format!("{}/xrpc/{}", origin, lexicon_id))
-        .bearer_auth(token)
-        .json(properties)
-        .send()
-        .await?;
-    if let Err(err) = resp.error_for_status_ref() {
-        let json: Value = resp.json().await?;
+    let resp = send_with_retry(retry_policy, || {
+        client
+            .post(format!("{}/xrpc/{}", origin, lexicon_id))
+            .bearer_auth(token)
+            .json(properties)
+    })
+    .await?;
+    budget.update(resp.headers());
+    if let Some(client_error) = ClientError::from_response(&resp) {
+        let url = resp.url().to_string();
+        let status = resp.status();
+        let json: Value = resp.json().await.unwrap_or_default();
         error!(
             "url={:?}, status-code={:?}, body={}",
-            err.url().map(ToString::to_string),
-            err.status(),
-            json
+            url,
+            status,
+            redact_json(&json)
         );
-        return Err(err.into());
+        return Err(client_error.into());
     }
     Ok(resp.json().await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn tokens_in_error_response_are_not_logged() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.server.createSession"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": "InvalidRequest",
+                "accessJwt": "secret-access-jwt",
+                "refreshJwt": "secret-refresh-jwt",
+                "password": "secret-password",
+            })))
+            .mount(&server)
+            .await;
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let result: Result<Value> = procedure(
+            &reqwest::Client::new(),
+            &RetryPolicy::default(),
+            &Budget::default(),
+            &server.uri(),
+            "secret-bearer-token",
+            "com.atproto.server.createSession",
+            &json!({ "identifier": "test", "password": "secret-password" }),
+        )
+        .await;
+
+        assert!(result.is_err());
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(log.contains("status-code=400"), "{}", log);
+        assert!(log.contains("InvalidRequest"), "{}", log);
+        assert!(!log.contains("secret"), "{}", log);
+    }
+}
@@ -18,6 +18,8 @@ use regex::Regex;
 
 use crate::{sources::source, store};
 
+use super::utils::SENSITIVE_LABELS;
+
 impl TryFrom<app::bsky::richtext::facet::Main> for store::operations::Facet {
     type Error = anyhow::Error;
 
@@ -47,6 +49,9 @@ impl From<app::bsky::embed::images::ViewImage> for store::operations::Medium {
         store::operations::Medium {
             alt: value.alt.clone(),
             url: value.fullsize.clone(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
         }
     }
 }
@@ -62,7 +67,7 @@ impl From<Box<app::bsky::embed::external::View>> for source::LiveExternal {
     }
 }
 
-fn to_external_uri(at_uri: &str) -> String {
+pub fn to_external_uri(at_uri: &str) -> String {
     let m = Regex::new(r"^at://(.+?)/app.bsky.feed.post/(.+?)$")
         .unwrap()
         .captures(at_uri)
@@ -74,7 +79,7 @@ fn to_external_uri(at_uri: &str) -> String {
     )
 }
 
-fn rewrite_content(
+pub fn rewrite_content(
     mut content: String,
     mut facets: Option<Vec<app::bsky::richtext::facet::Main>>,
     quote: Option<&str>,
@@ -181,7 +186,18 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
         else {
             unreachable!()
         };
-        let (media, external, quote) = parse_embed(value.data.post.data.embed);
+        let (mut media, external, quote) = parse_embed(value.data.post.data.embed);
+        let sensitive = value
+            .data
+            .post
+            .data
+            .labels
+            .iter()
+            .flatten()
+            .any(|label| SENSITIVE_LABELS.contains(&label.val.as_str()));
+        media
+            .iter_mut()
+            .for_each(|medium| medium.sensitive = sensitive);
         Ok(
             if let Some(Union::Refs(FeedViewPostReasonRefs::ReasonRepost(reason))) =
                 value.data.reason
@@ -190,6 +206,8 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
                     src_identifier: value.data.post.data.cid.as_ref().to_string(),
                     target_src_identifier: value.data.post.data.cid.as_ref().to_string(),
                     target_src_uri: to_external_uri(&value.data.post.data.uri),
+                    target_src_at_uri: Some(value.data.post.data.uri.clone()),
+                    target_src_cid: Some(value.data.post.data.cid.as_ref().to_string()),
                     created_at: DateTime::parse_from_rfc3339(
                         &reason.indexed_at.as_ref().to_rfc3339(),
                     )?,
@@ -216,6 +234,10 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
                         .map(|x| x.parent.cid.as_ref().to_string()),
                     media,
                     external,
+                    content_warning: None,
+                    poll: None,
+                    quote: None,
+                    custom_emojis: Vec::new(),
                     created_at: DateTime::parse_from_rfc3339(
                         &record.data.created_at.as_ref().to_rfc3339(),
                     )?,
@@ -224,3 +246,71 @@ impl TryFrom<app::bsky::feed::defs::FeedViewPost> for source::LiveStatus {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{super::utils::to_record, *};
+
+    const URI: &str = "at://did:plc:test/app.bsky.feed.post/3kabc";
+    const CID: &str = "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm";
+
+    /** 送った record を AppView が返す形にする。自己ラベルは labels に入って返ってくる */
+    fn feed_view_post(record: Value) -> app::bsky::feed::defs::FeedViewPost {
+        let labels: Vec<_> = record["labels"]["values"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|value| {
+                json!({
+                    "src": "did:plc:test",
+                    "uri": URI,
+                    "val": value["val"],
+                    "cts": "2024-01-01T00:00:00.000Z",
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "post": {
+                "uri": URI,
+                "cid": CID,
+                "author": { "did": "did:plc:test", "handle": "test.bsky.social" },
+                "record": record,
+                "embed": {
+                    "$type": "app.bsky.embed.images#view",
+                    "images": [{
+                        "thumb": "https://cdn.example.com/thumb.jpg",
+                        "fullsize": "https://cdn.example.com/fullsize.jpg",
+                        "alt": "alt",
+                    }],
+                },
+                "labels": labels,
+                "indexedAt": "2024-01-01T00:00:00.000Z",
+            },
+        }))
+        .unwrap()
+    }
+
+    fn is_sensitive(sensitive: bool) -> bool {
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let mut record =
+            serde_json::to_value(to_record("hello", &[], None, None, sensitive, &created_at))
+                .unwrap();
+        record["$type"] = json!("app.bsky.feed.post");
+
+        let source::LiveStatus::Post(post) = feed_view_post(record).try_into().unwrap() else {
+            panic!("unexpected status");
+        };
+        let [medium] = post.media.as_slice() else {
+            panic!("unexpected media");
+        };
+        medium.sensitive
+    }
+
+    #[test]
+    fn sensitive_flag_round_trips_through_self_labels() {
+        assert!(is_sensitive(true));
+        assert!(!is_sensitive(false));
+    }
+}
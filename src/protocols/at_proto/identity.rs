@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::Result;
+use atrium_api::com;
+
+use crate::{protocols::retry::RetryPolicy, rate_limit::Budget};
+
+use super::query;
+
+pub struct Identity {
+    origin: String,
+    retry_policy: RetryPolicy,
+    budget: Budget,
+}
+
+impl Identity {
+    pub fn new(origin: String, retry_policy: RetryPolicy, budget: Budget) -> Self {
+        Self {
+            origin,
+            retry_policy,
+            budget,
+        }
+    }
+
+    pub async fn resolve_handle(
+        &self,
+        client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+        handle: &str,
+    ) -> Result<com::atproto::identity::resolve_handle::Output> {
+        let token = &session.access_jwt;
+        let lexicon_id = "com.atproto.identity.resolveHandle";
+        let query_params = &[("handle", handle)];
+
+        query(
+            client,
+            &self.retry_policy,
+            &self.budget,
+            &self.origin,
+            token,
+            lexicon_id,
+            query_params,
+        )
+        .await
+    }
+}
+
+/** 使われていない順に捨てる、件数に上限のある対応表 */
+struct Lru {
+    capacity: usize,
+    entries: HashMap<String, Option<String>>,
+    /** 古い順 */
+    order: VecDeque<String>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.order.retain(|x| x != key);
+        self.order.push_back(key.to_owned());
+    }
+
+    fn get(&mut self, key: &str) -> Option<Option<String>> {
+        let value = self.entries.get(key)?.clone();
+        self.touch(key);
+        Some(value)
+    }
+
+    fn put(&mut self, key: &str, value: Option<String>) {
+        self.entries.insert(key.to_owned(), value);
+        self.touch(key);
+        while self.order.len() > self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/**
+ * handle から DID への対応の cache
+ *
+ * 解決できなかったものも None として覚えておき、同じものを何度も問い合わせないようにする
+ */
+pub struct ActorCache {
+    dids_by_handle: Lru,
+}
+
+impl ActorCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            dids_by_handle: Lru::new(capacity),
+        }
+    }
+
+    /** cache に無ければ None、解決できないと分かっていれば Some(None) */
+    pub fn did(&mut self, handle: &str) -> Option<Option<String>> {
+        self.dids_by_handle.get(&normalize_handle(handle))
+    }
+
+    pub fn put(&mut self, handle: &str, did: &str) {
+        self.dids_by_handle
+            .put(&normalize_handle(handle), Some(did.to_owned()));
+    }
+
+    pub fn put_unresolvable(&mut self, handle: &str) {
+        self.dids_by_handle.put(&normalize_handle(handle), None);
+    }
+}
+
+/** 先頭の @ を取り除き、大文字小文字を区別しないので小文字にする */
+pub fn normalize_handle(handle: &str) -> String {
+    handle.trim_start_matches('@').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut cache = ActorCache::new(2);
+        cache.put("a.example.com", "did:plc:a");
+        cache.put("b.example.com", "did:plc:b");
+        assert_eq!(cache.did("a.example.com"), Some(Some("did:plc:a".into())));
+
+        cache.put("c.example.com", "did:plc:c");
+
+        assert_eq!(cache.did("a.example.com"), Some(Some("did:plc:a".into())));
+        assert_eq!(cache.did("b.example.com"), None);
+        assert_eq!(cache.did("c.example.com"), Some(Some("did:plc:c".into())));
+    }
+
+    #[test]
+    fn unresolvable_handle_is_remembered() {
+        let mut cache = ActorCache::new(2);
+        assert_eq!(cache.did("nobody.example.com"), None);
+
+        cache.put_unresolvable("@Nobody.example.com");
+
+        assert_eq!(cache.did("nobody.example.com"), Some(None));
+    }
+}
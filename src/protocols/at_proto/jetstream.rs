@@ -0,0 +1,478 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use atrium_api::app;
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, trace, warn};
+
+use crate::{sources::source, store};
+
+use super::{
+    from_atrium::{rewrite_content, to_external_uri},
+    utils::SENSITIVE_LABELS,
+};
+
+const ENDPOINT: &str = "wss://jetstream2.us-east.bsky.network";
+const POST_COLLECTION: &str = "app.bsky.feed.post";
+const REPOST_COLLECTION: &str = "app.bsky.feed.repost";
+/** createdAt は投稿したクライアントの時刻なので、受信時刻との差の分だけ早めに遡る */
+const CURSOR_MARGIN: Duration = Duration::from_secs(60);
+/** この時間イベントが来なければ追いついたものとする */
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct Commit {
+    operation: String,
+    collection: String,
+    rkey: String,
+    #[serde(default)]
+    record: Option<Value>,
+    #[serde(default)]
+    cid: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    did: String,
+    time_us: i64,
+    kind: String,
+    #[serde(default)]
+    commit: Option<Commit>,
+}
+
+fn to_cdn_url(kind: &str, did: &str, blob: &Value) -> Option<String> {
+    let cid = blob.get("ref")?.get("$link")?.as_str()?;
+    Some(format!(
+        "https://cdn.bsky.app/img/{}/plain/{}/{}@jpeg",
+        kind, did, cid
+    ))
+}
+
+fn to_quote_uri(record: &Value) -> Option<String> {
+    let uri = record.get("uri")?.as_str()?;
+    // NOTE: フィードやリストの埋め込みは URL に変換できない
+    uri.contains(&format!("/{}/", POST_COLLECTION))
+        .then(|| to_external_uri(uri))
+}
+
+fn parse_media(did: &str, media: &Value) -> (Vec<store::operations::Medium>, source::LiveExternal) {
+    match media.get("$type").and_then(Value::as_str) {
+        Some("app.bsky.embed.images") => (
+            media
+                .get("images")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|image| {
+                    Some(store::operations::Medium {
+                        url: to_cdn_url("feed_fullsize", did, image.get("image")?)?,
+                        alt: image
+                            .get("alt")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_owned(),
+                        sensitive: false,
+                        focus: None,
+                        content_type: None,
+                    })
+                })
+                .collect(),
+            source::LiveExternal::None,
+        ),
+        Some("app.bsky.embed.external") => {
+            let Some(external) = media.get("external") else {
+                return (vec![], source::LiveExternal::None);
+            };
+            let get = |key: &str| {
+                external
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            (
+                vec![],
+                source::LiveExternal::Some(store::operations::External {
+                    uri: get("uri"),
+                    title: get("title"),
+                    description: get("description"),
+                    thumb_url: external
+                        .get("thumb")
+                        .and_then(|thumb| to_cdn_url("feed_thumbnail", did, thumb)),
+                }),
+            )
+        }
+        _ => (vec![], source::LiveExternal::None),
+    }
+}
+
+/** record の embed はビューと違って blob の参照なので、CDN の URL を組み立てる */
+fn parse_embed(
+    did: &str,
+    embed: Option<&Value>,
+) -> (
+    Vec<store::operations::Medium>,
+    source::LiveExternal,
+    Option<String>,
+) {
+    let Some(embed) = embed else {
+        return (vec![], source::LiveExternal::None, None);
+    };
+    match embed.get("$type").and_then(Value::as_str) {
+        Some("app.bsky.embed.record") => (
+            vec![],
+            source::LiveExternal::None,
+            embed.get("record").and_then(to_quote_uri),
+        ),
+        Some("app.bsky.embed.recordWithMedia") => {
+            let (media, external) = embed
+                .get("media")
+                .map(|media| parse_media(did, media))
+                .unwrap_or((vec![], source::LiveExternal::None));
+            let quote = embed
+                .get("record")
+                .and_then(|record| record.get("record"))
+                .and_then(to_quote_uri);
+            (media, external, quote)
+        }
+        _ => {
+            let (media, external) = parse_media(did, embed);
+            (media, external, None)
+        }
+    }
+}
+
+fn parse_created_at(record: &Value) -> Result<DateTime<FixedOffset>> {
+    let created_at = record
+        .get("createdAt")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("createdAt is not found"))?;
+    Ok(DateTime::parse_from_rfc3339(created_at)?)
+}
+
+fn to_live_post(did: &str, rkey: &str, cid: String, record: &Value) -> Result<source::LivePost> {
+    let text = record
+        .get("text")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned();
+    let facets: Option<Vec<app::bsky::richtext::facet::Main>> = record
+        .get("facets")
+        .map(|facets| serde_json::from_value(facets.clone()))
+        .transpose()?;
+    let (mut media, external, quote) = parse_embed(did, record.get("embed"));
+    let sensitive = record
+        .get("labels")
+        .and_then(|labels| labels.get("values"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|label| label.get("val").and_then(Value::as_str))
+        .any(|val| SENSITIVE_LABELS.contains(&val));
+    media
+        .iter_mut()
+        .for_each(|medium| medium.sensitive = sensitive);
+    Ok(source::LivePost {
+        identifier: cid,
+        uri: format!("at://{}/{}/{}", did, POST_COLLECTION, rkey),
+        facets: facets
+            .iter()
+            .flatten()
+            .filter_map(|x| x.to_owned().try_into().ok())
+            .collect(),
+        content: rewrite_content(text, facets, quote.as_deref()),
+        reply_src_identifier: record
+            .get("reply")
+            .and_then(|reply| reply.get("parent"))
+            .and_then(|parent| parent.get("cid"))
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        media,
+        external,
+        content_warning: None,
+        poll: None,
+        quote: None,
+        custom_emojis: Vec::new(),
+        created_at: parse_created_at(record)?,
+    })
+}
+
+/** 自分の投稿をリポストした場合に投稿と区別できるように、identifier はリポスト自体の cid にする */
+fn to_live_repost(
+    cid: String,
+    record: &Value,
+) -> Result<store::operations::CreateRepostOperationStatus> {
+    let subject = record
+        .get("subject")
+        .ok_or_else(|| anyhow!("subject is not found"))?;
+    let get = |key: &str| {
+        subject
+            .get(key)
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("subject.{} is not found", key))
+    };
+    Ok(store::operations::CreateRepostOperationStatus {
+        src_identifier: cid,
+        target_src_identifier: get("cid")?.to_owned(),
+        target_src_uri: to_external_uri(get("uri")?),
+        target_src_at_uri: Some(get("uri")?.to_owned()),
+        target_src_cid: Some(get("cid")?.to_owned()),
+        created_at: parse_created_at(record)?,
+    })
+}
+
+/**
+ * 投稿とリポストの作成のイベントを LiveStatus にする
+ *
+ * 削除や更新のイベントには rkey しか無く、cid を identifier にしている保存済みの status と
+ * 対応付けられないので扱わない。Jetstream で取得する場合は、削除や編集は送信先に反映されない
+ */
+fn to_live_status(event: Event) -> Result<Option<source::LiveStatus>> {
+    if event.kind != "commit" {
+        return Ok(None);
+    }
+    let Some(commit) = event.commit else {
+        return Ok(None);
+    };
+    if commit.operation != "create" {
+        debug!(
+            "{} is not supported by jetstream, ignored: {}/{}",
+            commit.operation, commit.collection, commit.rkey
+        );
+        return Ok(None);
+    }
+    let (Some(record), Some(cid)) = (commit.record, commit.cid) else {
+        return Ok(None);
+    };
+    Ok(match commit.collection.as_str() {
+        POST_COLLECTION => Some(source::LiveStatus::Post(to_live_post(
+            &event.did,
+            &commit.rkey,
+            cid,
+            &record,
+        )?)),
+        REPOST_COLLECTION => Some(source::LiveStatus::Repost(to_live_repost(cid, &record)?)),
+        _ => None,
+    })
+}
+
+fn identifier(status: &source::LiveStatus) -> &str {
+    match status {
+        source::LiveStatus::Post(post) => &post.identifier,
+        source::LiveStatus::Repost(repost) => &repost.src_identifier,
+        source::LiveStatus::Like(like) => &like.src_identifier,
+    }
+}
+
+/**
+ * cursor から until_us に追いつくか、イベントが途切れるまで読む
+ *
+ * 切断された場合に続きから読み直せるように、読んだ分だけ cursor を進める。
+ * 壊れたイベントがあっても、それだけを飛ばして読み続ける
+ */
+async fn read_events(
+    did: &str,
+    since: &DateTime<FixedOffset>,
+    cursor: &mut i64,
+    until_us: i64,
+    statuses: &mut Vec<source::LiveStatus>,
+) -> Result<()> {
+    let url = format!(
+        "{}/subscribe?wantedCollections={}&wantedCollections={}&wantedDids={}&cursor={}",
+        ENDPOINT, POST_COLLECTION, REPOST_COLLECTION, did, cursor
+    );
+    let (mut stream, _) = connect_async(url).await?;
+    loop {
+        let message = match timeout(IDLE_TIMEOUT, stream.next()).await {
+            Err(_) => return Ok(()),
+            Ok(None) => bail!("disconnected"),
+            Ok(Some(message)) => message?,
+        };
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let event: Event = match serde_json::from_str(&text) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("malformed jetstream event, skipped: {}", err);
+                continue;
+            }
+        };
+        *cursor = event.time_us;
+        let time_us = event.time_us;
+        match to_live_status(event) {
+            // NOTE: 余裕を持って遡った分は前回までに取得済みで、
+            //       取得できた範囲に含めると保存済みの status が消えたと判定されてしまう
+            Ok(Some(status)) if status.created_at() <= since => {}
+            Ok(Some(status)) => {
+                // NOTE: 読み直した場合は同じイベントが再び届く
+                if statuses
+                    .iter()
+                    .all(|stored| identifier(stored) != identifier(&status))
+                {
+                    trace!("jetstream event: {}", identifier(&status));
+                    statuses.push(status);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!("malformed jetstream commit, skipped: {}", err),
+        }
+        if time_us >= until_us {
+            return Ok(());
+        }
+    }
+}
+
+/**
+ * Jetstream から since 以降の自分の投稿とリポストを取得する
+ *
+ * 定期的に実行されるので、接続し続けずに現在時刻に追いついたところで切断する
+ */
+pub async fn fetch_statuses(
+    did: &str,
+    since: &DateTime<FixedOffset>,
+) -> Result<Vec<source::LiveStatus>> {
+    let until_us = Utc::now().timestamp_micros();
+    let mut cursor = since.timestamp_micros() - CURSOR_MARGIN.as_micros() as i64;
+    let mut statuses = Vec::new();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+    while let Err(err) = read_events(did, since, &mut cursor, until_us, &mut statuses).await {
+        attempts += 1;
+        if attempts >= MAX_ATTEMPTS {
+            return Err(err);
+        }
+        warn!("jetstream failed, reconnect: {:?}", err);
+        sleep(backoff).await;
+        backoff *= 2;
+    }
+    statuses.sort_by(|a, b| b.created_at().cmp(a.created_at()));
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(operation: &str, collection: &str, record: Value) -> Event {
+        serde_json::from_value(json!({
+            "did": "did:plc:test",
+            "time_us": 1725911162329308_i64,
+            "kind": "commit",
+            "commit": {
+                "rev": "3l3qo2vutsw2b",
+                "operation": operation,
+                "collection": collection,
+                "rkey": "3l3qo2vuowo2b",
+                "record": record,
+                "cid": "bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn post_commit_is_decoded() {
+        let event = event(
+            "create",
+            POST_COLLECTION,
+            json!({
+                "$type": "app.bsky.feed.post",
+                "createdAt": "2024-09-09T19:46:02.102Z",
+                "text": "hello",
+                "embed": {
+                    "$type": "app.bsky.embed.images",
+                    "images": [{
+                        "alt": "alt text",
+                        "image": {
+                            "$type": "blob",
+                            "ref": { "$link": "bafkreiblob" },
+                            "mimeType": "image/jpeg",
+                            "size": 1000
+                        }
+                    }]
+                },
+                "reply": {
+                    "parent": { "cid": "bafyreiparent", "uri": "at://did:plc:test/app.bsky.feed.post/parent" },
+                    "root": { "cid": "bafyreiparent", "uri": "at://did:plc:test/app.bsky.feed.post/parent" }
+                }
+            }),
+        );
+
+        let Some(source::LiveStatus::Post(post)) = to_live_status(event).unwrap() else {
+            panic!("not a post");
+        };
+
+        assert_eq!(
+            post.identifier,
+            "bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi"
+        );
+        assert_eq!(
+            post.uri,
+            "at://did:plc:test/app.bsky.feed.post/3l3qo2vuowo2b"
+        );
+        assert_eq!(post.content, "hello");
+        assert_eq!(post.reply_src_identifier.as_deref(), Some("bafyreiparent"));
+        assert_eq!(
+            post.media[0].url,
+            "https://cdn.bsky.app/img/feed_fullsize/plain/did:plc:test/bafkreiblob@jpeg"
+        );
+        assert_eq!(post.media[0].alt, "alt text");
+        assert_eq!(
+            post.created_at,
+            DateTime::parse_from_rfc3339("2024-09-09T19:46:02.102Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn repost_is_identified_by_its_own_cid() {
+        let event = event(
+            "create",
+            REPOST_COLLECTION,
+            json!({
+                "$type": "app.bsky.feed.repost",
+                "createdAt": "2024-09-09T19:46:02.102Z",
+                "subject": {
+                    "cid": "bafyreisubject",
+                    "uri": "at://did:plc:test/app.bsky.feed.post/subject"
+                }
+            }),
+        );
+
+        let Some(source::LiveStatus::Repost(repost)) = to_live_status(event).unwrap() else {
+            panic!("not a repost");
+        };
+
+        assert_eq!(
+            repost.src_identifier,
+            "bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi"
+        );
+        assert_eq!(repost.target_src_identifier, "bafyreisubject");
+        assert_eq!(repost.target_src_cid.as_deref(), Some("bafyreisubject"));
+        assert_eq!(
+            repost.target_src_at_uri.as_deref(),
+            Some("at://did:plc:test/app.bsky.feed.post/subject")
+        );
+    }
+
+    #[test]
+    fn delete_and_update_are_ignored() {
+        for operation in ["delete", "update"] {
+            let event = event(
+                operation,
+                POST_COLLECTION,
+                json!({ "createdAt": "2024-09-09T19:46:02.102Z", "text": "edited" }),
+            );
+            assert!(to_live_status(event).unwrap().is_none());
+        }
+    }
+}
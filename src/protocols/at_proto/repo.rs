@@ -6,7 +6,12 @@ use serde::Serialize;
 use serde_json::{json, Value};
 use tracing::error;
 
-use crate::{protocols::at_proto::procedure, utils::format_rfc3339};
+use crate::{
+    metrics,
+    protocols::{at_proto::procedure, redact::redact_json, retry::RetryPolicy},
+    rate_limit::Budget,
+    utils::format_rfc3339,
+};
 
 use super::query;
 
@@ -16,7 +21,15 @@ pub struct External {
     pub uri: String,
     pub title: String,
     pub description: String,
-    pub thumb: Value, // WTF: ドキュメントだと optional だが実装では必須になっている https://github.com/bluesky-social/atproto/blob/7f008c0/lexicons/app/bsky/embed/external.json#L18
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumb: Option<Value>, // NOTE: 以前は実装で必須だったが、現在は省略できる https://github.com/bluesky-social/atproto/blob/7f008c0/lexicons/app/bsky/embed/external.json#L18
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AspectRatio {
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Clone, Serialize)]
@@ -24,11 +37,44 @@ pub struct External {
 pub struct Image {
     pub image: Value,
     pub alt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<AspectRatio>,
 }
 
 pub enum Embed {
     External(External),
     Images(Vec<Image>),
+    /** 引用。record は引用する投稿の StrongRef */
+    Record(Value),
+    /** 画像付きの引用 */
+    RecordWithMedia {
+        record: Value,
+        media: Box<Embed>,
+    },
+}
+
+impl Embed {
+    pub fn into_json(self) -> Value {
+        match self {
+            Embed::External(external) => json!({
+                "$type": "app.bsky.embed.external",
+                "external": external,
+            }),
+            Embed::Images(images) => json!({
+                "$type": "app.bsky.embed.images",
+                "images": images,
+            }),
+            Embed::Record(record) => json!({
+                "$type": "app.bsky.embed.record",
+                "record": record,
+            }),
+            Embed::RecordWithMedia { record, media } => json!({
+                "$type": "app.bsky.embed.recordWithMedia",
+                "record": Embed::Record(record).into_json(),
+                "media": media.into_json(),
+            }),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -40,50 +86,99 @@ pub struct Record<'a> {
     pub reply: Option<ReplyRef>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub embed: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Value>,
     #[serde(with = "format_rfc3339")]
     pub created_at: &'a DateTime<FixedOffset>,
 }
 
 pub struct Repo {
     origin: String,
+    retry_policy: RetryPolicy,
+    budget: Budget,
 }
 
 impl Repo {
-    pub fn new(origin: String) -> Self {
-        Self { origin }
+    pub fn new(origin: String, retry_policy: RetryPolicy, budget: Budget) -> Self {
+        Self {
+            origin,
+            retry_policy,
+            budget,
+        }
     }
 
+    /**
+     * rkey を指定して putRecord で作る
+     *
+     * レスポンスが失われて再送しても、同じレコードを上書きするだけで重複しない
+     */
     pub async fn create_record(
         &self,
         client: &reqwest::Client,
         session: &com::atproto::server::create_session::Output,
+        rkey: &str,
         record: Record<'_>,
     ) -> Result<Value> {
-        let lexicon_id = "com.atproto.repo.createRecord";
+        let lexicon_id = "com.atproto.repo.putRecord";
         procedure(
             client,
+            &self.retry_policy,
+            &self.budget,
             &self.origin,
             &session.access_jwt,
             lexicon_id,
             &json!({
                 "repo": &session.did,
                 "collection": "app.bsky.feed.post",
+                "rkey": rkey,
                 "record": &record,
             }),
         )
         .await
     }
 
+    pub async fn put_record(
+        &self,
+        client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+        collection: &str,
+        rkey: &str,
+        record: &Value,
+        swap_record: Option<&str>,
+    ) -> Result<Value> {
+        let lexicon_id = "com.atproto.repo.putRecord";
+        let mut properties = json!({
+            "repo": &session.did,
+            "collection": collection,
+            "rkey": rkey,
+            "record": record,
+        });
+        if let Some(swap_record) = swap_record {
+            properties["swapRecord"] = swap_record.into();
+        }
+        procedure(
+            client,
+            &self.retry_policy,
+            &self.budget,
+            &self.origin,
+            &session.access_jwt,
+            lexicon_id,
+            &properties,
+        )
+        .await
+    }
+
     pub async fn delete_record(
         &self,
         client: &reqwest::Client,
         session: &com::atproto::server::create_session::Output,
+        collection: &str,
         rkey: &str,
     ) -> Result<()> {
         let lexicon_id = "com.atproto.repo.deleteRecord";
         let properties = &json!({
             "repo": &session.did,
-            "collection": "app.bsky.feed.post",
+            "collection": collection,
             "rkey": rkey
         });
 
@@ -93,13 +188,14 @@ impl Repo {
             .json(properties)
             .send()
             .await?;
+        self.budget.update(resp.headers());
         if let Err(err) = resp.error_for_status_ref() {
             let json: Value = resp.json().await?;
             error!(
                 "url={:?}, status-code={:?}, body={}",
                 err.url().map(ToString::to_string),
                 err.status(),
-                json
+                redact_json(&json)
             );
             return Err(err.into());
         }
@@ -111,17 +207,24 @@ impl Repo {
         &self,
         client: &reqwest::Client,
         session: &com::atproto::server::create_session::Output,
+        repo: &str,
+        collection: &str,
         rkey: &str,
     ) -> Result<com::atproto::repo::get_record::Output> {
         let token = &session.access_jwt;
         let lexicon_id = "com.atproto.repo.getRecord";
-        let query_params = &[
-            ("repo", session.did.as_str()),
-            ("collection", "app.bsky.feed.post"),
-            ("rkey", rkey),
-        ];
+        let query_params = &[("repo", repo), ("collection", collection), ("rkey", rkey)];
 
-        query(client, &self.origin, token, lexicon_id, query_params).await
+        query(
+            client,
+            &self.retry_policy,
+            &self.budget,
+            &self.origin,
+            token,
+            lexicon_id,
+            query_params,
+        )
+        .await
     }
 
     pub async fn upload_blob(
@@ -132,16 +235,19 @@ impl Repo {
         body: impl Into<Body>,
     ) -> Result<Value> {
         let lexicon_id = "com.atproto.repo.uploadBlob";
-        Ok(client
+        let body: Body = body.into();
+        let len = body.as_bytes().map_or(0, <[u8]>::len);
+        let resp = client
             .post(format!("{}/xrpc/{}", self.origin, lexicon_id))
             .bearer_auth(&session.access_jwt)
             .header(CONTENT_TYPE, content_type)
             .body(body)
             .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?)
+            .await?;
+        self.budget.update(resp.headers());
+        let json = resp.error_for_status()?.json().await?;
+        metrics::uploaded_bytes(&self.origin, len);
+        Ok(json)
         // {
         //     "blob": {
         //         "$type": "blob",
@@ -154,3 +260,58 @@ impl Repo {
         // }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_partial_json, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::protocols::at_proto::test_session;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_are_addressed_by_collection() {
+        let server = MockServer::start().await;
+        let repo = Repo::new(server.uri(), RetryPolicy::default(), Budget::default());
+        let client = reqwest::Client::new();
+        for collection in ["app.bsky.feed.post", "app.bsky.feed.repost"] {
+            Mock::given(method("GET"))
+                .and(path("/xrpc/com.atproto.repo.getRecord"))
+                .and(query_param("collection", collection))
+                .and(query_param("rkey", "rkey"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "uri": format!("at://did:plc:test/{}/rkey", collection),
+                    "value": { "$type": collection },
+                })))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+                .and(body_partial_json(json!({
+                    "repo": "did:plc:test",
+                    "collection": collection,
+                    "rkey": "rkey",
+                })))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let record = repo
+                .get_record(&client, &test_session(), "did:plc:test", collection, "rkey")
+                .await
+                .unwrap();
+            assert_eq!(
+                record.data.uri,
+                format!("at://did:plc:test/{}/rkey", collection)
+            );
+            repo.delete_record(&client, &test_session(), collection, "rkey")
+                .await
+                .unwrap();
+        }
+    }
+}
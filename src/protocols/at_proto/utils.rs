@@ -1,76 +1,164 @@
-use anyhow::{anyhow, Result};
+use std::{collections::HashMap, io::Cursor};
+
+use anyhow::{anyhow, bail, Result};
 use atrium_api::{
     app, com,
     record::KnownRecord,
     types::{Object, TryFromUnknown},
 };
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Utc};
+use image::ImageReader;
 use regex::Regex;
-use reqwest::header::CONTENT_TYPE;
-use serde_json::json;
+use reqwest::Url;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::warn;
 
-use crate::store::{self, operations::Facet::Link};
+use crate::{
+    config::Threadgate,
+    protocols::{
+        media::{transcode, DownloadOptions, MediaCache},
+        text::{fit, Counting},
+    },
+    store::{self, operations::Facet::Link},
+};
 
 use super::{
-    repo::{Embed, External, Image, Record},
+    repo::{AspectRatio, Embed, External, Image, Record},
     Api,
 };
 
+/** Bluesky の閲覧注意のラベル。他のプラットフォームの閲覧注意はこれらのどれかとして扱う */
+pub const SENSITIVE_LABELS: &[&str] = &["sexual", "nudity", "porn", "graphic-media"];
+
 pub fn to_record<'a>(
     text: &'a str,
     facets: &'a [store::operations::Facet],
     reply: Option<app::bsky::feed::post::ReplyRef>,
     embed: Option<Embed>,
+    sensitive: bool,
     created_at: &'a DateTime<FixedOffset>,
 ) -> Record<'a> {
     Record {
         text,
-        facets: facets
-            .iter()
-            .map(|facet| match facet {
-                // NOTE: 実装予定なし
-                // Mention {
-                //     byte_slice,
-                //     src_identifier,
-                // } => {
-                //     json!({
-                //         "index": {
-                //             "byteStart": byte_slice.start,
-                //             "byteEnd": byte_slice.end
-                //         },
-                //         "features": [{
-                //             "$type": "app.bsky.richtext.facet#mention",
-                //             "did": "TODO",
-                //         }]
-                //     })
-                // }
-                Link { byte_slice, uri } => json!({
-                    "index": {
-                        "byteStart": byte_slice.start,
-                        "byteEnd": byte_slice.end
-                    },
-                    "features": [{
-                        "$type": "app.bsky.richtext.facet#link",
-                        "uri": uri,
-                    }]
-                }),
-            })
-            .collect::<Vec<_>>(),
+        facets: to_facets(facets),
         reply,
-        embed: embed.map(|embed| match embed {
-            Embed::External(external) => json!({
-                "$type": "app.bsky.embed.external",
-                "external": external,
-            }),
-            Embed::Images(images) => json!({
-                "$type": "app.bsky.embed.images",
-                "images": images,
-            }),
+        embed: embed.map(Embed::into_json),
+        // NOTE: 程度が分からないので、一番弱い sexual を付ける
+        labels: sensitive.then(|| {
+            json!({
+                "$type": "com.atproto.label.defs#selfLabels",
+                "values": [{ "val": SENSITIVE_LABELS[0] }],
+            })
         }),
         created_at,
     }
 }
 
+pub fn to_facets(facets: &[store::operations::Facet]) -> Vec<Value> {
+    facets
+        .iter()
+        .map(|facet| match facet {
+            // NOTE: 実装予定なし
+            // Mention {
+            //     byte_slice,
+            //     src_identifier,
+            // } => {
+            //     json!({
+            //         "index": {
+            //             "byteStart": byte_slice.start,
+            //             "byteEnd": byte_slice.end
+            //         },
+            //         "features": [{
+            //             "$type": "app.bsky.richtext.facet#mention",
+            //             "did": "TODO",
+            //         }]
+            //     })
+            // }
+            Link { byte_slice, uri } => json!({
+                "index": {
+                    "byteStart": byte_slice.start,
+                    "byteEnd": byte_slice.end
+                },
+                "features": [{
+                    "$type": "app.bsky.richtext.facet#link",
+                    "uri": uri,
+                }]
+            }),
+        })
+        .collect()
+}
+
+const POLL_MARKS: [&str; 2] = ["○", "☐"];
+
+/** 投票機能が無いので、選択肢を本文の末尾にテキストで追記する */
+pub fn append_poll(content: &str, poll: Option<&store::operations::Poll>) -> String {
+    let Some(poll) = poll else {
+        return content.to_owned();
+    };
+    let mark = if poll.multiple {
+        POLL_MARKS[1]
+    } else {
+        POLL_MARKS[0]
+    };
+    let choices = poll
+        .choices
+        .iter()
+        .map(|choice| format!("{} {}", mark, choice))
+        .collect::<Vec<_>>()
+        .join("\n");
+    append_choices(content, &choices)
+}
+
+fn append_choices(content: &str, choices: &str) -> String {
+    if content.is_empty() {
+        choices.to_owned()
+    } else {
+        format!("{}\n\n{}", content, choices)
+    }
+}
+
+/**
+ * 既存の本文の末尾に追記してあった投票の選択肢を、新しい本文にも追記する
+ *
+ * 編集で届く本文には投票が含まれないので、そのまま書き戻すと選択肢が消える
+ */
+pub fn keep_poll(content: &str, current_text: &str) -> String {
+    let choices = current_text
+        .rsplit_once("\n\n")
+        .map_or(current_text, |(_, choices)| choices);
+    let is_poll = POLL_MARKS.iter().any(|mark| {
+        choices
+            .lines()
+            .all(|line| line.starts_with(&format!("{} ", mark)))
+    });
+    if is_poll && !choices.is_empty() {
+        append_choices(content, choices)
+    } else {
+        content.to_owned()
+    }
+}
+
+const BASE32_SORTABLE: &[u8] = b"234567abcdefghijklmnopqrstuvwxyz";
+
+/**
+ * key から毎回同じ TID を作る
+ *
+ * 投稿の rkey の順序は表示に使われないので、時刻の部分もハッシュから作る。
+ * 未来の時刻にならないように、時刻の部分の上位のビットは 0 にする
+ */
+pub fn to_deterministic_tid(key: &str) -> String {
+    // NOTE: 実行環境や Rust のバージョンで変わらないように、FNV-1a で計算する
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    let tid = hash >> 4;
+    (0..13)
+        .rev()
+        .map(|i| BASE32_SORTABLE[((tid >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
 pub fn uri_to_post_rkey(uri: &str) -> Result<String> {
     Ok(Regex::new(r"at://did:plc:.+?/app.bsky.feed.post/(.+)")
         .unwrap()
@@ -79,81 +167,414 @@ pub fn uri_to_post_rkey(uri: &str) -> Result<String> {
         .to_owned())
 }
 
-pub fn uri_to_repost_rkey(uri: &str) -> Result<String> {
-    Ok(Regex::new(r"at://did:plc:.+?/app.bsky.feed.repost/(.+)")
+/** 削除できるレコードの collection */
+const DELETABLE_COLLECTIONS: &[&str] = &[
+    "app.bsky.feed.post",
+    "app.bsky.feed.repost",
+    "app.bsky.feed.like",
+];
+
+/**
+ * identifier から (collection, rkey) を取り出す
+ *
+ * identifier は create_record の結果の JSON か、AT URI そのもの
+ */
+pub fn identifier_to_record_key(identifier: &str) -> Result<(String, String)> {
+    let uri = if identifier.starts_with("at://") {
+        identifier.to_owned()
+    } else {
+        let json: Value = serde_json::from_str(identifier)
+            .map_err(|err| anyhow!("invalid identifier ({}): {}", identifier, err))?;
+        json.get("uri")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("uri not found ({})", identifier))?
+            .to_owned()
+    };
+    let m = Regex::new(r"^at://[^/]+/([^/]+)/([^/]+)$")
+        .unwrap()
+        .captures(&uri)
+        .ok_or_else(|| anyhow!("invalid uri format ({})", uri))?;
+    if !DELETABLE_COLLECTIONS.contains(&&m[1]) {
+        bail!("unsupported collection: {}", &m[1]);
+    }
+    Ok((m[1].to_owned(), m[2].to_owned()))
+}
+
+/** (repo, rkey) */
+pub fn split_post_uri(uri: &str) -> Result<(String, String)> {
+    let m = Regex::new(r"^at://(.+?)/app.bsky.feed.post/(.+)$")
         .unwrap()
         .captures(uri)
-        .ok_or_else(|| anyhow!("invalid uri format"))?[1]
-        .to_owned())
+        .ok_or_else(|| anyhow!("invalid uri format"))?;
+    Ok((m[1].to_owned(), m[2].to_owned()))
+}
+
+/** bsky.app の URL を AT URI に戻す。プロフィールの部分は handle のこともある */
+pub fn external_uri_to_uri(uri: &str) -> Option<String> {
+    let m = Regex::new(r"^https://bsky\.app/profile/([^/]+)/post/([^/?#]+)")
+        .unwrap()
+        .captures(uri)?;
+    Some(format!("at://{}/app.bsky.feed.post/{}", &m[1], &m[2]))
+}
+
+pub fn to_threadgate_record(post_uri: &str, threadgate: Threadgate) -> Value {
+    let allow: Vec<Value> = match threadgate {
+        Threadgate::Nobody => vec![],
+        Threadgate::Mentioned => {
+            vec![json!({ "$type": "app.bsky.feed.threadgate#mentionRule" })]
+        }
+        Threadgate::Following => {
+            vec![json!({ "$type": "app.bsky.feed.threadgate#followingRule" })]
+        }
+    };
+    json!({
+        "$type": "app.bsky.feed.threadgate",
+        "post": post_uri,
+        "allow": allow,
+        "createdAt": Utc::now().to_rfc3339(),
+    })
+}
+
+/**
+ * 投稿と同じ rkey で threadgate を作る
+ *
+ * output は投稿の create_record の結果
+ */
+pub async fn put_threadgate(
+    api: &Api,
+    http_client: &reqwest::Client,
+    session: &com::atproto::server::create_session::Output,
+    output: &Value,
+    threadgate: Threadgate,
+) -> Result<()> {
+    let uri = output
+        .get("uri")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("uri not found"))?;
+    let rkey = uri_to_post_rkey(uri)?;
+    api.repo
+        .put_record(
+            http_client,
+            session,
+            "app.bsky.feed.threadgate",
+            &rkey,
+            &to_threadgate_record(uri, threadgate),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+fn to_aspect_ratio(bytes: &[u8]) -> Option<AspectRatio> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some(AspectRatio { width, height })
+}
+
+fn tenor_gif_path(uri: &str) -> Option<(String, String)> {
+    let url = Url::parse(uri).ok()?;
+    let host = url.host_str()?;
+    if !(host == "tenor.com" || host.ends_with(".tenor.com")) {
+        return None;
+    }
+    // NOTE: media1.tenor.com/m/{id}/{filename} の形式もある
+    let segments: Vec<_> = url
+        .path_segments()?
+        .filter(|segment| !segment.is_empty() && *segment != "m")
+        .collect();
+    let [id, filename] = segments[..] else {
+        return None;
+    };
+    filename
+        .ends_with(".gif")
+        .then(|| (id.to_owned(), filename.to_owned()))
+}
+
+/**
+ * Tenor の GIF を公式クライアントが GIF として再生できる URI にする
+ *
+ * media.tenor.com/{id}/{filename}?hh={height}&ww={width} の形式でないと GIF として扱われない
+ */
+fn to_tenor_gif_uri(uri: &str, aspect_ratio: &AspectRatio) -> Option<String> {
+    let (id, filename) = tenor_gif_path(uri)?;
+    Some(format!(
+        "https://media.tenor.com/{}/{}?hh={}&ww={}",
+        id, filename, aspect_ratio.height, aspect_ratio.width
+    ))
+}
+
+const SUPPORTED_IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/** Bluesky の画像の blob の上限 */
+const MAX_IMAGE_BYTES: usize = 1_000_000;
+
+/** 公式クライアントの代替テキストの上限 */
+const MAX_ALT_LENGTH: usize = 2000;
+
+fn truncate_alt(mut alt: String) -> String {
+    alt.truncate(fit(&alt, MAX_ALT_LENGTH, Counting::Graphemes));
+    alt
+}
+
+/**
+ * レコードの embed の画像の代替テキストを先頭から順に差し替える
+ *
+ * blob はそのまま使うので、アップロードし直さずに済む
+ */
+pub fn replace_media_alts(embed: &mut Value, media_alts: &[String]) {
+    let is_record_with_media =
+        embed.get("$type").and_then(Value::as_str) == Some("app.bsky.embed.recordWithMedia");
+    let media = if is_record_with_media {
+        &mut embed["media"]
+    } else {
+        embed
+    };
+    let Some(images) = media.get_mut("images").and_then(Value::as_array_mut) else {
+        return;
+    };
+    for (image, alt) in images.iter_mut().zip(media_alts) {
+        image["alt"] = truncate_alt(alt.clone()).into();
+    }
+}
+
+/**
+ * アップロード済みの blob を内容のハッシュごとに覚えておく
+ *
+ * 同じ画像が複数ある投稿や、失敗した投稿をやり直す場合に、同じ blob をアップロードし直さないようにする
+ */
+#[derive(Default)]
+pub struct BlobCache(HashMap<String, Value>);
+
+impl BlobCache {
+    async fn upload(
+        &mut self,
+        api: &Api,
+        http_client: &reqwest::Client,
+        session: &com::atproto::server::create_session::Output,
+        content_type: String,
+        bytes: Vec<u8>,
+    ) -> Result<Value> {
+        let key = format!("{:x}", Sha256::digest(&bytes));
+        if let Some(blob) = self.0.get(&key) {
+            return Ok(blob.clone());
+        }
+        let mut res = api
+            .repo
+            .upload_blob(http_client, session, content_type, bytes)
+            .await?;
+        let blob = res
+            .get_mut("blob")
+            .ok_or_else(|| anyhow!("blob not found"))?
+            .take();
+        self.0.insert(key, blob.clone());
+        Ok(blob)
+    }
+}
+
+/** bsky.app の投稿へのリンクなら、引用するための StrongRef を返す */
+async fn to_quote(
+    api: &Api,
+    http_client: &reqwest::Client,
+    session: &com::atproto::server::create_session::Output,
+    uri: &str,
+) -> Option<Value> {
+    let at_uri = external_uri_to_uri(uri)?;
+    let result = async {
+        let (repo, rkey) = split_post_uri(&at_uri)?;
+        let record = api
+            .repo
+            .get_record(http_client, session, &repo, "app.bsky.feed.post", &rkey)
+            .await?;
+        let cid = record
+            .data
+            .cid
+            .ok_or_else(|| anyhow!("cid not found ({})", at_uri))?;
+        anyhow::Ok(json!({ "uri": record.data.uri, "cid": cid.as_ref().to_string() }))
+    }
+    .await;
+    match result {
+        Ok(quote) => Some(quote),
+        // NOTE: 引用元が消えている場合などは、これまで通りリンクカードにする
+        Err(err) => {
+            warn!("resolve quote failed: {}", err);
+            None
+        }
+    }
+}
+
+/** 送信先の投稿の identifier は StrongRef の JSON なので、そのまま引用に使える */
+fn to_quote_record(quote_identifier: &str) -> Result<Value> {
+    let record: com::atproto::repo::strong_ref::Main = serde_json::from_str(quote_identifier)?;
+    Ok(json!({ "uri": record.uri, "cid": record.cid.as_ref().to_string() }))
+}
+
+/** 引用があれば、画像と合わせて recordWithMedia にする */
+fn with_quote(quote: Option<Value>, media: Embed) -> Embed {
+    match quote {
+        Some(record) => Embed::RecordWithMedia {
+            record,
+            media: Box::new(media),
+        },
+        None => media,
+    }
 }
 
 pub async fn to_embed(
     api: &Api,
     http_client: &reqwest::Client,
     session: &com::atproto::server::create_session::Output,
+    blob_cache: &mut BlobCache,
+    download_options: &DownloadOptions,
     images: Vec<store::operations::Medium>,
     external: Option<store::operations::External>,
+    quote_identifier: Option<&str>,
 ) -> Result<Option<Embed>> {
+    let mut media_cache = MediaCache::new(*download_options);
+    let quote = match (quote_identifier, &external) {
+        (Some(quote_identifier), _) => Some(to_quote_record(quote_identifier)?),
+        (None, Some(external)) => to_quote(api, http_client, session, &external.uri).await,
+        (None, None) => None,
+    };
     if !images.is_empty() {
         let mut array = Vec::new();
         for image in images {
-            let resp = http_client.get(&image.url).send().await?;
-            let content_type = resp
-                .headers()
-                .get(CONTENT_TYPE)
-                .ok_or_else(|| anyhow!("no content-type"))?
-                .to_str()?
-                .to_owned();
-
-            let mut res = api
-                .repo
-                .upload_blob(http_client, session, content_type, resp)
+            let downloaded = transcode(
+                media_cache.fetch(http_client, &image.url).await?,
+                SUPPORTED_IMAGE_TYPES,
+            )?;
+            let content_type = downloaded
+                .content_type
+                .clone()
+                .ok_or_else(|| anyhow!("no content-type"))?;
+            let aspect_ratio = to_aspect_ratio(&downloaded.bytes);
+
+            let alt = truncate_alt(image.alt);
+            let image = blob_cache
+                .upload(api, http_client, session, content_type, downloaded.bytes)
                 .await?;
-            let alt = image.alt;
-            let image = res
-                .get_mut("blob")
-                .ok_or_else(|| anyhow!("blob not found"))?
-                .take();
-            array.push(Image { image, alt });
+            array.push(Image {
+                image,
+                alt,
+                aspect_ratio,
+            });
         }
-        return Ok(Some(Embed::Images(array)));
+        return Ok(Some(with_quote(quote, Embed::Images(array))));
+    }
+    if let Some(record) = quote {
+        return Ok(Some(Embed::Record(record)));
     }
     if let Some(external) = external {
-        if let Some(thumb_url) = &external.thumb_url {
-            let resp = http_client.get(thumb_url).send().await?;
-            let content_type = resp
-                .headers()
-                .get(CONTENT_TYPE)
-                .ok_or_else(|| anyhow!("no content-type"))?
-                .to_str()?
-                .to_owned();
-
-            let mut res = api
-                .repo
-                .upload_blob(http_client, session, content_type, resp)
-                .await?;
-            let thumb = res
-                .get_mut("blob")
-                .ok_or_else(|| anyhow!("blob not found"))?
-                .take();
-            return Ok(Some(Embed::External(External {
-                uri: external.uri,
-                title: external.title,
-                description: external.description,
-                thumb,
-            })));
-        }
+        // NOTE: GIF の URL が直接貼られた場合は、それ自体をサムネイルにする
+        let thumb_url = external
+            .thumb_url
+            .clone()
+            .or_else(|| tenor_gif_path(&external.uri).map(|_| external.uri.clone()));
+        let mut uri = external.uri;
+        let thumb = if let Some(thumb_url) = &thumb_url {
+            let downloaded = transcode(
+                media_cache.fetch(http_client, thumb_url).await?,
+                SUPPORTED_IMAGE_TYPES,
+            )?;
+            let content_type = downloaded
+                .content_type
+                .clone()
+                .ok_or_else(|| anyhow!("no content-type"))?;
+            // NOTE: Tenor のページのリンクカードは og:image が GIF 本体を指している
+            if let Some(gif_uri) = to_aspect_ratio(&downloaded.bytes).and_then(|aspect_ratio| {
+                [&uri, thumb_url]
+                    .into_iter()
+                    .find_map(|x| to_tenor_gif_uri(x, &aspect_ratio))
+            }) {
+                uri = gif_uri;
+            }
+
+            // NOTE: GIF 本体をサムネイルにすると上限を超えることがあるので、その場合はサムネイル無しにする
+            if downloaded.bytes.len() > MAX_IMAGE_BYTES {
+                warn!("thumb is too large: {}", thumb_url);
+                None
+            } else {
+                Some(
+                    blob_cache
+                        .upload(api, http_client, session, content_type, downloaded.bytes)
+                        .await?,
+                )
+            }
+        } else {
+            None
+        };
+        return Ok(Some(Embed::External(External {
+            uri,
+            title: external.title,
+            description: external.description,
+            thumb,
+        })));
     }
     Ok(None)
 }
 
+/** to_embed と同じ形にするが、アップロードはせずに blob の代わりに元の URL を入れる */
+pub fn to_preview_embed(
+    images: Vec<store::operations::Medium>,
+    external: Option<store::operations::External>,
+    quote_identifier: Option<&str>,
+) -> Option<Embed> {
+    // NOTE: 送らないので cid は引かない
+    let quote = quote_identifier
+        .and_then(|quote_identifier| to_quote_record(quote_identifier).ok())
+        .or_else(|| {
+            external
+                .as_ref()
+                .and_then(|external| external_uri_to_uri(&external.uri))
+                .map(|uri| json!({ "uri": uri }))
+        });
+    if !images.is_empty() {
+        let images = Embed::Images(
+            images
+                .into_iter()
+                .map(|image| Image {
+                    image: json!({ "url": image.url }),
+                    alt: truncate_alt(image.alt),
+                    aspect_ratio: None,
+                })
+                .collect(),
+        );
+        return Some(with_quote(quote, images));
+    }
+    if let Some(record) = quote {
+        return Some(Embed::Record(record));
+    }
+    external.map(|external| {
+        Embed::External(External {
+            uri: external.uri,
+            title: external.title,
+            description: external.description,
+            thumb: external.thumb_url.map(|url| json!({ "url": url })),
+        })
+    })
+}
+
 pub async fn find_reply_root(
     api: &Api,
     http_client: &reqwest::Client,
     session: &com::atproto::server::create_session::Output,
     rkey: &str,
 ) -> Result<Option<com::atproto::repo::strong_ref::Main>> {
-    let record = api.repo.get_record(http_client, session, rkey).await?;
+    let record = api
+        .repo
+        .get_record(
+            http_client,
+            session,
+            session.did.as_str(),
+            "app.bsky.feed.post",
+            rkey,
+        )
+        .await?;
     let KnownRecord::AppBskyFeedPost(record) = KnownRecord::try_from_unknown(record.data.value)?
     else {
         unreachable!();
@@ -165,7 +586,7 @@ pub async fn find_reply_root(
     Ok(Some(reply.data.root))
 }
 
-pub async fn to_reply<'a>(
+pub async fn to_reply(
     api: &Api,
     http_client: &reqwest::Client,
     session: &com::atproto::server::create_session::Output,
@@ -183,3 +604,436 @@ pub async fn to_reply<'a>(
         root,
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        protocols::{at_proto::test_session, retry::RetryPolicy, text::measure},
+        rate_limit::Budget,
+    };
+
+    #[tokio::test]
+    async fn external_without_thumb_is_embedded() {
+        let api = Api::new(
+            "http://127.0.0.1:1".into(),
+            RetryPolicy::default(),
+            Budget::default(),
+        );
+        let embed = to_embed(
+            &api,
+            &reqwest::Client::new(),
+            &test_session(),
+            &mut BlobCache::default(),
+            &DownloadOptions::default(),
+            Vec::new(),
+            Some(store::operations::External {
+                uri: "https://example.com/".into(),
+                title: "title".into(),
+                description: "description".into(),
+                thumb_url: None,
+            }),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            embed.into_json(),
+            json!({
+                "$type": "app.bsky.embed.external",
+                "external": {
+                    "uri": "https://example.com/",
+                    "title": "title",
+                    "description": "description",
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn canned_ogp_is_embedded_as_external() {
+        use wiremock::{
+            matchers::{method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        let html = format!(
+            r#"<html><head>
+<meta property="og:title" content="OGP title">
+<meta property="og:description" content="OGP description">
+<meta property="og:image" content="{}/thumb.png">
+</head><body></body></html>"#,
+            server.uri()
+        );
+        Mock::given(method("GET"))
+            .and(path("/article"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(html, "text/html"))
+            .mount(&server)
+            .await;
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        Mock::given(method("GET"))
+            .and(path("/thumb.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(png, "image/png"))
+            .mount(&server)
+            .await;
+        let blob = json!({
+            "$type": "blob",
+            "mimeType": "image/png",
+            "ref": { "$link": "bafkreihkqppell6jipqwq2izfcleeft5oqzurzx6fplwtwvf4oub5zdnye" },
+            "size": 1,
+        });
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "blob": blob })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let api = Api::new(server.uri(), RetryPolicy::default(), Budget::default());
+        let http_client = reqwest::Client::new();
+        let uri = format!("{}/article", server.uri());
+
+        let external = crate::protocols::ogp::fetch_external(&http_client, &uri, None)
+            .await
+            .unwrap();
+        let embed = to_embed(
+            &api,
+            &http_client,
+            &test_session(),
+            &mut BlobCache::default(),
+            &DownloadOptions::default(),
+            Vec::new(),
+            Some(external),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            embed.into_json(),
+            json!({
+                "$type": "app.bsky.embed.external",
+                "external": {
+                    "uri": uri,
+                    "title": "OGP title",
+                    "description": "OGP description",
+                    "thumb": blob,
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn linked_post_with_image_is_embedded_as_record_with_media() {
+        use wiremock::{
+            matchers::{method, path, query_param},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        const CID: &str = "bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi";
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .and(query_param("repo", "did:plc:other"))
+            .and(query_param("rkey", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:other/app.bsky.feed.post/1",
+                "cid": CID,
+                "value": { "$type": "app.bsky.feed.post" },
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(png, "image/png"))
+            .mount(&server)
+            .await;
+        let blob = json!({
+            "$type": "blob",
+            "mimeType": "image/png",
+            "ref": { "$link": "bafkreihkqppell6jipqwq2izfcleeft5oqzurzx6fplwtwvf4oub5zdnye" },
+            "size": 1,
+        });
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "blob": blob })))
+            .mount(&server)
+            .await;
+        let api = Api::new(server.uri(), RetryPolicy::default(), Budget::default());
+
+        let embed = to_embed(
+            &api,
+            &reqwest::Client::new(),
+            &test_session(),
+            &mut BlobCache::default(),
+            &DownloadOptions::default(),
+            vec![store::operations::Medium {
+                url: format!("{}/image.png", server.uri()),
+                alt: "alt".into(),
+                sensitive: false,
+                focus: None,
+                content_type: None,
+            }],
+            Some(store::operations::External {
+                uri: "https://bsky.app/profile/did:plc:other/post/1".into(),
+                title: "title".into(),
+                description: "description".into(),
+                thumb_url: None,
+            }),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            embed.into_json(),
+            json!({
+                "$type": "app.bsky.embed.recordWithMedia",
+                "record": {
+                    "$type": "app.bsky.embed.record",
+                    "record": { "uri": "at://did:plc:other/app.bsky.feed.post/1", "cid": CID },
+                },
+                "media": {
+                    "$type": "app.bsky.embed.images",
+                    "images": [{
+                        "image": blob,
+                        "alt": "alt",
+                        "aspectRatio": { "width": 3, "height": 2 },
+                    }],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn only_tenor_hosts_are_treated_as_tenor() {
+        let expected = Some(("abc".to_owned(), "funny.gif".to_owned()));
+        assert_eq!(tenor_gif_path("https://tenor.com/abc/funny.gif"), expected);
+        assert_eq!(
+            tenor_gif_path("https://media1.tenor.com/m/abc/funny.gif"),
+            expected
+        );
+        assert_eq!(tenor_gif_path("https://nottenor.com/abc/funny.gif"), None);
+        assert_eq!(
+            tenor_gif_path("https://tenor.com.example/abc/funny.gif"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn too_large_tenor_gif_is_embedded_without_thumb() {
+        use wiremock::{
+            matchers::{method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let server = MockServer::start().await;
+        let mut gif = Vec::new();
+        image::RgbaImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut gif), image::ImageFormat::Gif)
+            .unwrap();
+        // NOTE: トレーラーの後ろは読まれないので、寸法を読めるまま上限を超えさせる
+        gif.resize(MAX_IMAGE_BYTES + 1, 0);
+        Mock::given(method("GET"))
+            .and(path("/abc/funny.gif"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(gif, "image/gif"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let api = Api::new(server.uri(), RetryPolicy::default(), Budget::default());
+        // NOTE: Tenor へのリクエストをモックサーバーに向ける
+        let http_client = reqwest::Client::builder()
+            .proxy(reqwest::Proxy::http(server.uri()).unwrap())
+            .build()
+            .unwrap();
+
+        let embed = to_embed(
+            &api,
+            &http_client,
+            &test_session(),
+            &mut BlobCache::default(),
+            &DownloadOptions::default(),
+            Vec::new(),
+            Some(store::operations::External {
+                uri: "http://media.tenor.com/abc/funny.gif".into(),
+                title: "".into(),
+                description: "".into(),
+                thumb_url: None,
+            }),
+            None,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            embed.into_json(),
+            json!({
+                "$type": "app.bsky.embed.external",
+                "external": {
+                    "uri": "https://media.tenor.com/abc/funny.gif?hh=2&ww=3",
+                    "title": "",
+                    "description": "",
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn quote_identifier_is_embedded_as_record() {
+        const URI: &str = "at://did:plc:abc/app.bsky.feed.post/3kabc";
+        const CID: &str = "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm";
+        let api = Api::new(
+            "http://127.0.0.1:1".into(),
+            RetryPolicy::default(),
+            Budget::default(),
+        );
+        let quote_identifier = json!({ "uri": URI, "cid": CID }).to_string();
+
+        let embed = to_embed(
+            &api,
+            &reqwest::Client::new(),
+            &test_session(),
+            &mut BlobCache::default(),
+            &DownloadOptions::default(),
+            Vec::new(),
+            None,
+            Some(&quote_identifier),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            embed.into_json(),
+            json!({
+                "$type": "app.bsky.embed.record",
+                "record": { "uri": URI, "cid": CID },
+            })
+        );
+    }
+
+    #[test]
+    fn over_length_alt_is_truncated_to_fit() {
+        let short = "あ".repeat(MAX_ALT_LENGTH);
+        assert_eq!(truncate_alt(short.clone()), short);
+
+        let long = format!("{}👨\u{200d}👩\u{200d}👧", "あ".repeat(MAX_ALT_LENGTH - 1)).repeat(2);
+        let alt = truncate_alt(long.clone());
+        assert_eq!(measure(&alt, Counting::Graphemes), MAX_ALT_LENGTH);
+        assert!(long.starts_with(&alt));
+        // NOTE: 絵文字の結合文字列の途中では切らない
+        assert!(alt.ends_with("👨\u{200d}👩\u{200d}👧"));
+    }
+
+    #[test]
+    fn image_carries_aspect_ratio() {
+        let mut bytes = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+            .unwrap();
+        let image = Image {
+            image: json!({ "$type": "blob" }),
+            alt: "alt".into(),
+            aspect_ratio: to_aspect_ratio(&bytes),
+        };
+
+        assert_eq!(
+            serde_json::to_value(image).unwrap(),
+            json!({
+                "image": { "$type": "blob" },
+                "alt": "alt",
+                "aspectRatio": { "width": 3, "height": 2 },
+            })
+        );
+        assert!(to_aspect_ratio(b"not an image").is_none());
+    }
+
+    #[tokio::test]
+    async fn threadgate_is_put_with_post_rkey() {
+        use wiremock::{
+            matchers::{body_partial_json, method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        const URI: &str = "at://did:plc:test/app.bsky.feed.post/3kabc";
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.putRecord"))
+            .and(body_partial_json(json!({
+                "repo": "did:plc:test",
+                "collection": "app.bsky.feed.threadgate",
+                "rkey": "3kabc",
+                "record": {
+                    "$type": "app.bsky.feed.threadgate",
+                    "post": URI,
+                    "allow": [{ "$type": "app.bsky.feed.threadgate#mentionRule" }],
+                },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.threadgate/3kabc",
+                "cid": "cid",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let api = Api::new(server.uri(), RetryPolicy::default(), Budget::default());
+
+        put_threadgate(
+            &api,
+            &reqwest::Client::new(),
+            &test_session(),
+            &json!({ "uri": URI, "cid": "cid" }),
+            Threadgate::Mentioned,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn record_key_is_taken_from_create_output_or_bare_uri() {
+        let post = json!({ "uri": "at://did:plc:test/app.bsky.feed.post/a", "cid": "cid" });
+        assert_eq!(
+            identifier_to_record_key(&post.to_string()).unwrap(),
+            ("app.bsky.feed.post".into(), "a".into())
+        );
+        assert_eq!(
+            identifier_to_record_key("at://did:plc:test/app.bsky.feed.repost/b").unwrap(),
+            ("app.bsky.feed.repost".into(), "b".into())
+        );
+    }
+
+    #[test]
+    fn malformed_identifier_is_rejected() {
+        for identifier in [
+            "not an identifier",
+            r#"{"cid":"cid"}"#,
+            "at://did:plc:test/app.bsky.feed.post",
+            "at://did:plc:test/app.bsky.graph.follow/c",
+        ] {
+            assert!(
+                identifier_to_record_key(identifier).is_err(),
+                "{}",
+                identifier
+            );
+        }
+    }
+}
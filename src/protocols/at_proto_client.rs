@@ -1,7 +1,7 @@
 use std::{
     str::FromStr,
     sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Result};
@@ -18,16 +18,85 @@ use atrium_api::{
 use atrium_xrpc_client::reqwest::ReqwestClient;
 use biscuit::{Timestamp, JWT};
 use chrono::{DateTime, FixedOffset};
-use serde_json::Value;
-use tracing::info;
+use serde_json::{json, Value};
+use tracing::{info, warn};
 
-use crate::{sources::source, store};
+use crate::{
+    config::{FetchMode, Threadgate},
+    rate_limit::Budget,
+    sources::source,
+    store,
+};
 
-use super::at_proto::{
-    utils::{to_embed, to_record, to_reply, uri_to_post_rkey, uri_to_repost_rkey},
-    Api,
+use super::{
+    at_proto::{
+        identity::ActorCache,
+        jetstream,
+        utils::{
+            append_poll, external_uri_to_uri, identifier_to_record_key, keep_poll, put_threadgate,
+            replace_media_alts, split_post_uri, to_deterministic_tid, to_embed, to_facets,
+            to_preview_embed, to_record, to_reply, uri_to_post_rkey, BlobCache,
+        },
+        Api,
+    },
+    error::ClientError,
+    is_caught_up,
+    media::DownloadOptions,
+    ogp::fetch_external,
+    retry::RetryPolicy,
+    text::{measure, normalize, shorten_links, truncate, Counting},
+    AccountInfo, Cursor, NewPost, MAX_CATCH_UP_PAGES,
 };
 
+const MAX_LENGTH: usize = 300;
+
+/** handle と DID の対応を覚えておく件数 */
+const ACTOR_CACHE_CAPACITY: usize = 1000;
+
+/** 正規化し、上限を超える場合はリンクの表示を短縮してから省略した本文 */
+fn fit_text(
+    content: &str,
+    facets: &[store::operations::Facet],
+    src_uri: Option<&str>,
+) -> (String, Vec<store::operations::Facet>) {
+    let (content, facets) = normalize(content, facets);
+    // NOTE: URL も文字数に数えられるので、収まらない場合は公式クライアントと同じく表示を短縮する
+    let (content, facets) = if measure(&content, Counting::Graphemes) > MAX_LENGTH {
+        shorten_links(&content, &facets)
+    } else {
+        (content, facets)
+    };
+    truncate(&content, &facets, MAX_LENGTH, Counting::Graphemes, src_uri)
+}
+
+/** 投票の追記、正規化、省略をした本文 */
+fn to_text(post: &NewPost<'_>) -> (String, Vec<store::operations::Facet>) {
+    // NOTE: 末尾に追記するだけなので facets の位置はずれない
+    let content = append_poll(post.content, post.poll);
+    fit_text(&content, post.facets, post.src_uri)
+}
+
+/**
+ * このクライアントで作っていない投稿をリポストするための identifier を作る
+ *
+ * AT URI が無ければ bsky.app の URL から作る。Bluesky の投稿でなければ None を返す。
+ * cid が分からない場合は repost で引く
+ */
+pub fn to_repost_target_identifier(
+    at_uri: Option<&str>,
+    uri: &str,
+    cid: Option<&str>,
+) -> Option<String> {
+    let at_uri = at_uri
+        .map(str::to_owned)
+        .or_else(|| external_uri_to_uri(uri))?;
+    let mut json = json!({ "uri": at_uri });
+    if let Some(cid) = cid {
+        json["cid"] = cid.into();
+    }
+    Some(json.to_string())
+}
+
 #[derive(Clone)]
 struct MySessionStore(Arc<Mutex<Option<String>>>);
 
@@ -62,7 +131,10 @@ async fn init_session(
 ) -> Result<()> {
     let Some(session) = agent.get_session().await else {
         info!("session not found, logging in");
-        agent.login(identifier, password).await?;
+        agent
+            .login(identifier, password)
+            .await
+            .map_err(ClientError::from)?;
         return Ok(());
     };
     let jwt: JWT<(), ()> = JWT::new_encoded(&session.access_jwt);
@@ -72,7 +144,14 @@ async fn init_session(
             "session is almost expired: {:?}",
             payload.registered.expiry.unwrap(),
         );
-        let result = agent.api.com.atproto.server.refresh_session().await?;
+        let result = agent
+            .api
+            .com
+            .atproto
+            .server
+            .refresh_session()
+            .await
+            .map_err(ClientError::from)?;
         info!("refreshed session");
         let active = matches!(session.active, Some(true));
         let session = Session::try_from_unknown(result.try_into_unknown()?)?;
@@ -93,11 +172,261 @@ async fn init_session(
     Ok(())
 }
 
+pub struct Options {
+    pub threadgate: Option<Threadgate>,
+    /** None の場合はリンクカードを生成しない */
+    pub link_card_timeout: Option<Duration>,
+    pub fetch_mode: FetchMode,
+    pub budget: Budget,
+    pub media: DownloadOptions,
+}
+
 pub struct Client {
     agent: AtpAgent<MySessionStore, ReqwestClient>,
     api: Api,
     http_client: Arc<reqwest::Client>,
     session_store: MySessionStore,
+    options: Options,
+    actors: Mutex<ActorCache>,
+    /** リトライで同じ投稿を送り直す場合にも使うので、投稿をまたいで持つ */
+    blobs: BlobCache,
+}
+
+impl Client {
+    /**
+     * 画像もリンクカードも無い投稿では、最初のリンク先からリンクカードを作る
+     *
+     * 取得に失敗しても投稿自体は続ける
+     */
+    async fn complete_external(&self, post: &NewPost<'_>) -> Option<store::operations::External> {
+        if post.external.is_some() || !post.images.is_empty() {
+            return post.external.clone();
+        }
+        let timeout = self.options.link_card_timeout?;
+        let uri = post
+            .facets
+            .iter()
+            .map(|facet| match facet {
+                store::operations::Facet::Link { uri, .. } => uri,
+            })
+            .next()?;
+        match fetch_external(&self.http_client, uri, Some(timeout)).await {
+            Ok(external) => Some(external),
+            Err(err) => {
+                warn!("fetch link card failed: {}", err);
+                None
+            }
+        }
+    }
+
+    /** create_record の結果か to_repost_target_identifier の identifier から、対象の投稿を参照する */
+    async fn to_strong_ref(
+        &self,
+        target_identifier: &str,
+    ) -> Result<com::atproto::repo::strong_ref::Main, ClientError> {
+        // NOTE: create_record の結果と to_repost_target_identifier のどちらも uri を持つ
+        let identifier: Value = serde_json::from_str(target_identifier)?;
+        let uri = identifier
+            .get("uri")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("uri not found ({})", target_identifier))?;
+        let subject = match identifier.get("cid").and_then(Value::as_str) {
+            Some(cid) => json!({ "uri": uri, "cid": cid }),
+            // NOTE: cid が分からない場合は getRecord で引く。handle の URI もここで DID のものになる
+            None => {
+                let (repo, rkey) = split_post_uri(uri)?;
+                // NOTE: 存在しない handle は、問い合わせ直さずに諦める
+                let repo = if repo.starts_with("did:") {
+                    repo
+                } else {
+                    self.resolve_handle(&repo)
+                        .await?
+                        .ok_or(ClientError::NotFound)?
+                };
+                let session = &self.agent.get_session().await.unwrap();
+                let record = self
+                    .api
+                    .repo
+                    .get_record(
+                        &self.http_client,
+                        session,
+                        &repo,
+                        "app.bsky.feed.post",
+                        &rkey,
+                    )
+                    .await?;
+                let cid = record
+                    .data
+                    .cid
+                    .ok_or_else(|| anyhow!("cid not found ({})", uri))?;
+                json!({ "uri": record.data.uri, "cid": cid.as_ref().to_string() })
+            }
+        };
+        Ok(serde_json::from_value(subject)?)
+    }
+
+    async fn create_known_record(
+        &self,
+        collection: &str,
+        record: KnownRecord,
+    ) -> Result<String, ClientError> {
+        let res = self
+            .agent
+            .api
+            .com
+            .atproto
+            .repo
+            .create_record(Object::from(com::atproto::repo::create_record::InputData {
+                collection: Nsid::from_str(collection).unwrap(),
+                record: record.try_into_unknown().map_err(anyhow::Error::from)?,
+                repo: self.agent.get_session().await.unwrap().did.clone().into(),
+                rkey: None,
+                swap_commit: None,
+                validate: None,
+            }))
+            .await
+            .map_err(ClientError::from)?;
+        Ok(serde_json::to_string(&res)?)
+    }
+
+    /**
+     * handle から DID を引く。存在しない handle は None
+     *
+     * 結果は client ごとに cache し、存在しないことも覚えておく
+     */
+    async fn resolve_handle(&self, handle: &str) -> Result<Option<String>> {
+        let cached = self.actors.lock().unwrap().did(handle);
+        if let Some(did) = cached {
+            return Ok(did);
+        }
+        let session = &self.agent.get_session().await.unwrap();
+        let result = self
+            .api
+            .identity
+            .resolve_handle(&self.http_client, session, handle)
+            .await;
+        let mut actors = self.actors.lock().unwrap();
+        match result {
+            Ok(output) => {
+                let did = output.data.did.as_str().to_owned();
+                actors.put(handle, &did);
+                Ok(Some(did))
+            }
+            // NOTE: 存在しない handle は 400 が返る
+            Err(err) => match ClientError::classify(err) {
+                ClientError::NotFound | ClientError::Permanent(_) => {
+                    actors.put_unresolvable(handle);
+                    Ok(None)
+                }
+                err => Err(err.into()),
+            },
+        }
+    }
+
+    /**
+     * 既存のレコードの本文を差し替えて書き戻す
+     *
+     * 代替テキストは blob と一緒にレコードにあるので、media_alts があれば embed も書き換える
+     */
+    async fn put_post_record(
+        &self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+        media_alts: Option<&[String]>,
+    ) -> Result<String, ClientError> {
+        let output: com::atproto::repo::create_record::Output = serde_json::from_str(identifier)?;
+        let rkey = uri_to_post_rkey(&output.uri)?;
+
+        let session = &self.agent.get_session().await.unwrap();
+        let current = self
+            .api
+            .repo
+            .get_record(
+                &self.http_client,
+                session,
+                session.did.as_str(),
+                "app.bsky.feed.post",
+                &rkey,
+            )
+            .await?;
+        let mut record = serde_json::to_value(&current.data.value)?;
+        // NOTE: 末尾に追記するだけなので facets の位置はずれない
+        let content = keep_poll(content, record["text"].as_str().unwrap_or_default());
+        let (content, facets) = fit_text(&content, facets, None);
+        record["text"] = content.into();
+        record["facets"] = to_facets(&facets).into();
+        if let (Some(media_alts), Some(embed)) = (media_alts, record.get_mut("embed")) {
+            replace_media_alts(embed, media_alts);
+        }
+        let swap_record = current
+            .data
+            .cid
+            .as_ref()
+            .map(|cid| cid.as_ref().to_string());
+        let output = self
+            .api
+            .repo
+            .put_record(
+                &self.http_client,
+                session,
+                "app.bsky.feed.post",
+                &rkey,
+                &record,
+                swap_record.as_deref(),
+            )
+            .await?;
+        Ok(serde_json::to_string(&output)?)
+    }
+
+    /**
+     * スレッドの先頭の投稿を削除する場合は、同じ rkey の threadgate も削除する
+     *
+     * 既に無いレコードの削除は PDS が成功として扱うので、そのまま成功になる
+     */
+    async fn delete_record(&self, identifier: &str) -> Result<(), ClientError> {
+        let (collection, rkey) = identifier_to_record_key(identifier)?;
+        let session = &self.agent.get_session().await.unwrap();
+        // NOTE: threadgate は返信には付けていないので、消す前に返信かどうかを確かめる
+        let is_root = collection == "app.bsky.feed.post"
+            && self.options.threadgate.is_some()
+            && match self
+                .api
+                .repo
+                .get_record(
+                    &self.http_client,
+                    session,
+                    session.did.as_str(),
+                    &collection,
+                    &rkey,
+                )
+                .await
+            {
+                Ok(record) => serde_json::to_value(&record.data.value)?
+                    .get("reply")
+                    .is_none(),
+                Err(err) => {
+                    warn!("get record failed: {:?}", err);
+                    false
+                }
+            };
+        self.api
+            .repo
+            .delete_record(&self.http_client, session, &collection, &rkey)
+            .await?;
+        if is_root {
+            self.api
+                .repo
+                .delete_record(
+                    &self.http_client,
+                    session,
+                    "app.bsky.feed.threadgate",
+                    &rkey,
+                )
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 impl Client {
@@ -108,8 +437,11 @@ impl Client {
         identifier: String,
         password: String,
         initial_session: Option<String>,
+        retry_policy: RetryPolicy,
+        options: Options,
     ) -> Result<Self> {
         let session_store = MySessionStore(Arc::new(Mutex::new(initial_session)));
+        // NOTE: atrium は別のバージョンの reqwest を使っているので http_client を共有できない
         let agent = AtpAgent::new(
             ReqwestClient::new("https://bsky.social"),
             session_store.clone(),
@@ -117,9 +449,12 @@ impl Client {
         init_session(&agent, &identifier, &password).await?;
         Ok(Self {
             agent,
-            api: Api::new(origin),
+            api: Api::new(origin, retry_policy, options.budget.clone()),
             http_client,
             session_store,
+            options,
+            actors: Mutex::new(ActorCache::new(ACTOR_CACHE_CAPACITY)),
+            blobs: BlobCache::default(),
         })
     }
 }
@@ -130,13 +465,78 @@ impl super::Client for Client {
         self.session_store.0.lock().unwrap().clone()
     }
 
+    #[tracing::instrument(name = "at_proto_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let output = self
+            .agent
+            .api
+            .com
+            .atproto
+            .server
+            .get_session()
+            .await
+            .map_err(ClientError::from)?;
+        Ok(AccountInfo {
+            id: output.data.did.as_str().to_owned(),
+            handle: output.data.handle.as_str().to_owned(),
+        })
+    }
+
     #[tracing::instrument(name = "at_proto_client::Client::fetch_statuses", skip_all)]
-    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
-        let params = Object::from(app::bsky::feed::get_author_feed::ParametersData {
-            actor: self.agent.get_session().await.unwrap().did.clone().into(),
-            cursor: None,
-            filter: None,
-            limit: Some(LimitedNonZeroU8::try_from(50).unwrap()),
+    async fn fetch_statuses(
+        &mut self,
+        cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        let did = self.agent.get_session().await.unwrap().did.clone();
+        if let (FetchMode::Jetstream, Some(cursor)) = (self.options.fetch_mode, cursor) {
+            return Ok(jetstream::fetch_statuses(did.as_str(), cursor.created_at).await?);
+        }
+        let mut statuses = Vec::new();
+        let mut page_cursor = None;
+        for _ in 0..MAX_CATCH_UP_PAGES {
+            let params = Object::from(app::bsky::feed::get_author_feed::ParametersData {
+                actor: did.clone().into(),
+                cursor: page_cursor,
+                filter: None,
+                limit: Some(LimitedNonZeroU8::try_from(50).unwrap()),
+            });
+            let output = self
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_author_feed(params)
+                .await
+                .map_err(ClientError::from)?;
+            let page = output
+                .data
+                .feed
+                .into_iter()
+                .map(|x| x.try_into())
+                .collect::<Result<Vec<_>>>()?;
+            let caught_up = is_caught_up(&page, cursor);
+            statuses.extend(page);
+            page_cursor = output.data.cursor;
+            if page_cursor.is_none() || caught_up {
+                break;
+            }
+        }
+        Ok(statuses)
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::get_status", skip_all)]
+    async fn get_status(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        // NOTE: identifier の cid からは取得できないので、自分の at:// の URI の場合のみ対応する
+        let did = self.agent.get_session().await.unwrap().did.clone();
+        if !identifier.starts_with(&format!("at://{}/", did.as_str())) {
+            return Ok(None);
+        }
+        let params = Object::from(app::bsky::feed::get_posts::ParametersData {
+            uris: vec![identifier.to_owned()],
         });
         let output = self
             .agent
@@ -144,110 +544,705 @@ impl super::Client for Client {
             .app
             .bsky
             .feed
-            .get_author_feed(params)
+            .get_posts(params)
             .await
-            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
-        output.data.feed.into_iter().map(|x| x.try_into()).collect()
+            .map_err(ClientError::from)?;
+        let Some(post) = output.data.posts.into_iter().next() else {
+            return Ok(None);
+        };
+        let feed_view_post = Object::from(app::bsky::feed::defs::FeedViewPostData {
+            feed_context: None,
+            post,
+            reason: None,
+            reply: None,
+        });
+        Ok(Some(feed_view_post.try_into()?))
     }
 
     #[tracing::instrument(name = "at_proto_client::Client::post", skip_all)]
-    async fn post(
-        &mut self,
-        content: &str,
-        facets: &[store::operations::Facet],
-        reply_identifier: Option<&str>,
-        images: Vec<store::operations::Medium>,
-        external: Option<store::operations::External>,
-        created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
         let session = &self.agent.get_session().await.unwrap();
-        let reply = to_reply(&self.api, &self.http_client, session, reply_identifier).await?;
-        let embed = to_embed(&self.api, &self.http_client, session, images, external).await?;
-        let record = to_record(content, facets, reply, embed, created_at);
+        let reply = to_reply(&self.api, &self.http_client, session, post.reply_identifier).await?;
+        let external = self.complete_external(&post).await;
+        let sensitive = post.images.iter().any(|image| image.sensitive);
+        let (content, facets) = to_text(&post);
+        let embed = to_embed(
+            &self.api,
+            &self.http_client,
+            session,
+            &mut self.blobs,
+            &self.options.media,
+            post.images,
+            external,
+            post.quote_identifier,
+        )
+        .await?;
+        let record = to_record(&content, &facets, reply, embed, sensitive, post.created_at);
 
         let output = self
             .api
             .repo
-            .create_record(&self.http_client, session, record)
+            .create_record(
+                &self.http_client,
+                session,
+                &to_deterministic_tid(post.idempotency_key),
+                record,
+            )
             .await?;
+        // NOTE: threadgate はスレッドの先頭にだけ付ける。
+        //       投稿は済んでいるので、失敗しても投稿は成功として扱う
+        if let (Some(threadgate), None) = (self.options.threadgate, post.reply_identifier) {
+            if let Err(err) =
+                put_threadgate(&self.api, &self.http_client, session, &output, threadgate).await
+            {
+                warn!("put threadgate failed: {:?}", err);
+            }
+        }
         Ok(serde_json::to_string(&output)?)
     }
 
+    #[tracing::instrument(name = "at_proto_client::Client::preview", skip_all)]
+    async fn preview(&mut self, post: NewPost<'_>) -> Result<Value, ClientError> {
+        let session = &self.agent.get_session().await.unwrap();
+        let reply = to_reply(&self.api, &self.http_client, session, post.reply_identifier).await?;
+        let external = self.complete_external(&post).await;
+        let sensitive = post.images.iter().any(|image| image.sensitive);
+        let (content, facets) = to_text(&post);
+        let embed = to_preview_embed(post.images, external, post.quote_identifier);
+        let record = to_record(&content, &facets, reply, embed, sensitive, post.created_at);
+        Ok(serde_json::to_value(&record)?)
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::update_post", skip_all)]
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        self.put_post_record(identifier, content, facets, None)
+            .await
+    }
+
+    #[tracing::instrument(
+        name = "at_proto_client::Client::update_post_with_media_alts",
+        skip_all
+    )]
+    async fn update_post_with_media_alts(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+        media_alts: &[String],
+    ) -> Result<String, ClientError> {
+        self.put_post_record(identifier, content, facets, Some(media_alts))
+            .await
+    }
+
     #[tracing::instrument(name = "at_proto_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
         target_identifier: &str,
         created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
-        let identifier: com::atproto::repo::create_record::Output =
-            serde_json::from_str(target_identifier)?;
+    ) -> Result<String, ClientError> {
+        let subject = self.to_strong_ref(target_identifier).await?;
         let record = KnownRecord::AppBskyFeedRepost(Box::new(Object::from(
             app::bsky::feed::repost::RecordData {
                 created_at: Datetime::new(created_at.to_owned()),
-                subject: Object::from(com::atproto::repo::strong_ref::MainData {
-                    cid: identifier.data.cid,
-                    uri: identifier.data.uri,
-                }),
+                subject,
             },
         )));
-        let res = self
-            .agent
-            .api
-            .com
-            .atproto
-            .repo
-            .create_record(Object::from(com::atproto::repo::create_record::InputData {
-                collection: Nsid::from_str("app.bsky.feed.repost").unwrap(),
-                record: record.try_into_unknown()?,
-                repo: self.agent.get_session().await.unwrap().did.clone().into(),
-                rkey: None,
-                swap_commit: None,
-                validate: None,
-            }))
+        self.create_known_record("app.bsky.feed.repost", record)
             .await
-            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
-        Ok(serde_json::to_string(&res)?)
     }
 
     #[tracing::instrument(name = "at_proto_client::Client::delete_post", skip_all)]
-    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
-        let json: Value = serde_json::from_str(identifier)?;
-        let uri = json
-            .get("uri")
-            .ok_or_else(|| anyhow!("uri not found ({})", identifier))?
-            .as_str()
-            .ok_or_else(|| anyhow!("uri is not string"))?;
-        let rkey = uri_to_post_rkey(uri)?;
-
-        let session = &self.agent.get_session().await.unwrap();
-        self.api
-            .repo
-            .delete_record(&self.http_client, session, &rkey)
-            .await?;
-        Ok(())
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete_record(identifier).await
     }
 
     #[tracing::instrument(name = "at_proto_client::Client::delete_repost", skip_all)]
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
-        let output: com::atproto::repo::put_record::Output = serde_json::from_str(identifier)?;
-        let rkey = uri_to_repost_rkey(&output.uri)?;
-
-        let input = Object::from(com::atproto::repo::delete_record::InputData {
-            collection: Nsid::from_str("app.bsky.feed.repost").unwrap(),
-            repo: self.agent.get_session().await.unwrap().did.clone().into(),
-            rkey,
-            swap_commit: None,
-            swap_record: None,
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
+        // NOTE: 対象が Bluesky に無いリポストは、リンクの投稿として送っているので collection で判断する
+        self.delete_record(identifier).await
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::like", skip_all)]
+    async fn like(
+        &mut self,
+        target_identifier: &str,
+        created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        let subject = self.to_strong_ref(target_identifier).await?;
+        let record = KnownRecord::AppBskyFeedLike(Box::new(Object::from(
+            app::bsky::feed::like::RecordData {
+                created_at: Datetime::new(created_at.to_owned()),
+                subject,
+            },
+        )));
+        self.create_known_record("app.bsky.feed.like", record).await
+    }
+
+    #[tracing::instrument(name = "at_proto_client::Client::unlike", skip_all)]
+    async fn unlike(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete_record(identifier).await
+    }
+}
+
+#[cfg(test)]
+impl Client {
+    /** テスト用のログイン済みのクライアント。API は origin に送る */
+    pub fn test(origin: &str, options: Options) -> Self {
+        let session = serde_json::to_string(&super::at_proto::test_session()).unwrap();
+        let session_store = MySessionStore(Arc::new(Mutex::new(Some(session))));
+        Self {
+            agent: AtpAgent::new(ReqwestClient::new(origin), session_store.clone()),
+            api: Api::new(
+                origin.into(),
+                RetryPolicy::default(),
+                options.budget.clone(),
+            ),
+            http_client: Arc::new(reqwest::Client::new()),
+            session_store,
+            options,
+            actors: Mutex::new(ActorCache::new(ACTOR_CACHE_CAPACITY)),
+            blobs: BlobCache::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_partial_json, method, path, query_param},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    use crate::protocols::{Client as _, NewPostFixture};
+
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            threadgate: None,
+            link_card_timeout: None,
+            fetch_mode: FetchMode::default(),
+            budget: Budget::default(),
+            media: DownloadOptions::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn preview_of_link_and_image() {
+        let fixture = NewPostFixture::default();
+        let mut client = Client::test("https://bsky.example", options());
+        let facets = [store::operations::Facet::Link {
+            byte_slice: 4..24,
+            uri: "https://example.com/".into(),
+        }];
+        let mut post = fixture.post("see https://example.com/");
+        post.facets = &facets;
+        post.images = vec![store::operations::Medium {
+            url: "https://src.example.com/1.png".into(),
+            alt: "alt".into(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }];
+
+        let preview = client.preview(post).await.unwrap();
+
+        assert_eq!(
+            preview,
+            json!({
+                "text": "see https://example.com/",
+                "facets": [{
+                    "index": { "byteStart": 4, "byteEnd": 24 },
+                    "features": [{
+                        "$type": "app.bsky.richtext.facet#link",
+                        "uri": "https://example.com/",
+                    }],
+                }],
+                "embed": {
+                    "$type": "app.bsky.embed.images",
+                    "images": [{
+                        "image": { "url": "https://src.example.com/1.png" },
+                        "alt": "alt",
+                    }],
+                },
+                "createdAt": "2024-01-01T00:00:00.000Z",
+            })
+        );
+    }
+
+    const CID: &str = "bafyreidwaivazkwu67xztlmuobx35hs2lnfh3kolmgfmucldvhd3sgzcqi";
+
+    fn created_at() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap()
+    }
+
+    #[tokio::test]
+    async fn handle_uri_is_resolved_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.identity.resolveHandle"))
+            .and(query_param("handle", "alice.example.com"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "did": "did:plc:alice" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .and(query_param("repo", "did:plc:alice"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:alice/app.bsky.feed.post/1",
+                "cid": CID,
+                "value": { "$type": "app.bsky.feed.post" },
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.repost/1",
+                "cid": CID,
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+        let target = json!({ "uri": "at://alice.example.com/app.bsky.feed.post/1" }).to_string();
+
+        for _ in 0..2 {
+            client.repost(&target, &created_at()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unresolvable_handle_is_not_asked_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.identity.resolveHandle"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": "InvalidRequest",
+                "message": "Unable to resolve handle",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+        let target = json!({ "uri": "at://nobody.example.com/app.bsky.feed.post/1" }).to_string();
+
+        for _ in 0..2 {
+            let err = client.repost(&target, &created_at()).await.unwrap_err();
+            assert!(matches!(err, ClientError::NotFound));
+        }
+    }
+
+    async fn put_record_bodies(server: &MockServer) -> Vec<Value> {
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .filter(|request| request.url.path() == "/xrpc/com.atproto.repo.putRecord")
+            .map(|request| serde_json::from_slice(&request.body).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn retried_post_targets_same_rkey() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.putRecord"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.post/1",
+                "cid": CID,
+            })))
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+
+        for _ in 0..2 {
+            client.post(fixture.post("hello")).await.unwrap();
+        }
+        let mut other = fixture.post("hello");
+        other.idempotency_key = "https://example.com/2";
+        client.post(other).await.unwrap();
+
+        let rkeys: Vec<_> = put_record_bodies(&server)
+            .await
+            .into_iter()
+            .map(|body| body["rkey"].as_str().unwrap().to_owned())
+            .collect();
+        let expected = to_deterministic_tid("https://example.com/1");
+        assert_eq!(rkeys[..2], [expected.clone(), expected.clone()]);
+        assert_ne!(rkeys[2], expected);
+    }
+
+    #[tokio::test]
+    async fn foreign_post_is_reposted_by_strong_ref() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({
+                "repo": "did:plc:test",
+                "collection": "app.bsky.feed.repost",
+                "record": {
+                    "subject": { "uri": "at://did:plc:other/app.bsky.feed.post/1", "cid": CID },
+                },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.repost/1",
+                "cid": CID,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+        let target = to_repost_target_identifier(
+            Some("at://did:plc:other/app.bsky.feed.post/1"),
+            "https://bsky.app/profile/did:plc:other/post/1",
+            Some(CID),
+        )
+        .unwrap();
+
+        client.repost(&target, &created_at()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reply_threads_onto_mirrored_parent() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let parent = json!({ "uri": "at://did:plc:test/app.bsky.feed.post/parent", "cid": CID });
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .and(query_param("rkey", "parent"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.post/parent",
+                "cid": CID,
+                "value": {
+                    "$type": "app.bsky.feed.post",
+                    "text": "parent",
+                    "createdAt": "2024-01-01T00:00:00.000Z",
+                },
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.putRecord"))
+            .and(body_partial_json(json!({
+                "record": { "reply": { "parent": parent, "root": parent } },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.post/reply",
+                "cid": CID,
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+        let parent_identifier = parent.to_string();
+        let mut post = fixture.post("reply");
+        post.reply_identifier = Some(&parent_identifier);
+
+        client.post(post).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn repost_is_deleted_from_its_collection() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+            .and(body_partial_json(json!({
+                "repo": "did:plc:test",
+                "collection": "app.bsky.feed.repost",
+                "rkey": "b",
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+
+        client
+            .delete_repost("at://did:plc:test/app.bsky.feed.repost/b")
+            .await
+            .unwrap();
+        let err = client.delete_post("not an identifier").await.unwrap_err();
+        assert!(matches!(err, ClientError::Permanent(_)));
+    }
+
+    #[tokio::test]
+    async fn repeated_image_reuses_uploaded_blob() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(2, 2))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        // NOTE: URL が違っても中身が同じなら同じ blob
+        for name in ["/1.png", "/2.png"] {
+            Mock::given(method("GET"))
+                .and(path(name))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(png.clone(), "image/png"))
+                .mount(&server)
+                .await;
+        }
+        let blob = json!({
+            "$type": "blob",
+            "ref": { "$link": CID },
+            "mimeType": "image/png",
+            "size": png.len(),
         });
-        self.agent
-            .api
-            .com
-            .atproto
-            .repo
-            .delete_record(input)
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "blob": blob })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.putRecord"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": "at://did:plc:test/app.bsky.feed.post/1",
+                "cid": CID,
+            })))
+            .mount(&server)
+            .await;
+        let mut client = Client::test(&server.uri(), options());
+        let image = |name: &str| store::operations::Medium {
+            url: format!("{}{}", server.uri(), name),
+            alt: String::new(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        };
+
+        // NOTE: 送り直しても再アップロードしない
+        for _ in 0..2 {
+            let mut post = fixture.post("images");
+            post.images = vec![image("/1.png"), image("/2.png")];
+            client.post(post).await.unwrap();
+        }
+
+        for body in put_record_bodies(&server).await {
+            let images = body["record"]["embed"]["images"].as_array().unwrap();
+            assert_eq!(images.len(), 2);
+            assert!(images.iter().all(|image| image["image"] == blob));
+        }
+    }
+
+    async fn mock_put_record(server: &MockServer, rkey: &str) {
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.putRecord"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": format!("at://did:plc:test/app.bsky.feed.post/{}", rkey),
+                "cid": CID,
+            })))
+            .mount(server)
+            .await;
+    }
+
+    async fn mock_get_post(server: &MockServer, rkey: &str, value: Value) {
+        Mock::given(method("GET"))
+            .and(path("/xrpc/com.atproto.repo.getRecord"))
+            .and(query_param("collection", "app.bsky.feed.post"))
+            .and(query_param("rkey", rkey))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "uri": format!("at://did:plc:test/app.bsky.feed.post/{}", rkey),
+                "cid": CID,
+                "value": value,
+            })))
+            .mount(server)
+            .await;
+    }
+
+    fn post_value(text: &str) -> Value {
+        json!({
+            "$type": "app.bsky.feed.post",
+            "text": text,
+            "createdAt": "2024-01-01T00:00:00.000Z",
+        })
+    }
+
+    #[tokio::test]
+    async fn threadgate_is_put_on_root_and_deleted_with_it() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        mock_put_record(&server, "root").await;
+        mock_get_post(&server, "root", post_value("root")).await;
+        for collection in ["app.bsky.feed.post", "app.bsky.feed.threadgate"] {
+            Mock::given(method("POST"))
+                .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+                .and(body_partial_json(
+                    json!({ "collection": collection, "rkey": "root" }),
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        let options = Options {
+            threadgate: Some(Threadgate::Mentioned),
+            ..options()
+        };
+        let mut client = Client::test(&server.uri(), options);
+
+        let root = client.post(fixture.post("root")).await.unwrap();
+        let mut reply = fixture.post("reply");
+        reply.idempotency_key = "https://example.com/2";
+        reply.reply_identifier = Some(&root);
+        client.post(reply).await.unwrap();
+        client.delete_post(&root).await.unwrap();
+
+        let threadgates: Vec<_> = put_record_bodies(&server)
             .await
-            .map_err(|err| anyhow::anyhow!("{:?}", err))?;
+            .into_iter()
+            .filter(|body| body["collection"] == "app.bsky.feed.threadgate")
+            .collect();
+        let [threadgate] = threadgates.as_slice() else {
+            panic!("threadgate should be put only on root");
+        };
+        assert_eq!(threadgate["rkey"], "root");
+        assert_eq!(
+            threadgate["record"]["post"],
+            "at://did:plc:test/app.bsky.feed.post/root"
+        );
+        assert_eq!(
+            threadgate["record"]["allow"],
+            json!([{ "$type": "app.bsky.feed.threadgate#mentionRule" }])
+        );
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn link_card_is_generated_only_when_enabled() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        mock_put_record(&server, "1").await;
+        let html = r#"<html><head>
+<meta property="og:title" content="OGP title">
+<meta property="og:description" content="OGP description">
+</head><body></body></html>"#;
+        Mock::given(method("GET"))
+            .and(path("/article"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(html, "text/html"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let uri = format!("{}/article", server.uri());
+        let content = format!("see {}", uri);
+        let facets = [store::operations::Facet::Link {
+            byte_slice: 4..content.len() as u32,
+            uri: uri.clone(),
+        }];
+
+        for link_card_timeout in [None, Some(Duration::from_secs(5))] {
+            let options = Options {
+                link_card_timeout,
+                ..options()
+            };
+            let mut client = Client::test(&server.uri(), options);
+            let mut post = fixture.post(&content);
+            post.facets = &facets;
+            client.post(post).await.unwrap();
+        }
+
+        let embeds: Vec<_> = put_record_bodies(&server)
+            .await
+            .into_iter()
+            .map(|body| body["record"]["embed"].clone())
+            .collect();
+        assert_eq!(
+            embeds,
+            [
+                Value::Null,
+                json!({
+                    "$type": "app.bsky.embed.external",
+                    "external": {
+                        "uri": uri,
+                        "title": "OGP title",
+                        "description": "OGP description",
+                    },
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_is_appended_as_choices() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        mock_put_record(&server, "1").await;
+        let mut client = Client::test(&server.uri(), options());
+        let poll = store::operations::Poll {
+            choices: vec!["a".into(), "b".into()],
+            multiple: false,
+            expires_at: None,
+        };
+        let mut post = fixture.post("which?");
+        post.poll = Some(&poll);
+
+        client.post(post).await.unwrap();
+
+        let bodies = put_record_bodies(&server).await;
+        assert_eq!(bodies[0]["record"]["text"], "which?\n\n○ a\n○ b");
+    }
+
+    #[tokio::test]
+    async fn edit_rewrites_text_and_alts_of_existing_record() {
+        let server = MockServer::start().await;
+        mock_put_record(&server, "1").await;
+        let blob = json!({
+            "$type": "blob",
+            "ref": { "$link": CID },
+            "mimeType": "image/png",
+            "size": 1,
+        });
+        // NOTE: 作成時に追記した投票の選択肢は、編集後も残す
+        let mut value = post_value("old\n\n○ yes\n○ no");
+        value["embed"] = json!({
+            "$type": "app.bsky.embed.images",
+            "images": [{ "image": blob, "alt": "old alt" }],
+        });
+        mock_get_post(&server, "1", value).await;
+        let mut client = Client::test(&server.uri(), options());
+        let identifier =
+            json!({ "uri": "at://did:plc:test/app.bsky.feed.post/1", "cid": CID }).to_string();
+
+        client.update_post(&identifier, "new", &[]).await.unwrap();
+        client
+            .update_post_with_media_alts(&identifier, "new", &[], &["new alt".into()])
+            .await
+            .unwrap();
+
+        let bodies = put_record_bodies(&server).await;
+        let alts: Vec<_> = bodies
+            .iter()
+            .map(|body| {
+                // NOTE: 書き換えている間に他で更新されていたら失敗させる
+                assert_eq!(body["rkey"], "1");
+                assert_eq!(body["swapRecord"], CID);
+                assert_eq!(body["record"]["text"], "new\n\n○ yes\n○ no");
+                assert_eq!(body["record"]["createdAt"], "2024-01-01T00:00:00.000Z");
+                let image = &body["record"]["embed"]["images"][0];
+                assert_eq!(image["image"], blob);
+                image["alt"].as_str().unwrap()
+            })
+            .collect();
+        assert_eq!(alts, ["old alt", "new alt"]);
     }
 }
@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{sources::source, store};
+
+use super::{
+    error::{ClientError, ResponseExt},
+    text::{measure, truncate, Counting},
+    AccountInfo, Cursor, NewPost,
+};
+
+pub const ORIGIN: &str = "https://discord.com";
+
+const MAX_LENGTH: usize = 2000;
+/** 1 つのメッセージに付けられる embed の上限 */
+const MAX_EMBEDS: usize = 10;
+
+fn get_str<'a>(json: &'a Value, key: &str) -> Result<&'a str> {
+    json.get(key)
+        .ok_or_else(|| anyhow!("{} is not found", key))?
+        .as_str()
+        .ok_or_else(|| anyhow!("{} is not str", key))
+}
+
+/** 画像は添付せずに embed で URL を渡して Discord 側に取得させる */
+fn to_embeds(images: &[store::operations::Medium]) -> Vec<Value> {
+    images
+        .iter()
+        .take(MAX_EMBEDS)
+        .map(|image| json!({ "image": { "url": image.url } }))
+        .collect()
+}
+
+/**
+ * webhook の URL (https://discord.com/api/webhooks/{id}/{token}) の id
+ *
+ * URL にはトークンが含まれるので、store のキーにはこちらを使う
+ */
+pub fn webhook_id(webhook_url: &str) -> &str {
+    let path = webhook_url.trim_end_matches('/');
+    let path = path.split('?').next().unwrap_or(path);
+    let mut segments = path.rsplit('/');
+    let _token = segments.next();
+    segments.next().unwrap_or(webhook_url)
+}
+
+fn wrap_content(content: &str, content_warning: Option<&str>, reply_uri: Option<&str>) -> String {
+    let content = match content_warning {
+        // NOTE: 本文はスポイラーで隠す
+        Some(content_warning) => format!("{}\n||{}||", content_warning, content),
+        None => content.to_owned(),
+    };
+    match reply_uri {
+        // NOTE: webhook は返信できないので元のメッセージへのリンクを付ける
+        Some(reply_uri) => format!("{}\n{}", reply_uri, content),
+        None => content,
+    }
+}
+
+/**
+ * 注意書きと返信先のリンクを付けたメッセージの本文
+ *
+ * スポイラーの閉じ記号を切らないように、付け足す分を差し引いた長さで本文を切り詰める
+ */
+fn to_content(
+    content: &str,
+    facets: &[store::operations::Facet],
+    content_warning: Option<&str>,
+    reply_uri: Option<&str>,
+    src_uri: Option<&str>,
+) -> String {
+    let overhead = measure(
+        &wrap_content("", content_warning, reply_uri),
+        Counting::Chars,
+    );
+    let (content, _) = truncate(
+        content,
+        facets,
+        MAX_LENGTH.saturating_sub(overhead),
+        Counting::Chars,
+        src_uri,
+    );
+    wrap_content(&content, content_warning, reply_uri)
+}
+
+pub struct Client {
+    http_client: Arc<reqwest::Client>,
+    webhook_url: String,
+    guild_id: String,
+    channel_id: String,
+}
+
+impl Client {
+    #[tracing::instrument(name = "discord_client::Client::new", skip_all)]
+    pub async fn new(http_client: Arc<reqwest::Client>, webhook_url: String) -> Result<Self> {
+        let json: Value = http_client
+            .get(&webhook_url)
+            .send()
+            .await?
+            .error_for_client_status()?
+            .json()
+            .await?;
+        let guild_id = get_str(&json, "guild_id")?.to_owned();
+        let channel_id = get_str(&json, "channel_id")?.to_owned();
+        info!("webhook for channel {}", channel_id);
+
+        Ok(Self {
+            http_client,
+            webhook_url,
+            guild_id,
+            channel_id,
+        })
+    }
+
+    fn to_message_uri(&self, message_id: &str) -> String {
+        format!(
+            "{}/channels/{}/{}/{}",
+            ORIGIN, self.guild_id, self.channel_id, message_id
+        )
+    }
+
+    async fn execute_webhook(&self, body: &Value) -> Result<String, ClientError> {
+        let json: Value = self
+            .http_client
+            .post(&self.webhook_url)
+            .query(&[("wait", "true")])
+            .json(body)
+            .send()
+            .await?
+            .error_for_client_status()?
+            .json()
+            .await?;
+        Ok(get_str(&json, "id")?.to_owned())
+    }
+
+    async fn delete_message(&self, message_id: &str) -> Result<(), ClientError> {
+        self.http_client
+            .delete(format!("{}/messages/{}", self.webhook_url, message_id))
+            .send()
+            .await?
+            .error_for_client_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl super::Client for Client {
+    fn to_session(&self) -> Option<String> {
+        None
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let json: Value = self
+            .http_client
+            .get(&self.webhook_url)
+            .send()
+            .await?
+            .error_for_client_status()?
+            .json()
+            .await?;
+        Ok(AccountInfo {
+            id: get_str(&json, "id")?.to_owned(),
+            handle: get_str(&json, "name")?.to_owned(),
+        })
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::fetch_statuses", skip_all)]
+    async fn fetch_statuses(
+        &mut self,
+        _cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        Err(anyhow!("discord is not supported as a source").into())
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::get_status", skip_all)]
+    async fn get_status(
+        &mut self,
+        _identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        Err(anyhow!("discord is not supported as a source").into())
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::post", skip_all)]
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let reply_uri = post
+            .reply_identifier
+            .map(|reply_identifier| self.to_message_uri(reply_identifier));
+        let content = to_content(
+            post.content,
+            post.facets,
+            post.content_warning,
+            reply_uri.as_deref(),
+            post.src_uri,
+        );
+        let body = json!({
+            "content": content,
+            "embeds": to_embeds(&post.images),
+            // NOTE: 本文中のメンションで通知を飛ばさない
+            "allowed_mentions": { "parse": [] },
+        });
+        self.execute_webhook(&body).await
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::update_post", skip_all)]
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        let (content, _) = truncate(content, facets, MAX_LENGTH, Counting::Chars, None);
+        self.http_client
+            .patch(format!("{}/messages/{}", self.webhook_url, identifier))
+            .json(&json!({ "content": content }))
+            .send()
+            .await?
+            .error_for_client_status()?;
+        Ok(identifier.to_owned())
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::repost", skip_all)]
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        let body = json!({
+            "content": format!("reposted: {}", self.to_message_uri(target_identifier)),
+            "allowed_mentions": { "parse": [] },
+        });
+        self.execute_webhook(&body).await
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::delete_post", skip_all)]
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete_message(identifier).await
+    }
+
+    #[tracing::instrument(name = "discord_client::Client::delete_repost", skip_all)]
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete_message(identifier).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_json, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::protocols::NewPostFixture;
+
+    use super::*;
+
+    async fn client(server: &MockServer) -> Client {
+        Mock::given(method("GET"))
+            .and(path("/api/webhooks/1/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "1",
+                "name": "webhook",
+                "guild_id": "2",
+                "channel_id": "3",
+            })))
+            .mount(server)
+            .await;
+        Client::new(
+            Arc::new(reqwest::Client::new()),
+            format!("{}/api/webhooks/1/token", server.uri()),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn webhook_id_is_extracted_without_token() {
+        assert_eq!(
+            webhook_id("https://discord.com/api/webhooks/123/secret"),
+            "123"
+        );
+        assert_eq!(
+            webhook_id("https://discord.com/api/webhooks/123/secret/"),
+            "123"
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_payload_has_content_and_embeds() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api/webhooks/1/token"))
+            .and(query_param("wait", "true"))
+            .and(body_json(json!({
+                "content": format!("{}/channels/2/3/9\ncw\n||hello||", ORIGIN),
+                "embeds": [{ "image": { "url": "https://example.com/a.png" } }],
+                "allowed_mentions": { "parse": [] },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "10" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let post = NewPost {
+            reply_identifier: Some("9"),
+            content_warning: Some("cw"),
+            images: vec![store::operations::Medium {
+                url: "https://example.com/a.png".into(),
+                alt: String::new(),
+                sensitive: false,
+                focus: None,
+                content_type: None,
+            }],
+            ..fixture.post("hello")
+        };
+
+        let identifier = super::super::Client::post(&mut client, post).await.unwrap();
+
+        assert_eq!(identifier, "10");
+    }
+
+    #[test]
+    fn wrapped_content_fits_within_limit() {
+        let content = "あ".repeat(MAX_LENGTH);
+        let reply_uri = format!("{}/channels/2/3/9", ORIGIN);
+
+        let text = to_content(
+            &content,
+            &[],
+            Some("cw"),
+            Some(&reply_uri),
+            Some("https://src.example.com/1"),
+        );
+
+        assert!(measure(&text, Counting::Chars) <= MAX_LENGTH);
+        assert!(text.starts_with(&format!("{}\ncw\n||", reply_uri)));
+        assert!(text.ends_with("https://src.example.com/1||"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    fmt::{self, Debug},
+    time::Duration,
+};
+
+use atrium_api::xrpc;
+use reqwest::{Response, StatusCode};
+use tracing::warn;
+
+use super::retry::parse_retry_after;
+
+/**
+ * 送信先とのやりとりで起きたエラーの分類
+ *
+ * Client はこれを返すので、送信処理で続けるかどうかを決められる
+ */
+#[derive(Debug)]
+pub enum ClientError {
+    RateLimited { retry_after: Option<Duration> },
+    Auth,
+    NotFound,
+    Transient,
+    Permanent(anyhow::Error),
+}
+
+impl ClientError {
+    pub fn from_status(status: StatusCode, retry_after: Option<Duration>) -> Self {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { retry_after },
+            // NOTE: 403 は投稿ごとに拒否されることもあるので、止めずにその operation だけ飛ばす
+            StatusCode::UNAUTHORIZED => Self::Auth,
+            StatusCode::NOT_FOUND | StatusCode::GONE => Self::NotFound,
+            StatusCode::REQUEST_TIMEOUT => Self::Transient,
+            status if status.is_server_error() => Self::Transient,
+            status => Self::Permanent(anyhow::anyhow!("unexpected status: {}", status)),
+        }
+    }
+
+    /** エラーのステータスなら、Retry-After も含めて分類する */
+    pub fn from_response(resp: &Response) -> Option<Self> {
+        let status = resp.status();
+        if !(status.is_client_error() || status.is_server_error()) {
+            return None;
+        }
+        Some(match Self::from_status(status, parse_retry_after(resp)) {
+            // NOTE: URL はクエリにトークンを含むことがあるので、パスまでにする
+            Self::Permanent(_) => Self::Permanent(anyhow::anyhow!(
+                "unexpected status: {} ({}{})",
+                status,
+                resp.url().origin().ascii_serialization(),
+                resp.url().path()
+            )),
+            client_error => client_error,
+        })
+    }
+
+    fn from_reqwest_error(err: &reqwest::Error) -> Option<Self> {
+        if let Some(status) = err.status() {
+            return Some(Self::from_status(status, None));
+        }
+        (err.is_timeout() || err.is_connect()).then_some(Self::Transient)
+    }
+
+    /**
+     * anyhow::Error になったエラーを分類する
+     *
+     * ClientError そのものか、原因に reqwest のエラーが含まれていればそれを使い、どちらも無ければ Permanent にする
+     */
+    pub fn classify(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(client_error) => return client_error,
+            Err(err) => err,
+        };
+        let client_error = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+            .and_then(Self::from_reqwest_error);
+        client_error.unwrap_or(Self::Permanent(err))
+    }
+
+    /** 時間をおけば成功する見込みがある */
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::RateLimited { .. } | Self::Transient)
+    }
+}
+
+/**
+ * 対象が既に無いエラーを成功として扱う
+ *
+ * 手動で消されたものの削除は何度やっても成功しないので、消えていれば目的は果たせている
+ */
+pub fn ignore_not_found(
+    result: Result<(), ClientError>,
+    identifier: &str,
+) -> Result<(), ClientError> {
+    match result {
+        Err(ClientError::NotFound) => {
+            warn!("already deleted: {}", identifier);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/** reqwest の error_for_status の代わりに、ステータスを ClientError に分類する */
+pub trait ResponseExt: Sized {
+    fn error_for_client_status(self) -> Result<Self, ClientError>;
+}
+
+impl ResponseExt for Response {
+    fn error_for_client_status(self) -> Result<Self, ClientError> {
+        match ClientError::from_response(&self) {
+            Some(client_error) => Err(client_error),
+            None => Ok(self),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited { retry_after } => {
+                write!(f, "rate limited (retry_after={:?})", retry_after)
+            }
+            Self::Auth => write!(f, "authentication failed"),
+            Self::NotFound => write!(f, "not found"),
+            Self::Transient => write!(f, "transient error"),
+            Self::Permanent(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<anyhow::Error> for ClientError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::classify(err)
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::from_reqwest_error(&err).unwrap_or_else(|| Self::Permanent(err.into()))
+    }
+}
+
+/** atrium のエラーは、XRPC のステータスか reqwest のエラーなら分類する */
+impl<E: Debug> From<xrpc::error::Error<E>> for ClientError {
+    fn from(err: xrpc::error::Error<E>) -> Self {
+        match err {
+            xrpc::error::Error::XrpcResponse(err) => {
+                match StatusCode::from_u16(err.status.as_u16()) {
+                    Ok(status) => match Self::from_status(status, None) {
+                        Self::Permanent(_) => Self::Permanent(anyhow::anyhow!("{:?}", err)),
+                        client_error => client_error,
+                    },
+                    Err(_) => Self::Permanent(anyhow::anyhow!("{:?}", err)),
+                }
+            }
+            xrpc::error::Error::HttpClient(err) => match err.downcast::<reqwest::Error>() {
+                Ok(err) => (*err).into(),
+                Err(err) => Self::Permanent(anyhow::anyhow!("{:?}", err)),
+            },
+            err => Self::Permanent(anyhow::anyhow!("{:?}", err)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Permanent(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    #[test]
+    fn status_is_mapped_to_variant() {
+        let classify =
+            |status: u16| ClientError::from_status(StatusCode::from_u16(status).unwrap(), None);
+        assert!(matches!(classify(429), ClientError::RateLimited { .. }));
+        assert!(matches!(classify(401), ClientError::Auth));
+        assert!(matches!(classify(403), ClientError::Permanent(_)));
+        assert!(matches!(classify(404), ClientError::NotFound));
+        assert!(matches!(classify(410), ClientError::NotFound));
+        assert!(matches!(classify(408), ClientError::Transient));
+        assert!(matches!(classify(500), ClientError::Transient));
+        assert!(matches!(classify(503), ClientError::Transient));
+        assert!(matches!(classify(400), ClientError::Permanent(_)));
+        assert!(matches!(classify(422), ClientError::Permanent(_)));
+    }
+
+    async fn respond(template: ResponseTemplate) -> Response {
+        let server = MockServer::start().await;
+        Mock::given(path("/"))
+            .respond_with(template)
+            .mount(&server)
+            .await;
+        reqwest::get(format!("{}/?access_token=secret", server.uri()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_populated() {
+        let resp = respond(ResponseTemplate::new(429).insert_header("retry-after", "7")).await;
+
+        let Err(err) = resp.error_for_client_status() else {
+            panic!("not an error");
+        };
+        assert!(matches!(
+            err,
+            ClientError::RateLimited {
+                retry_after: Some(retry_after)
+            } if retry_after == Duration::from_secs(7)
+        ));
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn success_is_passed_through() {
+        let resp = respond(ResponseTemplate::new(200)).await;
+
+        assert!(resp.error_for_client_status().is_ok());
+    }
+
+    #[tokio::test]
+    async fn permanent_error_does_not_contain_query() {
+        let resp = respond(ResponseTemplate::new(400)).await;
+
+        let Err(ClientError::Permanent(err)) = resp.error_for_client_status() else {
+            panic!("not a permanent error");
+        };
+        assert!(err.to_string().contains("400"));
+        assert!(!err.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn client_error_survives_anyhow() {
+        let err: anyhow::Error = ClientError::Transient.into();
+
+        assert!(matches!(ClientError::from(err), ClientError::Transient));
+    }
+
+    #[test]
+    fn not_found_is_ignored_but_others_are_kept() {
+        assert!(ignore_not_found(Err(ClientError::NotFound), "id").is_ok());
+        assert!(matches!(
+            ignore_not_found(Err(ClientError::Transient), "id"),
+            Err(ClientError::Transient)
+        ));
+    }
+}
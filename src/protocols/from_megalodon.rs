@@ -1,40 +1,71 @@
 use html2text::render::text_renderer::RichAnnotation;
+use megalodon::entities::attachment::AttachmentType;
 
 use crate::{sources::source, store};
 
-fn link(current_idx: usize, uri: &str) -> store::operations::Facet {
+fn link(current_idx: usize, text: &str, uri: &str) -> store::operations::Facet {
     store::operations::Facet::Link {
-        byte_slice: (current_idx as u32)..(current_idx as u32) + (uri.as_bytes().len() as u32),
+        byte_slice: (current_idx as u32)..(current_idx as u32) + (text.len() as u32),
         uri: uri.to_owned(),
     }
 }
 
+/**
+ * Mastodon の HTML を本文と facet に変換する
+ *
+ * 段落と改行は改行に、エンティティは文字に戻す。
+ * URL のリンクは表示上省略されていることがあるので href に置き換える
+ */
 fn html_to_content_facets(html: &str) -> (String, Vec<store::operations::Facet>) {
     let content = html2text::from_read_rich(html.as_bytes(), usize::MAX);
     let mut text = String::new();
     let mut facets = Vec::new();
     for line in content {
+        // NOTE: 1 つのリンクが span ごとに分かれて来るので、同じリンクのものはまとめる
+        let mut pieces: Vec<(String, Option<&String>)> = Vec::new();
         for string in line.tagged_strings() {
-            if string.tag.is_empty() {
-                text += &string.s;
-                continue;
-            }
-            assert_eq!(string.tag.len(), 1);
-            if let RichAnnotation::Link(_) = &string.tag[0] {
-                // NOTE: ハッシュタグは未対応
-                if !string.s.starts_with('#') {
-                    facets.push(link(text.as_bytes().len(), &string.s));
+            // NOTE: 太字などの装飾は無視する
+            let href = string.tag.iter().find_map(|tag| match tag {
+                RichAnnotation::Link(href) => Some(href),
+                _ => None,
+            });
+            match pieces.last_mut() {
+                Some((s, last_href)) if href.is_some() && *last_href == href => {
+                    *s += &string.s;
                 }
-                text += &string.s;
+                _ => pieces.push((string.s.clone(), href)),
+            }
+        }
+        for (s, href) in pieces {
+            let Some(href) = href else {
+                text += &s;
+                continue;
+            };
+            // NOTE: ハッシュタグは未対応
+            if s.starts_with('#') {
+                text += &s;
                 continue;
             }
-            unreachable!();
+            // NOTE: メンションは表示をそのままにしてリンクだけ付ける
+            let s = if s.starts_with('@') { &s } else { href };
+            facets.push(link(text.len(), s, href));
+            text += s;
         }
         text += "\n";
     }
     (text.trim_end().to_owned(), facets)
 }
 
+/** Mastodon は添付の種類しか返さないので、種類だけの content-type にする */
+fn to_content_type(attachment_type: &AttachmentType) -> Option<&'static str> {
+    match attachment_type {
+        AttachmentType::Image => Some("image/"),
+        AttachmentType::Gifv | AttachmentType::Video => Some("video/"),
+        AttachmentType::Audio => Some("audio/"),
+        AttachmentType::Unknown => None,
+    }
+}
+
 impl From<megalodon::entities::Status> for source::LiveStatus {
     fn from(value: megalodon::entities::Status) -> Self {
         if let Some(reblog) = value.reblog {
@@ -42,6 +73,8 @@ impl From<megalodon::entities::Status> for source::LiveStatus {
                 src_identifier: value.id,
                 target_src_identifier: reblog.id,
                 target_src_uri: reblog.uri,
+                target_src_at_uri: None,
+                target_src_cid: None,
                 created_at: value.created_at.into(),
             })
         } else {
@@ -55,9 +88,24 @@ impl From<megalodon::entities::Status> for source::LiveStatus {
                 media: value
                     .media_attachments
                     .into_iter()
-                    .map(|media| store::operations::Medium {
-                        url: media.url,
-                        alt: media.description.unwrap_or_default(),
+                    .filter_map(|media| {
+                        let focus = media.meta.and_then(|meta| meta.focus).map(|focus| {
+                            store::operations::Focus {
+                                x: focus.x,
+                                y: focus.y,
+                            }
+                        });
+                        // NOTE: 種類が分からない添付は url が空で、元のサーバーの URL だけが分かる
+                        let url = Some(media.url)
+                            .filter(|url| !url.is_empty())
+                            .or(media.remote_url)?;
+                        Some(store::operations::Medium {
+                            url,
+                            alt: media.description.unwrap_or_default(),
+                            sensitive: value.sensitive,
+                            focus,
+                            content_type: to_content_type(&media.r#type).map(str::to_owned),
+                        })
                     })
                     .collect(),
                 external: value.card.map_or_else(
@@ -71,8 +119,82 @@ impl From<megalodon::entities::Status> for source::LiveStatus {
                         })
                     },
                 ),
+                content_warning: (!value.spoiler_text.is_empty()).then_some(value.spoiler_text),
+                poll: value.poll.map(|poll| store::operations::Poll {
+                    choices: poll
+                        .options
+                        .into_iter()
+                        .map(|option| option.title)
+                        .collect(),
+                    multiple: poll.multiple,
+                    expires_at: poll.expires_at.map(|expires_at| expires_at.into()),
+                }),
+                quote: None,
+                custom_emojis: value
+                    .emojis
+                    .into_iter()
+                    .map(|emoji| emoji.shortcode)
+                    .collect(),
                 created_at: value.created_at.into(),
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link_texts(text: &str, facets: &[store::operations::Facet]) -> Vec<(String, String)> {
+        facets
+            .iter()
+            .map(|facet| match facet {
+                store::operations::Facet::Link { byte_slice, uri } => (
+                    text[byte_slice.start as usize..byte_slice.end as usize].to_owned(),
+                    uri.clone(),
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn html_is_converted_to_text_with_links() {
+        let html = concat!(
+            r#"<p>こんにちは &amp; &lt;world&gt;<br />2 行目</p>"#,
+            r#"<p><a href="https://example.com/very/long/path" rel="nofollow noopener" target="_blank">"#,
+            r#"<span class="invisible">https://</span><span class="ellipsis">example.com/very/</span>"#,
+            r#"<span class="invisible">long/path</span></a> と "#,
+            r#"<span class="h-card"><a href="https://mastodon.example/@alice" class="u-url mention">@<span>alice</span></a></span></p>"#,
+        );
+
+        let (text, facets) = html_to_content_facets(html);
+
+        assert_eq!(
+            text,
+            "こんにちは & <world>\n2 行目\n\nhttps://example.com/very/long/path と @alice"
+        );
+        assert_eq!(
+            link_texts(&text, &facets),
+            [
+                (
+                    "https://example.com/very/long/path".to_owned(),
+                    "https://example.com/very/long/path".to_owned()
+                ),
+                (
+                    "@alice".to_owned(),
+                    "https://mastodon.example/@alice".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn hashtag_is_kept_as_text() {
+        let html = r##"<p>tag <a href="https://mastodon.example/tags/rust" class="mention hashtag" rel="tag">#<span>rust</span></a></p>"##;
+
+        let (text, facets) = html_to_content_facets(html);
+
+        assert_eq!(text, "tag #rust");
+        assert!(facets.is_empty());
+    }
+}
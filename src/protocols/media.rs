@@ -0,0 +1,280 @@
+use std::{collections::HashMap, io::Cursor};
+
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, ImageFormat};
+use reqwest::header::CONTENT_TYPE;
+
+use crate::http::HttpConfig;
+
+use super::retry::{send_with_retry, RetryPolicy};
+
+#[derive(Clone, Copy)]
+pub struct DownloadOptions {
+    pub retry_policy: RetryPolicy,
+    /** 全てメモリーに載せるので、これを超える場合は諦める */
+    pub max_bytes: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            retry_policy: RetryPolicy::default(),
+            max_bytes: HttpConfig::default().max_media_bytes,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Downloaded {
+    pub content_type: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+/**
+ * 一時的なエラーは再試行してダウンロードする
+ *
+ * 全てメモリーに載せるので、上限を超える場合は読み終える前に諦める
+ */
+pub async fn download(
+    http_client: &reqwest::Client,
+    options: &DownloadOptions,
+    url: &str,
+) -> Result<Downloaded> {
+    let max_bytes = options.max_bytes;
+    let mut resp = send_with_retry(&options.retry_policy, || http_client.get(url))
+        .await?
+        .error_for_status()?;
+    if resp
+        .content_length()
+        .is_some_and(|len| len as usize > max_bytes)
+    {
+        bail!("media is too large: {}", url);
+    }
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    // NOTE: Content-Length が無い場合や偽っている場合もあるので、読みながら数える
+    let mut bytes = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if bytes.len() + chunk.len() > max_bytes {
+            bail!("media is too large: {}", url);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Downloaded {
+        content_type,
+        bytes,
+    })
+}
+
+/**
+ * 同じ URL のメディアを何度も取得しないようにする
+ *
+ * メモリーを使い続けないように、投稿 1 回ごとに作って捨てる
+ */
+pub struct MediaCache {
+    options: DownloadOptions,
+    downloaded: HashMap<String, Downloaded>,
+}
+
+impl MediaCache {
+    pub fn new(options: DownloadOptions) -> Self {
+        Self {
+            options,
+            downloaded: HashMap::new(),
+        }
+    }
+
+    pub async fn fetch(&mut self, http_client: &reqwest::Client, url: &str) -> Result<&Downloaded> {
+        if !self.downloaded.contains_key(url) {
+            let downloaded = download(http_client, &self.options, url).await?;
+            self.downloaded.insert(url.to_owned(), downloaded);
+        }
+        Ok(&self.downloaded[url])
+    }
+}
+
+/** URL の拡張子から content-type を推測する。Bluesky の CDN の末尾の @jpeg なども拡張子として扱う */
+pub fn guess_content_type(url: &str) -> Option<&'static str> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let extension = path.rsplit(['.', '@', '/']).next()?.to_lowercase();
+    Some(match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" => "audio/ogg",
+        _ => return None,
+    })
+}
+
+fn to_essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+/**
+ * 送信先が対応していない形式の画像を、透過があれば PNG、無ければ JPEG に変換する
+ *
+ * 画像でないものや content-type が分からないものはそのまま返す
+ */
+pub fn transcode(downloaded: &Downloaded, supported_types: &[&str]) -> Result<Downloaded> {
+    let Some(content_type) = downloaded.content_type.as_deref().map(to_essence) else {
+        return Ok(downloaded.clone());
+    };
+    if !content_type.starts_with("image/") || supported_types.contains(&content_type.as_str()) {
+        return Ok(downloaded.clone());
+    }
+    // NOTE: AVIF のデコードは image の avif-native (dav1d) が無いとできない
+    let image = image::load_from_memory(&downloaded.bytes)
+        .with_context(|| format!("failed to decode {}", content_type))?;
+    let (format, content_type, image) = if image.color().has_alpha() {
+        (ImageFormat::Png, "image/png", image)
+    } else {
+        // NOTE: JPEG のエンコーダーは RGB しか受け付けない
+        let image = DynamicImage::ImageRgb8(image.to_rgb8());
+        (ImageFormat::Jpeg, "image/jpeg", image)
+    };
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format)?;
+    Ok(Downloaded {
+        content_type: Some(content_type.to_owned()),
+        bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn repeated_url_is_fetched_once() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"png".to_vec(), "image/png"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/other.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"other".to_vec(), "image/png"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let http_client = reqwest::Client::new();
+        let url = format!("{}/image.png", server.uri());
+        let mut media_cache = MediaCache::new(DownloadOptions::default());
+
+        for _ in 0..3 {
+            let downloaded = media_cache.fetch(&http_client, &url).await.unwrap();
+            assert_eq!(downloaded.bytes, b"png");
+            assert_eq!(downloaded.content_type.as_deref(), Some("image/png"));
+        }
+        let other = format!("{}/other.png", server.uri());
+        let downloaded = media_cache.fetch(&http_client, &other).await.unwrap();
+        assert_eq!(downloaded.bytes, b"other");
+    }
+
+    fn options(max_bytes: usize) -> DownloadOptions {
+        DownloadOptions {
+            retry_policy: RetryPolicy {
+                max_retries: 1,
+                base_delay_millis: 1,
+            },
+            max_bytes,
+        }
+    }
+
+    #[tokio::test]
+    async fn too_large_content_length_is_rejected() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/large.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(vec![0; 16], "image/png"))
+            .mount(&server)
+            .await;
+
+        let err = download(
+            &reqwest::Client::new(),
+            &options(8),
+            &format!("{}/large.png", server.uri()),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert!(err.to_string().starts_with("media is too large"));
+    }
+
+    #[tokio::test]
+    async fn too_large_stream_is_rejected() {
+        let server = MockServer::start().await;
+        // NOTE: Content-Length を付けずに chunked で返させる
+        Mock::given(method("GET"))
+            .and(path("/large.png"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("transfer-encoding", "chunked")
+                    .set_body_raw(vec![0; 16], "image/png"),
+            )
+            .mount(&server)
+            .await;
+
+        let err = download(
+            &reqwest::Client::new(),
+            &options(8),
+            &format!("{}/large.png", server.uri()),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert!(err.to_string().starts_with("media is too large"));
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"png".to_vec(), "image/png"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let downloaded = download(
+            &reqwest::Client::new(),
+            &options(1024),
+            &format!("{}/image.png", server.uri()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(downloaded.bytes, b"png");
+    }
+}
@@ -1,16 +1,64 @@
-use anyhow::Result;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use futures::future::join_all;
 use http::header::ACCEPT;
 use megalodon::{
-    megalodon::{GetAccountStatusesInputOptions, PostStatusInputOptions, PostStatusOutput},
+    entities::StatusVisibility,
+    megalodon::{
+        EditStatusInputOptions, GetAccountStatusesInputOptions, PollOptions,
+        PostStatusInputOptions, PostStatusOutput,
+    },
     Megalodon,
 };
-use reqwest::{header::HeaderMap, multipart::Part, Body};
-use tracing::{debug, event_enabled, trace, Level};
+use reqwest::{header::HeaderMap, multipart::Part, StatusCode};
+use serde_json::{json, Value};
+use tracing::{debug, event_enabled, trace, warn, Level};
+
+use crate::{
+    config::MastodonVisibility, http::USER_AGENT, metrics, rate_limit::Budget, sources::source,
+    store,
+};
+
+use super::{
+    error::{ClientError, ResponseExt},
+    is_caught_up,
+    media::{download, transcode, DownloadOptions},
+    text::{truncate, Counting},
+    AccountInfo, Cursor, NewPost, MAX_CATCH_UP_PAGES,
+};
 
-use crate::{sources::source, store};
+const MAX_LENGTH: usize = 500;
+/**
+ * 予約投稿は公開されるまで status の id が無いので、予約の id にこれを付けて identifier にする
+ *
+ * 公開後の status を探せるように、予約の id の後ろに予約日時の UNIX 時間を付ける
+ */
+const SCHEDULED_PREFIX: &str = "scheduled:";
+/** 予約日時から実際に公開されるまでの遅れとして許容する時間 */
+const MAX_PUBLISH_DELAY_MINUTES: i64 = 5;
+/** 予約し直す場合に、サーバーが受け付ける予約日時までの最短の時間 */
+const MIN_SCHEDULE_AHEAD_MINUTES: i64 = 5;
+/** AVIF はサーバーのバージョンによっては受け付けられない */
+const SUPPORTED_IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/** megalodon は HTTP のエラーを自前のエラーにするので、ステータスを取り出して分類する */
+impl From<megalodon::error::Error> for ClientError {
+    fn from(err: megalodon::error::Error) -> Self {
+        match err {
+            megalodon::error::Error::RequestError(err) => err.into(),
+            megalodon::error::Error::OwnError(megalodon::error::OwnError {
+                status: Some(status),
+                ..
+            }) if StatusCode::from_u16(status).is_ok() => {
+                ClientError::from_status(StatusCode::from_u16(status).unwrap(), None)
+            }
+            err => ClientError::Permanent(err.into()),
+        }
+    }
+}
 
 fn trace_header(header: &HeaderMap) {
     if !event_enabled!(Level::TRACE) {
@@ -33,16 +81,28 @@ fn trace_header(header: &HeaderMap) {
 }
 
 async fn upload_media(
+    http_client: &reqwest::Client,
+    download_options: &DownloadOptions,
     origin: &str,
     access_token: &str,
-    src_url: &str,
+    medium: &store::operations::Medium,
 ) -> Result<megalodon::response::Response<megalodon::entities::Attachment>> {
-    let resp = reqwest::get(src_url).await?;
+    let downloaded = download(http_client, download_options, &medium.url).await?;
+    let downloaded = transcode(&downloaded, SUPPORTED_IMAGE_TYPES)?;
+    let len = downloaded.bytes.len();
 
-    let body = Body::from(resp);
-    let part = Part::stream(body).file_name("_");
-    let form = reqwest::multipart::Form::new().part("file", part);
-    let resp = reqwest::Client::new()
+    let mut part = Part::bytes(downloaded.bytes).file_name("_");
+    if let Some(content_type) = &downloaded.content_type {
+        part = part.mime_str(content_type)?;
+    }
+    let mut form = reqwest::multipart::Form::new().part("file", part);
+    if !medium.alt.is_empty() {
+        form = form.text("description", medium.alt.clone());
+    }
+    if let Some(focus) = medium.focus {
+        form = form.text("focus", format!("{},{}", focus.x, focus.y));
+    }
+    let resp = http_client
         .post(format!("{}{}", origin, "/api/v2/media"))
         .bearer_auth(access_token)
         .multipart(form)
@@ -53,6 +113,7 @@ async fn upload_media(
     let status_text = resp.status().to_string();
     let headers = resp.headers().to_owned();
     tracing::trace!("{} {} {:?}", status_code, status_text, headers);
+    metrics::uploaded_bytes(origin, len);
 
     let res = megalodon::response::Response::<megalodon::entities::Attachment>::new(
         resp.json().await?,
@@ -64,13 +125,15 @@ async fn upload_media(
 }
 
 async fn upload_media_list(
+    http_client: &reqwest::Client,
+    download_options: &DownloadOptions,
     origin: &str,
     access_token: &str,
     images: &[store::operations::Medium],
 ) -> Result<Vec<String>> {
     let upload_media_futures = images
         .iter()
-        .map(|image| upload_media(origin, access_token, &image.url));
+        .map(|image| upload_media(http_client, download_options, origin, access_token, image));
     Ok(join_all(upload_media_futures)
         .await
         .into_iter()
@@ -80,54 +143,354 @@ async fn upload_media_list(
         .collect())
 }
 
+fn to_scheduled_identifier(scheduled_id: &str, scheduled_at: &DateTime<Utc>) -> String {
+    format!(
+        "{}{}:{}",
+        SCHEDULED_PREFIX,
+        scheduled_id,
+        scheduled_at.timestamp()
+    )
+}
+
+/** 予約の id と予約日時を返す。予約日時を付けていなかった以前の identifier は None */
+fn parse_scheduled_identifier(identifier: &str) -> Option<(&str, Option<DateTime<Utc>>)> {
+    let scheduled = identifier.strip_prefix(SCHEDULED_PREFIX)?;
+    let Some((scheduled_id, timestamp)) = scheduled.split_once(':') else {
+        return Some((scheduled, None));
+    };
+    let scheduled_at = timestamp
+        .parse()
+        .ok()
+        .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single());
+    Some((scheduled_id, scheduled_at))
+}
+
+/**
+ * 予約投稿の内容を本文だけ差し替えて、同じ日時に予約し直すためのリクエスト
+ *
+ * 予約投稿は本文を編集できないので、予約時のパラメーターから作り直す
+ */
+fn to_rescheduled_json(scheduled: &Value, content: &str) -> Value {
+    let params = &scheduled["params"];
+    let mut json = json!({
+        "status": content,
+        "scheduled_at": scheduled["scheduled_at"],
+    });
+    for key in [
+        "media_ids",
+        "poll",
+        "sensitive",
+        "spoiler_text",
+        "visibility",
+        "in_reply_to_id",
+        "language",
+    ] {
+        if !params[key].is_null() {
+            json[key] = params[key].clone();
+        }
+    }
+    json
+}
+
+enum Scheduled {
+    /** まだ公開されていない予約投稿 */
+    Pending(Value),
+    /** 公開された status の id */
+    Published(String),
+}
+
+fn to_megalodon_visibility(visibility: MastodonVisibility) -> StatusVisibility {
+    match visibility {
+        MastodonVisibility::Public => StatusVisibility::Public,
+        MastodonVisibility::Unlisted => StatusVisibility::Unlisted,
+        MastodonVisibility::Private => StatusVisibility::Private,
+        MastodonVisibility::Direct => StatusVisibility::Direct,
+    }
+}
+
+/** ブーストは direct にできないので、最も狭い private にする */
+fn to_reblog_json(visibility: Option<MastodonVisibility>) -> Value {
+    match visibility {
+        Some(MastodonVisibility::Direct) => json!({ "visibility": MastodonVisibility::Private }),
+        Some(visibility) => json!({ "visibility": visibility }),
+        None => json!({}),
+    }
+}
+
+fn to_megalodon_poll_options(poll: &store::operations::Poll) -> PollOptions {
+    // NOTE: 締め切りは必須で、5 分未満は受け付けられない
+    const MIN_EXPIRES_IN: i64 = 5 * 60;
+    const DEFAULT_EXPIRES_IN: i64 = 24 * 60 * 60;
+    let expires_in = poll.expires_at.map_or(DEFAULT_EXPIRES_IN, |expires_at| {
+        (expires_at.with_timezone(&Utc) - Utc::now()).num_seconds()
+    });
+    PollOptions {
+        options: poll.choices.clone(),
+        expires_in: Some(expires_in.max(MIN_EXPIRES_IN) as u64),
+        multiple: Some(poll.multiple),
+        hide_totals: None,
+    }
+}
+
 fn to_megalodon_post_status_input_options(
+    post: &NewPost,
     media_ids: Vec<String>,
-    reply_identifier: Option<&str>,
+    visibility: Option<MastodonVisibility>,
 ) -> PostStatusInputOptions {
+    // NOTE: 画像と投票は併用できないので、投票を諦めて画像を優先する
+    if post.poll.is_some() && !media_ids.is_empty() {
+        warn!("poll cannot be attached with media, post without poll");
+    }
+    let poll = post
+        .poll
+        .filter(|_| media_ids.is_empty())
+        .map(to_megalodon_poll_options);
     PostStatusInputOptions {
         media_ids: if media_ids.is_empty() {
             None
         } else {
             Some(media_ids)
         },
-        poll: None,
-        in_reply_to_id: reply_identifier.map(|x| x.to_owned()),
-        sensitive: None,
-        spoiler_text: None,
-        visibility: None,
-        scheduled_at: None,
+        poll,
+        // NOTE: 予約投稿には返信できないので、返信先を外して送る
+        in_reply_to_id: post
+            .reply_identifier
+            .filter(|x| {
+                let is_scheduled = x.starts_with(SCHEDULED_PREFIX);
+                if is_scheduled {
+                    warn!("reply target is scheduled, post without reply: {}", x);
+                }
+                !is_scheduled
+            })
+            .map(|x| x.to_owned()),
+        sensitive: post
+            .images
+            .iter()
+            .any(|image| image.sensitive)
+            .then_some(true),
+        spoiler_text: post.content_warning.map(str::to_owned),
+        visibility: visibility.map(to_megalodon_visibility),
+        scheduled_at: post
+            .scheduled_at
+            .map(|scheduled_at| scheduled_at.with_timezone(&Utc)),
         language: None,
         quote_id: None,
     }
 }
 
 pub struct Client {
+    http_client: Arc<reqwest::Client>,
     origin: String,
     access_token: String,
     megalodon: Box<dyn Megalodon + Send + Sync>,
     account_id: String,
+    visibility: Option<MastodonVisibility>,
+    include_reposts: bool,
+    budget: Budget,
+    media: DownloadOptions,
 }
 
 impl Client {
     #[tracing::instrument(name = "megalodon_client::Client::new", skip_all)]
-    pub async fn new_mastodon(origin: String, access_token: String) -> Result<Self> {
+    pub async fn new_mastodon(
+        http_client: Arc<reqwest::Client>,
+        origin: String,
+        access_token: String,
+        visibility: Option<MastodonVisibility>,
+        include_reposts: bool,
+        budget: Budget,
+        media: DownloadOptions,
+    ) -> Result<Self> {
         let megalodon = megalodon::generator(
             megalodon::SNS::Mastodon,
             origin.clone(),
             Some(access_token.clone()),
-            None,
+            Some(USER_AGENT.to_owned()),
         );
-        let resp = megalodon.verify_account_credentials().await?;
+        // NOTE: ログインの失敗も送信と同じように分類できるようにしておく
+        let resp = megalodon
+            .verify_account_credentials()
+            .await
+            .map_err(ClientError::from)?;
         trace_header(&resp.header);
+        budget.update(&resp.header);
         let account_id = resp.json().id;
 
         Ok(Self {
+            http_client,
             origin,
             access_token,
             megalodon,
             account_id,
+            visibility,
+            include_reposts,
+            budget,
+            media,
         })
     }
+
+    fn record_header(&self, header: &HeaderMap) {
+        trace_header(header);
+        self.budget.update(header);
+    }
+
+    /**
+     * 予約投稿が公開済みなら、公開された status の id を探す
+     *
+     * 公開後は予約の id で引けないので、予約日時の直後に公開された自分の status から探す。
+     * 見つからないか、1 つに絞れない場合は別の status を操作しないようにエラーにする
+     */
+    async fn resolve_scheduled(&mut self, identifier: &str) -> Result<Scheduled, ClientError> {
+        let (scheduled_id, scheduled_at) = parse_scheduled_identifier(identifier)
+            .ok_or_else(|| anyhow!("not a scheduled status: {}", identifier))?;
+        let result = self
+            .http_client
+            .get(format!(
+                "{}/api/v1/scheduled_statuses/{}",
+                self.origin, scheduled_id
+            ))
+            .bearer_auth(&self.access_token)
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?
+            .error_for_client_status();
+        match result {
+            Ok(resp) => {
+                self.record_header(resp.headers());
+                return Ok(Scheduled::Pending(resp.json().await?));
+            }
+            Err(ClientError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let scheduled_at = scheduled_at.ok_or_else(|| {
+            anyhow!(
+                "published status cannot be resolved without scheduled_at: {}",
+                identifier
+            )
+        })?;
+        let published_id = self.find_published_status(&scheduled_at).await?;
+        published_id
+            .map(Scheduled::Published)
+            .ok_or_else(|| anyhow!("published status cannot be resolved: {}", identifier).into())
+    }
+
+    /** 予約日時に最も近く公開された status の id。近さで絞れなければ None */
+    async fn find_published_status(
+        &mut self,
+        scheduled_at: &DateTime<Utc>,
+    ) -> Result<Option<String>, ClientError> {
+        const LIMIT: u32 = 40;
+        let max_delay = chrono::Duration::minutes(MAX_PUBLISH_DELAY_MINUTES);
+        let mut candidates = Vec::new();
+        let mut max_id = None;
+        for _ in 0..MAX_CATCH_UP_PAGES {
+            let resp = self
+                .megalodon
+                .get_account_statuses(
+                    self.account_id.clone(),
+                    Some(&GetAccountStatusesInputOptions {
+                        limit: Some(LIMIT),
+                        max_id,
+                        exclude_reblogs: Some(true),
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+            self.record_header(&resp.header);
+            let page = resp.json();
+            let len = page.len();
+            max_id = page.last().map(|status| status.id.clone());
+            let reached = page
+                .last()
+                .is_some_and(|status| status.created_at < *scheduled_at);
+            candidates.extend(
+                page.into_iter()
+                    .filter(|status| status.reblog.is_none())
+                    .map(|status| (status.created_at - *scheduled_at, status.id))
+                    .filter(|(delay, _)| *delay >= chrono::Duration::zero() && *delay <= max_delay),
+            );
+            if len < LIMIT as usize || reached {
+                break;
+            }
+        }
+        candidates.sort();
+        match candidates.as_slice() {
+            [] => Ok(None),
+            [(_, id)] => Ok(Some(id.clone())),
+            [(first, id), (second, _), ..] if first < second => Ok(Some(id.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete_scheduled_status(&self, scheduled_id: &str) -> Result<(), ClientError> {
+        let resp = self
+            .http_client
+            .delete(format!(
+                "{}/api/v1/scheduled_statuses/{}",
+                self.origin, scheduled_id
+            ))
+            .bearer_auth(&self.access_token)
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?
+            .error_for_client_status()?;
+        self.record_header(resp.headers());
+        Ok(())
+    }
+
+    /** 本文と代替テキストを差し替えて予約し直し、新しい予約の identifier を返す */
+    async fn reschedule(
+        &mut self,
+        identifier: &str,
+        scheduled: &Value,
+        content: &str,
+        media_alts: Option<&[String]>,
+    ) -> Result<String, ClientError> {
+        let scheduled_at: DateTime<Utc> =
+            serde_json::from_value(scheduled["scheduled_at"].clone())?;
+        // NOTE: 予約日時が近すぎると予約し直せないので、公開されてから編集する
+        if scheduled_at - Utc::now() < chrono::Duration::minutes(MIN_SCHEDULE_AHEAD_MINUTES) {
+            warn!("scheduled status is about to be published: {}", identifier);
+            return Err(ClientError::Transient);
+        }
+        let media_ids: Vec<_> = scheduled["params"]["media_ids"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        for (media_id, alt) in media_ids.iter().zip(media_alts.unwrap_or_default()) {
+            let resp = self
+                .http_client
+                .put(format!("{}/api/v1/media/{}", self.origin, media_id))
+                .bearer_auth(&self.access_token)
+                .json(&json!({ "description": alt }))
+                .header(ACCEPT.as_str(), "application/json")
+                .send()
+                .await?
+                .error_for_client_status()?;
+            self.record_header(resp.headers());
+        }
+        let resp = self
+            .http_client
+            .post(format!("{}/api/v1/statuses", self.origin))
+            .bearer_auth(&self.access_token)
+            .json(&to_rescheduled_json(scheduled, content))
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?
+            .error_for_client_status()?;
+        self.record_header(resp.headers());
+        let json: Value = resp.json().await?;
+        let rescheduled_id = json
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("id is not found"))?;
+        // NOTE: 古い予約は新しい予約を作ってから消すので、途中で失敗しても投稿は失われない
+        let (scheduled_id, _) = parse_scheduled_identifier(identifier).unwrap();
+        self.delete_scheduled_status(scheduled_id).await?;
+        Ok(to_scheduled_identifier(rescheduled_id, &scheduled_at))
+    }
 }
 
 #[async_trait]
@@ -136,73 +499,264 @@ impl super::Client for Client {
         None
     }
 
+    #[tracing::instrument(name = "megalodon_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let resp = self.megalodon.verify_account_credentials().await?;
+        self.record_header(&resp.header);
+        let account = resp.json();
+        Ok(AccountInfo {
+            id: account.id,
+            handle: account.acct,
+        })
+    }
+
     #[tracing::instrument(name = "megalodon_client::Client::fetch_statuses", skip_all)]
-    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
-        let resp = self
-            .megalodon
-            .get_account_statuses(
-                self.account_id.clone(),
-                Some(&GetAccountStatusesInputOptions {
-                    limit: Some(40),
-                    // exclude_replies: Some(true), // TODO: include self replies
-                    ..Default::default()
-                }),
-            )
-            .await?;
-        trace_header(&resp.header);
-        let statuses: Vec<_> = resp
-            .json()
-            .into_iter()
-            .map(|status| status.into())
-            .collect();
+    async fn fetch_statuses(
+        &mut self,
+        cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        const LIMIT: u32 = 40;
+        let mut statuses = Vec::new();
+        let mut max_id = None;
+        for _ in 0..MAX_CATCH_UP_PAGES {
+            let resp = self
+                .megalodon
+                .get_account_statuses(
+                    self.account_id.clone(),
+                    Some(&GetAccountStatusesInputOptions {
+                        limit: Some(LIMIT),
+                        max_id,
+                        // exclude_replies: Some(true), // TODO: include self replies
+                        // NOTE: 除外したブーストは後から含めるようにしても、保存済みの投稿より古いので対象にならない
+                        exclude_reblogs: (!self.include_reposts).then_some(true),
+                        ..Default::default()
+                    }),
+                )
+                .await?;
+            self.record_header(&resp.header);
+            let page = resp.json();
+            let len = page.len();
+            max_id = page.last().map(|status| status.id.clone());
+            let page: Vec<source::LiveStatus> =
+                page.into_iter().map(|status| status.into()).collect();
+            // NOTE: cursor がブーストの場合もあるので、除外する前のページで確かめる
+            let caught_up = is_caught_up(&page, cursor);
+            statuses.extend(page.into_iter().filter(|status| {
+                self.include_reposts || !matches!(status, source::LiveStatus::Repost(_))
+            }));
+            if len < LIMIT as usize || caught_up {
+                break;
+            }
+        }
 
         Ok(statuses)
     }
 
-    #[tracing::instrument(name = "megalodon_client::Client::post", skip_all)]
-    async fn post(
+    #[tracing::instrument(name = "megalodon_client::Client::get_status", skip_all)]
+    async fn get_status(
         &mut self,
-        content: &str,
-        _facets: &[store::operations::Facet],
-        reply_identifier: Option<&str>,
-        images: Vec<store::operations::Medium>,
-        _external: Option<store::operations::External>,
-        _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
-        let media_ids = upload_media_list(&self.origin, &self.access_token, &images).await?;
-        if let PostStatusOutput::Status(status) = self
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        let resp = match self
+            .megalodon
+            .get_status(identifier.to_owned())
+            .await
+            .map_err(ClientError::from)
+        {
+            Ok(resp) => resp,
+            Err(ClientError::NotFound) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        self.record_header(&resp.header);
+        let status = resp.json();
+        if status.account.id != self.account_id {
+            return Ok(None);
+        }
+        Ok(Some(status.into()))
+    }
+
+    #[tracing::instrument(name = "megalodon_client::Client::post", skip_all)]
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let media_ids = upload_media_list(
+            &self.http_client,
+            &self.media,
+            &self.origin,
+            &self.access_token,
+            &post.images,
+        )
+        .await?;
+        let (content, _) = truncate(
+            post.content,
+            post.facets,
+            MAX_LENGTH,
+            Counting::Mastodon,
+            post.src_uri,
+        );
+        // NOTE: megalodon からは Idempotency-Key のヘッダーを付けられないので、重複は防げない
+        let resp = self
             .megalodon
             .post_status(
-                content.to_owned(),
+                content,
                 Some(&to_megalodon_post_status_input_options(
+                    &post,
                     media_ids,
-                    reply_identifier,
+                    self.visibility,
                 )),
             )
-            .await?
-            .json()
-        {
-            Ok(status.id)
-        } else {
-            unreachable!()
+            .await?;
+        self.record_header(&resp.header);
+        match resp.json() {
+            PostStatusOutput::Status(status) => Ok(status.id),
+            PostStatusOutput::ScheduledStatus(scheduled_status) => Ok(to_scheduled_identifier(
+                &scheduled_status.id,
+                &scheduled_status.scheduled_at,
+            )),
         }
     }
 
+    #[tracing::instrument(name = "megalodon_client::Client::update_post", skip_all)]
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        if identifier.starts_with(SCHEDULED_PREFIX) {
+            return match self.resolve_scheduled(identifier).await? {
+                Scheduled::Pending(scheduled) => {
+                    let (content, _) =
+                        truncate(content, facets, MAX_LENGTH, Counting::Mastodon, None);
+                    self.reschedule(identifier, &scheduled, &content, None)
+                        .await
+                }
+                Scheduled::Published(published_id) => {
+                    super::Client::update_post(self, &published_id, content, facets).await
+                }
+            };
+        }
+        let (content, _) = truncate(content, facets, MAX_LENGTH, Counting::Mastodon, None);
+        let resp = self
+            .megalodon
+            .edit_status(
+                identifier.to_owned(),
+                &EditStatusInputOptions {
+                    status: Some(content),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        self.record_header(&resp.header);
+        Ok(resp.json().id)
+    }
+
+    #[tracing::instrument(
+        name = "megalodon_client::Client::update_post_with_media_alts",
+        skip_all
+    )]
+    async fn update_post_with_media_alts(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+        media_alts: &[String],
+    ) -> Result<String, ClientError> {
+        if identifier.starts_with(SCHEDULED_PREFIX) {
+            return match self.resolve_scheduled(identifier).await? {
+                Scheduled::Pending(scheduled) => {
+                    let (content, _) =
+                        truncate(content, facets, MAX_LENGTH, Counting::Mastodon, None);
+                    self.reschedule(identifier, &scheduled, &content, Some(media_alts))
+                        .await
+                }
+                Scheduled::Published(published_id) => {
+                    super::Client::update_post_with_media_alts(
+                        self,
+                        &published_id,
+                        content,
+                        facets,
+                        media_alts,
+                    )
+                    .await
+                }
+            };
+        }
+        let (content, _) = truncate(content, facets, MAX_LENGTH, Counting::Mastodon, None);
+        let resp = self.megalodon.get_status(identifier.to_owned()).await?;
+        self.record_header(&resp.header);
+        let media_ids: Vec<_> = resp
+            .json()
+            .media_attachments
+            .into_iter()
+            .map(|attachment| attachment.id)
+            .collect();
+        // NOTE: 添付済みのメディアの説明は、media_ids と合わせて media_attributes で編集する。
+        //       megalodon の edit_status は media_attributes を渡せないので直接リクエストする
+        let media_attributes: Vec<_> = media_ids
+            .iter()
+            .zip(media_alts)
+            .map(|(id, alt)| json!({ "id": id, "description": alt }))
+            .collect();
+        let resp = self
+            .http_client
+            .put(format!("{}/api/v1/statuses/{}", self.origin, identifier))
+            .bearer_auth(&self.access_token)
+            .json(&json!({
+                "status": content,
+                "media_ids": media_ids,
+                "media_attributes": media_attributes,
+            }))
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?
+            .error_for_client_status()?;
+        self.record_header(resp.headers());
+        let json: Value = resp.json().await?;
+        Ok(json
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("id is not found"))?
+            .to_owned())
+    }
+
     #[tracing::instrument(name = "megalodon_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
         target_identifier: &str,
         _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
-        let res = self
-            .megalodon
-            .reblog_status(target_identifier.to_owned())
-            .await?;
-        Ok(res.json().id)
+    ) -> Result<String, ClientError> {
+        // NOTE: megalodon の reblog_status は公開範囲を指定できないので直接リクエストする
+        let resp = self
+            .http_client
+            .post(format!(
+                "{}/api/v1/statuses/{}/reblog",
+                self.origin, target_identifier
+            ))
+            .bearer_auth(&self.access_token)
+            .json(&to_reblog_json(self.visibility))
+            .header(ACCEPT.as_str(), "application/json")
+            .send()
+            .await?
+            .error_for_client_status()?;
+        self.record_header(resp.headers());
+        let json: Value = resp.json().await?;
+        Ok(json
+            .get("id")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("id is not found"))?
+            .to_owned())
     }
 
     #[tracing::instrument(name = "megalodon_client::Client::delete_post", skip_all)]
-    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        // NOTE: 公開された後は予約の id では消せないので、公開された status を消す
+        if let Some((scheduled_id, _)) = parse_scheduled_identifier(identifier) {
+            return match self.resolve_scheduled(identifier).await? {
+                Scheduled::Pending(_) => self.delete_scheduled_status(scheduled_id).await,
+                Scheduled::Published(published_id) => {
+                    super::Client::delete_post(self, &published_id).await
+                }
+            };
+        }
         let result = self.megalodon.delete_status(identifier.to_owned()).await;
         debug!("megalodon delete_post: {:?}", result);
         const IGNORE_ERROR_MSG: &str =
@@ -217,12 +771,17 @@ impl super::Client for Client {
             {
                 Ok(())
             }
+            Err(megalodon::error::Error::RequestError(err))
+                if err.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+            {
+                Err(ClientError::NotFound)
+            }
             Err(err) => Err(err.into()),
         }
     }
 
     #[tracing::instrument(name = "megalodon_client::Client::delete_repost", skip_all)]
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
         let result = self.megalodon.delete_status(identifier.to_owned()).await;
         debug!("megalodon delete_repost: {:?}", result);
         const IGNORE_ERROR_MSG: &str =
@@ -237,7 +796,740 @@ impl super::Client for Client {
             {
                 Ok(())
             }
+            Err(megalodon::error::Error::RequestError(err))
+                if err.status() == Some(reqwest::StatusCode::NOT_FOUND) =>
+            {
+                Err(ClientError::NotFound)
+            }
             Err(err) => Err(err.into()),
         }
     }
 }
+
+/** テスト用の Mastodon の API が返す account */
+#[cfg(test)]
+pub fn test_account(id: &str, acct: &str) -> Value {
+    json!({
+        "id": id,
+        "username": acct,
+        "acct": acct,
+        "display_name": acct,
+        "locked": false,
+        "bot": false,
+        "discoverable": true,
+        "group": false,
+        "created_at": "2024-01-01T00:00:00.000Z",
+        "note": "",
+        "url": format!("https://example.com/@{}", acct),
+        "avatar": "https://example.com/avatar.png",
+        "avatar_static": "https://example.com/avatar.png",
+        "header": "https://example.com/header.png",
+        "header_static": "https://example.com/header.png",
+        "followers_count": 0,
+        "following_count": 0,
+        "statuses_count": 0,
+        "last_status_at": "2024-01-01",
+        "emojis": [],
+        "fields": [],
+    })
+}
+
+/** テスト用の Mastodon の API が返す、id が 1 の account の status */
+#[cfg(test)]
+pub fn test_status(id: &str) -> Value {
+    json!({
+        "id": id,
+        "created_at": "2024-01-01T00:00:00.000Z",
+        "in_reply_to_id": null,
+        "in_reply_to_account_id": null,
+        "sensitive": false,
+        "spoiler_text": "",
+        "visibility": "public",
+        "language": "ja",
+        "uri": format!("https://example.com/users/test/statuses/{}", id),
+        "url": format!("https://example.com/@test/{}", id),
+        "replies_count": 0,
+        "reblogs_count": 0,
+        "favourites_count": 0,
+        "edited_at": null,
+        "favourited": false,
+        "reblogged": false,
+        "muted": false,
+        "bookmarked": false,
+        "pinned": false,
+        "content": "<p>hello</p>",
+        "reblog": null,
+        "application": null,
+        "account": test_account("1", "test"),
+        "media_attachments": [],
+        "mentions": [],
+        "tags": [],
+        "emojis": [],
+        "card": null,
+        "poll": null,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_partial_json, header, method, path, query_param, query_param_is_missing},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::protocols::NewPostFixture;
+
+    use super::*;
+
+    async fn client(server: &MockServer) -> Client {
+        client_with_reposts(server, true).await
+    }
+
+    async fn client_with_reposts(server: &MockServer, include_reposts: bool) -> Client {
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_account("1", "test")))
+            .mount(server)
+            .await;
+        Client::new_mastodon(
+            Arc::new(reqwest::Client::new()),
+            server.uri(),
+            "token".into(),
+            None,
+            include_reposts,
+            Budget::default(),
+            DownloadOptions::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn megalodon_status_error_is_retryable() {
+        for status in [429, 500, 502, 503] {
+            let err = megalodon::error::Error::new_own(
+                "error".into(),
+                megalodon::error::Kind::HTTPStatusError,
+                None,
+                Some(status),
+            );
+            assert!(ClientError::from(err).is_retryable(), "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn status_is_posted() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(body_partial_json(json!({ "status": "hello" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_status("10")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let identifier = super::super::Client::post(&mut client, fixture.post("hello"))
+            .await
+            .unwrap();
+
+        assert_eq!(identifier, "10");
+    }
+
+    #[tokio::test]
+    async fn only_own_existing_status_is_got() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let mut others = test_status("11");
+        others["account"] = test_account("2", "other");
+        for (id, response) in [
+            (
+                "10",
+                ResponseTemplate::new(200).set_body_json(test_status("10")),
+            ),
+            ("11", ResponseTemplate::new(200).set_body_json(others)),
+            ("12", ResponseTemplate::new(404)),
+        ] {
+            Mock::given(method("GET"))
+                .and(path(format!("/api/v1/statuses/{}", id)))
+                .respond_with(response)
+                .mount(&server)
+                .await;
+        }
+
+        let status = super::super::Client::get_status(&mut client, "10")
+            .await
+            .unwrap();
+        let Some(source::LiveStatus::Post(post)) = status else {
+            panic!("unexpected status");
+        };
+        assert_eq!(post.identifier, "10");
+        assert_eq!(post.content, "hello");
+        for id in ["11", "12"] {
+            let status = super::super::Client::get_status(&mut client, id)
+                .await
+                .unwrap();
+            assert!(status.is_none(), "{}", id);
+        }
+    }
+
+    fn test_attachment(id: &str, description: &str) -> Value {
+        json!({
+            "id": id,
+            "type": "image",
+            "url": format!("https://example.com/media/{}.png", id),
+            "preview_url": format!("https://example.com/media/{}_small.png", id),
+            "remote_url": null,
+            "text_url": null,
+            "meta": { "focus": { "x": 0.5, "y": -0.5 } },
+            "description": description,
+            "blurhash": null,
+        })
+    }
+
+    #[tokio::test]
+    async fn captioned_images_and_card_are_decoded() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let mut status = test_status("10");
+        status["sensitive"] = true.into();
+        status["media_attachments"] = json!([
+            test_attachment("1", "first"),
+            test_attachment("2", "second")
+        ]);
+        status["card"] = json!({
+            "url": "https://example.com/article",
+            "title": "Article",
+            "description": "An article",
+            "type": "link",
+            "image": "https://example.com/thumb.png",
+            "author_name": "",
+            "author_url": "",
+            "provider_name": "",
+            "provider_url": "",
+            "html": "",
+            "width": 0,
+            "height": 0,
+            "embed_url": "",
+            "blurhash": null,
+        });
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([status])))
+            .mount(&server)
+            .await;
+
+        let statuses = super::super::Client::fetch_statuses(&mut client, None)
+            .await
+            .unwrap();
+
+        let [source::LiveStatus::Post(post)] = statuses.as_slice() else {
+            panic!("unexpected statuses");
+        };
+        let media: Vec<_> = post
+            .media
+            .iter()
+            .map(|medium| (medium.url.as_str(), medium.alt.as_str(), medium.sensitive))
+            .collect();
+        assert_eq!(
+            media,
+            [
+                ("https://example.com/media/1.png", "first", true),
+                ("https://example.com/media/2.png", "second", true),
+            ]
+        );
+        let focus = post.media[0].focus.as_ref().unwrap();
+        assert_eq!((focus.x, focus.y), (0.5, -0.5));
+        let source::LiveExternal::Some(external) = &post.external else {
+            panic!("external not found");
+        };
+        assert_eq!(external.uri, "https://example.com/article");
+        assert_eq!(external.title, "Article");
+        assert_eq!(
+            external.thumb_url.as_deref(),
+            Some("https://example.com/thumb.png")
+        );
+    }
+
+    #[tokio::test]
+    async fn burst_larger_than_one_page_is_fetched_back_to_cursor() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let page = |ids: std::ops::RangeInclusive<u32>| {
+            let statuses: Vec<_> = ids.rev().map(|id| test_status(&id.to_string())).collect();
+            ResponseTemplate::new(200).set_body_json(statuses)
+        };
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .and(query_param_is_missing("max_id"))
+            .respond_with(page(41..=80))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .and(query_param("max_id", "41"))
+            .respond_with(page(1..=40))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        // NOTE: 投稿日時が全て同じでも、identifier で cursor を見つけるまで遡る
+        let cursor = Cursor {
+            identifier: "20",
+            created_at: &created_at,
+        };
+
+        let statuses = super::super::Client::fetch_statuses(&mut client, Some(&cursor))
+            .await
+            .unwrap();
+        assert_eq!(statuses.len(), 80);
+        assert_eq!(statuses.last().unwrap().identifier(), "1");
+
+        // NOTE: 初回は最新のページだけ
+        let statuses = super::super::Client::fetch_statuses(&mut client, None)
+            .await
+            .unwrap();
+        assert_eq!(statuses.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn status_error_is_classified() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+
+        let err = super::super::Client::post(&mut client, fixture.post("hello"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::RateLimited { .. }));
+    }
+
+    #[tokio::test]
+    async fn alt_is_forwarded_as_description() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"png".to_vec(), "image/png"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/media"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "100",
+                "type": "image",
+                "url": format!("{}/image.png", server.uri()),
+                "preview_url": format!("{}/image.png", server.uri()),
+                "remote_url": null,
+                "text_url": null,
+                "meta": null,
+                "description": null,
+                "blurhash": null,
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let images: Vec<_> = ["alt text", ""]
+            .into_iter()
+            .map(|alt| store::operations::Medium {
+                url: format!("{}/image.png", server.uri()),
+                alt: alt.into(),
+                sensitive: false,
+                focus: None,
+                content_type: None,
+            })
+            .collect();
+
+        let media_ids = upload_media_list(
+            &reqwest::Client::new(),
+            &DownloadOptions::default(),
+            &server.uri(),
+            "token",
+            &images,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(media_ids, ["100", "100"]);
+        let uploads: Vec<_> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|req| req.url.path() == "/api/v2/media")
+            .map(|req| String::from_utf8(req.body).unwrap())
+            .collect();
+        let descriptions = uploads
+            .iter()
+            .filter(|body| body.contains("name=\"description\"\r\n\r\nalt text\r\n"))
+            .count();
+        assert_eq!(descriptions, 1);
+        assert!(uploads
+            .iter()
+            .any(|body| !body.contains("name=\"description\"")));
+    }
+
+    #[tokio::test]
+    async fn focus_survives_fetch_and_post_round_trip() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let mut attachment = test_attachment("1", "");
+        attachment["url"] = format!("{}/image.png", server.uri()).into();
+        let mut status = test_status("10");
+        status["media_attachments"] = json!([attachment]);
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([status])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/image.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(b"png".to_vec(), "image/png"))
+            .mount(&server)
+            .await;
+        let mut uploaded = test_attachment("100", "");
+        uploaded["meta"] = Value::Null;
+        Mock::given(method("POST"))
+            .and(path("/api/v2/media"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(uploaded))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let statuses = super::super::Client::fetch_statuses(&mut client, None)
+            .await
+            .unwrap();
+        let [source::LiveStatus::Post(post)] = statuses.as_slice() else {
+            panic!("unexpected statuses");
+        };
+        // NOTE: operation として store に保存されてから送られる
+        let medium: store::operations::Medium =
+            serde_json::from_value(serde_json::to_value(&post.media[0]).unwrap()).unwrap();
+        let centered = store::operations::Medium {
+            focus: None,
+            ..medium.clone()
+        };
+        upload_media_list(
+            &reqwest::Client::new(),
+            &DownloadOptions::default(),
+            &server.uri(),
+            "token",
+            &[medium, centered],
+        )
+        .await
+        .unwrap();
+
+        let uploads: Vec<_> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|req| req.url.path() == "/api/v2/media")
+            .map(|req| String::from_utf8(req.body).unwrap())
+            .collect();
+        let focuses = uploads
+            .iter()
+            .filter(|body| body.contains("name=\"focus\"\r\n\r\n0.5,-0.5\r\n"))
+            .count();
+        assert_eq!(focuses, 1);
+        // NOTE: 無い場合は送らず、サーバーの既定の中央に任せる
+        assert!(uploads.iter().any(|body| !body.contains("name=\"focus\"")));
+    }
+
+    #[test]
+    fn spoiler_text_is_set_only_when_provided() {
+        let fixture = NewPostFixture::default();
+        let mut post = fixture.post("hello");
+        let options = to_megalodon_post_status_input_options(&post, Vec::new(), None);
+        assert_eq!(options.spoiler_text, None);
+        assert_eq!(options.sensitive, None);
+
+        post.content_warning = Some("spoiler");
+        post.images = vec![store::operations::Medium {
+            url: "https://example.com/image.png".into(),
+            alt: String::new(),
+            sensitive: true,
+            focus: None,
+            content_type: None,
+        }];
+        let options = to_megalodon_post_status_input_options(&post, vec!["1".into()], None);
+        assert_eq!(options.spoiler_text.as_deref(), Some("spoiler"));
+        assert_eq!(options.sensitive, Some(true));
+    }
+
+    #[test]
+    fn scheduled_at_is_set_for_delayed_post() {
+        let fixture = NewPostFixture::default();
+        let scheduled_at = DateTime::parse_from_rfc3339("2024-01-01T09:00:00+09:00").unwrap();
+        let mut post = fixture.post("hello");
+        let options = to_megalodon_post_status_input_options(&post, Vec::new(), None);
+        assert_eq!(options.scheduled_at, None);
+
+        post.scheduled_at = Some(&scheduled_at);
+        // NOTE: 予約中の投稿には返信できない
+        post.reply_identifier = Some("scheduled:1");
+        let options = to_megalodon_post_status_input_options(&post, Vec::new(), None);
+        assert_eq!(options.scheduled_at, Some(scheduled_at.with_timezone(&Utc)));
+        assert_eq!(options.in_reply_to_id, None);
+    }
+
+    #[test]
+    fn reblog_visibility_is_populated_from_config() {
+        assert_eq!(to_reblog_json(None), json!({}));
+        for (visibility, expected) in [
+            (MastodonVisibility::Public, "public"),
+            (MastodonVisibility::Unlisted, "unlisted"),
+            (MastodonVisibility::Private, "private"),
+            // NOTE: direct ではブーストできない
+            (MastodonVisibility::Direct, "private"),
+        ] {
+            assert_eq!(
+                to_reblog_json(Some(visibility)),
+                json!({ "visibility": expected })
+            );
+        }
+    }
+
+    fn identifiers(statuses: &[source::LiveStatus]) -> Vec<&str> {
+        statuses
+            .iter()
+            .map(|status| match status {
+                source::LiveStatus::Post(post) => post.identifier.as_str(),
+                source::LiveStatus::Repost(repost) => repost.src_identifier.as_str(),
+                source::LiveStatus::Like(like) => like.src_identifier.as_str(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn boosts_are_excluded_unless_included() {
+        let server = MockServer::start().await;
+        let mut boost = test_status("11");
+        boost["reblog"] = test_status("5");
+        // NOTE: exclude_reblogs を無視するサーバーでも除外する
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                test_status("12"),
+                boost,
+                test_status("10"),
+            ])))
+            .mount(&server)
+            .await;
+
+        let mut client = client_with_reposts(&server, false).await;
+        let statuses = super::super::Client::fetch_statuses(&mut client, None)
+            .await
+            .unwrap();
+        assert_eq!(identifiers(&statuses), ["12", "10"]);
+
+        let mut client = client_with_reposts(&server, true).await;
+        let statuses = super::super::Client::fetch_statuses(&mut client, None)
+            .await
+            .unwrap();
+        assert_eq!(identifiers(&statuses), ["12", "11", "10"]);
+        assert!(matches!(statuses[1], source::LiveStatus::Repost(_)));
+    }
+
+    #[tokio::test]
+    async fn excluded_boost_as_cursor_stops_catching_up() {
+        let server = MockServer::start().await;
+        let mut client = client_with_reposts(&server, false).await;
+        let statuses: Vec<_> = (41..=80)
+            .rev()
+            .map(|id| {
+                let mut status = test_status(&id.to_string());
+                if id == 60 {
+                    status["reblog"] = test_status("5");
+                }
+                status
+            })
+            .collect();
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .and(query_param_is_missing("max_id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(statuses))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .and(query_param("max_id", "41"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        // NOTE: 前回の最新がブーストで、後からブーストを含めない設定にした場合
+        let cursor = Cursor {
+            identifier: "60",
+            created_at: &created_at,
+        };
+
+        let statuses = super::super::Client::fetch_statuses(&mut client, Some(&cursor))
+            .await
+            .unwrap();
+
+        assert_eq!(statuses.len(), 39);
+    }
+
+    #[test]
+    fn scheduled_identifier_keeps_scheduled_at() {
+        let scheduled_at = Utc.timestamp_opt(1704067200, 0).unwrap();
+        let identifier = to_scheduled_identifier("1", &scheduled_at);
+        assert_eq!(
+            parse_scheduled_identifier(&identifier),
+            Some(("1", Some(scheduled_at)))
+        );
+        // NOTE: 予約日時を付けていなかった以前の identifier
+        assert_eq!(parse_scheduled_identifier("scheduled:1"), Some(("1", None)));
+        assert_eq!(parse_scheduled_identifier("1"), None);
+    }
+
+    fn scheduled_status(id: &str, scheduled_at: &DateTime<Utc>) -> Value {
+        json!({
+            "id": id,
+            "scheduled_at": scheduled_at.to_rfc3339(),
+            "params": {
+                "text": "hello",
+                "media_ids": ["5"],
+                "visibility": "public",
+                "sensitive": null,
+                "spoiler_text": null,
+                "poll": null,
+                "in_reply_to_id": null,
+                "language": null,
+                "application_id": 1,
+                "idempotency": null,
+                "with_rate_limit": false,
+                "scheduled_at": null,
+            },
+            "media_attachments": [],
+        })
+    }
+
+    #[tokio::test]
+    async fn published_scheduled_status_is_deleted() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/scheduled_statuses/1"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        let statuses: Vec<_> = [
+            ("12", "2024-01-01T00:10:00.000Z"),
+            ("11", "2024-01-01T00:00:03.000Z"),
+            ("10", "2023-12-31T23:59:00.000Z"),
+        ]
+        .into_iter()
+        .map(|(id, created_at)| {
+            let mut status = test_status(id);
+            status["created_at"] = created_at.into();
+            status
+        })
+        .collect();
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(statuses))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/statuses/11"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_status("11")))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/scheduled_statuses/1"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let identifier = to_scheduled_identifier("1", &Utc.timestamp_opt(1704067200, 0).unwrap());
+
+        super::super::Client::delete_post(&mut client, &identifier)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unresolved_scheduled_status_is_not_reported_as_updated() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/scheduled_statuses/1"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let result =
+            super::super::Client::update_post(&mut client, "scheduled:1", "edited", &[]).await;
+
+        assert!(matches!(result, Err(ClientError::Permanent(_))));
+        let result = super::super::Client::delete_post(&mut client, "scheduled:1").await;
+        assert!(matches!(result, Err(ClientError::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn pending_scheduled_status_is_rescheduled_with_edits() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let scheduled_at = Utc
+            .timestamp_opt((Utc::now() + chrono::Duration::hours(1)).timestamp(), 0)
+            .unwrap();
+        Mock::given(method("GET"))
+            .and(path("/api/v1/scheduled_statuses/1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(scheduled_status("1", &scheduled_at)),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/api/v1/media/5"))
+            .and(body_partial_json(json!({ "description": "alt" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_attachment("5", "alt")))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(body_partial_json(json!({
+                "status": "edited",
+                "media_ids": ["5"],
+                "visibility": "public",
+                "scheduled_at": scheduled_at.to_rfc3339(),
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(scheduled_status("2", &scheduled_at)),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/scheduled_statuses/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let identifier = super::super::Client::update_post_with_media_alts(
+            &mut client,
+            &to_scheduled_identifier("1", &scheduled_at),
+            "edited",
+            &[],
+            &["alt".to_owned()],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(identifier, to_scheduled_identifier("2", &scheduled_at));
+    }
+}
@@ -2,13 +2,29 @@ use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use chrono::{DateTime, FixedOffset};
-use linkify::LinkFinder;
-use reqwest::multipart::{Form, Part};
+use chrono::{DateTime, FixedOffset, Utc};
+use mime::Mime;
+use reqwest::{
+    multipart::{Form, Part},
+    StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use tracing::trace;
 
-use crate::{sources::source, store};
+use crate::{config::MisskeyVisibility, metrics, sources::source, store};
+
+use super::{
+    error::{ClientError, ResponseExt},
+    media::{DownloadOptions, MediaCache},
+    redact::redact_json,
+    retry::{send_with_retry, RetryPolicy},
+    text::{create_link_facets, truncate, Counting},
+    AccountInfo, Cursor, NewPost,
+};
+
+const MAX_LENGTH: usize = 3000;
 
 fn get_value<'a>(value: &'a Value, key: &str) -> Result<&'a Value> {
     value.get(key).ok_or_else(|| {
@@ -34,14 +50,146 @@ fn get_as_array<'a>(value: &'a Value, key: &str) -> Result<&'a Vec<Value>> {
         .ok_or_else(|| anyhow!("{} is not array", key))
 }
 
-fn create_facets(content: &str) -> Vec<store::operations::Facet> {
-    LinkFinder::new()
-        .links(content)
-        .map(|link| store::operations::Facet::Link {
-            byte_slice: link.start() as u32..link.end() as u32,
-            uri: link.as_str().to_owned(),
+/** メールアドレスや mailto: などはリンクにしない */
+/** 添付ファイルが無く、末尾にリンクが 1 つだけある場合のみリンクカードの対象にする */
+fn to_live_external(
+    content: &str,
+    facets: &[store::operations::Facet],
+    has_media: bool,
+) -> source::LiveExternal {
+    if has_media {
+        return source::LiveExternal::None;
+    }
+    match facets {
+        [store::operations::Facet::Link { byte_slice, .. }]
+            if byte_slice.end as usize == content.trim_end().len() =>
+        {
+            source::LiveExternal::Unknown
+        }
+        _ => source::LiveExternal::None,
+    }
+}
+
+fn to_poll(poll: &Value) -> Result<store::operations::Poll> {
+    Ok(store::operations::Poll {
+        choices: get_as_array(poll, "choices")?
+            .iter()
+            .map(|choice| get_as_string(choice, "text"))
+            .collect::<Result<_>>()?,
+        multiple: get_value(poll, "multiple")?.as_bool().unwrap_or_default(),
+        expires_at: get_as_string_opt(poll, "expiresAt")?
+            .map(|expires_at| DateTime::parse_from_rfc3339(&expires_at))
+            .transpose()?,
+    })
+}
+
+fn to_poll_json(poll: &store::operations::Poll) -> Value {
+    let mut json = json!({
+        "choices": poll.choices,
+        "multiple": poll.multiple,
+    });
+    // NOTE: 過去の日時は受け付けられないので、締め切り済みなら無期限にする
+    if let Some(expires_at) = poll.expires_at.filter(|x| *x > Utc::now()) {
+        json["expiresAt"] = expires_at.timestamp_millis().into();
+    }
+    json
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    user_id: String,
+    /** user_id を確かめたアクセストークンのハッシュ。トークンを差し替えたら確かめ直す */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reactions_since_id: Option<String>,
+}
+
+fn to_extension(content_type: &str) -> &'static str {
+    match content_type.split(';').next().unwrap_or_default().trim() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "video/mp4" => "mp4",
+        "video/quicktime" => "mov",
+        "video/webm" => "webm",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        _ => "bin",
+    }
+}
+
+fn to_token_hash(access_token: &str) -> String {
+    format!("{:x}", Sha256::digest(access_token))
+}
+
+/** URL のファイル名を優先し、拡張子が無ければ content-type から補う */
+fn to_file_name(url: &str, content_type: Option<&str>) -> String {
+    let file_name = Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()?
+                .next_back()
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_owned)
         })
-        .collect()
+        .unwrap_or_else(|| "file".to_owned());
+    if file_name.contains('.') {
+        return file_name;
+    }
+    format!("{}.{}", file_name, content_type.map_or("jpg", to_extension))
+}
+
+/** content-type が不正な場合も投稿は諦めずに、種類の分からないファイルとして送る */
+fn to_part(url: &str, bytes: Vec<u8>, content_type: Option<&str>) -> Part {
+    let mime = content_type
+        .and_then(|content_type| content_type.parse::<Mime>().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    Part::bytes(bytes)
+        .file_name(to_file_name(url, content_type))
+        .mime_str(mime.as_ref())
+        .unwrap()
+}
+
+pub struct Options {
+    pub visibility: Option<MisskeyVisibility>,
+    pub visible_user_ids: Vec<String>,
+    pub local_only: bool,
+    pub link_preview: bool,
+    pub fetch_limit: usize,
+    pub mirror_reactions: bool,
+    pub media: DownloadOptions,
+}
+
+impl Options {
+    fn apply_to(&self, json: &mut Value) {
+        if let Some(visibility) = self.visibility {
+            json["visibility"] = json!(visibility);
+            if let MisskeyVisibility::Specified = visibility {
+                json["visibleUserIds"] = json!(self.visible_user_ids);
+            }
+        }
+        if self.local_only {
+            json["localOnly"] = true.into();
+        }
+    }
+}
+
+/** 既に無いノートは 404 ではなく、400 とエラーコードで返ってくる */
+async fn error_for_delete_status(resp: reqwest::Response) -> Result<(), ClientError> {
+    let Some(client_error) = ClientError::from_response(&resp) else {
+        return Ok(());
+    };
+    let json: Value = resp.json().await.unwrap_or_default();
+    if json.pointer("/error/code").and_then(Value::as_str) == Some("NO_SUCH_NOTE") {
+        return Err(ClientError::NotFound);
+    }
+    Err(client_error)
 }
 
 pub struct Client {
@@ -49,125 +197,388 @@ pub struct Client {
     origin: String,
     access_token: String,
     user_id: String,
+    token_hash: String,
+    since_id: Option<String>,
+    reactions_since_id: Option<String>,
+    options: Options,
+    retry_policy: RetryPolicy,
 }
 
 impl Client {
+    fn to_note_uri(&self, note: &Value) -> Result<String> {
+        // WTF: uri が出力されない
+        if let Some(uri) = note.get("uri").and_then(Value::as_str) {
+            return Ok(uri.to_owned());
+        }
+        Ok(format!(
+            "{}/notes/{}",
+            self.origin,
+            get_as_string(note, "id")?
+        ))
+    }
+
+    fn to_live_status(&self, item: &Value) -> Result<source::LiveStatus> {
+        let created_at = DateTime::parse_from_rfc3339(&get_as_string(item, "createdAt")?)?;
+        let renote = item.get("renote").filter(|renote| !renote.is_null());
+        let text = get_as_string_opt(item, "text")?.unwrap_or_default(); // renote のみの場合は null になる
+        if let Some(renote) = renote.filter(|_| text.is_empty()) {
+            Ok(source::LiveStatus::Repost(
+                store::operations::CreateRepostOperationStatus {
+                    src_identifier: get_as_string(item, "id")?,
+                    target_src_identifier: get_as_string(renote, "id")?,
+                    target_src_uri: self.to_note_uri(renote)?,
+                    target_src_at_uri: None,
+                    target_src_cid: None,
+                    created_at,
+                },
+            ))
+        } else {
+            let identifier = get_as_string(item, "id")?;
+            let uri = self.to_note_uri(item)?;
+            let content = text;
+            // NOTE: 本文付きの renote は引用。引用先へのリンクは送信先に合わせて後で付ける
+            let quote = renote
+                .map(|renote| {
+                    anyhow::Ok(store::operations::Quote {
+                        src_identifier: get_as_string(renote, "id")?,
+                        src_uri: self.to_note_uri(renote)?,
+                    })
+                })
+                .transpose()?;
+            let facets = create_link_facets(&content);
+            let content_warning = item.get("cw").and_then(Value::as_str).map(str::to_owned);
+            let media: Vec<_> = get_as_array(item, "files")?
+                .iter()
+                .map(|file| {
+                    Ok(store::operations::Medium {
+                        url: get_as_string(file, "url")?,
+                        alt: get_as_string_opt(file, "comment")?.unwrap_or_default(),
+                        // NOTE: CW の付いたノートは添付ファイルも隠れるので、閲覧注意として扱う
+                        sensitive: content_warning.is_some()
+                            || file
+                                .get("isSensitive")
+                                .and_then(Value::as_bool)
+                                .unwrap_or_default(),
+                        focus: None,
+                        content_type: get_as_string_opt(file, "type")?,
+                    })
+                })
+                .collect::<Result<_>>()?;
+            let external = if self.options.link_preview {
+                to_live_external(&content, &facets, !media.is_empty())
+            } else {
+                source::LiveExternal::None
+            };
+            Ok(source::LiveStatus::Post(source::LivePost {
+                identifier,
+                uri,
+                content,
+                facets,
+                reply_src_identifier: get_as_string_opt(item, "replyId")?,
+                media,
+                external,
+                content_warning,
+                poll: item
+                    .get("poll")
+                    .filter(|poll| !poll.is_null())
+                    .map(to_poll)
+                    .transpose()?,
+                quote,
+                // NOTE: リモートのノートのみ含まれる
+                custom_emojis: item
+                    .get("emojis")
+                    .and_then(Value::as_object)
+                    .map(|emojis| emojis.keys().cloned().collect())
+                    .unwrap_or_default(),
+                created_at,
+            }))
+        }
+    }
+
+    /** リアクションは対象のノートへのいいねとして扱う */
+    fn to_live_like(&self, item: &Value) -> Result<source::LiveStatus> {
+        let note = get_value(item, "note")?;
+        Ok(source::LiveStatus::Like(
+            store::operations::CreateLikeOperationStatus {
+                src_identifier: get_as_string(item, "id")?,
+                target_src_identifier: get_as_string(note, "id")?,
+                target_src_uri: self.to_note_uri(note)?,
+                created_at: DateTime::parse_from_rfc3339(&get_as_string(item, "createdAt")?)?,
+            },
+        ))
+    }
+
+    /** 前回以降に付けたリアクションを新しい順に取得する。初回は最新の 1 ページだけ */
+    async fn fetch_reactions(&self) -> Result<Vec<Value>> {
+        const MAX_LIMIT_PER_REQUEST: usize = 100;
+        let mut body = json!({
+            "userId": self.user_id,
+            "limit": self.options.fetch_limit.min(MAX_LIMIT_PER_REQUEST),
+        });
+        if let Some(since_id) = &self.reactions_since_id {
+            body["sinceId"] = since_id.clone().into();
+        }
+        let resp = self
+            .http_client
+            .post(format!("{}/api/users/reactions", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(&body)
+            .send()
+            .await?
+            .error_for_client_status()?;
+        let json: Value = resp.json().await?;
+        let Value::Array(mut reactions) = json else {
+            return Err(anyhow!("root is not array"));
+        };
+        let id = |item: &Value| item.get("id").and_then(Value::as_str).map(str::to_owned);
+        reactions.sort_by_key(|item| std::cmp::Reverse(id(item)));
+        Ok(reactions)
+    }
+
+    async fn fetch_notes(&self, body: &Value) -> Result<Vec<Value>> {
+        let resp = self
+            .http_client
+            .post(format!("{}/api/users/notes", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(body)
+            .send()
+            .await?
+            .error_for_client_status()?;
+        let json: Value = resp.json().await?;
+        match json {
+            Value::Array(array) => Ok(array),
+            _ => Err(anyhow!("root is not array")),
+        }
+    }
+
+    /**
+     * sinceId がある場合はそれ以降を、無い場合は untilId で過去に遡って fetch_limit 件まで取得する
+     *
+     * 結果は新しい順に並べる
+     */
+    async fn fetch_all_notes(&self) -> Result<Vec<Value>> {
+        const MAX_LIMIT_PER_REQUEST: usize = 100;
+        let mut notes: Vec<Value> = Vec::new();
+        let mut since_id = self.since_id.clone();
+        let mut until_id: Option<String> = None;
+        while notes.len() < self.options.fetch_limit {
+            let limit = (self.options.fetch_limit - notes.len()).min(MAX_LIMIT_PER_REQUEST);
+            let mut body = json!({ "userId": self.user_id, "limit": limit });
+            if let Some(since_id) = &since_id {
+                body["sinceId"] = since_id.clone().into();
+            }
+            if let Some(until_id) = &until_id {
+                body["untilId"] = until_id.clone().into();
+            }
+            let page = self.fetch_notes(&body).await?;
+            let ids = page
+                .iter()
+                .filter_map(|item| item.get("id").and_then(Value::as_str));
+            // NOTE: id はソート可能な形式なので、文字列の最大値が最新になる
+            if since_id.is_some() {
+                since_id = ids.max().map(str::to_owned);
+            } else {
+                until_id = ids.min().map(str::to_owned);
+            }
+            let len = page.len();
+            notes.extend(page);
+            if len < limit {
+                break;
+            }
+        }
+        let id = |item: &Value| item.get("id").and_then(Value::as_str).map(str::to_owned);
+        notes.sort_by_key(|item| std::cmp::Reverse(id(item)));
+        Ok(notes)
+    }
+
     #[tracing::instrument(name = "misskey_client::Client::new", skip_all)]
     pub async fn new(
         http_client: Arc<reqwest::Client>,
         origin: String,
         access_token: String,
+        initial_session: Option<String>,
+        options: Options,
+        retry_policy: RetryPolicy,
     ) -> Result<Self> {
-        let resp = http_client
-            .post(format!("{}/api/i", origin))
-            .json(&json!({ "i": access_token }))
-            .send()
-            .await?;
-        let json: Value = resp.json().await?;
-        let user_id = get_as_string(&json, "id")?;
+        let session = initial_session
+            .as_deref()
+            .and_then(|session| serde_json::from_str::<Session>(session).ok());
+        let token_hash = to_token_hash(&access_token);
+        let session = match session {
+            Some(session) if session.token_hash.as_ref() == Some(&token_hash) => session,
+            session => {
+                let resp = http_client
+                    .post(format!("{}/api/i", origin))
+                    .json(&json!({ "i": access_token }))
+                    .send()
+                    .await?
+                    .error_for_client_status()?;
+                let json: Value = resp.json().await?;
+                let user_id = get_as_string(&json, "id")?;
+                // NOTE: トークンを作り直しただけで同じアカウントなら、続きから取得する
+                match session.filter(|session| session.user_id == user_id) {
+                    Some(session) => Session {
+                        token_hash: Some(token_hash),
+                        ..session
+                    },
+                    None => Session {
+                        user_id,
+                        token_hash: Some(token_hash),
+                        since_id: None,
+                        reactions_since_id: None,
+                    },
+                }
+            }
+        };
         Ok(Self {
             http_client,
             origin,
             access_token,
-            user_id,
+            user_id: session.user_id,
+            token_hash: session.token_hash.unwrap_or_default(),
+            since_id: session.since_id,
+            reactions_since_id: session.reactions_since_id,
+            options,
+            retry_policy,
         })
     }
+
+    /** mediaIds 以外の notes/create の body */
+    fn to_note_json(&self, post: &NewPost<'_>) -> Value {
+        let (content, _) = truncate(
+            post.content,
+            post.facets,
+            MAX_LENGTH,
+            Counting::Chars,
+            post.src_uri,
+        );
+        let mut json = json!({
+            "replyId": post.reply_identifier,
+            "text": content,
+        });
+        if let Some(content_warning) = post.content_warning {
+            json["cw"] = content_warning.into();
+        }
+        if let Some(poll) = post.poll {
+            json["poll"] = to_poll_json(poll);
+        }
+        if let Some(quote_identifier) = post.quote_identifier {
+            json["renoteId"] = quote_identifier.into();
+        }
+        self.options.apply_to(&mut json);
+        json
+    }
 }
 
 #[async_trait]
 impl super::Client for Client {
     fn to_session(&self) -> Option<String> {
-        None
+        serde_json::to_string(&Session {
+            user_id: self.user_id.clone(),
+            token_hash: Some(self.token_hash.clone()),
+            since_id: self.since_id.clone(),
+            reactions_since_id: self.reactions_since_id.clone(),
+        })
+        .ok()
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let json: Value = self
+            .http_client
+            .post(format!("{}/api/i", self.origin))
+            .bearer_auth(self.access_token.to_owned())
+            .json(&json!({}))
+            .send()
+            .await?
+            .error_for_client_status()?
+            .json()
+            .await?;
+        Ok(AccountInfo {
+            id: get_as_string(&json, "id")?,
+            handle: get_as_string(&json, "username")?,
+        })
     }
 
     #[tracing::instrument(name = "misskey_client::Client::fetch_statuses", skip_all)]
-    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
+    async fn fetch_statuses(
+        &mut self,
+        // NOTE: sinceId で前回以降を全て取得しているので使わない
+        _cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        let root = self.fetch_all_notes().await?;
+        if let Some(last_id) = root
+            .first()
+            .and_then(|item| item.get("id"))
+            .and_then(Value::as_str)
+        {
+            self.since_id = Some(last_id.to_owned());
+        }
+        let mut statuses = root
+            .iter()
+            .map(|item| self.to_live_status(item))
+            .collect::<Result<Vec<_>>>()?;
+        if self.options.mirror_reactions {
+            let reactions = self.fetch_reactions().await?;
+            if let Some(last_id) = reactions
+                .first()
+                .and_then(|item| item.get("id"))
+                .and_then(Value::as_str)
+            {
+                self.reactions_since_id = Some(last_id.to_owned());
+            }
+            statuses.extend(
+                reactions
+                    .iter()
+                    .map(|item| self.to_live_like(item))
+                    .collect::<Result<Vec<_>>>()?,
+            );
+            statuses.sort_by_key(|status| std::cmp::Reverse(*status.created_at()));
+        }
+        Ok(statuses)
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::get_status", skip_all)]
+    async fn get_status(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
         let resp = self
             .http_client
-            .post(format!("{}/api/users/notes", self.origin))
+            .post(format!("{}/api/notes/show", self.origin))
             .bearer_auth(self.access_token.to_owned())
-            .json(&json!({ "userId": self.user_id, "limit": 100 }))
+            .json(&json!({ "noteId": identifier }))
             .send()
             .await?;
-        let json: Value = resp.json().await?;
-        let root = json
-            .as_array()
-            .ok_or_else(|| anyhow!("root is not array"))?;
-        Ok(root
-            .iter()
-            .map(|item| {
-                let created_at = DateTime::parse_from_rfc3339(&get_as_string(item, "createdAt")?)?;
-                if let Some(renote) = item.get("renote") {
-                    let target_src_identifier = get_as_string(renote, "id")?;
-                    let target_src_uri = renote
-                        .get("uri") // WTF: uri が出力されない
-                        .and_then(Value::as_str)
-                        .map_or_else(
-                            || format!("{}/notes/{}", self.origin, target_src_identifier),
-                            str::to_owned,
-                        );
-                    Ok(source::LiveStatus::Repost(
-                        store::operations::CreateRepostOperationStatus {
-                            src_identifier: get_as_string(item, "id")?,
-                            target_src_identifier,
-                            target_src_uri,
-                            created_at,
-                        },
-                    ))
-                } else {
-                    let identifier = get_as_string(item, "id")?;
-                    let uri = item
-                        .get("uri") // WTF: uri が出力されない
-                        .and_then(Value::as_str)
-                        .map_or_else(
-                            || format!("{}/notes/{}", self.origin, identifier),
-                            str::to_owned,
-                        );
-                    let content = get_as_string_opt(item, "text")?.unwrap_or_default(); // renote のみの場合は null になる
-                    let facets = create_facets(&content);
-                    Ok(source::LiveStatus::Post(source::LivePost {
-                        identifier,
-                        uri,
-                        content,
-                        facets,
-                        reply_src_identifier: get_as_string_opt(item, "replyId")?,
-                        media: get_as_array(item, "files")?
-                            .iter()
-                            .map(|file| {
-                                Ok(store::operations::Medium {
-                                    url: get_as_string(file, "url")?,
-                                    alt: get_as_string_opt(file, "comment")?.unwrap_or_default(),
-                                })
-                            })
-                            .collect::<Result<_>>()?,
-                        external: source::LiveExternal::Unknown,
-                        created_at,
-                    }))
-                }
-            })
-            .collect::<Result<Vec<_>>>()?)
+        // NOTE: 存在しない場合は 400 (NO_SUCH_NOTE) が返る
+        if [StatusCode::BAD_REQUEST, StatusCode::NOT_FOUND].contains(&resp.status()) {
+            return Ok(None);
+        }
+        let json: Value = resp.error_for_client_status()?.json().await?;
+        if get_as_string(&json, "userId")? != self.user_id {
+            return Ok(None);
+        }
+        Ok(Some(self.to_live_status(&json)?))
     }
 
     #[tracing::instrument(name = "misskey_client::Client::post", skip_all)]
-    async fn post(
-        &mut self,
-        content: &str,
-        _facets: &[store::operations::Facet],
-        reply_identifier: Option<&str>,
-        images: Vec<store::operations::Medium>,
-        _external: Option<store::operations::External>,
-        _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
-        let mut json = json!({
-            "replyId": reply_identifier,
-            "text": content,
-        });
-        if !images.is_empty() {
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let mut json = self.to_note_json(&post);
+        if !post.images.is_empty() {
             let mut media_ids = Vec::new();
-            for image in images {
-                let resp = self.http_client.get(image.url).send().await?;
-                trace!("{:?}", resp);
-                let multipart = Form::new().part("file", Part::stream(resp).file_name("file.jpg"));
+            let mut media_cache = MediaCache::new(self.options.media);
+            for image in post.images {
+                let downloaded = media_cache.fetch(&self.http_client, &image.url).await?;
+                let part = to_part(
+                    &image.url,
+                    downloaded.bytes.clone(),
+                    downloaded.content_type.as_deref(),
+                );
+                let mut multipart = Form::new().part("file", part);
+                // NOTE: CW で隠れる他のプラットフォームに合わせて、CW 付きの投稿の添付ファイルは閲覧注意にする
+                if image.sensitive || post.content_warning.is_some() {
+                    multipart = multipart.text("isSensitive", "true");
+                }
                 let url = format!("{}/api/drive/files/create", self.origin);
                 let resp = self
                     .http_client
@@ -175,8 +586,10 @@ impl super::Client for Client {
                     .bearer_auth(self.access_token.to_owned())
                     .multipart(multipart)
                     .send()
-                    .await?;
+                    .await?
+                    .error_for_client_status()?;
                 let json: Value = resp.json().await?;
+                metrics::uploaded_bytes(&self.origin, downloaded.bytes.len());
                 let media_id = json
                     .get("id")
                     .ok_or_else(|| anyhow!("id is not found"))?
@@ -186,16 +599,21 @@ impl super::Client for Client {
             }
             json["mediaIds"] = media_ids.into();
         }
-        let resp = self
-            .http_client
-            .post(format!("{}/api/notes/create", self.origin))
-            .bearer_auth(self.access_token.to_owned())
-            .json(&json)
-            .send()
-            .await?;
+        let resp = send_with_retry(&self.retry_policy, || {
+            self.http_client
+                .post(format!("{}/api/notes/create", self.origin))
+                .bearer_auth(self.access_token.to_owned())
+                .json(&json)
+        })
+        .await?
+        .error_for_client_status()?;
         let json: Value = resp.json().await?;
-        trace!("resp: {}", serde_json::to_string_pretty(&json)?);
-        json.as_object()
+        trace!(
+            "resp: {}",
+            serde_json::to_string_pretty(&redact_json(&json))?
+        );
+        Ok(json
+            .as_object()
             .ok_or_else(|| anyhow!("root is not object"))?
             .get("createdNote")
             .ok_or_else(|| anyhow!("createdNote is not found"))?
@@ -204,26 +622,67 @@ impl super::Client for Client {
             .get("id")
             .ok_or_else(|| anyhow!("id is not found"))?
             .as_str()
-            .ok_or_else(|| anyhow!("id is not str"))
-            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("id is not str"))?
+            .to_owned())
     }
 
-    #[tracing::instrument(name = "misskey_client::Client::repost", skip_all)]
-    async fn repost(
+    #[tracing::instrument(name = "misskey_client::Client::preview", skip_all)]
+    async fn preview(&mut self, post: NewPost<'_>) -> Result<Value, ClientError> {
+        let mut json = self.to_note_json(&post);
+        if !post.images.is_empty() {
+            // NOTE: アップロードしないと id が無いので、元の URL を入れる
+            json["mediaIds"] = post
+                .images
+                .iter()
+                .map(|image| image.url.clone())
+                .collect::<Vec<_>>()
+                .into();
+        }
+        Ok(json)
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::update_post", skip_all)]
+    async fn update_post(
         &mut self,
-        target_identifier: &str,
-        _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
+        identifier: &str,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        let (content, _) = truncate(content, facets, MAX_LENGTH, Counting::Chars, None);
         let resp = self
             .http_client
-            .post(format!("{}/api/notes/create", self.origin))
+            .post(format!("{}/api/notes/update", self.origin))
             .bearer_auth(self.access_token.to_owned())
-            .json(&json!({ "renoteId": target_identifier }))
+            .json(&json!({ "noteId": identifier, "text": content }))
             .send()
             .await?;
+        resp.error_for_client_status()?;
+        Ok(identifier.to_owned())
+    }
+
+    #[tracing::instrument(name = "misskey_client::Client::repost", skip_all)]
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        let mut json = json!({ "renoteId": target_identifier });
+        self.options.apply_to(&mut json);
+        let resp = send_with_retry(&self.retry_policy, || {
+            self.http_client
+                .post(format!("{}/api/notes/create", self.origin))
+                .bearer_auth(self.access_token.to_owned())
+                .json(&json)
+        })
+        .await?
+        .error_for_client_status()?;
         let json: Value = resp.json().await?;
-        trace!("resp: {}", serde_json::to_string_pretty(&json)?);
-        json.as_object()
+        trace!(
+            "resp: {}",
+            serde_json::to_string_pretty(&redact_json(&json))?
+        );
+        Ok(json
+            .as_object()
             .ok_or_else(|| anyhow!("root is not object"))?
             .get("createdNote")
             .ok_or_else(|| anyhow!("createdNote is not found"))?
@@ -232,12 +691,12 @@ impl super::Client for Client {
             .get("renoteId")
             .ok_or_else(|| anyhow!("renoteId is not found"))?
             .as_str()
-            .ok_or_else(|| anyhow!("renoteId is not str"))
-            .map(str::to_owned)
+            .ok_or_else(|| anyhow!("renoteId is not str"))?
+            .to_owned())
     }
 
     #[tracing::instrument(name = "misskey_client::Client::delete_post", skip_all)]
-    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
         let resp = self
             .http_client
             .post(format!("{}/api/notes/delete", self.origin))
@@ -245,11 +704,11 @@ impl super::Client for Client {
             .json(&json!({ "noteId": identifier }))
             .send()
             .await?;
-        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+        error_for_delete_status(resp).await
     }
 
     #[tracing::instrument(name = "misskey_client::Client::delete_repost", skip_all)]
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
         let resp = self
             .http_client
             .post(format!("{}/api/notes/unrenote", self.origin))
@@ -257,6 +716,582 @@ impl super::Client for Client {
             .json(&json!({ "noteId": identifier }))
             .send()
             .await?;
-        resp.error_for_status().map(|_| ()).map_err(|e| e.into())
+        error_for_delete_status(resp).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{body_json, body_partial_json, method, path},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    use crate::protocols::{Client as _, NewPostFixture};
+
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            visibility: None,
+            visible_user_ids: Vec::new(),
+            local_only: false,
+            link_preview: false,
+            fetch_limit: 100,
+            mirror_reactions: false,
+            media: DownloadOptions::default(),
+        }
+    }
+
+    async fn client(origin: &str, access_token: &str, session: Option<Value>) -> Client {
+        Client::new(
+            Arc::new(reqwest::Client::new()),
+            origin.into(),
+            access_token.into(),
+            session.map(|session| session.to_string()),
+            options(),
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /** 保存済みのセッションから作るので /api/i は呼ばない */
+    async fn client_with_session(origin: &str, options: Options) -> Client {
+        let session = json!({ "userId": "user", "tokenHash": to_token_hash("token") });
+        Client::new(
+            Arc::new(reqwest::Client::new()),
+            origin.into(),
+            "token".into(),
+            Some(session.to_string()),
+            options,
+            RetryPolicy::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    /** API が返すノートの形に、fields で上書きする */
+    fn note(id: &str, fields: Value) -> Value {
+        let mut note = json!({
+            "id": id,
+            "createdAt": "2024-01-01T00:00:00.000Z",
+            "userId": "user",
+            "text": null,
+            "cw": null,
+            "replyId": null,
+            "renote": null,
+            "files": [],
+            "poll": null,
+        });
+        for (key, value) in fields.as_object().unwrap() {
+            note[key] = value.clone();
+        }
+        note
+    }
+
+    async fn mock_i(server: &MockServer, access_token: &str, user_id: &str) {
+        Mock::given(method("POST"))
+            .and(path("/api/i"))
+            .and(body_json(json!({ "i": access_token })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": user_id })))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn session_round_trips() {
+        let session = json!({
+            "userId": "user",
+            "tokenHash": to_token_hash("token"),
+            "sinceId": "9xyz",
+            "reactionsSinceId": "9abc",
+        });
+        // NOTE: 同じトークンなら /api/i は呼ばない
+        let client = client("http://127.0.0.1:1", "token", Some(session.clone())).await;
+
+        let restored: Value = serde_json::from_str(&client.to_session().unwrap()).unwrap();
+        assert_eq!(restored, session);
+    }
+
+    #[tokio::test]
+    async fn session_is_revalidated_when_token_changes() {
+        let server = MockServer::start().await;
+        mock_i(&server, "new", "user").await;
+        let session = json!({
+            "userId": "user",
+            "tokenHash": to_token_hash("old"),
+            "sinceId": "9xyz",
+        });
+
+        let client = client(&server.uri(), "new", Some(session)).await;
+
+        assert_eq!(client.user_id, "user");
+        assert_eq!(client.since_id.as_deref(), Some("9xyz"));
+        assert_eq!(client.token_hash, to_token_hash("new"));
+    }
+
+    #[tokio::test]
+    async fn cursor_is_dropped_when_account_changes() {
+        let server = MockServer::start().await;
+        mock_i(&server, "token", "other").await;
+        // NOTE: ハッシュの無い以前のセッションも確かめ直す
+        let session = json!({ "userId": "user", "sinceId": "9xyz" });
+
+        let client = client(&server.uri(), "token", Some(session)).await;
+
+        assert_eq!(client.user_id, "other");
+        assert_eq!(client.since_id, None);
+    }
+
+    #[tokio::test]
+    async fn cw_is_sent_only_when_present() {
+        let fixture = NewPostFixture::default();
+        let client = client_with_session("https://misskey.example", options()).await;
+
+        let json = client.to_note_json(&NewPost {
+            content_warning: Some("spoiler"),
+            ..fixture.post("text")
+        });
+        assert_eq!(json["cw"], "spoiler");
+
+        let json = client.to_note_json(&fixture.post("text"));
+        assert!(json.get("cw").is_none());
+    }
+
+    #[tokio::test]
+    async fn cw_marks_media_sensitive() {
+        let client = client_with_session("https://misskey.example", options()).await;
+        let note = note(
+            "9xyz",
+            json!({
+                "text": "text",
+                "cw": "spoiler",
+                "files": [{
+                    "url": "https://misskey.example/files/a.png",
+                    "comment": null,
+                    "isSensitive": false,
+                }],
+            }),
+        );
+
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        assert_eq!(post.content_warning.as_deref(), Some("spoiler"));
+        assert!(post.media[0].sensitive);
+    }
+
+    #[tokio::test]
+    async fn link_preview_is_disabled_by_default() {
+        let content = "see https://example.com/";
+        let note = note("9xyz", json!({ "text": content }));
+
+        let client = client_with_session("https://misskey.example", options()).await;
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        assert!(matches!(post.external, source::LiveExternal::None));
+
+        let options = Options {
+            link_preview: true,
+            ..options()
+        };
+        let client = client_with_session("https://misskey.example", options).await;
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        assert!(matches!(post.external, source::LiveExternal::Unknown));
+    }
+
+    #[test]
+    fn only_trailing_link_without_media_is_previewed() {
+        let content = "https://example.com/ is good";
+        let facets = create_link_facets(content);
+        assert!(matches!(
+            to_live_external(content, &facets, false),
+            source::LiveExternal::None
+        ));
+
+        let content = "see https://example.com/";
+        let facets = create_link_facets(content);
+        assert!(matches!(
+            to_live_external(content, &facets, true),
+            source::LiveExternal::None
+        ));
+    }
+
+    fn notes(ids: impl Iterator<Item = usize>) -> Value {
+        ids.map(|id| note(&format!("{:04}", id), json!({ "text": "text" })))
+            .collect()
+    }
+
+    async fn mock_notes(server: &MockServer, body: Value, notes: Value) {
+        Mock::given(method("POST"))
+            .and(path("/api/users/notes"))
+            .and(body_partial_json(body))
+            .respond_with(ResponseTemplate::new(200).set_body_json(notes))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    fn ids(notes: &[Value]) -> Vec<usize> {
+        notes
+            .iter()
+            .map(|note| note["id"].as_str().unwrap().parse().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pages_are_concatenated_backwards() {
+        let server = MockServer::start().await;
+        mock_notes(&server, json!({ "limit": 100 }), notes((51..=150).rev())).await;
+        mock_notes(
+            &server,
+            json!({ "limit": 50, "untilId": "0051" }),
+            notes((1..=50).rev()),
+        )
+        .await;
+        let options = Options {
+            fetch_limit: 150,
+            ..options()
+        };
+        let client = client_with_session(&server.uri(), options).await;
+
+        let notes = client.fetch_all_notes().await.unwrap();
+
+        assert_eq!(ids(&notes), (1..=150).rev().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn pages_are_concatenated_forwards() {
+        let server = MockServer::start().await;
+        mock_notes(
+            &server,
+            json!({ "limit": 100, "sinceId": "0000" }),
+            notes(1..=100),
+        )
+        .await;
+        mock_notes(
+            &server,
+            json!({ "limit": 100, "sinceId": "0100" }),
+            notes(101..=120),
+        )
+        .await;
+        let mut client = client_with_session(&server.uri(), options()).await;
+        client.since_id = Some("0000".into());
+        client.options.fetch_limit = 200;
+
+        let notes = client.fetch_all_notes().await.unwrap();
+
+        assert_eq!(ids(&notes), (1..=120).rev().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn pure_renote_is_repost() {
+        let client = client_with_session("https://misskey.example", options()).await;
+        let note = note(
+            "9xyz",
+            json!({ "renote": note("9abc", json!({ "text": "src" })) }),
+        );
+
+        let source::LiveStatus::Repost(repost) = client.to_live_status(&note).unwrap() else {
+            panic!("not a repost");
+        };
+        assert_eq!(repost.src_identifier, "9xyz");
+        assert_eq!(repost.target_src_identifier, "9abc");
+        assert_eq!(repost.target_src_uri, "https://misskey.example/notes/9abc");
+    }
+
+    async fn mock_reactions(server: &MockServer, reactions: Value, times: u64) {
+        Mock::given(method("POST"))
+            .and(path("/api/users/reactions"))
+            .and(body_partial_json(json!({ "userId": "user" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(reactions))
+            .expect(times)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reaction_is_fetched_as_like() {
+        let server = MockServer::start().await;
+        mock_notes(
+            &server,
+            json!({}),
+            json!([note("9xyz", json!({ "text": "text" }))]),
+        )
+        .await;
+        let reactions = json!([{
+            "id": "9r01",
+            "createdAt": "2024-01-02T00:00:00.000Z",
+            "type": "👍",
+            "note": note("9abc", json!({ "userId": "other", "text": "src" })),
+        }]);
+        mock_reactions(&server, reactions, 1).await;
+        let options = Options {
+            mirror_reactions: true,
+            ..options()
+        };
+        let mut client = client_with_session(&server.uri(), options).await;
+
+        let statuses = client.fetch_statuses(None).await.unwrap();
+
+        // NOTE: ノートと混ぜて新しい順に並ぶ
+        assert_eq!(statuses.len(), 2);
+        let source::LiveStatus::Like(like) = &statuses[0] else {
+            panic!("not a like");
+        };
+        assert_eq!(like.src_identifier, "9r01");
+        assert_eq!(like.target_src_identifier, "9abc");
+        assert_eq!(like.target_src_uri, format!("{}/notes/9abc", server.uri()));
+        assert!(matches!(statuses[1], source::LiveStatus::Post(_)));
+        assert_eq!(client.reactions_since_id.as_deref(), Some("9r01"));
+    }
+
+    #[tokio::test]
+    async fn reactions_are_not_fetched_by_default() {
+        let server = MockServer::start().await;
+        mock_notes(&server, json!({}), json!([])).await;
+        mock_reactions(&server, json!([]), 0).await;
+        let mut client = client_with_session(&server.uri(), options()).await;
+
+        let statuses = client.fetch_statuses(None).await.unwrap();
+
+        assert!(statuses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn renote_with_text_is_quote() {
+        let client = client_with_session("https://misskey.example", options()).await;
+        let note = note(
+            "9xyz",
+            json!({ "text": "comment", "renote": note("9abc", json!({ "text": "src" })) }),
+        );
+
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        assert_eq!(post.content, "comment");
+        assert!(post.facets.is_empty());
+        let quote = post.quote.unwrap();
+        assert_eq!(quote.src_identifier, "9abc");
+        assert_eq!(quote.src_uri, "https://misskey.example/notes/9abc");
+    }
+
+    #[tokio::test]
+    async fn quote_is_sent_as_renote_id() {
+        let fixture = NewPostFixture::default();
+        let client = client_with_session("https://misskey.example", options()).await;
+
+        let json = client.to_note_json(&NewPost {
+            quote_identifier: Some("9abc"),
+            ..fixture.post("comment")
+        });
+
+        assert_eq!(json["renoteId"], "9abc");
+        assert_eq!(json["text"], "comment");
+    }
+
+    #[tokio::test]
+    async fn poll_is_decoded() {
+        let client = client_with_session("https://misskey.example", options()).await;
+        let note = note(
+            "9xyz",
+            json!({
+                "text": "which?",
+                "poll": {
+                    "choices": [
+                        { "text": "a", "votes": 1, "isVoted": false },
+                        { "text": "b", "votes": 0, "isVoted": false },
+                    ],
+                    "multiple": true,
+                    "expiresAt": "2024-01-02T00:00:00.000Z",
+                },
+            }),
+        );
+
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        let poll = post.poll.unwrap();
+        assert_eq!(poll.choices, vec!["a", "b"]);
+        assert!(poll.multiple);
+        assert_eq!(
+            poll.expires_at,
+            Some(DateTime::parse_from_rfc3339("2024-01-02T00:00:00Z").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_without_deadline_is_decoded() {
+        let client = client_with_session("https://misskey.example", options()).await;
+        let note = note(
+            "9xyz",
+            json!({
+                "text": "which?",
+                "poll": {
+                    "choices": [{ "text": "a", "votes": 0, "isVoted": false }],
+                    "multiple": false,
+                    "expiresAt": null,
+                },
+            }),
+        );
+
+        let source::LiveStatus::Post(post) = client.to_live_status(&note).unwrap() else {
+            panic!("not a post");
+        };
+        let poll = post.poll.unwrap();
+        assert!(!poll.multiple);
+        assert_eq!(poll.expires_at, None);
+    }
+
+    #[test]
+    fn visibility_is_serialized() {
+        for (visibility, expected) in [
+            (MisskeyVisibility::Public, "public"),
+            (MisskeyVisibility::Home, "home"),
+            (MisskeyVisibility::Followers, "followers"),
+            (MisskeyVisibility::Specified, "specified"),
+        ] {
+            let options = Options {
+                visibility: Some(visibility),
+                visible_user_ids: vec!["9abc".into()],
+                ..options()
+            };
+            let mut json = json!({});
+            options.apply_to(&mut json);
+
+            assert_eq!(json["visibility"], expected);
+            assert_eq!(
+                json.get("visibleUserIds").is_some(),
+                expected == "specified"
+            );
+        }
+        let mut json = json!({});
+        options().apply_to(&mut json);
+        assert!(json.get("visibility").is_none());
+    }
+
+    #[test]
+    fn local_only_is_sent_only_when_enabled() {
+        let mut json = json!({});
+        Options {
+            local_only: true,
+            ..options()
+        }
+        .apply_to(&mut json);
+        assert_eq!(json["localOnly"], true);
+
+        let mut json = json!({});
+        options().apply_to(&mut json);
+        assert!(json.get("localOnly").is_none());
+    }
+
+    fn medium(url: String) -> store::operations::Medium {
+        store::operations::Medium {
+            url,
+            alt: String::new(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }
+    }
+
+    /** content_type で画像を返すサーバーに投稿し、drive/files/create に送られた multipart を返す */
+    async fn upload(file_name: &str, content_type: &str) -> String {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/files/{}", file_name)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-type", content_type)
+                    .set_body_bytes(b"\x89PNG".to_vec()),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/drive/files/create"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "file" })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/notes/create"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "createdNote": { "id": "note" } })),
+            )
+            .mount(&server)
+            .await;
+        let mut client = client_with_session(&server.uri(), options()).await;
+
+        let identifier = client
+            .post(NewPost {
+                images: vec![medium(format!("{}/files/{}", server.uri(), file_name))],
+                ..fixture.post("text")
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(identifier, "note");
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        let upload = requests
+            .iter()
+            .find(|request| request.url.path() == "/api/drive/files/create")
+            .unwrap();
+        String::from_utf8_lossy(&upload.body).into_owned()
+    }
+
+    #[tokio::test]
+    async fn png_is_uploaded_as_png() {
+        let body = upload("a.png", "image/png").await;
+
+        assert!(body.contains(r#"filename="a.png""#), "{}", body);
+        assert!(body.contains("Content-Type: image/png"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn invalid_content_type_falls_back_to_octet_stream() {
+        let body = upload("a", "not a mime type").await;
+
+        assert!(body.contains(r#"filename="a.bin""#), "{}", body);
+        assert!(
+            body.contains("Content-Type: application/octet-stream"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn preview_of_link_and_image() {
+        let fixture = NewPostFixture::default();
+        let mut client = client_with_session("https://misskey.example", options()).await;
+        let facets = [store::operations::Facet::Link {
+            byte_slice: 4..24,
+            uri: "https://example.com/".into(),
+        }];
+        let mut post = fixture.post("see https://example.com/");
+        post.facets = &facets;
+        post.images = vec![store::operations::Medium {
+            url: "https://src.example.com/1.png".into(),
+            alt: "alt".into(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }];
+
+        let preview = client.preview(post).await.unwrap();
+
+        assert_eq!(
+            preview,
+            json!({
+                "replyId": null,
+                "text": "see https://example.com/",
+                "mediaIds": ["https://src.example.com/1.png"],
+            })
+        );
     }
 }
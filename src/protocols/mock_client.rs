@@ -0,0 +1,196 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+
+use crate::{sources::source, store};
+
+use super::{error::ClientError, AccountInfo, Cursor, NewPost};
+
+/** MockClient に送られた投稿 */
+#[derive(Clone, Debug)]
+pub struct MockPost {
+    pub identifier: String,
+    pub content: String,
+    pub reply_identifier: Option<String>,
+    pub media_len: usize,
+    pub scheduled_at: Option<DateTime<FixedOffset>>,
+}
+
+type SplitContent =
+    fn(&str, &[store::operations::Facet]) -> Vec<(String, Vec<store::operations::Facet>)>;
+
+/** MockClient に送られた更新 */
+#[derive(Clone, Debug)]
+pub struct MockUpdate {
+    pub identifier: String,
+    pub content: String,
+    pub media_alts: Option<Vec<String>>,
+}
+
+#[derive(Default)]
+pub struct MockState {
+    /** fetch_statuses と get_status で返す status */
+    pub statuses: Vec<source::LiveStatus>,
+    pub posts: Vec<MockPost>,
+    pub updates: Vec<MockUpdate>,
+    pub deleted: Vec<String>,
+    /** 投稿がこの件数に達した後は Transient で失敗する */
+    pub fail_after: Option<usize>,
+    pub split_content: Option<SplitContent>,
+}
+
+/**
+ * テスト用の、送った内容を覚えておくだけの Client
+ *
+ * Box にして渡した後も state から中身を確かめられる
+ */
+#[derive(Clone, Default)]
+pub struct MockClient {
+    pub state: Arc<Mutex<MockState>>,
+}
+
+impl MockClient {
+    pub fn posts(&self) -> Vec<MockPost> {
+        self.state.lock().unwrap().posts.clone()
+    }
+
+    pub fn updates(&self) -> Vec<MockUpdate> {
+        self.state.lock().unwrap().updates.clone()
+    }
+}
+
+#[async_trait]
+impl super::Client for MockClient {
+    fn to_session(&self) -> Option<String> {
+        None
+    }
+
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        Ok(AccountInfo {
+            id: "mock".into(),
+            handle: "mock".into(),
+        })
+    }
+
+    async fn fetch_statuses(
+        &mut self,
+        _cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        Ok(self.state.lock().unwrap().statuses.clone())
+    }
+
+    async fn get_status(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .statuses
+            .iter()
+            .find(|status| status.identifier() == identifier)
+            .cloned())
+    }
+
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .fail_after
+            .is_some_and(|fail_after| state.posts.len() >= fail_after)
+        {
+            return Err(ClientError::Transient);
+        }
+        let identifier = format!("post-{}", state.posts.len() + 1);
+        state.posts.push(MockPost {
+            identifier: identifier.clone(),
+            content: post.content.to_owned(),
+            reply_identifier: post.reply_identifier.map(str::to_owned),
+            media_len: post.images.len(),
+            scheduled_at: post.scheduled_at.copied(),
+        });
+        Ok(identifier)
+    }
+
+    async fn preview(&mut self, post: NewPost<'_>) -> Result<serde_json::Value, ClientError> {
+        Ok(serde_json::json!({
+            "content": post.content,
+            "mediaLen": post.images.len(),
+        }))
+    }
+
+    fn split_content(
+        &self,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Vec<(String, Vec<store::operations::Facet>)> {
+        match self.state.lock().unwrap().split_content {
+            Some(split_content) => split_content(content, facets),
+            None => vec![(content.to_owned(), facets.to_vec())],
+        }
+    }
+
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        _facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        self.state.lock().unwrap().updates.push(MockUpdate {
+            identifier: identifier.to_owned(),
+            content: content.to_owned(),
+            media_alts: None,
+        });
+        Ok(identifier.to_owned())
+    }
+
+    async fn update_post_with_media_alts(
+        &mut self,
+        identifier: &str,
+        content: &str,
+        _facets: &[store::operations::Facet],
+        media_alts: &[String],
+    ) -> Result<String, ClientError> {
+        self.state.lock().unwrap().updates.push(MockUpdate {
+            identifier: identifier.to_owned(),
+            content: content.to_owned(),
+            media_alts: Some(media_alts.to_vec()),
+        });
+        Ok(identifier.to_owned())
+    }
+
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        Ok(format!("repost-{}", target_identifier))
+    }
+
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.state
+            .lock()
+            .unwrap()
+            .deleted
+            .push(identifier.to_owned());
+        Ok(())
+    }
+
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.state
+            .lock()
+            .unwrap()
+            .deleted
+            .push(identifier.to_owned());
+        Ok(())
+    }
+
+    async fn like(
+        &mut self,
+        target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        Ok(format!("like-{}", target_identifier))
+    }
+}
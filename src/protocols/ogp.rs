@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::store;
+
+async fn fetch_html(
+    http_client: &reqwest::Client,
+    uri: &str,
+    timeout: Option<Duration>,
+) -> Result<webpage::HTML> {
+    let mut req = http_client.get(uri);
+    if let Some(timeout) = timeout {
+        req = req.timeout(timeout);
+    }
+    let text = req.send().await?.error_for_status()?.text().await?;
+    Ok(webpage::HTML::from_string(text, Some(uri.to_owned()))?)
+}
+
+/** og: の値を優先し、無ければ HTML の値を使う */
+fn to_external(uri: &str, html: webpage::HTML) -> store::operations::External {
+    let og = &html.opengraph.properties;
+    store::operations::External {
+        uri: uri.to_owned(),
+        title: og.get("title").cloned().or(html.title).unwrap_or_default(),
+        description: og
+            .get("description")
+            .cloned()
+            .or(html.description)
+            .unwrap_or_default(),
+        thumb_url: html.opengraph.images.first().map(|g| g.url.clone()),
+    }
+}
+
+/** リンク先のページを取得してリンクカードの内容を作る */
+pub async fn fetch_external(
+    http_client: &reqwest::Client,
+    uri: &str,
+    timeout: Option<Duration>,
+) -> Result<store::operations::External> {
+    let html = fetch_html(http_client, uri, timeout).await?;
+    Ok(to_external(uri, html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "https://example.com/article";
+
+    fn html(head: &str) -> webpage::HTML {
+        let text = format!("<html><head>{}</head><body></body></html>", head);
+        webpage::HTML::from_string(text, Some(URI.to_owned())).unwrap()
+    }
+
+    #[test]
+    fn og_properties_are_preferred() {
+        let html = html(concat!(
+            r#"<title>HTML title</title>"#,
+            r#"<meta name="description" content="HTML description">"#,
+            r#"<meta property="og:title" content="OG title">"#,
+            r#"<meta property="og:description" content="OG description">"#,
+            r#"<meta property="og:image" content="https://example.com/thumb.png">"#,
+        ));
+
+        let external = to_external(URI, html);
+
+        assert_eq!(external.uri, URI);
+        assert_eq!(external.title, "OG title");
+        assert_eq!(external.description, "OG description");
+        assert_eq!(
+            external.thumb_url.as_deref(),
+            Some("https://example.com/thumb.png")
+        );
+    }
+
+    #[test]
+    fn html_values_are_used_without_og() {
+        let html = html(concat!(
+            r#"<title>HTML title</title>"#,
+            r#"<meta name="description" content="HTML description">"#,
+        ));
+
+        let external = to_external(URI, html);
+
+        assert_eq!(external.title, "HTML title");
+        assert_eq!(external.description, "HTML description");
+        assert_eq!(external.thumb_url, None);
+    }
+}
@@ -0,0 +1,91 @@
+use regex::Regex;
+use serde_json::Value;
+
+const MASK: &str = "[REDACTED]";
+
+/** 値を伏せるキー。Misskey はリクエストの i にトークンを入れる */
+const SECRET_KEYS: &[&str] = &[
+    "accessJwt",
+    "refreshJwt",
+    "access_jwt",
+    "refresh_jwt",
+    "accessToken",
+    "access_token",
+    "refreshToken",
+    "refresh_token",
+    "password",
+    "token",
+    "i",
+];
+
+/** ログに出す前に JSON のトークンやパスワードを伏せる */
+pub fn redact_json(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = if SECRET_KEYS.contains(&key.as_str()) {
+                        Value::String(MASK.to_owned())
+                    } else {
+                        redact_json(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect(),
+        ),
+        Value::Array(array) => Value::Array(array.iter().map(redact_json).collect()),
+        value => value.clone(),
+    }
+}
+
+/**
+ * ログに出す前に文字列のトークンを伏せる
+ *
+ * JSON として読めればキーで判断し、読めなければ Bearer トークンと JWT らしき文字列を伏せる
+ */
+pub fn redact_text(text: &str) -> String {
+    if let Ok(json) = serde_json::from_str::<Value>(text) {
+        return redact_json(&json).to_string();
+    }
+    let text = Regex::new(r"(?i)(bearer\s+)[^\s,;]+")
+        .unwrap()
+        .replace_all(text, format!("${{1}}{}", MASK));
+    Regex::new(r"eyJ[\w-]+\.[\w-]+\.[\w-]+")
+        .unwrap()
+        .replace_all(&text, MASK)
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn secrets_in_json_are_masked() {
+        assert_eq!(
+            redact_json(&json!({
+                "i": "misskey-token",
+                "session": { "accessJwt": "jwt", "did": "did:plc:test" },
+                "text": "hello",
+            })),
+            json!({
+                "i": MASK,
+                "session": { "accessJwt": MASK, "did": "did:plc:test" },
+                "text": "hello",
+            })
+        );
+    }
+
+    #[test]
+    fn bearer_tokens_and_jwts_in_text_are_masked() {
+        let text = "Authorization: Bearer abc123, jwt=eyJhbGciOi.eyJzdWIiOi.c2lnbmF0dXJl";
+        let redacted = redact_text(text);
+
+        assert_eq!(
+            redacted,
+            format!("Authorization: Bearer {}, jwt={}", MASK, MASK)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, Method, Request, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::time::sleep;
+use tracing::warn;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/** これより長く待つ必要がある場合は諦める */
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_base_delay_millis")]
+    pub base_delay_millis: u64,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_base_delay_millis() -> u64 {
+    500
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            base_delay_millis: default_base_delay_millis(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay_millis.saturating_mul(1 << attempt.min(16));
+        // NOTE: 乱数のためだけに依存を増やしたくないので、現在時刻のナノ秒をジッターに使う
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.subsec_nanos() as u64)
+            .unwrap_or_default();
+        let jitter = nanos % self.base_delay_millis.max(1);
+        Duration::from_millis(delay + jitter)
+    }
+}
+
+/**
+ * 同じリクエストを繰り返しても結果が変わらないか
+ *
+ * POST でも Idempotency-Key があればサーバー側で重複を防げる
+ */
+fn is_idempotent(req: &Request) -> bool {
+    matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    ) || req.headers().contains_key(IDEMPOTENCY_KEY)
+}
+
+/**
+ * 429 と接続の失敗はサーバーが処理していないので、冪等でなくても再送できる
+ *
+ * 5xx やタイムアウトは処理された後かもしれないので、冪等なリクエストだけ再送する
+ */
+fn is_retryable(result: &reqwest::Result<Response>, idempotent: bool) -> bool {
+    match result {
+        Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => true,
+        Ok(resp) => idempotent && resp.status().is_server_error(),
+        Err(err) if err.is_connect() => true,
+        Err(err) => idempotent && err.is_timeout(),
+    }
+}
+
+pub fn parse_retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date_time = DateTime::parse_from_rfc2822(value).ok()?;
+    (date_time.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/**
+ * 429 や 5xx、接続エラーの場合に指数バックオフで再送する
+ *
+ * Retry-After ヘッダーがあればそれに従う。投稿の作成などの冪等でないリクエストは、
+ * 重複しないように処理されていないことが確かな場合だけ再送する
+ */
+pub async fn send_with_retry(
+    policy: &RetryPolicy,
+    build: impl Fn() -> RequestBuilder,
+) -> reqwest::Result<Response> {
+    let idempotent = build().build().is_ok_and(|req| is_idempotent(&req));
+    let mut attempt = 0;
+    loop {
+        let result = build().send().await;
+        if !is_retryable(&result, idempotent) {
+            return result;
+        }
+        let retry_after = result.as_ref().ok().and_then(parse_retry_after);
+        if attempt >= policy.max_retries {
+            return result;
+        }
+        let delay = retry_after.unwrap_or_else(|| policy.backoff(attempt));
+        if delay > MAX_DELAY {
+            return result;
+        }
+        match &result {
+            Ok(resp) => warn!("retry after {:?} (status={})", delay, resp.status()),
+            Err(err) => warn!("retry after {:?} ({})", delay, err),
+        }
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        base_delay_millis: 1,
+    };
+
+    async fn flaky_server(method_name: &str, first_status: u16) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method(method_name))
+            .respond_with(ResponseTemplate::new(first_status))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method(method_name))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn rate_limited_post_is_retried() {
+        let server = flaky_server("POST", 429).await;
+        let client = reqwest::Client::new();
+
+        let resp = send_with_retry(&POLICY, || client.post(server.uri()))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn server_error_is_retried_only_when_idempotent() {
+        let client = reqwest::Client::new();
+
+        let server = flaky_server("GET", 503).await;
+        let resp = send_with_retry(&POLICY, || client.get(server.uri()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let server = flaky_server("POST", 503).await;
+        let resp = send_with_retry(&POLICY, || client.post(server.uri()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let server = flaky_server("POST", 503).await;
+        let resp = send_with_retry(&POLICY, || {
+            client.post(server.uri()).header(IDEMPOTENCY_KEY, "key")
+        })
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn retry_after_over_limit_is_not_waited() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "3600"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+
+        let resp = send_with_retry(&POLICY, || client.get(server.uri()))
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,611 @@
+use linkify::{LinkFinder, LinkKind};
+use regex::Regex;
+use reqwest::Url;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::store::{self, operations::Facet::Link};
+
+const ELLIPSIS: &str = "…";
+
+/** プラットフォームごとの文字数の数え方 */
+#[derive(Clone, Copy)]
+pub enum Counting {
+    /** Bluesky */
+    Graphemes,
+    /** Misskey、Discord */
+    Chars,
+    /** Mastodon。URL は長さに関わらず 23 文字として数える */
+    Mastodon,
+    /** Twitter。CJK や絵文字は 2 文字、URL は長さに関わらず t.co の長さとして数える */
+    TwitterWeighted,
+}
+
+/** t.co で短縮された URL の長さ。Mastodon も同じ長さで数える */
+const URL_LENGTH: usize = 23;
+
+fn twitter_weight(c: char) -> usize {
+    match c as u32 {
+        0..=0x10FF | 0x2000..=0x200D | 0x2010..=0x201F | 0x2032..=0x2037 => 1,
+        _ => 2,
+    }
+}
+
+fn grapheme_length(grapheme: &str, counting: Counting) -> usize {
+    match counting {
+        Counting::Graphemes => 1,
+        Counting::Chars | Counting::Mastodon => grapheme.chars().count(),
+        // NOTE: 絵文字の結合文字列は全体で 1 つの絵文字として数えられる
+        Counting::TwitterWeighted => grapheme.chars().next().map_or(0, twitter_weight),
+    }
+}
+
+/**
+ * 数える単位ごとの (開始位置のバイト数, 文字数)
+ *
+ * 基本は書記素ごとだが、Twitter と Mastodon では URL 全体を 1 つの単位として数える
+ */
+fn units(text: &str, counting: Counting) -> Vec<(usize, usize)> {
+    let links: Vec<_> = match counting {
+        Counting::TwitterWeighted | Counting::Mastodon => {
+            create_link_facets(text).iter().map(facet_range).collect()
+        }
+        Counting::Graphemes | Counting::Chars => Vec::new(),
+    };
+    let mut units = Vec::new();
+    let mut links = links.into_iter().peekable();
+    for (idx, grapheme) in text.grapheme_indices(true) {
+        while links.next_if(|&(_, end)| end <= idx).is_some() {}
+        match links.peek() {
+            Some(&(start, _)) if start == idx => units.push((idx, URL_LENGTH)),
+            Some(&(start, _)) if start < idx => {}
+            _ => units.push((idx, grapheme_length(grapheme, counting))),
+        }
+    }
+    units
+}
+
+pub fn measure(text: &str, counting: Counting) -> usize {
+    units(text, counting)
+        .into_iter()
+        .map(|(_, length)| length)
+        .sum()
+}
+
+/**
+ * 先頭から max_length に収まる範囲のバイト数を返す
+ *
+ * 書記素の途中では切らない。Twitter と Mastodon では URL の途中でも切らない
+ */
+pub fn fit(text: &str, max_length: usize, counting: Counting) -> usize {
+    let mut length = 0;
+    for (idx, unit_length) in units(text, counting) {
+        length += unit_length;
+        if length > max_length {
+            return idx;
+        }
+    }
+    text.len()
+}
+
+fn facet_range(facet: &store::operations::Facet) -> (usize, usize) {
+    match facet {
+        Link { byte_slice, .. } => (byte_slice.start as usize, byte_slice.end as usize),
+    }
+}
+
+/**
+ * counting で数えて max_length に収まるように本文を切り詰める
+ *
+ * 切り詰めた場合は末尾に省略記号と元の投稿へのリンクを付け、切断位置をまたぐ facet は取り除く
+ */
+pub fn truncate(
+    content: &str,
+    facets: &[store::operations::Facet],
+    max_length: usize,
+    counting: Counting,
+    src_uri: Option<&str>,
+) -> (String, Vec<store::operations::Facet>) {
+    if measure(content, counting) <= max_length {
+        return (content.to_owned(), facets.to_vec());
+    }
+    let suffix_length =
+        measure(ELLIPSIS, counting) + src_uri.map_or(0, |src_uri| 1 + measure(src_uri, counting));
+    let keep = max_length.saturating_sub(suffix_length);
+    let mut cut = fit(content, keep, counting);
+    // NOTE: リンクの途中で切れる場合はリンクごと落とす
+    if let Some((start, _)) = facets
+        .iter()
+        .map(facet_range)
+        .find(|&(start, end)| start < cut && cut < end)
+    {
+        cut = start;
+    }
+    let mut text = content[..cut].trim_end().to_owned();
+    let mut new_facets: Vec<_> = facets
+        .iter()
+        .filter(|facet| facet_range(facet).1 <= text.len())
+        .cloned()
+        .collect();
+    text.push_str(ELLIPSIS);
+    if let Some(src_uri) = src_uri {
+        text.push('\n');
+        let start = text.len();
+        text.push_str(src_uri);
+        new_facets.push(Link {
+            byte_slice: start as u32..text.len() as u32,
+            uri: src_uri.to_owned(),
+        });
+    }
+    (text, new_facets)
+}
+
+/** 並べ替えて、重なっている範囲をまとめる */
+fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort_unstable();
+    ranges
+        .into_iter()
+        .fold(Vec::<(usize, usize)>::new(), |mut acc, (start, end)| {
+            match acc.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => acc.push((start, end)),
+            }
+            acc
+        })
+}
+
+/** removed はまとめてあること */
+fn remove_text(content: &str, removed: &[(usize, usize)]) -> String {
+    let mut text = String::new();
+    let mut last = 0;
+    for &(start, end) in removed {
+        text.push_str(&content[last..start]);
+        last = end;
+    }
+    text.push_str(&content[last..]);
+    text
+}
+
+/**
+ * content から removed の範囲を取り除き、後ろの facet の位置を詰める
+ *
+ * 取り除く範囲にかかる facet は取り除く
+ */
+fn remove_ranges(
+    content: &str,
+    facets: &[store::operations::Facet],
+    removed: Vec<(usize, usize)>,
+) -> (String, Vec<store::operations::Facet>) {
+    if removed.is_empty() {
+        return (content.to_owned(), facets.to_vec());
+    }
+    // NOTE: :a:b: のように重なっている場合は範囲をまとめる
+    let removed = merge_ranges(removed);
+    let text = remove_text(content, &removed);
+    let removed_before = |idx: usize| -> usize {
+        removed
+            .iter()
+            .filter(|&&(_, end)| end <= idx)
+            .map(|&(start, end)| end - start)
+            .sum()
+    };
+    let new_facets = facets
+        .iter()
+        .filter(|facet| {
+            let (start, end) = facet_range(facet);
+            !removed
+                .iter()
+                .any(|&(removed_start, removed_end)| start < removed_end && removed_start < end)
+        })
+        .map(|facet| match facet {
+            Link { byte_slice, uri } => {
+                let shift = removed_before(byte_slice.start as usize) as u32;
+                Link {
+                    byte_slice: byte_slice.start - shift..byte_slice.end - shift,
+                    uri: uri.clone(),
+                }
+            }
+        })
+        .collect();
+    (text, new_facets)
+}
+
+/**
+ * カスタム絵文字の :shortcode: を取り除き、後ろの facet の位置を詰める
+ *
+ * 取り除く範囲にかかる facet は取り除く
+ */
+pub fn strip_custom_emojis(
+    content: &str,
+    facets: &[store::operations::Facet],
+    custom_emojis: &[String],
+) -> (String, Vec<store::operations::Facet>) {
+    let removed: Vec<(usize, usize)> = custom_emojis
+        .iter()
+        .flat_map(|shortcode| {
+            let pattern = format!(":{}:", shortcode);
+            content
+                .match_indices(&pattern)
+                .map(|(idx, matched)| (idx, idx + matched.len()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    remove_ranges(content, facets, removed)
+}
+
+/**
+ * Markdown の強調 (**bold**、__bold__、~~strike~~) の記号を取り除き、後ろの facet の位置を詰める
+ *
+ * リンクの中の記号はそのまま残す。* や _ が 1 つのものは誤検出が多いので対象にしない
+ */
+pub fn strip_markdown(
+    content: &str,
+    facets: &[store::operations::Facet],
+) -> (String, Vec<store::operations::Facet>) {
+    let in_link = |idx: usize| {
+        facets.iter().any(|facet| {
+            let (start, end) = facet_range(facet);
+            start <= idx && idx < end
+        })
+    };
+    let removed: Vec<(usize, usize)> = [r"\*\*", "__", "~~"]
+        .into_iter()
+        .flat_map(|delimiter| {
+            Regex::new(&format!(r"{0}(\S(?:[^\n]*?\S)?){0}", delimiter))
+                .unwrap()
+                .find_iter(content)
+                .filter(|m| !in_link(m.start()) && !in_link(m.end() - 1))
+                .flat_map(|m| [(m.start(), m.start() + 2), (m.end() - 2, m.end())])
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    remove_ranges(content, facets, removed)
+}
+
+/** 表示されず、リンクの途中に紛れ込むと facet の位置がずれる原因になる文字 */
+const ZERO_WIDTH_CHARS: &[char] = &['\u{200B}', '\u{2060}', '\u{FEFF}'];
+
+/**
+ * 改行を LF に揃え、ゼロ幅の文字と前後の空白を取り除く
+ *
+ * 送信先でも同じように正規化されることがあるので、先に済ませて facet の位置を実際に送る本文に合わせる。
+ * facet の途中の文字を取り除いた場合は、その分だけ facet を縮める
+ */
+pub fn normalize(
+    content: &str,
+    facets: &[store::operations::Facet],
+) -> (String, Vec<store::operations::Facet>) {
+    let mut removed: Vec<(usize, usize)> = content
+        .char_indices()
+        .filter(|&(idx, c)| {
+            ZERO_WIDTH_CHARS.contains(&c) || (c == '\r' && content[idx + 1..].starts_with('\n'))
+        })
+        .map(|(idx, c)| (idx, idx + c.len_utf8()))
+        .collect();
+    let trimmed_start = content.len() - content.trim_start().len();
+    let trimmed_end = content.trim_end().len();
+    removed.push((0, trimmed_start));
+    removed.push((trimmed_end.max(trimmed_start), content.len()));
+    let removed = merge_ranges(
+        removed
+            .into_iter()
+            .filter(|(start, end)| start < end)
+            .collect(),
+    );
+    if removed.is_empty() {
+        return (content.to_owned(), facets.to_vec());
+    }
+    let text = remove_text(content, &removed);
+    let to_new_idx = |idx: usize| -> u32 {
+        let shift: usize = removed
+            .iter()
+            .filter(|&&(start, _)| start < idx)
+            .map(|&(start, end)| end.min(idx) - start)
+            .sum();
+        (idx - shift) as u32
+    };
+    let new_facets = facets
+        .iter()
+        .filter_map(|facet| match facet {
+            Link { byte_slice, uri } => {
+                let byte_slice =
+                    to_new_idx(byte_slice.start as usize)..to_new_idx(byte_slice.end as usize);
+                (!byte_slice.is_empty()).then(|| Link {
+                    byte_slice,
+                    uri: uri.clone(),
+                })
+            }
+        })
+        .collect();
+    (text, new_facets)
+}
+
+fn is_web_url(uri: &str) -> bool {
+    Url::parse(uri).is_ok_and(|url| ["http", "https"].contains(&url.scheme()))
+}
+
+/** プレーンテキストの本文から http と https のリンクの facet を作る */
+pub fn create_link_facets(content: &str) -> Vec<store::operations::Facet> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+    finder
+        .links(content)
+        .filter(|link| is_web_url(link.as_str()))
+        .map(|link| Link {
+            byte_slice: link.start() as u32..link.end() as u32,
+            uri: link.as_str().to_owned(),
+        })
+        .collect()
+}
+
+/** 本文の末尾にリンクを付ける。既に本文にある場合は付けない */
+pub fn append_link(
+    content: &str,
+    facets: &[store::operations::Facet],
+    uri: &str,
+) -> (String, Vec<store::operations::Facet>) {
+    let mut content = content.to_owned();
+    let mut facets = facets.to_vec();
+    if content.contains(uri) {
+        return (content, facets);
+    }
+    if !content.is_empty() {
+        content.push_str("\n\n");
+    }
+    let start = content.len();
+    content.push_str(uri);
+    facets.push(Link {
+        byte_slice: start as u32..content.len() as u32,
+        uri: uri.to_owned(),
+    });
+    (content, facets)
+}
+
+/** 短縮した表示で残すパス以降の文字数。公式クライアントに合わせている */
+const MAX_SHORT_URL_PATH_LENGTH: usize = 15;
+
+/** スキームを除き、長いパス以降を省略した表示用の URL */
+fn to_short_url(display: &str) -> Option<String> {
+    let url = Url::parse(display).ok()?;
+    let host = url.host_str()?;
+    let mut rest = url.path().trim_end_matches('/').to_owned();
+    if let Some(query) = url.query() {
+        rest.push('?');
+        rest.push_str(query);
+    }
+    if let Some(fragment) = url.fragment() {
+        rest.push('#');
+        rest.push_str(fragment);
+    }
+    if rest.chars().count() <= MAX_SHORT_URL_PATH_LENGTH {
+        return Some(format!("{}{}", host, rest));
+    }
+    let rest: String = rest.chars().take(MAX_SHORT_URL_PATH_LENGTH - 2).collect();
+    Some(format!("{}{}{}", host, rest, ELLIPSIS))
+}
+
+/**
+ * URL がそのまま表示されているリンクを短縮した表示にする
+ *
+ * facet の uri は元のままで、位置だけを短縮後の表示に合わせる。重なっている facet は取り除く
+ */
+pub fn shorten_links(
+    content: &str,
+    facets: &[store::operations::Facet],
+) -> (String, Vec<store::operations::Facet>) {
+    let mut sorted: Vec<_> = facets.iter().collect();
+    sorted.sort_by_key(|facet| facet_range(facet));
+    let mut text = String::new();
+    let mut new_facets = Vec::new();
+    let mut last = 0;
+    for facet in sorted {
+        let (start, end) = facet_range(facet);
+        if start < last {
+            continue;
+        }
+        let Link { uri, .. } = facet;
+        text.push_str(&content[last..start]);
+        let display = &content[start..end];
+        let display = if is_web_url(display) {
+            to_short_url(display).unwrap_or_else(|| display.to_owned())
+        } else {
+            display.to_owned()
+        };
+        let new_start = text.len();
+        text.push_str(&display);
+        new_facets.push(Link {
+            byte_slice: new_start as u32..text.len() as u32,
+            uri: uri.clone(),
+        });
+        last = end;
+    }
+    text.push_str(&content[last..]);
+    (text, new_facets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_facets_are_valid(text: &str, facets: &[store::operations::Facet]) {
+        for facet in facets {
+            let (start, end) = facet_range(facet);
+            assert!(start < end && end <= text.len());
+            assert!(text.is_char_boundary(start) && text.is_char_boundary(end));
+            let Link { uri, .. } = facet;
+            assert_eq!(&text[start..end], uri);
+        }
+    }
+
+    #[test]
+    fn email_and_mailto_are_not_facets() {
+        let content = "mail me@example.com or mailto:me@example.com, see https://example.com/";
+        let facets = create_link_facets(content);
+
+        let [Link { byte_slice, uri }] = facets.as_slice() else {
+            panic!("unexpected facets: {:?}", facets);
+        };
+        assert_eq!(uri, "https://example.com/");
+        assert_eq!(
+            &content[byte_slice.start as usize..byte_slice.end as usize],
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn length_is_counted_by_units_not_bytes() {
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let e_acute = "e\u{301}";
+        assert_eq!(family.len(), 18);
+        assert_eq!(measure(family, Counting::Graphemes), 1);
+        assert_eq!(measure(family, Counting::Chars), 5);
+        assert_eq!(measure(family, Counting::TwitterWeighted), 2);
+        assert_eq!(measure(e_acute, Counting::Graphemes), 1);
+        assert_eq!(measure(e_acute, Counting::Chars), 2);
+        assert_eq!(measure("あいう", Counting::Graphemes), 3);
+        assert_eq!(measure("あいう", Counting::TwitterWeighted), 6);
+
+        // NOTE: 書記素の途中では切らない
+        let text = format!("{}{}", family, family);
+        assert_eq!(fit(&text, 1, Counting::Graphemes), family.len());
+        assert_eq!(fit(&text, 4, Counting::Chars), 0);
+        assert_eq!(
+            fit(&format!("{}{}", e_acute, e_acute), 3, Counting::Chars),
+            3
+        );
+    }
+
+    /** twitter-text の仕様にある例 */
+    #[test]
+    fn twitter_weighted_length_matches_documented_examples() {
+        assert_eq!(measure("Hello world", Counting::TwitterWeighted), 11);
+        // NOTE: Latin-1 と一般的な句読点は 1 文字
+        assert_eq!(measure("café — “quoted”", Counting::TwitterWeighted), 15);
+        // NOTE: CJK は 140 文字で上限の 280 になる
+        let cjk = "日".repeat(140);
+        assert_eq!(measure(&cjk, Counting::TwitterWeighted), 280);
+        assert_eq!(measure("😷", Counting::TwitterWeighted), 2);
+        // NOTE: URL は長さに関わらず 23 文字
+        let short = "https://t.co";
+        let long = format!("https://example.com/{}", "a".repeat(200));
+        assert_eq!(measure(short, Counting::TwitterWeighted), 23);
+        assert_eq!(
+            measure(
+                &format!("a {} b {}", short, long),
+                Counting::TwitterWeighted
+            ),
+            2 + 23 + 3 + 23
+        );
+        // NOTE: URL の途中では切らない
+        let text = format!("ab {}", long);
+        assert_eq!(fit(&text, 10, Counting::TwitterWeighted), 3);
+        assert_eq!(fit(&text, 26, Counting::TwitterWeighted), text.len());
+    }
+
+    #[test]
+    fn multibyte_content_is_truncated_within_limit() {
+        let link = "https://example.com/";
+        let content = format!("{}{} {}", "あ".repeat(200), link, "👨‍👩‍👧".repeat(200));
+        let facets = create_link_facets(&content);
+        let src_uri = "https://src.example.com/1";
+        for counting in [
+            Counting::Graphemes,
+            Counting::Chars,
+            Counting::Mastodon,
+            Counting::TwitterWeighted,
+        ] {
+            let (text, new_facets) = truncate(&content, &facets, 300, counting, Some(src_uri));
+            assert!(measure(&text, counting) <= 300);
+            assert!(text.len() < content.len());
+            assert!(text.ends_with(src_uri));
+            assert_facets_are_valid(&text, &new_facets);
+            assert_eq!(new_facets.last().map(facet_range).unwrap().1, text.len());
+        }
+    }
+
+    #[test]
+    fn mastodon_counts_urls_as_fixed_length() {
+        let link = format!("https://example.com/{}", "a".repeat(100));
+        let content = format!("{} {}", "あ".repeat(400), link);
+        assert_eq!(measure(&content, Counting::Mastodon), 400 + 1 + URL_LENGTH);
+        let facets = create_link_facets(&content);
+        let (text, new_facets) = truncate(&content, &facets, 500, Counting::Mastodon, None);
+        assert_eq!(text, content);
+        assert_facets_are_valid(&text, &new_facets);
+    }
+
+    #[test]
+    fn markdown_inside_link_is_kept() {
+        let content = "~~old~~ __new__ https://example.com/**a**/b";
+        let facets = create_link_facets(content);
+
+        let (text, facets) = strip_markdown(content, &facets);
+
+        assert_eq!(text, "old new https://example.com/**a**/b");
+        assert_facets_are_valid(&text, &facets);
+    }
+
+    #[test]
+    fn link_facet_covers_url_after_normalization() {
+        let prefix = "  see\u{FEFF}\r\n";
+        let url = "https://exam\u{200B}ple.com/";
+        let content = format!("{}{}  \r\n", prefix, url);
+        let facets = [Link {
+            byte_slice: prefix.len() as u32..(prefix.len() + url.len()) as u32,
+            uri: "https://example.com/".into(),
+        }];
+
+        let (text, facets) = normalize(&content, &facets);
+
+        assert_eq!(text, "see\nhttps://example.com/");
+        assert_eq!(facets.len(), 1);
+        assert_facets_are_valid(&text, &facets);
+    }
+
+    #[test]
+    fn long_links_are_shortened_to_fit() {
+        const MAX_LENGTH: usize = 300;
+        let first = format!("https://example.com/{}", "a".repeat(130));
+        let second = format!("https://example.org/{}?q=1", "b".repeat(130));
+        let prefix = "x".repeat(20);
+        let content = format!("{} {} and {}", prefix, first, second);
+        let first_start = prefix.len() + 1;
+        let second_start = first_start + first.len() + " and ".len();
+        let facets = [
+            Link {
+                byte_slice: first_start as u32..(first_start + first.len()) as u32,
+                uri: first.clone(),
+            },
+            Link {
+                byte_slice: second_start as u32..(second_start + second.len()) as u32,
+                uri: second.clone(),
+            },
+        ];
+        assert!(measure(&content, Counting::Graphemes) > MAX_LENGTH);
+
+        let (text, facets) = shorten_links(&content, &facets);
+
+        assert!(measure(&text, Counting::Graphemes) <= MAX_LENGTH);
+        assert_eq!(
+            text,
+            format!(
+                "{} example.com/aaaaaaaaaaaa… and example.org/bbbbbbbbbbbb…",
+                prefix
+            )
+        );
+        let spans: Vec<_> = facets
+            .iter()
+            .map(|facet| {
+                let (start, end) = facet_range(facet);
+                let Link { uri, .. } = facet;
+                (&text[start..end], uri.as_str())
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                ("example.com/aaaaaaaaaaaa…", first.as_str()),
+                ("example.org/bbbbbbbbbbbb…", second.as_str()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,595 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use reqwest::{Method, StatusCode};
+use serde_json::Value;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{sources::source, store};
+
+use super::{
+    error::{ClientError, ResponseExt},
+    is_caught_up,
+    text::{create_link_facets, truncate, Counting},
+    AccountInfo, Cursor, NewPost, MAX_CATCH_UP_PAGES,
+};
+
+pub const ORIGIN: &str = "https://www.threads.net";
+
+const API_ORIGIN: &str = "https://graph.threads.net/v1.0";
+const MAX_LENGTH: usize = 500;
+/** カルーセルに入れられる上限 */
+const MAX_CAROUSEL_ITEMS: usize = 20;
+const FIELDS: &str =
+    "id,media_type,media_url,permalink,text,timestamp,owner,children{media_type,media_url}";
+/** 画像や動画のコンテナは、作ってから公開できるようになるまで時間がかかる */
+const CONTAINER_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+const MAX_CONTAINER_CHECKS: usize = 10;
+
+fn get_str<'a>(json: &'a Value, key: &str) -> Result<&'a str> {
+    json.get(key)
+        .ok_or_else(|| anyhow!("{} is not found", key))?
+        .as_str()
+        .ok_or_else(|| anyhow!("{} is not str", key))
+}
+
+fn is_video(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    [".mp4", ".mov", ".webm"]
+        .iter()
+        .any(|extension| path.to_lowercase().ends_with(extension))
+}
+
+/** 画像や動画は Threads 側に URL から取得させる */
+fn to_media_params(medium: &store::operations::Medium) -> Vec<(&'static str, String)> {
+    let mut params = if is_video(&medium.url) {
+        vec![
+            ("media_type", "VIDEO".to_owned()),
+            ("video_url", medium.url.clone()),
+        ]
+    } else {
+        vec![
+            ("media_type", "IMAGE".to_owned()),
+            ("image_url", medium.url.clone()),
+        ]
+    };
+    if !medium.alt.is_empty() {
+        params.push(("alt_text", medium.alt.clone()));
+    }
+    params
+}
+
+fn to_media(json: &Value) -> Vec<store::operations::Medium> {
+    let to_medium = |json: &Value| {
+        let content_type = match json.get("media_type")?.as_str()? {
+            "IMAGE" => "image/",
+            "VIDEO" => "video/",
+            _ => return None,
+        };
+        Some(store::operations::Medium {
+            url: json.get("media_url")?.as_str()?.to_owned(),
+            alt: String::new(),
+            sensitive: false,
+            focus: None,
+            content_type: Some(content_type.to_owned()),
+        })
+    };
+    match json.get("media_type").and_then(Value::as_str) {
+        Some("IMAGE" | "VIDEO") => to_medium(json).into_iter().collect(),
+        Some("CAROUSEL_ALBUM") => json
+            .get("children")
+            .and_then(|children| children.get("data"))
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(to_medium)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/** 返信や引用の情報は取得できないので、単独の投稿として扱う */
+fn to_live_status(json: &Value) -> Result<Option<source::LiveStatus>> {
+    // NOTE: リポストは元の投稿の内容を取得できないので扱わない
+    if json.get("media_type").and_then(Value::as_str) == Some("REPOST_FACADE") {
+        return Ok(None);
+    }
+    let content = json
+        .get("text")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_owned();
+    let facets = create_link_facets(&content);
+    let media = to_media(json);
+    let external = if media.is_empty() && !facets.is_empty() {
+        source::LiveExternal::Unknown
+    } else {
+        source::LiveExternal::None
+    };
+    Ok(Some(source::LiveStatus::Post(source::LivePost {
+        identifier: get_str(json, "id")?.to_owned(),
+        uri: get_str(json, "permalink")?.to_owned(),
+        content,
+        facets,
+        reply_src_identifier: None,
+        media,
+        external,
+        content_warning: None,
+        poll: None,
+        quote: None,
+        custom_emojis: Vec::new(),
+        // NOTE: +0000 の形式なので RFC 3339 としては読めない
+        created_at: DateTime::parse_from_str(get_str(json, "timestamp")?, "%Y-%m-%dT%H:%M:%S%z")?,
+    })))
+}
+
+pub struct Client {
+    http_client: Arc<reqwest::Client>,
+    api_origin: String,
+    access_token: String,
+    user_id: String,
+}
+
+impl Client {
+    #[tracing::instrument(name = "threads_client::Client::new", skip_all)]
+    pub async fn new(
+        http_client: Arc<reqwest::Client>,
+        access_token: String,
+        user_id: String,
+    ) -> Result<Self> {
+        Ok(Self {
+            http_client,
+            api_origin: API_ORIGIN.into(),
+            access_token,
+            user_id,
+        })
+    }
+
+    /** テスト用に、リクエスト先をモックのサーバーに差し替える */
+    #[cfg(test)]
+    pub fn with_api_origin(mut self, api_origin: &str) -> Self {
+        self.api_origin = api_origin.to_owned();
+        self
+    }
+
+    fn build_request(
+        &self,
+        method: Method,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> reqwest::RequestBuilder {
+        self.http_client
+            .request(method, format!("{}/{}", self.api_origin, path))
+            .query(params)
+            .query(&[("access_token", &self.access_token)])
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<Value, ClientError> {
+        let json: Value = self
+            .build_request(method, path, params)
+            .send()
+            .await?
+            .error_for_client_status()?
+            .json()
+            .await?;
+        Ok(json)
+    }
+
+    async fn create_container(&self, params: &[(&str, String)]) -> Result<String, ClientError> {
+        let json = self
+            .request(Method::POST, &format!("{}/threads", self.user_id), params)
+            .await?;
+        Ok(get_str(&json, "id")?.to_owned())
+    }
+
+    async fn wait_for_container(&self, container_id: &str) -> Result<(), ClientError> {
+        for _ in 0..MAX_CONTAINER_CHECKS {
+            let json = self
+                .request(
+                    Method::GET,
+                    container_id,
+                    &[("fields", "status,error_message".to_owned())],
+                )
+                .await?;
+            match get_str(&json, "status")? {
+                "FINISHED" | "PUBLISHED" => return Ok(()),
+                "ERROR" | "EXPIRED" => return Err(anyhow!("container failed: {}", json).into()),
+                _ => sleep(CONTAINER_CHECK_INTERVAL).await,
+            }
+        }
+        // NOTE: 時間をおけば準備ができている見込みがある
+        warn!("container is not ready: {}", container_id);
+        Err(ClientError::Transient)
+    }
+
+    /** コンテナの準備ができるのを待ってから公開し、公開された投稿の id を返す */
+    async fn publish(&self, container_id: &str) -> Result<String, ClientError> {
+        self.wait_for_container(container_id).await?;
+        let json = self
+            .request(
+                Method::POST,
+                &format!("{}/threads_publish", self.user_id),
+                &[("creation_id", container_id.to_owned())],
+            )
+            .await?;
+        Ok(get_str(&json, "id")?.to_owned())
+    }
+
+    async fn delete(&self, identifier: &str) -> Result<(), ClientError> {
+        let resp = self
+            .build_request(Method::DELETE, identifier, &[])
+            .send()
+            .await?;
+        let Some(client_error) = ClientError::from_response(&resp) else {
+            return Ok(());
+        };
+        // NOTE: 既に無い投稿は 404 ではなく、400 と code=100, error_subcode=33 で返ってくる
+        let json: Value = resp.json().await.unwrap_or_default();
+        let error = json.get("error");
+        let code = error
+            .and_then(|error| error.get("code"))
+            .and_then(Value::as_u64);
+        let subcode = error
+            .and_then(|error| error.get("error_subcode"))
+            .and_then(Value::as_u64);
+        if code == Some(100) && subcode == Some(33) {
+            return Err(ClientError::NotFound);
+        }
+        Err(client_error)
+    }
+}
+
+#[async_trait]
+impl super::Client for Client {
+    fn to_session(&self) -> Option<String> {
+        None
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let json = self
+            .request(Method::GET, "me", &[("fields", "id,username".to_owned())])
+            .await?;
+        Ok(AccountInfo {
+            id: get_str(&json, "id")?.to_owned(),
+            handle: get_str(&json, "username")?.to_owned(),
+        })
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::fetch_statuses", skip_all)]
+    async fn fetch_statuses(
+        &mut self,
+        cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        let mut statuses = Vec::new();
+        let mut after: Option<String> = None;
+        for _ in 0..MAX_CATCH_UP_PAGES {
+            let mut params = vec![("fields", FIELDS.to_owned()), ("limit", "25".to_owned())];
+            if let Some(after) = after {
+                params.push(("after", after));
+            }
+            let json = self
+                .request(Method::GET, &format!("{}/threads", self.user_id), &params)
+                .await?;
+            let page = json
+                .get("data")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|item| to_live_status(item).transpose())
+                .collect::<Result<Vec<_>>>()?;
+            let caught_up = is_caught_up(&page, cursor);
+            statuses.extend(page);
+            after = json
+                .get("paging")
+                .filter(|paging| paging.get("next").is_some())
+                .and_then(|paging| paging.get("cursors"))
+                .and_then(|cursors| cursors.get("after"))
+                .and_then(Value::as_str)
+                .map(str::to_owned);
+            if after.is_none() || caught_up {
+                break;
+            }
+        }
+        Ok(statuses)
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::get_status", skip_all)]
+    async fn get_status(
+        &mut self,
+        identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        let resp = self
+            .build_request(Method::GET, identifier, &[("fields", FIELDS.to_owned())])
+            .send()
+            .await?;
+        // NOTE: 存在しない id は 400 で返ってくることがある
+        if [StatusCode::BAD_REQUEST, StatusCode::NOT_FOUND].contains(&resp.status()) {
+            return Ok(None);
+        }
+        let json: Value = resp.error_for_client_status()?.json().await?;
+        let owner = json
+            .get("owner")
+            .and_then(|owner| owner.get("id"))
+            .and_then(Value::as_str);
+        if owner != Some(self.user_id.as_str()) {
+            return Ok(None);
+        }
+        Ok(to_live_status(&json)?)
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::post", skip_all)]
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let (content, _) = truncate(
+            post.content,
+            post.facets,
+            MAX_LENGTH,
+            Counting::Chars,
+            post.src_uri,
+        );
+        // NOTE: CW は無いので、本文の前に付ける
+        let content = match post.content_warning {
+            Some(content_warning) => format!("{}\n\n{}", content_warning, content),
+            None => content,
+        };
+        let mut params = vec![("text", content)];
+        if let Some(reply_identifier) = post.reply_identifier {
+            params.push(("reply_to_id", reply_identifier.to_owned()));
+        }
+        let images: Vec<_> = post.images.iter().take(MAX_CAROUSEL_ITEMS).collect();
+        match images.as_slice() {
+            [] => {
+                params.push(("media_type", "TEXT".to_owned()));
+                if let Some(external) = &post.external {
+                    params.push(("link_attachment", external.uri.clone()));
+                }
+            }
+            [medium] => params.extend(to_media_params(medium)),
+            media => {
+                let mut children = Vec::new();
+                for medium in media {
+                    let mut item = to_media_params(medium);
+                    item.push(("is_carousel_item", "true".to_owned()));
+                    children.push(self.create_container(&item).await?);
+                }
+                // NOTE: 子のコンテナの準備ができてからでないとカルーセルを作れない
+                for child in &children {
+                    self.wait_for_container(child).await?;
+                }
+                params.push(("media_type", "CAROUSEL".to_owned()));
+                params.push(("children", children.join(",")));
+            }
+        }
+        let container_id = self.create_container(&params).await?;
+        self.publish(&container_id).await
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::update_post", skip_all)]
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        _content: &str,
+        _facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        // NOTE: API からは編集できない
+        warn!(
+            "threads does not support editing (identifier={})",
+            identifier
+        );
+        Ok(identifier.to_owned())
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::repost", skip_all)]
+    async fn repost(
+        &mut self,
+        target_identifier: &str,
+        _created_at: &DateTime<FixedOffset>,
+    ) -> Result<String, ClientError> {
+        let json = self
+            .request(Method::POST, &format!("{}/repost", target_identifier), &[])
+            .await?;
+        Ok(get_str(&json, "id")?.to_owned())
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::delete_post", skip_all)]
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete(identifier).await
+    }
+
+    #[tracing::instrument(name = "threads_client::Client::delete_repost", skip_all)]
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
+        self.delete(identifier).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    use crate::protocols::{Client as _, NewPostFixture};
+
+    use super::*;
+
+    async fn client(server: &MockServer) -> Client {
+        Client::new(
+            Arc::new(reqwest::Client::new()),
+            "token".into(),
+            "user".into(),
+        )
+        .await
+        .unwrap()
+        .with_api_origin(&server.uri())
+    }
+
+    async fn mount_container(server: &MockServer, param: (&str, &str), id: &str) {
+        Mock::given(method("POST"))
+            .and(path("/user/threads"))
+            .and(query_param(param.0, param.1))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": id })))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    /** コンテナは全てすぐに公開できる状態にする */
+    async fn mount_finished_and_publish(server: &MockServer, creation_id: &str) {
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "status": "FINISHED" })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/user/threads_publish"))
+            .and(query_param("creation_id", creation_id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "published" })))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    async fn request_paths(server: &MockServer) -> Vec<String> {
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.url.path()))
+            .collect()
+    }
+
+    fn image(url: &str) -> store::operations::Medium {
+        store::operations::Medium {
+            url: url.into(),
+            alt: String::new(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn text_container_is_created_then_published() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        mount_container(&server, ("media_type", "TEXT"), "container").await;
+        mount_finished_and_publish(&server, "container").await;
+        let mut client = client(&server).await;
+
+        let identifier = client.post(fixture.post("hello")).await.unwrap();
+
+        assert_eq!(identifier, "published");
+        assert_eq!(
+            request_paths(&server).await,
+            [
+                "POST /user/threads",
+                "GET /container",
+                "POST /user/threads_publish"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn carousel_is_created_after_its_items() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        mount_container(
+            &server,
+            ("image_url", "https://example.com/1.png"),
+            "item-1",
+        )
+        .await;
+        mount_container(
+            &server,
+            ("image_url", "https://example.com/2.png"),
+            "item-2",
+        )
+        .await;
+        mount_container(&server, ("children", "item-1,item-2"), "carousel").await;
+        mount_finished_and_publish(&server, "carousel").await;
+        let mut client = client(&server).await;
+        let mut post = fixture.post("hello");
+        post.images = vec![
+            image("https://example.com/1.png"),
+            image("https://example.com/2.png"),
+        ];
+
+        let identifier = client.post(post).await.unwrap();
+
+        assert_eq!(identifier, "published");
+        assert_eq!(
+            request_paths(&server).await,
+            [
+                "POST /user/threads",
+                "POST /user/threads",
+                "GET /item-1",
+                "GET /item-2",
+                "POST /user/threads",
+                "GET /carousel",
+                "POST /user/threads_publish",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn deleted_post_is_not_found() {
+        let server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/1"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": { "code": 100, "error_subcode": 33 },
+            })))
+            .mount(&server)
+            .await;
+        let mut client = client(&server).await;
+
+        let result = client.delete_post("1").await;
+
+        assert!(matches!(result, Err(ClientError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn reposts_are_skipped_when_fetching() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user/threads"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [
+                    {
+                        "id": "2",
+                        "media_type": "REPOST_FACADE",
+                        "permalink": "https://www.threads.net/@user/post/b",
+                        "timestamp": "2024-01-02T00:00:00+0000",
+                    },
+                    {
+                        "id": "1",
+                        "media_type": "TEXT_POST",
+                        "permalink": "https://www.threads.net/@user/post/a",
+                        "text": "hello",
+                        "timestamp": "2024-01-01T00:00:00+0000",
+                    },
+                ],
+            })))
+            .mount(&server)
+            .await;
+        let mut client = client(&server).await;
+
+        let statuses = client.fetch_statuses(None).await.unwrap();
+
+        let [source::LiveStatus::Post(post)] = statuses.as_slice() else {
+            panic!("unexpected statuses");
+        };
+        assert_eq!(post.identifier, "1");
+        assert_eq!(post.content, "hello");
+        assert_eq!(post.created_at.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    }
+}
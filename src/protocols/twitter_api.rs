@@ -12,6 +12,8 @@ use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use tracing::{error, event_enabled, trace, Level};
 
+use super::{error::ClientError, redact::redact_text};
+
 async fn trace_header_and_throw_if_error_status(resp: Response) -> Result<Response> {
     if event_enabled!(Level::TRACE) {
         resp.headers()
@@ -37,10 +39,9 @@ async fn trace_header_and_throw_if_error_status(resp: Response) -> Result<Respon
                 trace!("{}: {}", key, value);
             });
     }
-    let err = resp.error_for_status_ref().err();
-    if let Some(err) = err {
-        error!("{:?}", resp.text().await?);
-        return Err(err.into());
+    if let Some(client_error) = ClientError::from_response(&resp) {
+        error!("{:?}", redact_text(&resp.text().await?));
+        return Err(client_error.into());
     }
     Ok(resp)
 }
@@ -56,8 +57,13 @@ pub struct TweetBody<'a> {
     pub text: &'a str,
 }
 
+const API_ORIGIN: &str = "https://api.twitter.com";
+const UPLOAD_ORIGIN: &str = "https://upload.twitter.com";
+
 pub struct Api {
     http_client: Arc<reqwest::Client>,
+    api_origin: String,
+    upload_origin: String,
     oauth1_request_builder: oauth1_request::Builder<'static, oauth1_request::HmacSha1>,
 }
 
@@ -71,6 +77,8 @@ impl Api {
     ) -> Self {
         Self {
             http_client,
+            api_origin: API_ORIGIN.to_owned(),
+            upload_origin: UPLOAD_ORIGIN.to_owned(),
             oauth1_request_builder: oauth1_request::Builder::<_, _>::new(
                 Credentials {
                     identifier: api_key,
@@ -86,11 +94,19 @@ impl Api {
         }
     }
 
-    pub async fn _get_me<T: DeserializeOwned>(&self) -> Result<T> {
-        let url = "https://api.twitter.com/2/users/me";
+    /** テスト用に、リクエスト先をモックのサーバーに差し替える */
+    #[cfg(test)]
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.api_origin = origin.to_owned();
+        self.upload_origin = origin.to_owned();
+        self
+    }
+
+    pub async fn get_me<T: DeserializeOwned>(&self) -> Result<T> {
+        let url = format!("{}/2/users/me", self.api_origin);
         let resp = self
             .http_client
-            .get(url)
+            .get(&url)
             .header(AUTHORIZATION, self.oauth1_request_builder.get(url, &()))
             .send()
             .await?;
@@ -99,10 +115,10 @@ impl Api {
     }
 
     pub async fn create_tweet<T: DeserializeOwned>(&self, body: TweetBody<'_>) -> Result<T> {
-        let url = "https://api.twitter.com/2/tweets";
+        let url = format!("{}/2/tweets", self.api_origin);
         let resp = self
             .http_client
-            .post(url)
+            .post(&url)
             .header(AUTHORIZATION, self.oauth1_request_builder.post(url, &()))
             .json(&body)
             .send()
@@ -112,7 +128,7 @@ impl Api {
     }
 
     pub async fn delete_tweet<T: DeserializeOwned>(&self, id: &str) -> Result<T> {
-        let url = format!("https://api.twitter.com/2/tweets/{}", id);
+        let url = format!("{}/2/tweets/{}", self.api_origin, id);
         let resp = self
             .http_client
             .delete(&url)
@@ -125,10 +141,7 @@ impl Api {
     }
 
     pub async fn create_retweet_1_1<T: DeserializeOwned>(&self, tweet_id: &str) -> Result<T> {
-        let url = format!(
-            "https://api.twitter.com/1.1/statuses/retweet/{}.json",
-            tweet_id
-        );
+        let url = format!("{}/1.1/statuses/retweet/{}.json", self.api_origin, tweet_id);
         let resp = self
             .http_client
             .post(&url)
@@ -151,8 +164,8 @@ impl Api {
 
     pub async fn delete_retweet_1_1<T: DeserializeOwned>(&self, tweet_id: &str) -> Result<T> {
         let url = format!(
-            "https://api.twitter.com/1.1/statuses/unretweet/{}.json",
-            tweet_id
+            "{}/1.1/statuses/unretweet/{}.json",
+            self.api_origin, tweet_id
         );
         let resp = self
             .http_client
@@ -170,10 +183,10 @@ impl Api {
     }
 
     pub async fn verify_credentials<T: DeserializeOwned>(&self) -> Result<T> {
-        let url = "https://api.twitter.com/1.1/account/verify_credentials.json";
+        let url = format!("{}/1.1/account/verify_credentials.json", self.api_origin);
         let resp = self
             .http_client
-            .get(url)
+            .get(&url)
             .header(AUTHORIZATION, self.oauth1_request_builder.get(url, &()))
             .send()
             .await?;
@@ -182,13 +195,13 @@ impl Api {
     }
 
     pub async fn upload<T: DeserializeOwned>(&self, body: impl Into<Body>) -> Result<T> {
-        let url = "https://upload.twitter.com/1.1/media/upload.json";
+        let url = format!("{}/1.1/media/upload.json", self.upload_origin);
         let query = [("media_category", "tweet_image")];
         let multipart = Form::new().part("media", Part::stream(body));
 
         let resp = self
             .http_client
-            .post(url)
+            .post(&url)
             .header(
                 AUTHORIZATION,
                 self.oauth1_request_builder
@@ -201,4 +214,82 @@ impl Api {
         let resp = trace_header_and_throw_if_error_status(resp).await?;
         Ok(resp.json().await?)
     }
+
+    async fn post_media_command(
+        &self,
+        query: &[(&str, &str)],
+        form: Option<Form>,
+    ) -> Result<Response> {
+        let url = format!("{}/1.1/media/upload.json", self.upload_origin);
+        let req = self
+            .http_client
+            .post(&url)
+            .header(
+                AUTHORIZATION,
+                self.oauth1_request_builder
+                    .post(url, &ParameterList::new(query.to_vec())),
+            )
+            .query(query);
+        let req = match form {
+            Some(form) => req.multipart(form),
+            None => req,
+        };
+        trace_header_and_throw_if_error_status(req.send().await?).await
+    }
+
+    pub async fn upload_init<T: DeserializeOwned>(
+        &self,
+        total_bytes: usize,
+        media_type: &str,
+    ) -> Result<T> {
+        let total_bytes = total_bytes.to_string();
+        // NOTE: 署名のためにキーの昇順に並べる
+        let query = [
+            ("command", "INIT"),
+            ("media_category", "tweet_video"),
+            ("media_type", media_type),
+            ("total_bytes", total_bytes.as_str()),
+        ];
+        Ok(self.post_media_command(&query, None).await?.json().await?)
+    }
+
+    pub async fn upload_append(
+        &self,
+        media_id: &str,
+        segment_index: usize,
+        chunk: Vec<u8>,
+    ) -> Result<()> {
+        let segment_index = segment_index.to_string();
+        let query = [
+            ("command", "APPEND"),
+            ("media_id", media_id),
+            ("segment_index", segment_index.as_str()),
+        ];
+        let form = Form::new().part("media", Part::bytes(chunk));
+        self.post_media_command(&query, Some(form)).await?;
+        Ok(())
+    }
+
+    pub async fn upload_finalize<T: DeserializeOwned>(&self, media_id: &str) -> Result<T> {
+        let query = [("command", "FINALIZE"), ("media_id", media_id)];
+        Ok(self.post_media_command(&query, None).await?.json().await?)
+    }
+
+    pub async fn upload_status<T: DeserializeOwned>(&self, media_id: &str) -> Result<T> {
+        let url = format!("{}/1.1/media/upload.json", self.upload_origin);
+        let query = [("command", "STATUS"), ("media_id", media_id)];
+        let resp = self
+            .http_client
+            .get(&url)
+            .header(
+                AUTHORIZATION,
+                self.oauth1_request_builder
+                    .get(url, &ParameterList::new(query)),
+            )
+            .query(&query)
+            .send()
+            .await?;
+        let resp = trace_header_and_throw_if_error_status(resp).await?;
+        Ok(resp.json().await?)
+    }
 }
@@ -1,21 +1,139 @@
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use chrono::{DateTime, FixedOffset};
 use futures::future::join_all;
 use serde_json::{json, Value};
-use tracing::info;
+use tracing::{info, trace, warn};
 
-use crate::{sources::source, store};
+use crate::{
+    metrics,
+    sources::source,
+    store::{self, operations::Facet::Link},
+};
 
-use super::twitter_api::{Api, TweetBody};
+use super::{
+    error::{ignore_not_found, ClientError},
+    media::{download, transcode, DownloadOptions},
+    text::{fit, measure, truncate, Counting},
+    twitter_api::{Api, TweetBody},
+    AccountInfo, Cursor, NewPost,
+};
 
 pub const ORIGIN: &str = "https://twitter.com";
 
+const MAX_TWEET_LENGTH: usize = 280;
+/** スレッドの連番 " (nn/nn)" のために空けておく文字数 */
+const COUNTER_LENGTH: usize = 8;
+
+/**
+ * 以前は分割したツイートの id をカンマ区切りで 1 つの identifier にまとめていた
+ *
+ * 今は先頭のツイートだけを identifier にして、残りは follow_up_identifiers に持つ
+ */
+fn split_identifier(identifier: &str) -> impl DoubleEndedIterator<Item = &str> {
+    identifier.split(',')
+}
+
+fn find_link_start(facets: &[store::operations::Facet], idx: usize) -> Option<usize> {
+    facets.iter().find_map(|facet| match facet {
+        Link { byte_slice, .. } => ((byte_slice.start as usize) < idx
+            && idx < byte_slice.end as usize)
+            .then_some(byte_slice.start as usize),
+    })
+}
+
+/** range に収まる facet を、range の先頭からの位置にずらして返す */
+fn slice_facets(
+    facets: &[store::operations::Facet],
+    range: Range<usize>,
+) -> Vec<store::operations::Facet> {
+    facets
+        .iter()
+        .filter_map(|facet| match facet {
+            Link { byte_slice, uri } => (range.start <= byte_slice.start as usize
+                && byte_slice.end as usize <= range.end)
+                .then(|| Link {
+                    byte_slice: byte_slice.start - range.start as u32
+                        ..byte_slice.end - range.start as u32,
+                    uri: uri.clone(),
+                }),
+        })
+        .collect()
+}
+
+/**
+ * 文字数の上限を超える本文を、リンクの途中で切らないように空白の位置で分割する
+ *
+ * facet はそれぞれのツイートの中の位置にずらす
+ */
+pub fn split_into_thread(
+    content: &str,
+    facets: &[store::operations::Facet],
+) -> Vec<(String, Vec<store::operations::Facet>)> {
+    if measure(content, Counting::TwitterWeighted) <= MAX_TWEET_LENGTH {
+        return vec![(content.to_owned(), facets.to_vec())];
+    }
+    let limit = MAX_TWEET_LENGTH - COUNTER_LENGTH;
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < content.len() {
+        let rest = &content[start..];
+        let cut = fit(rest, limit, Counting::TwitterWeighted);
+        if cut == rest.len() {
+            ranges.push(start..start + rest.trim_end().len());
+            break;
+        }
+        let cut = start + cut;
+        let split = content[start..cut]
+            .char_indices()
+            .rev()
+            .find(|&(idx, c)| idx > 0 && c.is_whitespace())
+            .map(|(idx, _)| start + idx)
+            // NOTE: 空白が無い場合はリンクの手前か、上限の位置で切る
+            .or_else(|| find_link_start(facets, cut).filter(|&idx| idx > start))
+            .unwrap_or(cut);
+        ranges.push(start..start + content[start..split].trim_end().len());
+        start = split
+            + content[split..]
+                .char_indices()
+                .find(|(_, c)| !c.is_whitespace())
+                .map_or(content.len() - split, |(idx, _)| idx);
+    }
+    let len = ranges.len();
+    ranges
+        .into_iter()
+        .enumerate()
+        .map(|(i, range)| {
+            (
+                format!("{} ({}/{})", &content[range.clone()], i + 1, len),
+                slice_facets(facets, range),
+            )
+        })
+        .collect()
+}
+
+/** WebP や AVIF は受け付けられないことがある */
+const SUPPORTED_IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif"];
+const VIDEO_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/** 動画の処理が終わるのを待つ回数と、1 回に待つ秒数の上限 */
+const MAX_PROCESSING_CHECKS: usize = 30;
+const MAX_CHECK_AFTER_SECS: u64 = 30;
+
+fn get_media_id(json: &Value) -> Result<String> {
+    Ok(json
+        .get("media_id_string")
+        .ok_or_else(|| anyhow!("media_id_string is not found"))?
+        .as_str()
+        .ok_or_else(|| anyhow!("media_id_string is not str"))?
+        .to_owned())
+}
+
 pub struct Client {
     http_client: Arc<reqwest::Client>,
     api: Api,
+    media: DownloadOptions,
 }
 
 impl Client {
@@ -26,6 +144,7 @@ impl Client {
         api_key_secret: String,
         access_token: String,
         access_token_secret: String,
+        media: DownloadOptions,
     ) -> Result<Self> {
         let api = Api::new(
             http_client.clone(),
@@ -43,7 +162,48 @@ impl Client {
             .to_owned();
         info!("logged in as {}", user_id);
 
-        Ok(Self { http_client, api })
+        Ok(Self {
+            http_client,
+            api,
+            media,
+        })
+    }
+
+    /** 動画は分割アップロードし、サーバー側の処理が終わるまで待つ */
+    async fn upload_video(&self, bytes: &[u8], media_type: &str) -> Result<String> {
+        let json: Value = self.api.upload_init(bytes.len(), media_type).await?;
+        let media_id = get_media_id(&json)?;
+        for (segment_index, chunk) in bytes.chunks(VIDEO_CHUNK_SIZE).enumerate() {
+            self.api
+                .upload_append(&media_id, segment_index, chunk.to_vec())
+                .await?;
+        }
+        let mut json: Value = self.api.upload_finalize(&media_id).await?;
+        for _ in 0..MAX_PROCESSING_CHECKS {
+            let Some(processing_info) = json.get("processing_info") else {
+                return Ok(media_id);
+            };
+            trace!("processing_info: {}", processing_info);
+            let state = processing_info
+                .get("state")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("state is not found"))?;
+            match state {
+                "succeeded" => return Ok(media_id),
+                "failed" => bail!("video processing failed: {}", processing_info),
+                _ => {}
+            }
+            let check_after_secs = processing_info
+                .get("check_after_secs")
+                .and_then(Value::as_u64)
+                .unwrap_or(1)
+                .min(MAX_CHECK_AFTER_SECS);
+            tokio::time::sleep(std::time::Duration::from_secs(check_after_secs)).await;
+            json = self.api.upload_status(&media_id).await?;
+        }
+        // NOTE: 時間をおけば処理が終わっている見込みがある
+        warn!("video processing is not finished: {}", media_id);
+        Err(ClientError::Transient.into())
     }
 }
 
@@ -53,55 +213,92 @@ impl super::Client for Client {
         None
     }
 
+    #[tracing::instrument(name = "twitter_client::Client::verify", skip_all)]
+    async fn verify(&self) -> Result<AccountInfo, ClientError> {
+        let json: Value = self.api.get_me().await?;
+        let get = |key: &str| {
+            json.get("data")
+                .and_then(|data| data.get(key))
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("data.{} is not found", key))
+        };
+        Ok(AccountInfo {
+            id: get("id")?,
+            handle: get("username")?,
+        })
+    }
+
     #[tracing::instrument(name = "twitter_client::Client::fetch_statuses", skip_all)]
-    async fn fetch_statuses(&mut self) -> Result<Vec<source::LiveStatus>> {
-        todo!()
+    async fn fetch_statuses(
+        &mut self,
+        _cursor: Option<&Cursor<'_>>,
+    ) -> Result<Vec<source::LiveStatus>, ClientError> {
+        // NOTE: 送信先としてのみ使い、取得元にはできない
+        Err(ClientError::Permanent(anyhow!(
+            "twitter is not supported as a source"
+        )))
     }
 
-    #[tracing::instrument(name = "twitter_client::Client::post", skip_all)]
-    async fn post(
+    #[tracing::instrument(name = "twitter_client::Client::get_status", skip_all)]
+    async fn get_status(
         &mut self,
-        content: &str,
-        _facets: &[store::operations::Facet],
-        reply_identifier: Option<&str>,
-        images: Vec<store::operations::Medium>,
-        _external: Option<store::operations::External>,
-        _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
-        let media = if images.is_empty() {
+        _identifier: &str,
+    ) -> Result<Option<source::LiveStatus>, ClientError> {
+        Err(ClientError::Permanent(anyhow!(
+            "twitter is not supported as a source"
+        )))
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::post", skip_all)]
+    async fn post(&mut self, post: NewPost<'_>) -> Result<String, ClientError> {
+        let media = if post.images.is_empty() {
             None
         } else {
             // TODO: alt
-            let media_ids = join_all(images.into_iter().map(|image| async {
-                let resp = self.http_client.get(image.url).send().await?;
-                let res: Value = self.api.upload(resp).await?;
-                Ok(res)
+            let media_ids = join_all(post.images.into_iter().map(|image| async {
+                let url = image.url;
+                let downloaded = download(&self.http_client, &self.media, &url).await?;
+                let content_type = downloaded.content_type.clone().unwrap_or_default();
+                if content_type.starts_with("video/") {
+                    let media_id = self.upload_video(&downloaded.bytes, &content_type).await?;
+                    metrics::uploaded_bytes(ORIGIN, downloaded.bytes.len());
+                    return Ok(media_id);
+                }
+                let downloaded = transcode(&downloaded, SUPPORTED_IMAGE_TYPES)?;
+                let len = downloaded.bytes.len();
+                let res: Value = self.api.upload(downloaded.bytes).await?;
+                metrics::uploaded_bytes(ORIGIN, len);
+                get_media_id(&res)
             }))
             .await
             .into_iter()
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .map(|res: Value| {
-                Ok(res
-                    .get("media_id_string")
-                    .ok_or_else(|| anyhow!("media_id_string is not found"))?
-                    .as_str()
-                    .ok_or_else(|| anyhow!("media_id_string is not str"))?
-                    .to_owned())
-            })
             .collect::<Result<Vec<_>>>()?;
             Some(json!({ "media_ids": media_ids }))
         };
 
+        // NOTE: 以前のスレッドの identifier では、返信は最後のツイートにぶら下げ、引用は先頭のツイートにする
+        let reply_identifier = post
+            .reply_identifier
+            .and_then(|reply_identifier| split_identifier(reply_identifier).next_back());
+        let quote_tweet_id = post
+            .quote_identifier
+            .and_then(|quote_identifier| split_identifier(quote_identifier).next());
+        // NOTE: 長い本文は split_content でスレッドに分けてから渡されるので、ここでは収まらない分を省略する
+        let (text, _) = truncate(
+            post.content,
+            post.facets,
+            MAX_TWEET_LENGTH,
+            Counting::TwitterWeighted,
+            post.src_uri,
+        );
         let body = TweetBody {
             media,
-            quote_tweet_id: None,
-            reply: reply_identifier.map(
-                |reply_identifier| serde_json::json!({ "in_reply_to_tweet_id": reply_identifier }),
-            ),
-            text: content,
+            quote_tweet_id,
+            reply: reply_identifier
+                .map(|reply_identifier| json!({ "in_reply_to_tweet_id": reply_identifier })),
+            text: &text,
         };
-
         let json: Value = self.api.create_tweet(body).await?;
         let id = json
             .get("data")
@@ -115,12 +312,38 @@ impl super::Client for Client {
         Ok(id.to_owned())
     }
 
+    fn split_content(
+        &self,
+        content: &str,
+        facets: &[store::operations::Facet],
+    ) -> Vec<(String, Vec<store::operations::Facet>)> {
+        split_into_thread(content, facets)
+    }
+
+    #[tracing::instrument(name = "twitter_client::Client::update_post", skip_all)]
+    async fn update_post(
+        &mut self,
+        identifier: &str,
+        _content: &str,
+        _facets: &[store::operations::Facet],
+    ) -> Result<String, ClientError> {
+        // NOTE: API からは編集できない
+        warn!(
+            "twitter does not support editing (identifier={})",
+            identifier
+        );
+        Ok(identifier.to_owned())
+    }
+
     #[tracing::instrument(name = "twitter_client::Client::repost", skip_all)]
     async fn repost(
         &mut self,
         target_identifier: &str,
         _created_at: &DateTime<FixedOffset>,
-    ) -> Result<String> {
+    ) -> Result<String, ClientError> {
+        let target_identifier = split_identifier(target_identifier)
+            .next()
+            .ok_or_else(|| anyhow!("identifier is empty"))?;
         let result = self
             .api
             .create_retweet_1_1::<Value>(target_identifier)
@@ -129,7 +352,8 @@ impl super::Client for Client {
             return Ok(target_identifier.into());
         };
         // 1.1 のアクセス違反の場合のみ proxy を使う
-        if !err.to_string().contains("403") {
+        let err = ClientError::classify(err);
+        if !matches!(err, ClientError::Auth) {
             return Err(err);
         }
         let json: Value = self.api.create_retweet_proxy(target_identifier).await?;
@@ -146,13 +370,20 @@ impl super::Client for Client {
     }
 
     #[tracing::instrument(name = "twitter_client::Client::delete_post", skip_all)]
-    async fn delete_post(&mut self, identifier: &str) -> Result<()> {
-        let _: Value = self.api.delete_tweet(identifier).await?;
+    async fn delete_post(&mut self, identifier: &str) -> Result<(), ClientError> {
+        // NOTE: スレッドの一部だけ消されていても、残りを消す
+        for identifier in split_identifier(identifier) {
+            let result = self.api.delete_tweet::<Value>(identifier).await;
+            ignore_not_found(
+                result.map(|_| ()).map_err(ClientError::classify),
+                identifier,
+            )?;
+        }
         Ok(())
     }
 
     #[tracing::instrument(name = "twitter_client::Client::delete_repost", skip_all)]
-    async fn delete_repost(&mut self, identifier: &str) -> Result<()> {
+    async fn delete_repost(&mut self, identifier: &str) -> Result<(), ClientError> {
         let target_identifier = identifier;
         let result = self
             .api
@@ -162,7 +393,8 @@ impl super::Client for Client {
             return Ok(());
         };
         // 1.1 のアクセス違反の場合のみ proxy を使う
-        if !err.to_string().contains("403") {
+        let err = ClientError::classify(err);
+        if !matches!(err, ClientError::Auth) {
             return Err(err);
         }
         self.api
@@ -171,3 +403,222 @@ impl super::Client for Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, Request, ResponseTemplate,
+    };
+
+    use crate::protocols::{text::create_link_facets, NewPostFixture};
+
+    use super::*;
+
+    async fn client(server: &MockServer) -> Client {
+        let http_client = Arc::new(reqwest::Client::new());
+        let api = Api::new(
+            http_client.clone(),
+            "key".into(),
+            "secret".into(),
+            "token".into(),
+            "token_secret".into(),
+        )
+        .with_origin(&server.uri());
+        for (command, body) in [
+            ("INIT", json!({ "media_id_string": "100" })),
+            ("APPEND", json!({})),
+            (
+                "FINALIZE",
+                json!({ "processing_info": { "state": "pending", "check_after_secs": 0 } }),
+            ),
+        ] {
+            Mock::given(method("POST"))
+                .and(query_param("command", command))
+                .respond_with(ResponseTemplate::new(200).set_body_json(body))
+                .mount(server)
+                .await;
+        }
+        Client {
+            http_client,
+            api,
+            media: DownloadOptions::default(),
+        }
+    }
+
+    fn processing(state: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "processing_info": { "state": state, "check_after_secs": 0 },
+        }))
+    }
+
+    #[tokio::test]
+    async fn fetching_is_an_error() {
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+
+        let result = super::super::Client::fetch_statuses(&mut client, None).await;
+        assert!(matches!(result, Err(ClientError::Permanent(_))));
+        let result = super::super::Client::get_status(&mut client, "1").await;
+        assert!(matches!(result, Err(ClientError::Permanent(_))));
+    }
+
+    #[tokio::test]
+    async fn video_processing_is_polled_until_succeeded() {
+        let server = MockServer::start().await;
+        let client = client(&server).await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(processing("in_progress"))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(processing("succeeded"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let media_id = client.upload_video(b"video", "video/mp4").await.unwrap();
+
+        assert_eq!(media_id, "100");
+    }
+
+    #[tokio::test]
+    async fn failed_video_processing_is_error() {
+        let server = MockServer::start().await;
+        let client = client(&server).await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(processing("failed"))
+            .mount(&server)
+            .await;
+
+        let err = client
+            .upload_video(b"video", "video/mp4")
+            .await
+            .unwrap_err();
+
+        assert!(!ClientError::classify(err).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn video_processing_polling_is_capped() {
+        let server = MockServer::start().await;
+        let client = client(&server).await;
+        Mock::given(method("GET"))
+            .and(query_param("command", "STATUS"))
+            .respond_with(processing("in_progress"))
+            .expect(MAX_PROCESSING_CHECKS as u64)
+            .mount(&server)
+            .await;
+
+        let err = client
+            .upload_video(b"video", "video/mp4")
+            .await
+            .unwrap_err();
+
+        assert!(matches!(ClientError::classify(err), ClientError::Transient));
+    }
+
+    #[tokio::test]
+    async fn webp_is_uploaded_as_jpeg() {
+        let fixture = NewPostFixture::default();
+        let server = MockServer::start().await;
+        let mut client = client(&server).await;
+        let mut webp = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(2, 2))
+            .write_to(&mut Cursor::new(&mut webp), ImageFormat::WebP)
+            .unwrap();
+        Mock::given(method("GET"))
+            .and(path("/image.webp"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(webp, "image/webp"))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/1.1/media/upload.json"))
+            .and(query_param("media_category", "tweet_image"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "media_id_string": "100" })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/2/tweets"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "data": { "id": "1" } })),
+            )
+            .mount(&server)
+            .await;
+        let mut post = fixture.post("hello");
+        post.images = vec![store::operations::Medium {
+            url: format!("{}/image.webp", server.uri()),
+            alt: String::new(),
+            sensitive: false,
+            focus: None,
+            content_type: None,
+        }];
+
+        super::super::Client::post(&mut client, post).await.unwrap();
+
+        let requests: Vec<Request> = server.received_requests().await.unwrap();
+        let upload = requests
+            .iter()
+            .find(|request| request.url.path() == "/1.1/media/upload.json")
+            .unwrap();
+        // NOTE: JPEG の先頭の SOI マーカー
+        assert!(upload
+            .body
+            .windows(3)
+            .any(|window| window == [0xFF, 0xD8, 0xFF]));
+        assert!(!upload.body.windows(4).any(|window| window == b"WEBP"));
+    }
+
+    #[test]
+    fn facets_are_shifted_into_each_tweet() {
+        let uri = "https://example.com/path";
+        let content = format!("{}{} {}", "word ".repeat(60), uri, "word ".repeat(60));
+        let start = content.find(uri).unwrap() as u32;
+        let facets = [Link {
+            byte_slice: start..start + uri.len() as u32,
+            uri: uri.into(),
+        }];
+
+        let tweets = split_into_thread(content.trim_end(), &facets);
+
+        assert_eq!(tweets.len(), 3);
+        let links: Vec<_> = tweets
+            .iter()
+            .flat_map(|(text, facets)| {
+                facets.iter().map(move |facet| match facet {
+                    Link { byte_slice, .. } => {
+                        &text[byte_slice.start as usize..byte_slice.end as usize]
+                    }
+                })
+            })
+            .collect();
+        assert_eq!(links, [uri]);
+    }
+
+    #[test]
+    fn long_urls_do_not_split_tweet() {
+        let content = (1..=10)
+            .map(|i| format!("https://example.com/{}/{}", i, "a".repeat(100)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let facets = create_link_facets(&content);
+        assert!(content.chars().count() > MAX_TWEET_LENGTH);
+
+        let tweets = split_into_thread(&content, &facets);
+
+        // NOTE: 10 * 23 + 9 = 239 文字
+        assert_eq!(tweets.len(), 1);
+        assert_eq!(tweets[0].0, content);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::DateTime;
+use reqwest::header::HeaderMap;
+use tokio::time::{sleep, Instant};
+use tracing::debug;
+
+use crate::{app::AccountKey, config, protocols::error::ClientError};
+
+/** 残りがこれ以下になったらリセットまで待つ */
+const LOW_REMAINING: u64 = 5;
+
+/** リセットまでこれより長く待つ場合は待たずに次回の実行に回す */
+const MAX_BUDGET_WAIT: Duration = Duration::from_secs(60);
+
+/** 送信先が返した残りのリクエスト数 */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Remaining {
+    pub remaining: u64,
+    pub reset_at: SystemTime,
+}
+
+fn get_header<'a>(headers: &'a HeaderMap, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+/**
+ * Bluesky の ratelimit-* と Mastodon の x-ratelimit-* を読む
+ *
+ * reset は Bluesky では UNIX 時間の秒、Mastodon では ISO 8601 の日時
+ */
+pub fn parse_rate_limit_headers(headers: &HeaderMap) -> Option<Remaining> {
+    let remaining = get_header(headers, &["ratelimit-remaining", "x-ratelimit-remaining"])?
+        .parse()
+        .ok()?;
+    let reset = get_header(headers, &["ratelimit-reset", "x-ratelimit-reset"])?;
+    let reset_at = if let Ok(secs) = reset.parse::<u64>() {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    } else {
+        DateTime::parse_from_rfc3339(reset).ok()?.into()
+    };
+    Some(Remaining {
+        remaining,
+        reset_at,
+    })
+}
+
+/**
+ * Client と RateLimiter で共有する、アカウントごとの残りのリクエスト数
+ *
+ * Client はレスポンスを受け取る度に更新し、RateLimiter は送信前に参照する
+ */
+#[derive(Clone, Default)]
+pub struct Budget(Arc<Mutex<Option<Remaining>>>);
+
+impl Budget {
+    pub fn update(&self, headers: &HeaderMap) {
+        if let Some(remaining) = parse_rate_limit_headers(headers) {
+            *self.0.lock().unwrap() = Some(remaining);
+        }
+    }
+
+    /** 残りが少なければリセットまでの時間を返す */
+    pub fn wait(&self, now: SystemTime) -> Option<Duration> {
+        let remaining = (*self.0.lock().unwrap())?;
+        if remaining.remaining > LOW_REMAINING {
+            return None;
+        }
+        remaining.reset_at.duration_since(now).ok()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/** アカウントごとのトークンバケット */
+pub struct RateLimiter<'a> {
+    rate_limits: &'a config::RateLimits,
+    buckets: HashMap<AccountKey, Bucket>,
+    budgets: HashMap<AccountKey, Budget>,
+}
+
+impl<'a> RateLimiter<'a> {
+    pub fn new(rate_limits: &'a config::RateLimits) -> Self {
+        Self {
+            rate_limits,
+            buckets: HashMap::new(),
+            budgets: HashMap::new(),
+        }
+    }
+
+    /** Client に渡して、レスポンスの残りのリクエスト数を記録させる */
+    pub fn budget(&mut self, account: &config::Account) -> Budget {
+        self.budgets
+            .entry(account.to_account_key())
+            .or_default()
+            .clone()
+    }
+
+    /**
+     * トークンが無い場合は補充されるまで待つ
+     *
+     * 送信先が返した残りのリクエスト数が少ない場合も、リセットされるまで待つ。
+     * リセットが遠い場合は実行時間を使い切らないように、待たずに RateLimited を返す
+     */
+    pub async fn acquire(&mut self, account: &config::Account) -> Result<(), ClientError> {
+        if let Some(wait) = self.budget(account).wait(SystemTime::now()) {
+            if wait > MAX_BUDGET_WAIT {
+                return Err(ClientError::RateLimited {
+                    retry_after: Some(wait),
+                });
+            }
+            debug!("remaining budget is low, wait {:?}", wait);
+            sleep(wait).await;
+        }
+        let rate_limit = self.rate_limits.get(account);
+        let tokens_per_sec = rate_limit.requests_per_minute / 60.0;
+        let bucket = self
+            .buckets
+            .entry(account.to_account_key())
+            .or_insert_with(|| Bucket {
+                tokens: rate_limit.burst,
+                updated_at: Instant::now(),
+            });
+        loop {
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * tokens_per_sec).min(rate_limit.burst);
+            bucket.updated_at = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return Ok(());
+            }
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / tokens_per_sec);
+            debug!("rate limited, wait {:?}", wait);
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn headers(remaining: &str, reset: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", remaining.parse().unwrap());
+        headers.insert("x-ratelimit-reset", reset.parse().unwrap());
+        headers
+    }
+
+    fn account() -> config::Account {
+        serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://example.com",
+            "accessToken": "token",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn rate_limit_headers_are_parsed() {
+        assert_eq!(
+            parse_rate_limit_headers(&headers("3", "1700000000")),
+            Some(Remaining {
+                remaining: 3,
+                reset_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            })
+        );
+        assert_eq!(
+            parse_rate_limit_headers(&headers("3", "2023-11-14T22:13:20.000Z")),
+            Some(Remaining {
+                remaining: 3,
+                reset_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            })
+        );
+        assert_eq!(parse_rate_limit_headers(&headers("3", "soon")), None);
+        assert_eq!(parse_rate_limit_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn budget_waits_only_when_remaining_is_low() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let budget = Budget::default();
+        assert_eq!(budget.wait(now), None);
+
+        budget.update(&headers("100", "1700000010"));
+        assert_eq!(budget.wait(now), None);
+
+        budget.update(&headers("1", "1700000010"));
+        assert_eq!(budget.wait(now), Some(Duration::from_secs(10)));
+        // NOTE: リセット済みなら待たない
+        assert_eq!(budget.wait(now + Duration::from_secs(20)), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_budget_wait_is_returned_as_rate_limited() {
+        let rate_limits = config::RateLimits::default();
+        let account = account();
+        let mut rate_limiter = RateLimiter::new(&rate_limits);
+        let reset_at = SystemTime::now() + Duration::from_secs(3600);
+        let reset = reset_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        rate_limiter
+            .budget(&account)
+            .update(&headers("0", &reset.to_string()));
+
+        let started_at = Instant::now();
+        let result = rate_limiter.acquire(&account).await;
+
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+        let Err(ClientError::RateLimited {
+            retry_after: Some(retry_after),
+        }) = result
+        else {
+            panic!("{:?}", result);
+        };
+        assert!(retry_after > MAX_BUDGET_WAIT, "{:?}", retry_after);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_paced_after_burst() {
+        let rate_limits: config::RateLimits = serde_json::from_value(json!({
+            "mastodon": { "requestsPerMinute": 600, "burst": 2 },
+        }))
+        .unwrap();
+        let account = account();
+        let mut rate_limiter = RateLimiter::new(&rate_limits);
+
+        let started_at = Instant::now();
+        rate_limiter.acquire(&account).await.unwrap();
+        rate_limiter.acquire(&account).await.unwrap();
+        let burst = started_at.elapsed();
+        rate_limiter.acquire(&account).await.unwrap();
+        rate_limiter.acquire(&account).await.unwrap();
+        let paced = started_at.elapsed();
+
+        // NOTE: 10 件/秒なので、バースト後の 2 件は 100ms ずつ待つ (時間は止めてあるので待った分だけ進む)
+        assert_eq!(burst, Duration::ZERO);
+        assert!(paced >= Duration::from_millis(200), "{:?}", paced);
+        assert!(paced < Duration::from_millis(210), "{:?}", paced);
+    }
+}
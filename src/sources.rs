@@ -1,3 +1,4 @@
 mod merge_operations;
 mod operation_factory;
 pub mod source;
+mod transform;
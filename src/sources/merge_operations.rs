@@ -1,45 +1,86 @@
-use tracing::warn;
+use std::collections::HashSet;
 
 use super::source::Operation;
 use crate::{
     app::AccountKey,
+    config,
     store::{
         self,
-        operations::Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
+        operations::Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, UpdatePost,
+        },
     },
 };
 
+fn is_skipped(dst: &config::Destination, operation: &Operation) -> bool {
+    match operation {
+        Operation::CreatePost(status) => dst.skip_replies && status.reply_src_identifier.is_some(),
+        Operation::CreateRepost(_) => dst.skip_reposts,
+        Operation::CreateLike(_) | Operation::DeleteLike(_) => {
+            dst.like_action() == config::LikeAction::Ignore
+        }
+        Operation::UpdatePost(_) | Operation::DeletePost(_) | Operation::DeleteRepost(_) => false,
+    }
+}
+
+/**
+ * 送信先の like_action に従って、いいねをリポストに置き換える
+ *
+ * 置き換えたリポストの src_identifier はいいねのものなので、取り消しも同じリポストに届く
+ */
+fn to_store_operation(
+    dst: &config::Destination,
+    operation: &Operation,
+    account_pair: store::operations::AccountPair,
+) -> store::operations::Operation {
+    match (operation, dst.like_action()) {
+        (Operation::CreateLike(status), config::LikeAction::Repost) => {
+            CreateRepost(store::operations::CreateRepostOperation {
+                account_pair,
+                status: store::operations::CreateRepostOperationStatus {
+                    src_identifier: status.src_identifier.clone(),
+                    target_src_identifier: status.target_src_identifier.clone(),
+                    target_src_uri: status.target_src_uri.clone(),
+                    target_src_at_uri: None,
+                    target_src_cid: None,
+                    created_at: status.created_at,
+                },
+                backfill: false,
+            })
+        }
+        (Operation::DeleteLike(status), config::LikeAction::Repost) => {
+            DeleteRepost(store::operations::DeleteRepostOperation {
+                account_pair,
+                status: store::operations::DeleteRepostOperationStatus {
+                    src_identifier: status.src_identifier.clone(),
+                },
+            })
+        }
+        _ => operation.to_store(account_pair),
+    }
+}
+
 fn to_store_operations(
-    dst_account_keys: &[AccountKey],
+    dsts: &[&config::Destination],
     operations: &[Operation],
     src_account_key: &AccountKey,
 ) -> Vec<store::operations::Operation> {
-    dst_account_keys
-        .iter()
-        .flat_map(|dst_account_key| {
+    dsts.iter()
+        .flat_map(|dst| {
             let account_pair = store::operations::AccountPair::from_keys(
                 src_account_key.clone(),
-                dst_account_key.clone(),
+                dst.account.to_account_key(),
             );
 
             operations
                 .iter()
-                .map(|operation| operation.to_store(account_pair.clone()))
+                .filter(|operation| !is_skipped(dst, operation))
+                .map(|operation| to_store_operation(dst, operation, account_pair.clone()))
                 .collect::<Vec<_>>()
         })
         .collect()
 }
 
-/** 投稿は降順で、それ以外は末尾に積む */
-fn sort_operations(operations: &mut [store::operations::Operation]) {
-    operations.sort_by_key(|operation| -match operation {
-        CreatePost(content) => content.status.created_at.timestamp_micros(),
-        CreateRepost(content) => content.status.created_at.timestamp_micros(),
-        UpdatePost(_) | DeleteRepost(_) => i64::MAX - 1,
-        DeletePost(_) => i64::MAX,
-    });
-}
-
 fn to_update_post_operation_status(
     src_operation: &Operation,
 ) -> Option<&store::operations::UpdatePostOperationStatus> {
@@ -70,6 +111,16 @@ fn to_delete_repost_operation_status(
     }
 }
 
+fn to_delete_like_operation_status(
+    src_operation: &Operation,
+) -> Option<&store::operations::DeleteLikeOperationStatus> {
+    if let Operation::DeleteLike(status) = src_operation {
+        Some(status)
+    } else {
+        None
+    }
+}
+
 fn create_operation_target_state(
     content: &store::operations::CreatePostOperation,
 ) -> (AccountKey, &str) {
@@ -79,21 +130,142 @@ fn create_operation_target_state(
     )
 }
 
+/** 送信先にその src の status を送った記録があるもの。分けて送る途中で止まったものも含む */
+pub fn has_dst_status(
+    users: &[store::user::User],
+    account_pair: &store::operations::AccountPair,
+    src_identifier: &str,
+) -> bool {
+    users
+        .iter()
+        .filter(|user| user.src.origin == account_pair.src_origin)
+        .filter(|user| user.src.identifier == account_pair.src_account_identifier)
+        .flat_map(|user| &user.dsts)
+        .filter(|dst| dst.origin == account_pair.dst_origin)
+        .filter(|dst| dst.identifier == account_pair.dst_account_identifier)
+        .flat_map(|dst| &dst.statuses)
+        .any(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(post) => post.src_identifier == src_identifier,
+            store::user::DestinationStatus::Repost(repost) => {
+                repost.src_identifier == src_identifier
+            }
+            store::user::DestinationStatus::Like(like) => like.src_identifier == src_identifier,
+        })
+}
+
 pub fn merge_operations(
     store: &mut store::Store,
-    dst_account_keys: &[AccountKey],
+    dsts: &[&config::Destination],
     src_account_key: &AccountKey,
     src_operations: &[Operation],
 ) {
-    let mut new_operations = to_store_operations(dst_account_keys, src_operations, src_account_key);
+    let mut new_operations = to_store_operations(dsts, src_operations, src_account_key);
 
     let operations = &mut store.operations;
 
     // 投稿の更新
-    src_operations
+    let updating_post_statuses: Vec<_> = src_operations
         .iter()
         .filter_map(to_update_post_operation_status)
-        .for_each(|_| warn!("もし create が未送信なら、create を書き換える必要がある"));
+        .collect();
+    // create が未送信なら create を書き換え、update は送らない
+    operations.iter_mut().for_each(|dst_operation| {
+        let CreatePost(content) = dst_operation else {
+            return;
+        };
+        if &content.account_pair.to_src_key() != src_account_key {
+            return;
+        }
+        if let Some(status) = updating_post_statuses
+            .iter()
+            .find(|status| status.src_identifier == content.status.src_identifier)
+        {
+            content.status.content = status.content.clone();
+            content.status.facets = status.facets.clone();
+            if let Some(media_alts) = &status.media_alts {
+                content
+                    .status
+                    .media
+                    .iter_mut()
+                    .zip(media_alts)
+                    .for_each(|(medium, alt)| medium.alt = alt.clone());
+            }
+        }
+    });
+    new_operations.retain(|new_operation| {
+        let UpdatePost(update) = new_operation else {
+            return true;
+        };
+        !operations.iter().any(|dst_operation| match dst_operation {
+            CreatePost(content) => {
+                content.account_pair == update.account_pair
+                    && content.status.src_identifier == update.status.src_identifier
+            }
+            CreateRepost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | CreateLike(_)
+            | DeleteLike(_) => false,
+        })
+    });
+    // 古い未送信の update は新しい update で置き換える
+    // NOTE: 新しい update で代替テキストが変わっていなければ、古い update の変更を引き継ぐ
+    new_operations.iter_mut().for_each(|new_operation| {
+        let UpdatePost(new_update) = new_operation else {
+            return;
+        };
+        if new_update.status.media_alts.is_some() {
+            return;
+        }
+        let media_alts = operations
+            .iter()
+            .find_map(|dst_operation| match dst_operation {
+                UpdatePost(update)
+                    if update.account_pair == new_update.account_pair
+                        && update.status.src_identifier == new_update.status.src_identifier =>
+                {
+                    update.status.media_alts.clone()
+                }
+                _ => None,
+            });
+        new_update.status.media_alts = media_alts;
+    });
+    operations.retain(|dst_operation| {
+        let UpdatePost(update) = dst_operation else {
+            return true;
+        };
+        !new_operations
+            .iter()
+            .any(|new_operation| match new_operation {
+                UpdatePost(new_update) => {
+                    new_update.account_pair == update.account_pair
+                        && new_update.status.src_identifier == update.status.src_identifier
+                }
+                CreatePost(_) | CreateRepost(_) | DeletePost(_) | DeleteRepost(_)
+                | CreateLike(_) | DeleteLike(_) => false,
+            })
+    });
+    // NOTE: 送信先ごとに、まだ送っていない create。削除を適用すると消えるので先に控えておく
+    //       分けて送る途中で止まったものは先頭が送信先にあるので、delete を打ち消さない
+    let pending_creates: HashSet<_> = operations
+        .iter()
+        .filter(|dst_operation| {
+            matches!(
+                dst_operation,
+                CreatePost(_) | CreateRepost(_) | CreateLike(_)
+            )
+        })
+        .filter(|dst_operation| {
+            !has_dst_status(
+                &store.users,
+                dst_operation.account_pair(),
+                dst_operation.src_identifier(),
+            )
+        })
+        .map(|dst_operation| {
+            (
+                dst_operation.account_pair().clone(),
+                dst_operation.src_identifier().to_owned(),
+            )
+        })
+        .collect();
     // 投稿の削除を適用
     let deleting_post_full_identifiers: Vec<_> = src_operations
         .iter()
@@ -112,7 +284,14 @@ pub fn merge_operations(
             );
             !deleting_post_full_identifiers.contains(&operation_full_identifier)
         }
-        UpdatePost(_) | DeletePost(_) | DeleteRepost(_) => true,
+        CreateLike(content) => {
+            let operation_full_identifier = (
+                content.account_pair.to_src_key(),
+                content.status.target_src_identifier.as_str(),
+            );
+            !deleting_post_full_identifiers.contains(&operation_full_identifier)
+        }
+        UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | DeleteLike(_) => true,
     });
     // repost の削除を適用
     let deleting_repost_full_identifiers: Vec<_> = src_operations
@@ -128,9 +307,305 @@ pub fn merge_operations(
             );
             !deleting_repost_full_identifiers.contains(&operation_full_identifier)
         }
-        CreatePost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) => true,
+        CreatePost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | CreateLike(_)
+        | DeleteLike(_) => true,
+    });
+    // いいねの取り消しを適用
+    let deleting_like_full_identifiers: Vec<_> = src_operations
+        .iter()
+        .filter_map(to_delete_like_operation_status)
+        .map(|status| (src_account_key.clone(), status.src_identifier.as_str()))
+        .collect();
+    operations.retain(|dst_operation| match dst_operation {
+        CreateLike(content) => {
+            let operation_full_identifier = (
+                content.account_pair.to_src_key(),
+                content.status.src_identifier.as_str(),
+            );
+            !deleting_like_full_identifiers.contains(&operation_full_identifier)
+        }
+        // NOTE: like_action でリポストに置き換えたもの
+        CreateRepost(content) => {
+            let operation_full_identifier = (
+                content.account_pair.to_src_key(),
+                content.status.src_identifier.as_str(),
+            );
+            !deleting_like_full_identifiers.contains(&operation_full_identifier)
+        }
+        CreatePost(_) | UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | DeleteLike(_) => true,
+    });
+
+    // 未送信の create を取り消したものは、送信先に何も無いので delete も積まない
+    new_operations.retain(|new_operation| match new_operation {
+        DeletePost(_) | DeleteRepost(_) | DeleteLike(_) => !pending_creates.contains(&(
+            new_operation.account_pair().clone(),
+            new_operation.src_identifier().to_owned(),
+        )),
+        CreatePost(_) | CreateRepost(_) | UpdatePost(_) | CreateLike(_) => true,
     });
 
-    operations.append(&mut new_operations);
-    sort_operations(operations);
+    // 未送信の operation と同じものは積まない
+    new_operations.retain(|new_operation| {
+        !operations
+            .iter()
+            .any(|dst_operation| dst_operation.is_equivalent(new_operation))
+    });
+
+    operations.extend(new_operations);
+    store.sort_operations();
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn dst(skip_replies: bool, skip_reposts: bool) -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": "mastodon",
+            "origin": "https://dst.example.com",
+            "accessToken": "dst",
+            "skipReplies": skip_replies,
+            "skipReposts": skip_reposts,
+        }))
+        .unwrap()
+    }
+
+    fn src_operations() -> Vec<Operation> {
+        let post = store::operations::CreatePostOperation::test("1", "hello").status;
+        let reply = store::operations::CreatePostOperationStatus {
+            reply_src_identifier: Some("1".into()),
+            ..store::operations::CreatePostOperation::test("2", "reply").status
+        };
+        let repost =
+            store::operations::CreateRepostOperation::test("3", "https://src.example.com/other")
+                .status;
+        vec![
+            Operation::CreatePost(post),
+            Operation::CreatePost(reply),
+            Operation::CreateRepost(repost),
+        ]
+    }
+
+    fn merged_src_identifiers(dst: &config::Destination) -> Vec<String> {
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+        merge_operations(&mut store, &[dst], &src_account_key, &src_operations());
+        store
+            .operations
+            .iter()
+            .map(|operation| operation.src_identifier().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn all_operations_are_merged_by_default() {
+        assert_eq!(merged_src_identifiers(&dst(false, false)), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn replies_are_skipped() {
+        assert_eq!(merged_src_identifiers(&dst(true, false)), ["1", "3"]);
+    }
+
+    #[test]
+    fn reposts_are_skipped() {
+        assert_eq!(merged_src_identifiers(&dst(false, true)), ["1", "2"]);
+    }
+
+    #[test]
+    fn remerging_does_not_grow_queue() {
+        let dst = dst(false, false);
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+        merge_operations(&mut store, &[&dst], &src_account_key, &src_operations());
+        let len = store.operations.len();
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &src_operations());
+
+        assert_eq!(store.operations.len(), len);
+    }
+
+    fn delete_post(src_identifier: &str) -> Operation {
+        Operation::DeletePost(store::operations::DeletePostOperation::test(src_identifier).status)
+    }
+
+    #[test]
+    fn pending_create_and_its_delete_cancel_each_other() {
+        let dst = dst(false, false);
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+        merge_operations(&mut store, &[&dst], &src_account_key, &src_operations());
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &[delete_post("1")]);
+
+        let src_identifiers: Vec<_> = store
+            .operations
+            .iter()
+            .map(|operation| operation.src_identifier())
+            .collect();
+        assert_eq!(src_identifiers, ["2", "3"]);
+    }
+
+    #[test]
+    fn delete_is_kept_when_create_was_already_sent() {
+        let dst = dst(false, false);
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+        merge_operations(&mut store, &[&dst], &src_account_key, &src_operations());
+        // NOTE: 先頭の投稿だけ送信済み
+        store
+            .operations
+            .retain(|operation| operation.src_identifier() != "1");
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &[delete_post("1")]);
+
+        let operations: Vec<_> = store
+            .operations
+            .iter()
+            .map(|operation| (operation.kind(), operation.src_identifier()))
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                ("delete_post", "1"),
+                ("create_post", "2"),
+                ("create_repost", "3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_is_kept_when_thread_was_partially_sent() {
+        let dst = dst(false, false);
+        let mut store = store::Store::default();
+        let account_pair = store::operations::AccountPair::test();
+        let src_account_key = account_pair.to_src_key();
+        merge_operations(&mut store, &[&dst], &src_account_key, &src_operations());
+        // NOTE: 先頭の投稿は送信済みで、続きを送る create が残っている
+        store.users.push(store::user::User {
+            src: store::user::Source {
+                origin: account_pair.src_origin.clone(),
+                identifier: account_pair.src_account_identifier.clone(),
+                session: None,
+                cursor: None,
+                statuses: Vec::new(),
+            },
+            dsts: vec![store::user::Destination {
+                origin: account_pair.dst_origin.clone(),
+                identifier: account_pair.dst_account_identifier.clone(),
+                session: None,
+                statuses: vec![store::user::DestinationStatus::Post(
+                    store::user::DestinationPost {
+                        identifier: "dst-1".into(),
+                        src_identifier: "1".into(),
+                        src_uri: "https://src.example.com/1".into(),
+                        follow_up_identifiers: vec!["dst-1-2".into()],
+                    },
+                )],
+            }],
+        });
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &[delete_post("1")]);
+
+        assert_eq!(
+            merged_kinds(&store),
+            [
+                ("delete_post", "1"),
+                ("create_post", "2"),
+                ("create_repost", "3"),
+            ]
+        );
+    }
+
+    fn like_dst(protocol: &str, like_as: Option<&str>) -> config::Destination {
+        serde_json::from_value(json!({
+            "protocol": protocol,
+            "origin": "https://dst.example.com",
+            "identifier": "dst.example.com",
+            "password": "dst",
+            "accessToken": "dst",
+            "likeAs": like_as,
+        }))
+        .unwrap()
+    }
+
+    fn like(src_identifier: &str) -> Operation {
+        Operation::CreateLike(
+            store::operations::CreateLikeOperation::test(
+                src_identifier,
+                "https://src.example.com/other",
+            )
+            .status,
+        )
+    }
+
+    fn unlike(src_identifier: &str) -> Operation {
+        Operation::DeleteLike(store::operations::DeleteLikeOperation::test(src_identifier).status)
+    }
+
+    fn merged_kinds(store: &store::Store) -> Vec<(&'static str, &str)> {
+        store
+            .operations
+            .iter()
+            .map(|operation| (operation.kind(), operation.src_identifier()))
+            .collect()
+    }
+
+    #[test]
+    fn like_is_mirrored_as_like() {
+        for dst in [like_dst("atproto", None), like_dst("atproto", Some("like"))] {
+            let mut store = store::Store::default();
+            let src_account_key = store::operations::AccountPair::test().to_src_key();
+
+            merge_operations(&mut store, &[&dst], &src_account_key, &[like("4")]);
+
+            assert_eq!(merged_kinds(&store), [("create_like", "4")]);
+        }
+    }
+
+    #[test]
+    fn like_is_ignored() {
+        for dst in [
+            like_dst("mastodon", None),
+            like_dst("atproto", Some("ignore")),
+        ] {
+            let mut store = store::Store::default();
+            let src_account_key = store::operations::AccountPair::test().to_src_key();
+
+            merge_operations(
+                &mut store,
+                &[&dst],
+                &src_account_key,
+                &[like("4"), unlike("5")],
+            );
+
+            assert!(store.operations.is_empty());
+        }
+    }
+
+    #[test]
+    fn like_is_mirrored_as_repost() {
+        let dst = like_dst("mastodon", Some("repost"));
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &[like("4")]);
+
+        assert_eq!(merged_kinds(&store), [("create_repost", "4")]);
+    }
+
+    #[test]
+    fn unlike_is_kept_when_like_was_already_sent() {
+        let dst = like_dst("atproto", None);
+        let mut store = store::Store::default();
+        let src_account_key = store::operations::AccountPair::test().to_src_key();
+        merge_operations(&mut store, &[&dst], &src_account_key, &[like("4")]);
+        store.operations.clear();
+
+        merge_operations(&mut store, &[&dst], &src_account_key, &[unlike("4")]);
+
+        assert_eq!(merged_kinds(&store), [("delete_like", "4")]);
+    }
 }
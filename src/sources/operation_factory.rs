@@ -1,25 +1,23 @@
+use std::collections::HashSet;
+
 use anyhow::Result;
 use futures::future::join_all;
-use tracing::warn;
+use tracing::{debug, warn};
 
-use crate::store::{
-    self,
-    operations::{DeleteRepostOperationStatus, Facet::Link},
-    user::SourceStatus,
+use crate::{
+    config,
+    protocols::ogp::fetch_external,
+    store::{
+        self,
+        operations::{DeleteLikeOperationStatus, DeleteRepostOperationStatus, Facet::Link},
+        user::SourceStatus,
+    },
 };
 
-use super::source::{LiveExternal, LiveStatus, Operation};
-
-async fn fetch_html(http_client: &reqwest::Client, uri: String) -> Result<webpage::HTML> {
-    let text = http_client
-        .get(&uri)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-    Ok(webpage::HTML::from_string(text, Some(uri))?)
-}
+use super::{
+    source::{LiveExternal, LiveStatus, Operation},
+    transform::{apply_transforms, create_transforms, Transform},
+};
 
 async fn create_external(
     facets: &[store::operations::Facet],
@@ -27,29 +25,65 @@ async fn create_external(
 ) -> Result<Option<store::operations::External>> {
     for facet in facets {
         match facet {
-            Link { byte_slice: _, uri } => {
-                let html = match fetch_html(http_client, uri.clone()).await {
-                    Ok(ok) => ok,
-                    Err(err) => {
-                        warn!("extract external from facet failed: {}", err);
-                        continue;
-                    }
-                };
-                return Ok(Some(store::operations::External {
-                    uri: uri.clone(),
-                    title: html.title.unwrap_or_default(),
-                    description: html.description.unwrap_or_default(),
-                    thumb_url: html.opengraph.images.first().map(|g| g.url.clone()),
-                }));
-            }
+            Link { byte_slice: _, uri } => match fetch_external(http_client, uri, None).await {
+                Ok(external) => return Ok(Some(external)),
+                Err(err) => {
+                    warn!("extract external from facet failed: {}", err);
+                    continue;
+                }
+            },
         }
     }
     Ok(None)
 }
 
-async fn try_into_operation(live: LiveStatus, http_client: &reqwest::Client) -> Result<Operation> {
+/**
+ * 行頭か空白の後にある # から始まるハッシュタグ
+ *
+ * URL のフラグメントなどを誤検出しないよう、リンクの範囲にあるものは除く
+ */
+fn hashtags<'a>(
+    content: &'a str,
+    facets: &'a [store::operations::Facet],
+) -> impl Iterator<Item = &'a str> {
+    let in_link = move |idx: usize| {
+        facets.iter().any(|facet| match facet {
+            Link { byte_slice, .. } => byte_slice.contains(&(idx as u32)),
+        })
+    };
+    content.match_indices('#').filter_map(move |(idx, _)| {
+        let preceded_by_space = content[..idx]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace);
+        if !preceded_by_space || in_link(idx) {
+            return None;
+        }
+        let tag = &content[idx + 1..];
+        let len = tag
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(tag.len());
+        (len > 0).then_some(&tag[..len])
+    })
+}
+
+/** 大文字小文字は区別しない。ハッシュタグはタグ全体が一致するものだけ */
+fn is_blocked(content: &str, facets: &[store::operations::Facet], blocklist: &[String]) -> bool {
+    let lower_content = content.to_lowercase();
+    blocklist.iter().any(|word| match word.strip_prefix('#') {
+        Some(tag) => hashtags(content, facets).any(|x| x.to_lowercase() == tag.to_lowercase()),
+        None => lower_content.contains(&word.to_lowercase()),
+    })
+}
+
+async fn try_into_operation(
+    live: LiveStatus,
+    http_client: &reqwest::Client,
+    transforms: &[Box<dyn Transform>],
+) -> Result<Operation> {
     Ok(match live {
-        LiveStatus::Post(post) => {
+        LiveStatus::Post(mut post) => {
+            apply_transforms(transforms, &mut post);
             let external = match post.external {
                 LiveExternal::Some(external) => Some(external),
                 LiveExternal::None => None,
@@ -63,21 +97,41 @@ async fn try_into_operation(live: LiveStatus, http_client: &reqwest::Client) ->
                 reply_src_identifier: post.reply_src_identifier,
                 media: post.media,
                 external,
+                content_warning: post.content_warning,
+                poll: post.poll,
+                quote: post.quote,
+                custom_emojis: post.custom_emojis,
+                reply_deferrals: 0,
                 created_at: post.created_at,
             })
         }
         LiveStatus::Repost(repost) => Operation::CreateRepost(repost),
+        LiveStatus::Like(like) => Operation::CreateLike(like),
     })
 }
 
+/** 取得済みかどうかに関わらず、1 つの status から operation を作る */
+pub async fn create_operation(
+    http_client: &reqwest::Client,
+    config_user: &config::User,
+    live: LiveStatus,
+) -> Result<Operation> {
+    let transforms = create_transforms(config_user)?;
+    try_into_operation(live, http_client, &transforms).await
+}
+
 pub async fn create_operations(
     http_client: &reqwest::Client,
+    config_user: &config::User,
     live_statuses: &[LiveStatus],
     stored_statuses: &[store::user::SourceStatus],
+    mirrored_identifiers: &HashSet<String>,
+    own_reply_targets: &HashSet<String>,
 ) -> Result<Vec<Operation>> {
     if live_statuses.is_empty() || stored_statuses.is_empty() {
         return Ok(vec![]);
     }
+    let transforms = create_transforms(config_user)?;
     // C
     let last_date_time = stored_statuses
         .iter()
@@ -86,23 +140,44 @@ pub async fn create_operations(
     let c = live_statuses
         .iter()
         .filter(|live| {
-            last_date_time.map_or(true, |last_date_time| live.created_at() > last_date_time)
+            last_date_time.is_none_or(|last_date_time| live.created_at() > last_date_time)
         })
-        .filter(|live| {
-            if let LiveStatus::Post(post) = live {
-                // 自分宛てのリプライのみを投稿対象にする
-                if let Some(reply_src_identifier) = &post.reply_src_identifier {
-                    return live_statuses.iter().any(|live| match live {
-                        LiveStatus::Post(post) => &post.identifier == reply_src_identifier,
-                        LiveStatus::Repost(_) => false,
-                    });
+        .filter(|live| match live {
+            LiveStatus::Post(post) => {
+                !is_blocked(&post.content, &post.facets, &config_user.blocklist)
+            }
+            LiveStatus::Repost(_) | LiveStatus::Like(_) => true,
+        })
+        .filter_map(|live| {
+            let LiveStatus::Post(post) = live else {
+                return Some(live.clone());
+            };
+            let Some(reply_src_identifier) = &post.reply_src_identifier else {
+                return Some(live.clone());
+            };
+            let is_self_thread = live_statuses.iter().any(|live| match live {
+                LiveStatus::Post(post) => &post.identifier == reply_src_identifier,
+                LiveStatus::Repost(_) | LiveStatus::Like(_) => false,
+            }) || stored_statuses.iter().any(|stored| match stored {
+                SourceStatus::Post(post) => &post.identifier == reply_src_identifier,
+                SourceStatus::Repost(_) | SourceStatus::Like(_) => false,
+            }) || own_reply_targets.contains(reply_src_identifier);
+            match config_user.reply_policy {
+                config::ReplyPolicy::All if !is_self_thread => {
+                    // NOTE: 返信先は送信先に無いので、返信先を外して通常の投稿にする
+                    let mut post = post.clone();
+                    post.reply_src_identifier = None;
+                    Some(LiveStatus::Post(post))
                 }
+                config::ReplyPolicy::All => Some(live.clone()),
+                config::ReplyPolicy::SelfThreadOnly => is_self_thread.then(|| live.clone()),
+                config::ReplyPolicy::None => None,
             }
-            true
         })
-        .map(|live| try_into_operation(live.clone(), http_client));
+        .map(|live| try_into_operation(live, http_client, &transforms));
     let c = join_all(c).await.into_iter().collect::<Result<Vec<_>>>()?;
     // UD
+    // NOTE: 取得できた範囲より古いものは、消えたのか取得件数から溢れただけなのか区別できないので対象にしない
     let since = &live_statuses
         .iter()
         .min_by_key(|status| status.created_at())
@@ -117,37 +192,54 @@ pub async fn create_operations(
                     .iter()
                     .filter_map(|live| match live {
                         LiveStatus::Post(live) => Some(live),
-                        LiveStatus::Repost(_) => None,
+                        LiveStatus::Repost(_) | LiveStatus::Like(_) => None,
                     })
                     .find(|live| live.identifier == post.identifier);
                 if let Some(live) = live {
-                    if live.content == post.content {
+                    let live_media_alts: Vec<_> =
+                        live.media.iter().map(|medium| medium.alt.clone()).collect();
+                    // NOTE: メディアの増減は送信先に反映できないので、数が同じ場合だけ比べる
+                    let is_alt_changed = post.media_alts.as_ref().is_some_and(|media_alts| {
+                        media_alts.len() == live_media_alts.len() && *media_alts != live_media_alts
+                    });
+                    if live.content == post.content && !is_alt_changed {
                         return None;
                     }
+                    // NOTE: 保存している本文は加工前のものなので、比べた後に加工する
+                    let mut live = live.clone();
+                    apply_transforms(&transforms, &mut live);
                     Some(Operation::UpdatePost(
                         store::operations::UpdatePostOperationStatus {
-                            src_identifier: live.identifier.clone(),
-                            content: live.content.clone(),
-                            facets: live.facets.clone(),
+                            src_identifier: live.identifier,
+                            content: live.content,
+                            facets: live.facets,
+                            media_alts: is_alt_changed.then_some(live_media_alts),
+                            src_uri: live.uri,
+                            custom_emojis: live.custom_emojis,
+                            quote: live.quote,
                         },
                     ))
-                } else {
+                } else if stored.created_at() > since {
                     Some(Operation::DeletePost(
                         store::operations::DeletePostOperationStatus {
                             src_identifier: post.identifier.clone(),
                         },
                     ))
+                } else {
+                    // NOTE: 境界と同じ日時のものは取得件数から溢れただけかもしれない
+                    None
                 }
             }
             store::user::SourceStatus::Repost(repost) => {
                 let live = live_statuses
                     .iter()
                     .filter_map(|live| match live {
-                        LiveStatus::Post(_) => None,
+                        LiveStatus::Post(_) | LiveStatus::Like(_) => None,
                         LiveStatus::Repost(repost) => Some(repost),
                     })
                     .find(|live| live.src_identifier == repost.identifier);
-                if live.is_some() {
+                // NOTE: 境界と同じ日時のものは取得件数から溢れただけかもしれない
+                if live.is_some() || stored.created_at() <= since {
                     None
                 } else {
                     Some(Operation::DeleteRepost(DeleteRepostOperationStatus {
@@ -155,7 +247,144 @@ pub async fn create_operations(
                     }))
                 }
             }
+            store::user::SourceStatus::Like(like) => {
+                let live = live_statuses
+                    .iter()
+                    .filter_map(|live| match live {
+                        LiveStatus::Post(_) | LiveStatus::Repost(_) => None,
+                        LiveStatus::Like(like) => Some(like),
+                    })
+                    .find(|live| live.src_identifier == like.identifier);
+                // NOTE: 境界と同じ日時のものは取得件数から溢れただけかもしれない
+                if live.is_some() || stored.created_at() <= since {
+                    None
+                } else {
+                    Some(Operation::DeleteLike(DeleteLikeOperationStatus {
+                        src_identifier: like.identifier.clone(),
+                    }))
+                }
+            }
         });
 
-    Ok(c.into_iter().chain(ud).collect())
+    // NOTE: 他の src から送ったものを送り返さない
+    Ok(c.into_iter()
+        .chain(ud)
+        .filter(|operation| {
+            let is_mirrored = mirrored_identifiers.contains(operation.src_identifier());
+            if is_mirrored {
+                debug!("skip mirrored status: {}", operation.src_identifier());
+            }
+            !is_mirrored
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{protocols::text::create_link_facets, sources::source::LivePost};
+
+    use super::*;
+
+    fn blocked(content: &str, blocklist: &[&str]) -> bool {
+        let blocklist: Vec<_> = blocklist.iter().map(|&word| word.to_owned()).collect();
+        is_blocked(content, &create_link_facets(content), &blocklist)
+    }
+
+    #[test]
+    fn blocklisted_hashtag_is_blocked() {
+        assert!(blocked("hello #NoCrosspost", &["#nocrosspost"]));
+        assert!(blocked("#nocrosspost\nhello", &["#nocrosspost"]));
+        assert!(blocked("hello NoCrosspost world", &["nocrosspost"]));
+    }
+
+    #[test]
+    fn partial_hashtag_is_not_blocked() {
+        assert!(!blocked("hello #nocrosspostplease", &["#nocrosspost"]));
+        assert!(!blocked("hello a#nocrosspost", &["#nocrosspost"]));
+        assert!(!blocked(
+            "see https://example.com/#nocrosspost",
+            &["#nocrosspost"]
+        ));
+    }
+
+    fn user(reply_policy: &str) -> config::User {
+        serde_json::from_value(json!({
+            "src": { "protocol": "mastodon", "origin": "https://a.example.com", "accessToken": "a" },
+            "dsts": [{ "protocol": "mastodon", "origin": "https://b.example.com", "accessToken": "b" }],
+            "replyPolicy": reply_policy,
+        }))
+        .unwrap()
+    }
+
+    fn reply(identifier: &str, reply_src_identifier: &str, created_at: &str) -> LiveStatus {
+        LiveStatus::Post(LivePost {
+            reply_src_identifier: Some(reply_src_identifier.into()),
+            ..LivePost::test(identifier, "reply", created_at)
+        })
+    }
+
+    /** 作られた CreatePost の identifier と返信先 */
+    async fn created_replies(reply_policy: &str) -> Vec<(String, Option<String>)> {
+        let stored: Vec<SourceStatus> =
+            vec![LiveStatus::Post(LivePost::test("1", "hello", "2024-01-01T00:00:00Z")).into()];
+        let live_statuses = vec![
+            // NOTE: 取得範囲より前の自分の投稿への返信。get_status で返信先を調べたもの
+            reply("4", "old", "2024-01-04T00:00:00Z"),
+            reply("3", "stranger", "2024-01-03T00:00:00Z"),
+            reply("2", "1", "2024-01-02T00:00:00Z"),
+            LiveStatus::Post(LivePost::test("1", "hello", "2024-01-01T00:00:00Z")),
+        ];
+        let own_reply_targets = HashSet::from(["old".to_owned()]);
+
+        let operations = create_operations(
+            &reqwest::Client::new(),
+            &user(reply_policy),
+            &live_statuses,
+            &stored,
+            &HashSet::new(),
+            &own_reply_targets,
+        )
+        .await
+        .unwrap();
+
+        operations
+            .into_iter()
+            .map(|operation| match operation {
+                Operation::CreatePost(status) => {
+                    (status.src_identifier, status.reply_src_identifier)
+                }
+                _ => panic!("unexpected operation"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_self_replies_are_included_for_self_thread_only() {
+        assert_eq!(
+            created_replies("selfThreadOnly").await,
+            [
+                ("4".to_owned(), Some("old".to_owned())),
+                ("2".to_owned(), Some("1".to_owned())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_to_stranger_is_detached_when_all_are_included() {
+        assert_eq!(
+            created_replies("all").await,
+            [
+                ("4".to_owned(), Some("old".to_owned())),
+                ("3".to_owned(), None),
+                ("2".to_owned(), Some("1".to_owned())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_replies_are_included() {
+        assert!(created_replies("none").await.is_empty());
+    }
 }
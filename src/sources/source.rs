@@ -1,24 +1,31 @@
 use std::{
-    convert::Into,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
 };
 
-use anyhow::Result;
-use chrono::{DateTime, FixedOffset};
-use tracing::trace;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use serde_json::Value;
+use tracing::{debug, trace, warn};
 
 use crate::{
     app::AccountKey,
     config,
-    protocols::{create_client, Client},
+    protocols::{create_client, retry::RetryPolicy, Client, Cursor},
+    rate_limit::Budget,
     store::{
         self,
-        operations::Operation::{CreatePost, CreateRepost, DeletePost, DeleteRepost, UpdatePost},
-        user::SourceStatus::{Post, Repost},
+        operations::Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, UpdatePost,
+        },
+        user::SourceStatus::{Like, Post, Repost},
     },
 };
 
-use super::{merge_operations::merge_operations, operation_factory::create_operations};
+use super::{
+    merge_operations::{has_dst_status, merge_operations},
+    operation_factory::{create_operation, create_operations},
+};
 
 #[derive(Clone, Debug)]
 pub enum LiveExternal {
@@ -36,27 +43,68 @@ pub struct LivePost {
     pub reply_src_identifier: Option<String>,
     pub media: Vec<store::operations::Medium>,
     pub external: LiveExternal,
+    pub content_warning: Option<String>,
+    pub poll: Option<store::operations::Poll>,
+    /** 本文付きの引用。引用元へのリンクは本文に含めない */
+    pub quote: Option<store::operations::Quote>,
+    /** 本文中の :shortcode: のうちカスタム絵文字のもの */
+    pub custom_emojis: Vec<String>,
     pub created_at: DateTime<FixedOffset>,
 }
 
+#[cfg(test)]
+impl LivePost {
+    /** テスト用の本文だけの投稿 */
+    pub fn test(identifier: &str, content: &str, created_at: &str) -> Self {
+        Self {
+            identifier: identifier.into(),
+            uri: format!("https://src.example.com/{}", identifier),
+            content: content.into(),
+            facets: Vec::new(),
+            reply_src_identifier: None,
+            media: Vec::new(),
+            external: LiveExternal::None,
+            content_warning: None,
+            poll: None,
+            quote: None,
+            custom_emojis: Vec::new(),
+            created_at: DateTime::parse_from_rfc3339(created_at).unwrap(),
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum LiveStatus {
     Post(LivePost),
     Repost(store::operations::CreateRepostOperationStatus),
+    Like(store::operations::CreateLikeOperationStatus),
 }
 
 impl LiveStatus {
+    pub fn identifier(&self) -> &str {
+        match self {
+            LiveStatus::Post(post) => &post.identifier,
+            LiveStatus::Repost(repost) => &repost.src_identifier,
+            LiveStatus::Like(like) => &like.src_identifier,
+        }
+    }
+
     pub fn created_at(&self) -> &DateTime<FixedOffset> {
         match self {
             LiveStatus::Post(LivePost { created_at, .. })
             | LiveStatus::Repost(store::operations::CreateRepostOperationStatus {
                 created_at,
                 ..
+            })
+            | LiveStatus::Like(store::operations::CreateLikeOperationStatus {
+                created_at, ..
             }) => created_at,
         }
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Operation {
     CreatePost(store::operations::CreatePostOperationStatus),
@@ -64,9 +112,23 @@ pub enum Operation {
     UpdatePost(store::operations::UpdatePostOperationStatus),
     DeletePost(store::operations::DeletePostOperationStatus),
     DeleteRepost(store::operations::DeleteRepostOperationStatus),
+    CreateLike(store::operations::CreateLikeOperationStatus),
+    DeleteLike(store::operations::DeleteLikeOperationStatus),
 }
 
 impl Operation {
+    pub fn src_identifier(&self) -> &str {
+        match self {
+            Operation::CreatePost(status) => &status.src_identifier,
+            Operation::CreateRepost(status) => &status.src_identifier,
+            Operation::UpdatePost(status) => &status.src_identifier,
+            Operation::DeletePost(status) => &status.src_identifier,
+            Operation::DeleteRepost(status) => &status.src_identifier,
+            Operation::CreateLike(status) => &status.src_identifier,
+            Operation::DeleteLike(status) => &status.src_identifier,
+        }
+    }
+
     pub fn to_store(
         &self,
         account_pair: store::operations::AccountPair,
@@ -75,11 +137,13 @@ impl Operation {
             Operation::CreatePost(status) => CreatePost(store::operations::CreatePostOperation {
                 account_pair,
                 status: status.clone(),
+                backfill: false,
             }),
             Operation::CreateRepost(status) => {
                 CreateRepost(store::operations::CreateRepostOperation {
                     account_pair,
                     status: status.clone(),
+                    backfill: false,
                 })
             }
             Operation::UpdatePost(status) => UpdatePost(store::operations::UpdatePostOperation {
@@ -96,126 +160,1071 @@ impl Operation {
                     status: status.clone(),
                 })
             }
+            Operation::CreateLike(status) => CreateLike(store::operations::CreateLikeOperation {
+                account_pair,
+                status: status.clone(),
+                backfill: false,
+            }),
+            Operation::DeleteLike(status) => DeleteLike(store::operations::DeleteLikeOperation {
+                account_pair,
+                status: status.clone(),
+            }),
         }
     }
 }
 
+/**
+ * 取得結果より古い保存済みの status は引き継ぐ
+ *
+ * sinceId などで差分だけを取得するクライアントでも、返信先や削除の解決に必要な status が残るようにする。
+ * 取得結果は削除の検出に使うので全て残し、それより古いものは config_user の保存期間の分だけ残す
+ */
+fn merge_statuses(
+    config_user: &config::User,
+    live_statuses: Vec<LiveStatus>,
+    stored_statuses: &[store::user::SourceStatus],
+) -> Vec<store::user::SourceStatus> {
+    let since = live_statuses
+        .iter()
+        .map(LiveStatus::created_at)
+        .min()
+        .copied();
+    let expires_at: Option<DateTime<FixedOffset>> = config_user
+        .max_stored_status_age_days
+        .map(|days| (Utc::now() - Duration::days(days as i64)).into());
+    let older_statuses = stored_statuses
+        .iter()
+        .filter(|stored| since.is_none_or(|since| stored.created_at() < &since))
+        .filter(|stored| expires_at.is_none_or(|expires_at| stored.created_at() >= &expires_at))
+        .take(
+            config_user
+                .max_stored_statuses
+                .saturating_sub(live_statuses.len()),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    live_statuses
+        .into_iter()
+        .map(Into::into)
+        .chain(older_statuses)
+        .collect()
+}
+
+/**
+ * 新しいリプライのうち、手元に無い返信先が自分の status かどうかを個別に取得して確かめる
+ *
+ * 取得範囲より古い自分の投稿へのリプライを、他人へのリプライと区別するために使う
+ */
+async fn find_own_reply_targets(
+    src_client: &mut dyn Client,
+    live_statuses: &[LiveStatus],
+    stored_statuses: &[store::user::SourceStatus],
+) -> HashSet<String> {
+    let Some(last_date_time) = stored_statuses
+        .iter()
+        .map(|stored| stored.created_at())
+        .max()
+    else {
+        return HashSet::new();
+    };
+    let known: HashSet<_> = live_statuses
+        .iter()
+        .filter_map(|live| match live {
+            LiveStatus::Post(post) => Some(post.identifier.as_str()),
+            LiveStatus::Repost(_) | LiveStatus::Like(_) => None,
+        })
+        .chain(stored_statuses.iter().filter_map(|stored| match stored {
+            Post(post) => Some(post.identifier.as_str()),
+            Repost(_) | Like(_) => None,
+        }))
+        .collect();
+    let reply_targets: HashSet<_> = live_statuses
+        .iter()
+        .filter(|live| live.created_at() > last_date_time)
+        .filter_map(|live| match live {
+            LiveStatus::Post(post) => post.reply_src_identifier.as_deref(),
+            LiveStatus::Repost(_) | LiveStatus::Like(_) => None,
+        })
+        .filter(|reply| !known.contains(reply))
+        .collect();
+    let mut own_reply_targets = HashSet::new();
+    for reply in reply_targets {
+        match src_client.get_status(reply).await {
+            Ok(Some(_)) => {
+                own_reply_targets.insert(reply.to_owned());
+            }
+            Ok(None) => {}
+            Err(err) => warn!("get reply target failed: {:?}", err),
+        }
+    }
+    own_reply_targets
+}
+
 async fn fetch_statuses(
     src_client: &mut dyn Client,
     http_client: &reqwest::Client,
+    config_user: &config::User,
     src_statuses: &[store::user::SourceStatus],
-) -> Result<(Vec<store::user::SourceStatus>, Vec<Operation>)> {
-    let live_statuses = src_client.fetch_statuses().await?;
+    cursor: Option<&str>,
+    mirrored_identifiers: &HashSet<String>,
+) -> Result<(
+    Vec<store::user::SourceStatus>,
+    Vec<Operation>,
+    Option<String>,
+)> {
+    // NOTE: cursor の status が保存されていなければ、最新のページだけを取得する
+    let cursor = cursor.and_then(|identifier| {
+        src_statuses
+            .iter()
+            .find(|stored| stored.identifier() == identifier)
+            .map(|stored| Cursor {
+                identifier,
+                created_at: stored.created_at(),
+            })
+    });
+    let mut live_statuses = src_client.fetch_statuses(cursor.as_ref()).await?;
+    // NOTE: 新しいものは無かったことにして、次回以降の実行で改めて扱う。
+    //       初回は既存の status を送らないので、全て保存しておく
+    if config_user.min_age_seconds > 0 && !src_statuses.is_empty() {
+        let threshold: DateTime<FixedOffset> =
+            (Utc::now() - Duration::seconds(config_user.min_age_seconds as i64)).into();
+        live_statuses.retain(|live| live.created_at() <= &threshold);
+    }
+    // NOTE: 後回しにしたものより先に進めると、次回に遡らなくなるので残したものの最新にする
+    let next_cursor = live_statuses
+        .first()
+        .map(|live| live.identifier().to_owned());
 
-    let operations = create_operations(http_client, &live_statuses, src_statuses).await?;
-    let statuses: Vec<_> = live_statuses.into_iter().map(Into::into).collect();
-    Ok((statuses, operations))
+    // NOTE: リプライを転送しない場合は、返信先を調べる必要が無い
+    let own_reply_targets = if config_user.reply_policy == config::ReplyPolicy::None {
+        HashSet::new()
+    } else {
+        find_own_reply_targets(src_client, &live_statuses, src_statuses).await
+    };
+    let operations = create_operations(
+        http_client,
+        config_user,
+        &live_statuses,
+        src_statuses,
+        mirrored_identifiers,
+        &own_reply_targets,
+    )
+    .await?;
+    let statuses = merge_statuses(config_user, live_statuses, src_statuses);
+    Ok((statuses, operations, next_cursor))
+}
+
+/**
+ * dst の identifier から、src として取得した場合の identifier を得る
+ *
+ * Bluesky は create_record の結果の JSON で、Twitter のスレッドはカンマ区切りになっている
+ */
+fn to_src_identifiers(dst_identifier: &str) -> Vec<String> {
+    if let Ok(json) = serde_json::from_str::<Value>(dst_identifier) {
+        if let Some(cid) = json.get("cid").and_then(Value::as_str) {
+            return vec![cid.to_owned()];
+        }
+    }
+    dst_identifier.split(',').map(str::to_owned).collect()
 }
 
-fn has_users_operations(operations: &[store::operations::Operation], src_key: &AccountKey) -> bool {
-    operations
+/**
+ * dst としてこのアカウントに作った status の identifier を全て返す
+ */
+fn mirrored_identifiers(users: &[store::user::User], src_key: &AccountKey) -> HashSet<String> {
+    users
         .iter()
-        .any(|operation| &operation.account_pair().to_src_key() == src_key)
+        .flat_map(|user| &user.dsts)
+        .filter(|dst| dst.origin == src_key.origin && dst.identifier == src_key.identifier)
+        .flat_map(|dst| &dst.statuses)
+        .flat_map(|dst_status| match dst_status {
+            store::user::DestinationStatus::Post(post) => [&post.identifier]
+                .into_iter()
+                .chain(&post.follow_up_identifiers)
+                .flat_map(|identifier| to_src_identifiers(identifier))
+                .collect(),
+            store::user::DestinationStatus::Repost(repost) => {
+                to_src_identifiers(&repost.identifier)
+            }
+            // NOTE: いいねは src として取得しないので、送り返すことはない
+            store::user::DestinationStatus::Like(_) => vec![],
+        })
+        .collect()
 }
 
 pub async fn get(
     http_client: &Arc<reqwest::Client>,
     config_user: &config::User,
+    src: &config::Account,
     store: &Mutex<&mut store::Store>,
+    retry_policy: &RetryPolicy,
+    max_media_bytes: usize,
 ) -> Result<()> {
-    let session = store
-        .lock()
-        .unwrap()
-        .get_or_create_user_mut(&config_user.src.to_account_key())
-        .src
-        .session
-        .clone();
-
-    let mut src_client = create_client(http_client.clone(), &config_user.src, session).await?;
-    {
-        let mut store = store.lock().unwrap();
-        store
-            .get_or_create_user_mut(&config_user.src.to_account_key())
-            .src
-            .session = src_client.to_session();
+    if !config_user.enabled {
+        debug!("user is disabled, skipped");
+        return Ok(());
     }
-
-    let src_account_key = config_user.src.to_account_key();
-    let (has_users_operations, src_statuses) = {
+    // NOTE: 他の src の取得と並行して通信するので、lock は store の読み書きの間だけ持ち、
+    //       await をまたがないようにする
+    let src_account_key = src.to_account_key();
+    let (session, mirrored_identifiers, src_statuses, cursor) = {
         let mut store = store.lock().unwrap();
-        let has_users_operations = has_users_operations(&store.operations, &src_account_key);
+        let mirrored_identifiers = mirrored_identifiers(&store.users, &src_account_key);
         let stored_user = store.get_or_create_user_mut(&src_account_key);
-        (has_users_operations, &stored_user.src.statuses.clone())
+        (
+            stored_user.src.session.clone(),
+            mirrored_identifiers,
+            stored_user.src.statuses.clone(),
+            // NOTE: cursor を保存する前の Store では、保存順で先頭の最新のものを使う
+            stored_user.src.cursor.clone().or_else(|| {
+                stored_user
+                    .src
+                    .statuses
+                    .first()
+                    .map(|stored| stored.identifier().to_owned())
+            }),
+        )
     };
 
-    let (statuses, operations) =
-        fetch_statuses(src_client.as_mut(), http_client.as_ref(), src_statuses).await?;
+    let mut src_client = create_client(
+        http_client.clone(),
+        src,
+        session,
+        retry_policy,
+        max_media_bytes,
+        Budget::default(),
+    )
+    .await?;
+    // NOTE: 取得に失敗してもログインし直さずに済むように、先にセッションを保存しておく
+    store
+        .lock()
+        .unwrap()
+        .get_or_create_user_mut(&src_account_key)
+        .src
+        .session = src_client.to_session();
 
-    {
-        let mut store = store.lock().unwrap();
-        let stored_user = store.get_or_create_user_mut(&src_account_key);
-        stored_user.src.statuses = statuses;
-    }
+    let (statuses, operations, next_cursor) = fetch_statuses(
+        src_client.as_mut(),
+        http_client.as_ref(),
+        config_user,
+        &src_statuses,
+        cursor.as_deref(),
+        &mirrored_identifiers,
+    )
+    .await?;
     trace!("new operations: {:?}", operations);
-    if operations.is_empty() && !has_users_operations {
-        return Ok(());
+
+    let mut store = store.lock().unwrap();
+    let stored_user = store.get_or_create_user_mut(&src_account_key);
+    stored_user.src.session = src_client.to_session();
+    stored_user.src.statuses = statuses;
+    if next_cursor.is_some() {
+        stored_user.src.cursor = next_cursor;
+    }
+    if !operations.is_empty() {
+        let dsts = config_user.dsts_for(src);
+        merge_operations(&mut store, &dsts, &src_account_key, &operations);
     }
+    Ok(())
+}
 
-    let dst_account_keys: Vec<AccountKey> = config_user
-        .dsts
+/** 送信先にその src の status を送ったもの、または送る operation が積まれているもの */
+fn is_sent(
+    store: &store::Store,
+    account_pair: &store::operations::AccountPair,
+    src_identifier: &str,
+) -> bool {
+    has_dst_status(&store.users, account_pair, src_identifier)
+        || store.operations.iter().any(|operation| {
+            operation.account_pair() == account_pair && operation.src_identifier() == src_identifier
+        })
+}
+
+/**
+ * src の 1 つの status を取得し直して、送信先に送る operation を積む
+ *
+ * 取得済みかどうかを見ないので、送信に失敗した投稿を送り直せる。
+ * 重複しないように、既に送ったか送る operation が積まれている送信先には積まない
+ */
+pub async fn replay(
+    store: &mut store::Store,
+    http_client: Arc<reqwest::Client>,
+    config: &config::Config,
+    src_account_key: &AccountKey,
+    src_identifier: &str,
+) -> Result<()> {
+    trace!("replay");
+    let src = config
+        .users
         .iter()
-        .map(|dst| dst.to_account_key())
-        .collect();
+        .flat_map(|user| &user.srcs)
+        .find(|src| src.to_account_key() == *src_account_key)
+        .ok_or_else(|| anyhow!("src not found"))?;
+    let session = store
+        .get_or_create_user_mut(src_account_key)
+        .src
+        .session
+        .clone();
+    let mut src_client = create_client(
+        http_client.clone(),
+        src,
+        session,
+        &config.retry,
+        config.http.max_media_bytes,
+        Budget::default(),
+    )
+    .await?;
+    enqueue_replay(
+        store,
+        src_client.as_mut(),
+        http_client.as_ref(),
+        config,
+        src_account_key,
+        src_identifier,
+    )
+    .await
+}
 
-    if !operations.is_empty() {
-        let mut store = store.lock().unwrap();
-        merge_operations(&mut store, &dst_account_keys, &src_account_key, &operations);
+async fn enqueue_replay(
+    store: &mut store::Store,
+    src_client: &mut dyn Client,
+    http_client: &reqwest::Client,
+    config: &config::Config,
+    src_account_key: &AccountKey,
+    src_identifier: &str,
+) -> Result<()> {
+    let (config_user, src) = config
+        .users
+        .iter()
+        .flat_map(|user| user.srcs.iter().map(move |src| (user, src)))
+        .find(|(_, src)| src.to_account_key() == *src_account_key)
+        .ok_or_else(|| anyhow!("src not found"))?;
+    if mirrored_identifiers(&store.users, src_account_key).contains(src_identifier) {
+        bail!("status is mirrored from another src: {}", src_identifier);
     }
+    let live = src_client
+        .get_status(src_identifier)
+        .await?
+        .ok_or_else(|| anyhow!("status not found: {}", src_identifier))?;
+    let operation = create_operation(http_client, config_user, live.clone()).await?;
+
+    let stored_user = store.get_or_create_user_mut(src_account_key);
+    stored_user.src.session = src_client.to_session();
+    // NOTE: 保存していない status だと、送った後に送信先の status が不要なものとして消される
+    let src_status: store::user::SourceStatus = live.into();
+    let is_stored = stored_user.src.statuses.iter().any(|stored| match stored {
+        Post(post) => post.identifier == operation.src_identifier(),
+        Repost(repost) => repost.identifier == operation.src_identifier(),
+        Like(like) => like.identifier == operation.src_identifier(),
+    });
+    if !is_stored {
+        stored_user.src.statuses.push(src_status);
+    }
+
+    let dsts: Vec<_> = config_user
+        .dsts_for(src)
+        .into_iter()
+        .filter(|dst| {
+            let account_pair = store::operations::AccountPair::from_keys(
+                src_account_key.clone(),
+                dst.account.to_account_key(),
+            );
+            let is_sent = is_sent(store, &account_pair, operation.src_identifier());
+            if is_sent {
+                debug!("already sent, skipped: {}", account_pair.dst_origin);
+            }
+            !is_sent
+        })
+        .collect();
+    let src_identifier = operation.src_identifier().to_owned();
+    merge_operations(store, &dsts, src_account_key, &[operation]);
+    // NOTE: 取得し直した古い status なので、溜まっていたものとして扱う
+    store
+        .operations
+        .iter_mut()
+        .filter(|operation| {
+            &operation.account_pair().to_src_key() == src_account_key
+                && operation.src_identifier() == src_identifier
+        })
+        .for_each(store::operations::Operation::mark_backfill);
     Ok(())
 }
 
 /**
  * src が参照している post の identifier を全て返す
  */
-fn necessary_post_src_identifiers(users: &[store::user::User]) -> Vec<String> {
+fn necessary_post_src_identifiers(users: &[store::user::User]) -> HashSet<String> {
     users
         .iter()
         .flat_map(|user| user.src.statuses.iter())
         .map(|src_status| match src_status {
             Post(post) => post.identifier.clone(),
             Repost(repost) => repost.target_identifier.clone(),
+            Like(like) => like.liked_identifier.clone(),
         })
         .collect()
 }
 
-fn necessary_repost_src_identifiers(users: &[store::user::User]) -> Vec<String> {
+fn necessary_repost_src_identifiers(users: &[store::user::User]) -> HashSet<String> {
     users
         .iter()
         .flat_map(|user| user.src.statuses.iter())
         .filter_map(|src_status| match src_status {
-            Post(_) => None,
+            Post(_) | Like(_) => None,
             Repost(repost) => Some(repost.identifier.clone()),
         })
         .collect()
 }
 
+fn necessary_like_src_identifiers(users: &[store::user::User]) -> HashSet<String> {
+    users
+        .iter()
+        .flat_map(|user| user.src.statuses.iter())
+        .filter_map(|src_status| match src_status {
+            Post(_) | Repost(_) => None,
+            Like(like) => Some(like.identifier.clone()),
+        })
+        .collect()
+}
+
+/**
+ * src ごとに保存している status の identifier を返す
+ */
+fn src_status_identifiers(users: &[store::user::User]) -> HashMap<AccountKey, HashSet<String>> {
+    users
+        .iter()
+        .map(|user| {
+            let account_key = AccountKey {
+                origin: user.src.origin.clone(),
+                identifier: user.src.identifier.clone(),
+            };
+            let identifiers = user
+                .src
+                .statuses
+                .iter()
+                .map(|src_status| match src_status {
+                    Post(post) => post.identifier.clone(),
+                    Repost(repost) => repost.identifier.clone(),
+                    Like(like) => like.identifier.clone(),
+                })
+                .collect();
+            (account_key, identifiers)
+        })
+        .collect()
+}
+
 pub async fn retain_all_dst_statuses(store: &mut store::Store) -> Result<()> {
     let necessary_post_src_identifiers = necessary_post_src_identifiers(&store.users);
     let necessary_repost_src_identifiers = necessary_repost_src_identifiers(&store.users);
+    let necessary_like_src_identifiers = necessary_like_src_identifiers(&store.users);
+    let src_status_identifiers = src_status_identifiers(&store.users);
 
     store
         .users
         .iter_mut()
         .flat_map(|user| user.dsts.iter_mut())
         .for_each(|dst| {
+            // NOTE: 送信先が src でもある場合、送り返さないように src 側に残っている間は消さない
+            let mirrored = src_status_identifiers.get(&AccountKey {
+                origin: dst.origin.clone(),
+                identifier: dst.identifier.clone(),
+            });
+            let is_mirrored = |identifier: &str| {
+                mirrored.is_some_and(|mirrored| {
+                    to_src_identifiers(identifier)
+                        .iter()
+                        .any(|identifier| mirrored.contains(identifier))
+                })
+            };
             dst.statuses.retain(|status| match status {
                 store::user::DestinationStatus::Post(post) => {
                     necessary_post_src_identifiers.contains(&post.src_identifier)
+                        || is_mirrored(&post.identifier)
                 }
+                // NOTE: like_action でいいねをリポストとして送った場合は、いいねの identifier になる
                 store::user::DestinationStatus::Repost(repost) => {
                     necessary_repost_src_identifiers.contains(&repost.src_identifier)
+                        || necessary_like_src_identifiers.contains(&repost.src_identifier)
+                        || is_mirrored(&repost.identifier)
+                }
+                store::user::DestinationStatus::Like(like) => {
+                    necessary_like_src_identifiers.contains(&like.src_identifier)
                 }
             });
         });
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Condvar,
+        },
+        time,
+    };
+
+    use futures::future::join_all;
+    use serde_json::{json, Value};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, Request, Respond, ResponseTemplate,
+    };
+
+    use crate::{
+        http::HttpConfig,
+        protocols::{megalodon_client::test_account, mock_client::MockClient},
+    };
+
+    use super::*;
+
+    /** A と B の 2 つの src を互いの dst にする */
+    fn mutual_config() -> config::Config {
+        let a = json!({ "protocol": "mastodon", "origin": "https://a.example.com", "accessToken": "a" });
+        let b = json!({ "protocol": "mastodon", "origin": "https://b.example.com", "accessToken": "b" });
+        serde_json::from_value(json!({
+            "users": [{ "srcs": [a, b], "dsts": [a, b] }],
+        }))
+        .unwrap()
+    }
+
+    fn live_post(identifier: &str, created_at: &str) -> LiveStatus {
+        LiveStatus::Post(LivePost::test(identifier, "hello", created_at))
+    }
+
+    /** 前回までに保存した status を元に、src の取得結果から operation を積む */
+    async fn fetch(
+        store: &mut store::Store,
+        config_user: &config::User,
+        src: &config::Account,
+        live_statuses: Vec<LiveStatus>,
+    ) {
+        let src_account_key = src.to_account_key();
+        let mirrored_identifiers = mirrored_identifiers(&store.users, &src_account_key);
+        let src_statuses = store
+            .get_or_create_user_mut(&src_account_key)
+            .src
+            .statuses
+            .clone();
+        let operations = create_operations(
+            &reqwest::Client::new(),
+            config_user,
+            &live_statuses,
+            &src_statuses,
+            &mirrored_identifiers,
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+        store.get_or_create_user_mut(&src_account_key).src.statuses =
+            merge_statuses(config_user, live_statuses, &src_statuses);
+        merge_operations(
+            store,
+            &config_user.dsts_for(src),
+            &src_account_key,
+            &operations,
+        );
+    }
+
+    /** 積まれている operation を全て送ったことにする */
+    fn send_all(store: &mut store::Store) {
+        while let Some(operation) = store.operations.pop_front() {
+            let CreatePost(operation) = operation else {
+                panic!("unexpected operation");
+            };
+            store
+                .get_or_create_dst_mut(&operation.account_pair)
+                .statuses
+                .push(store::user::DestinationStatus::Post(
+                    store::user::DestinationPost {
+                        identifier: format!("mirror-{}", operation.status.src_identifier),
+                        src_identifier: operation.status.src_identifier,
+                        src_uri: operation.status.src_uri,
+                        follow_up_identifiers: Vec::new(),
+                    },
+                ));
+        }
+    }
+
+    fn dst_origins(store: &store::Store) -> Vec<(&str, &str)> {
+        store
+            .operations
+            .iter()
+            .map(|operation| {
+                (
+                    operation.src_identifier(),
+                    operation.account_pair().dst_origin.as_str(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn each_src_is_mirrored_to_the_other() {
+        let config = mutual_config();
+        let config_user = &config.users[0];
+        let [a, b] = config_user.srcs.as_slice() else {
+            panic!("unexpected srcs");
+        };
+        let mut store = store::Store::default();
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![live_post("a-1", "2024-01-01T00:00:00Z")],
+        )
+        .await;
+        fetch(
+            &mut store,
+            config_user,
+            b,
+            vec![live_post("b-1", "2024-01-01T00:00:00Z")],
+        )
+        .await;
+
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![
+                live_post("a-2", "2024-01-02T00:00:00Z"),
+                live_post("a-1", "2024-01-01T00:00:00Z"),
+            ],
+        )
+        .await;
+        fetch(
+            &mut store,
+            config_user,
+            b,
+            vec![
+                live_post("b-2", "2024-01-02T00:00:00Z"),
+                live_post("b-1", "2024-01-01T00:00:00Z"),
+            ],
+        )
+        .await;
+
+        // NOTE: 自分自身には送らず、src ごとに別々に保存する
+        assert_eq!(
+            dst_origins(&store),
+            [
+                ("a-2", "https://b.example.com"),
+                ("b-2", "https://a.example.com")
+            ]
+        );
+        assert_eq!(store.users.len(), 2);
+    }
+
+    fn dst_post(src_identifier: &str) -> store::user::DestinationStatus {
+        store::user::DestinationStatus::Post(store::user::DestinationPost {
+            identifier: format!("dst-{}", src_identifier),
+            src_identifier: src_identifier.into(),
+            src_uri: format!("https://src.example.com/{}", src_identifier),
+            follow_up_identifiers: Vec::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn thousands_of_dst_statuses_are_retained() {
+        const COUNT: usize = 10_000;
+        let created_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let src_statuses = (0..COUNT)
+            .map(|i| {
+                Post(store::user::SourcePost {
+                    identifier: i.to_string(),
+                    content: String::new(),
+                    media_alts: None,
+                    created_at,
+                })
+            })
+            .collect();
+        // NOTE: 半分は src から消えたもの
+        let dst_statuses = (0..COUNT * 2).map(|i| dst_post(&i.to_string())).collect();
+        let mut store = store::Store::default();
+        store.users.push(store::user::User {
+            src: store::user::Source {
+                origin: "https://src.example.com".into(),
+                identifier: "src".into(),
+                session: None,
+                cursor: None,
+                statuses: src_statuses,
+            },
+            dsts: vec![store::user::Destination {
+                origin: "https://dst.example.com".into(),
+                identifier: "dst".into(),
+                session: None,
+                statuses: dst_statuses,
+            }],
+        });
+
+        retain_all_dst_statuses(&mut store).await.unwrap();
+
+        let statuses = &store.users[0].dsts[0].statuses;
+        assert_eq!(statuses.len(), COUNT);
+        assert!(statuses.iter().all(|status| match status {
+            store::user::DestinationStatus::Post(post) =>
+                post.src_identifier.parse::<usize>().unwrap() < COUNT,
+            _ => false,
+        }));
+    }
+
+    #[tokio::test]
+    async fn only_posts_within_fetched_window_are_deleted() {
+        let config = mutual_config();
+        let config_user = &config.users[0];
+        let a = &config_user.srcs[0];
+        let mut store = store::Store::default();
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![
+                live_post("a-3", "2024-01-03T00:00:00Z"),
+                live_post("a-2", "2024-01-02T00:00:00Z"),
+                live_post("a-1", "2024-01-01T00:00:00Z"),
+            ],
+        )
+        .await;
+        assert!(store.operations.is_empty());
+
+        // NOTE: a-3 は取得できた範囲の中で消えていて、a-1 は取得件数から溢れただけ
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![
+                live_post("a-4", "2024-01-04T00:00:00Z"),
+                live_post("a-2", "2024-01-02T00:00:00Z"),
+            ],
+        )
+        .await;
+
+        let operations: Vec<_> = store
+            .operations
+            .iter()
+            .map(|operation| (operation.kind(), operation.src_identifier()))
+            .collect();
+        assert_eq!(operations, [("create_post", "a-4"), ("delete_post", "a-3")]);
+    }
+
+    #[tokio::test]
+    async fn fresh_post_is_deferred_and_old_post_is_emitted() {
+        let config: config::Config = serde_json::from_value(json!({
+            "users": [{
+                "src": { "protocol": "mastodon", "origin": "https://a.example.com", "accessToken": "a" },
+                "dsts": [{ "protocol": "mastodon", "origin": "https://b.example.com", "accessToken": "b" }],
+                "minAgeSeconds": 600,
+            }],
+        }))
+        .unwrap();
+        let now = Utc::now();
+        let at = |minutes: i64| (now - Duration::minutes(minutes)).to_rfc3339();
+        let stored = merge_statuses(&config.users[0], vec![live_post("1", &at(60 * 24))], &[]);
+        let mut src_client = MockClient::default();
+        src_client.state.lock().unwrap().statuses = vec![
+            live_post("3", &at(1)),
+            live_post("2", &at(60)),
+            live_post("1", &at(60 * 24)),
+        ];
+
+        let (statuses, operations, cursor) = fetch_statuses(
+            &mut src_client,
+            &reqwest::Client::new(),
+            &config.users[0],
+            &stored,
+            Some("1"),
+            &HashSet::new(),
+        )
+        .await
+        .unwrap();
+
+        let [Operation::CreatePost(operation)] = operations.as_slice() else {
+            panic!("unexpected operations");
+        };
+        assert_eq!(operation.src_identifier, "2");
+        // NOTE: 保存しないので、次回以降の実行で新しいものとして扱われる
+        let identifiers: Vec<_> = statuses
+            .iter()
+            .filter_map(|status| match status {
+                store::user::SourceStatus::Post(post) => Some(post.identifier.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(identifiers, ["2", "1"]);
+        assert_eq!(cursor.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn disabled_user_is_skipped() {
+        // NOTE: 取得しようとすると接続できずに失敗する
+        let config: config::Config = serde_json::from_value(json!({
+            "users": [{
+                "src": { "protocol": "mastodon", "origin": "http://127.0.0.1:1", "accessToken": "a" },
+                "dsts": [{ "protocol": "mastodon", "origin": "https://b.example.com", "accessToken": "b" }],
+                "enabled": false,
+            }],
+        }))
+        .unwrap();
+        let config_user = &config.users[0];
+        let mut store = store::Store::default();
+
+        get(
+            &Arc::new(reqwest::Client::new()),
+            config_user,
+            &config_user.srcs[0],
+            &Mutex::new(&mut store),
+            &RetryPolicy::default(),
+            HttpConfig::default().max_media_bytes,
+        )
+        .await
+        .unwrap();
+
+        assert!(store.users.is_empty());
+        assert!(store.operations.is_empty());
+    }
+
+    fn retention_user(retention: Value) -> config::User {
+        let mut user = json!({
+            "src": { "protocol": "mastodon", "origin": "https://a.example.com", "accessToken": "a" },
+            "dsts": [{ "protocol": "mastodon", "origin": "https://b.example.com", "accessToken": "b" }],
+        });
+        user.as_object_mut()
+            .unwrap()
+            .extend(retention.as_object().unwrap().clone());
+        serde_json::from_value(user).unwrap()
+    }
+
+    fn identifiers(statuses: &[store::user::SourceStatus]) -> Vec<&str> {
+        statuses.iter().map(|stored| stored.identifier()).collect()
+    }
+
+    #[test]
+    fn retention_keeps_most_recent_statuses() {
+        let config_user = retention_user(json!({ "maxStoredStatuses": 3 }));
+        let stored: Vec<store::user::SourceStatus> = (1..=5)
+            .rev()
+            .map(|i| live_post(&i.to_string(), &format!("2024-01-0{}T00:00:00Z", i)).into())
+            .collect();
+        let live_statuses = vec![
+            live_post("7", "2024-01-07T00:00:00Z"),
+            live_post("6", "2024-01-06T00:00:00Z"),
+        ];
+
+        let statuses = merge_statuses(&config_user, live_statuses, &stored);
+
+        assert_eq!(identifiers(&statuses), ["7", "6", "5"]);
+    }
+
+    #[test]
+    fn retention_prunes_statuses_older_than_max_age() {
+        let config_user = retention_user(json!({ "maxStoredStatusAgeDays": 1 }));
+        let now = Utc::now();
+        let at = |hours: i64| (now - Duration::hours(hours)).to_rfc3339();
+        let stored: Vec<store::user::SourceStatus> = vec![
+            live_post("3", &at(12)).into(),
+            live_post("2", &at(36)).into(),
+            live_post("1", &at(48)).into(),
+        ];
+        let live_statuses = vec![live_post("4", &at(6))];
+
+        let statuses = merge_statuses(&config_user, live_statuses, &stored);
+
+        assert_eq!(identifiers(&statuses), ["4", "3"]);
+    }
+
+    /** 取得に DELAY かかる Mastodon の src */
+    /**
+     * 2 つのサーバーに取得が揃うまで応答を止めておく
+     *
+     * 順に取得すると 2 つ目が届かないので、時間切れになって揃わなかったことを記録する
+     */
+    #[derive(Clone, Default)]
+    struct Rendezvous {
+        arrived: Arc<(Mutex<usize>, Condvar)>,
+        met: Arc<AtomicUsize>,
+    }
+
+    impl Respond for Rendezvous {
+        fn respond(&self, _request: &Request) -> ResponseTemplate {
+            let (arrived, condvar) = &*self.arrived;
+            let mut arrived = arrived.lock().unwrap();
+            *arrived += 1;
+            condvar.notify_all();
+            let (arrived, _) = condvar
+                .wait_timeout_while(arrived, time::Duration::from_secs(5), |arrived| {
+                    *arrived < 2
+                })
+                .unwrap();
+            if *arrived >= 2 {
+                self.met.fetch_add(1, Ordering::SeqCst);
+            }
+            ResponseTemplate::new(200).set_body_json(json!([]))
+        }
+    }
+
+    async fn rendezvous_mastodon_server(rendezvous: &Rendezvous) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_account("1", "src")))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .respond_with(rendezvous.clone())
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn users_are_fetched_concurrently() {
+        let rendezvous = Rendezvous::default();
+        let servers = [
+            rendezvous_mastodon_server(&rendezvous).await,
+            rendezvous_mastodon_server(&rendezvous).await,
+        ];
+        let users: Vec<_> = servers
+            .iter()
+            .map(|server| {
+                json!({
+                    "src": { "protocol": "mastodon", "origin": server.uri(), "accessToken": "src" },
+                    "dsts": [{ "protocol": "mastodon", "origin": "https://dst.example.com", "accessToken": "dst" }],
+                })
+            })
+            .collect();
+        let config: config::Config = serde_json::from_value(json!({ "users": users })).unwrap();
+        let mut store = store::Store::default();
+        let http_client = Arc::new(reqwest::Client::new());
+        let locked_store = Mutex::new(&mut store);
+
+        let results = join_all(config.users.iter().map(|config_user| {
+            get(
+                &http_client,
+                config_user,
+                &config_user.srcs[0],
+                &locked_store,
+                &RetryPolicy::default(),
+                HttpConfig::default().max_media_bytes,
+            )
+        }))
+        .await;
+
+        for result in results {
+            result.unwrap();
+        }
+        // NOTE: 順に取得すると、どちらも相手の取得が届く前に時間切れになる
+        assert_eq!(rendezvous.met.load(Ordering::SeqCst), 2);
+        assert_eq!(store.users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mirrored_post_is_not_sent_back() {
+        let config = mutual_config();
+        let config_user = &config.users[0];
+        let [a, b] = config_user.srcs.as_slice() else {
+            panic!("unexpected srcs");
+        };
+        let mut store = store::Store::default();
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![live_post("a-1", "2024-01-01T00:00:00Z")],
+        )
+        .await;
+        fetch(
+            &mut store,
+            config_user,
+            b,
+            vec![live_post("b-1", "2024-01-01T00:00:00Z")],
+        )
+        .await;
+        fetch(
+            &mut store,
+            config_user,
+            a,
+            vec![
+                live_post("a-2", "2024-01-02T00:00:00Z"),
+                live_post("a-1", "2024-01-01T00:00:00Z"),
+            ],
+        )
+        .await;
+        send_all(&mut store);
+        // NOTE: 再起動しても保存した内容から判定できる
+        let mut store: store::Store =
+            serde_json::from_str(&serde_json::to_string(&store).unwrap()).unwrap();
+
+        fetch(
+            &mut store,
+            config_user,
+            b,
+            vec![
+                live_post("mirror-a-2", "2024-01-02T00:00:00Z"),
+                live_post("b-1", "2024-01-01T00:00:00Z"),
+            ],
+        )
+        .await;
+
+        assert!(store.operations.is_empty());
+    }
+
+    async fn replay_a(store: &mut store::Store, src_identifier: &str) {
+        let config = mutual_config();
+        let mut src_client = MockClient::default();
+        src_client.state.lock().unwrap().statuses =
+            vec![live_post(src_identifier, "2024-01-01T00:00:00Z")];
+
+        enqueue_replay(
+            store,
+            &mut src_client,
+            &reqwest::Client::new(),
+            &config,
+            &config.users[0].srcs[0].to_account_key(),
+            src_identifier,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_enqueues_create_without_dst_status() {
+        let mut store = store::Store::default();
+
+        replay_a(&mut store, "a-1").await;
+
+        let [CreatePost(operation)] = store.operations.make_contiguous() else {
+            panic!("unexpected operations");
+        };
+        assert_eq!(operation.status.src_identifier, "a-1");
+        assert_eq!(operation.account_pair.dst_origin, "https://b.example.com");
+        assert!(operation.backfill);
+        // NOTE: 送った後に不要なものとして消されないように、src の status も保存する
+        let src_account_key = mutual_config().users[0].srcs[0].to_account_key();
+        assert_eq!(
+            store.get_or_create_user_mut(&src_account_key).src.statuses[0].identifier(),
+            "a-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_skips_dst_with_status() {
+        let mut store = store::Store::default();
+        replay_a(&mut store, "a-1").await;
+        send_all(&mut store);
+
+        replay_a(&mut store, "a-1").await;
+
+        assert!(store.operations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replay_skips_pending_operation() {
+        let mut store = store::Store::default();
+        replay_a(&mut store, "a-1").await;
+
+        replay_a(&mut store, "a-1").await;
+
+        assert_eq!(store.operations.len(), 1);
+    }
+}
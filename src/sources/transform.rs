@@ -0,0 +1,249 @@
+use anyhow::Result;
+use regex::{Captures, Regex};
+use reqwest::Url;
+
+use crate::{config, protocols::text::create_link_facets, store::operations::Facet::Link};
+
+use super::source::{LiveExternal, LivePost};
+
+/** 投稿を送る前の加工 */
+pub trait Transform: Send + Sync {
+    fn apply(&self, post: &mut LivePost);
+}
+
+fn is_tracking_param(key: &str, tracking_params: &[String]) -> bool {
+    tracking_params
+        .iter()
+        .any(|param| match param.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == param,
+        })
+}
+
+/** 表示上の文字列は変えずに、リンク先の URI からトラッキング用のクエリパラメーターを取り除く */
+fn strip_tracking_params(uri: &str, tracking_params: &[String]) -> String {
+    let Ok(mut url) = Url::parse(uri) else {
+        return uri.to_owned();
+    };
+    let pairs: Vec<_> = url.query_pairs().into_owned().collect();
+    let retained: Vec<_> = pairs
+        .iter()
+        .filter(|(key, _)| !is_tracking_param(key, tracking_params))
+        .collect();
+    if retained.len() == pairs.len() {
+        return uri.to_owned();
+    }
+    if retained.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(retained);
+    }
+    url.to_string()
+}
+
+/** リンク先とリンクカードの URI だけを書き換えるので、本文は変わらない */
+struct StripTrackingParams(Vec<String>);
+
+impl Transform for StripTrackingParams {
+    fn apply(&self, post: &mut LivePost) {
+        post.facets.iter_mut().for_each(|facet| match facet {
+            Link { uri, .. } => *uri = strip_tracking_params(uri, &self.0),
+        });
+        if let LiveExternal::Some(external) = &mut post.external {
+            external.uri = strip_tracking_params(&external.uri, &self.0);
+        }
+    }
+}
+
+struct RegexReplace {
+    regex: Regex,
+    replacement: String,
+}
+
+impl Transform for RegexReplace {
+    fn apply(&self, post: &mut LivePost) {
+        post.content = self
+            .regex
+            .replace_all(&post.content, self.replacement.as_str())
+            .into_owned();
+    }
+}
+
+/** 前の空白ごと取り除くので、末尾に並んだハッシュタグは行ごと消える */
+struct StripHashtags {
+    regex: Regex,
+    /** 小文字にしてある。空の場合は全て */
+    tags: Vec<String>,
+}
+
+impl Transform for StripHashtags {
+    fn apply(&self, post: &mut LivePost) {
+        let content = self.regex.replace_all(&post.content, |caps: &Captures| {
+            let tag = caps[1].to_lowercase();
+            if self.tags.is_empty() || self.tags.contains(&tag) {
+                String::new()
+            } else {
+                caps[0].to_owned()
+            }
+        });
+        post.content = content.trim().to_owned();
+    }
+}
+
+/**
+ * config_user の設定から、適用する順に加工を並べる
+ *
+ * trackingParams の除去は以前から常に行っているので先頭に置く
+ */
+pub fn create_transforms(config_user: &config::User) -> Result<Vec<Box<dyn Transform>>> {
+    let mut transforms: Vec<Box<dyn Transform>> = vec![Box::new(StripTrackingParams(
+        config_user.tracking_params.clone(),
+    ))];
+    // NOTE: operation_factory の blocklist のハッシュタグと同じく、英数字と _ をタグとみなす
+    let hashtag = Regex::new(r"(?:^|\s)#([\p{Alphabetic}\p{N}_]+)").unwrap();
+    for transform in &config_user.transforms {
+        transforms.push(match transform {
+            config::Transform::RegexReplace {
+                pattern,
+                replacement,
+            } => Box::new(RegexReplace {
+                regex: Regex::new(pattern)?,
+                replacement: replacement.clone(),
+            }),
+            config::Transform::StripHashtags { tags } => Box::new(StripHashtags {
+                regex: hashtag.clone(),
+                tags: tags
+                    .iter()
+                    .map(|tag| tag.trim_start_matches('#').to_lowercase())
+                    .collect(),
+            }),
+            config::Transform::StripTrackingParams { params } => {
+                Box::new(StripTrackingParams(params.clone()))
+            }
+        });
+    }
+    Ok(transforms)
+}
+
+/**
+ * 順に加工を適用する
+ *
+ * 本文が変わると facet の位置がずれるので、本文から作り直す
+ */
+pub fn apply_transforms(transforms: &[Box<dyn Transform>], post: &mut LivePost) {
+    for transform in transforms {
+        let before = post.content.clone();
+        transform.apply(post);
+        if post.content != before {
+            post.facets = create_link_facets(&post.content);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use serde_json::json;
+
+    use super::*;
+
+    fn post(content: &str) -> LivePost {
+        LivePost {
+            identifier: "1".into(),
+            uri: "https://example.com/1".into(),
+            content: content.into(),
+            facets: create_link_facets(content),
+            reply_src_identifier: None,
+            media: Vec::new(),
+            external: LiveExternal::None,
+            content_warning: None,
+            poll: None,
+            quote: None,
+            custom_emojis: Vec::new(),
+            created_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+        }
+    }
+
+    #[test]
+    fn composes_transforms_in_order() {
+        let config_user: config::User = serde_json::from_value(json!({
+            "srcs": [],
+            "dsts": [],
+            "transforms": [
+                { "type": "regexReplace", "pattern": "foo", "replacement": "bar" },
+                { "type": "stripHashtags", "tags": ["#bar"] },
+            ],
+        }))
+        .unwrap();
+        let transforms = create_transforms(&config_user).unwrap();
+        let mut post = post("hello foo https://example.com/foo #foo #keep");
+
+        apply_transforms(&transforms, &mut post);
+
+        assert_eq!(post.content, "hello bar https://example.com/bar #keep");
+        let [Link { byte_slice, uri }] = post.facets.as_slice() else {
+            panic!("unexpected facets: {:?}", post.facets);
+        };
+        assert_eq!(
+            &post.content[byte_slice.start as usize..byte_slice.end as usize],
+            "https://example.com/bar"
+        );
+        assert_eq!(uri, "https://example.com/bar");
+    }
+
+    #[test]
+    fn tracking_params_are_stripped() {
+        let params = default_params();
+        assert_eq!(
+            strip_tracking_params(
+                "https://example.com/a?utm_source=x&id=1&fbclid=y#top",
+                &params
+            ),
+            "https://example.com/a?id=1#top"
+        );
+        assert_eq!(
+            strip_tracking_params("https://example.com/a?utm_medium=x&gclid=y", &params),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn urls_without_tracking_params_are_untouched() {
+        let params = default_params();
+        // NOTE: 作り直すとエンコードや順序が変わるので、取り除くものがなければそのまま返す
+        for uri in [
+            "https://example.com/a?q=a+b&lang=ja",
+            "https://example.com/search?q=%E3%81%82&utm",
+            "https://example.com/a?",
+            "not a url?utm_source=x",
+        ] {
+            assert_eq!(strip_tracking_params(uri, &params), uri);
+        }
+    }
+
+    #[test]
+    fn only_link_uri_is_rewritten() {
+        let transforms =
+            vec![Box::new(StripTrackingParams(default_params())) as Box<dyn Transform>];
+        let content = "see https://example.com/a?utm_source=x&id=1 now";
+        let mut post = post(content);
+
+        apply_transforms(&transforms, &mut post);
+
+        assert_eq!(post.content, content);
+        let [Link { byte_slice, uri }] = post.facets.as_slice() else {
+            panic!("unexpected facets: {:?}", post.facets);
+        };
+        assert_eq!(
+            &post.content[byte_slice.start as usize..byte_slice.end as usize],
+            "https://example.com/a?utm_source=x&id=1"
+        );
+        assert_eq!(uri, "https://example.com/a?id=1");
+    }
+
+    fn default_params() -> Vec<String> {
+        let config_user: config::User =
+            serde_json::from_value(json!({ "srcs": [], "dsts": [] })).unwrap();
+        config_user.tracking_params
+    }
+}
@@ -1,20 +1,82 @@
 pub mod operations;
+pub mod summary;
 pub mod user;
 
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::app::AccountKey;
 
 use self::{
-    operations::{AccountPair, Operation},
+    operations::{
+        AccountPair, Operation,
+        Operation::{
+            CreateLike, CreatePost, CreateRepost, DeleteLike, DeletePost, DeleteRepost, UpdatePost,
+        },
+    },
     user::{Destination, Source, User},
 };
 
-#[derive(Clone, Default, Deserialize, Serialize)]
+/** 保存する Store の形式の版。形式を変える場合は上げて、migrate に変換を足す */
+pub const CURRENT_VERSION: u32 = 1;
+
+#[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Store {
+    /** 無い場合は 0 */
+    #[serde(default)]
+    pub version: u32,
     pub users: Vec<User>,
-    pub operations: Vec<Operation>,
+    /** 先頭から順に消化する */
+    pub operations: VecDeque<Operation>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_VERSION,
+            users: Vec::default(),
+            operations: VecDeque::default(),
+        }
+    }
+}
+
+/** v0 では投稿の operation の media に sensitive が無い */
+fn migrate_to_v1(value: &mut Value) {
+    value
+        .get_mut("operations")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|operation| operation.get_mut("media").and_then(Value::as_array_mut))
+        .flatten()
+        .filter_map(Value::as_object_mut)
+        .for_each(|medium| {
+            medium.entry("sensitive").or_insert(false.into());
+        });
+}
+
+/**
+ * 保存されていた JSON を現在の形式に変換してから読む
+ *
+ * 新しい版のものは読むと壊してしまうのでエラーにする
+ */
+pub fn migrate(mut value: Value) -> Result<Store> {
+    if !value.is_object() {
+        bail!("store is not object");
+    }
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > CURRENT_VERSION as u64 {
+        bail!("unsupported store version: {}", version);
+    }
+    if version < 1 {
+        migrate_to_v1(&mut value);
+    }
+    value["version"] = CURRENT_VERSION.into();
+    Ok(serde_json::from_value(value)?)
 }
 
 impl Store {
@@ -30,6 +92,7 @@ impl Store {
                 origin: account_key.origin.clone(),
                 identifier: account_key.identifier.clone(),
                 session: None,
+                cursor: None,
                 statuses: Vec::default(),
             },
             dsts: Vec::default(),
@@ -44,4 +107,106 @@ impl Store {
         self.get_or_create_user_mut(&account_pair.to_src_key())
             .get_or_create_dst_mut(&account_pair.to_dst_key())
     }
+
+    /**
+     * 削除と更新を積んだ順に先頭に並べ、その後ろに投稿を古い順に並べる
+     *
+     * 1 回の実行で送る数に上限があっても、削除や更新が溜まった投稿の後ろで待たされないようにする。
+     * 未送信の投稿に対する削除や更新は merge_operations で打ち消しているので、先に送っても対象はある
+     */
+    pub fn sort_operations(&mut self) {
+        self.operations
+            .make_contiguous()
+            .sort_by_key(|operation| match operation {
+                CreatePost(content) => content.status.created_at.timestamp_micros(),
+                CreateRepost(content) => content.status.created_at.timestamp_micros(),
+                CreateLike(content) => content.status.created_at.timestamp_micros(),
+                UpdatePost(_) | DeletePost(_) | DeleteRepost(_) | DeleteLike(_) => i64::MIN,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use serde_json::json;
+
+    use super::{
+        operations::{CreatePostOperation, DeletePostOperation, UpdatePostOperation},
+        *,
+    };
+
+    fn create_post(src_identifier: &str, created_at: &str) -> Operation {
+        let mut operation = CreatePostOperation::test(src_identifier, "hello");
+        operation.status.created_at = DateTime::parse_from_rfc3339(created_at).unwrap();
+        CreatePost(operation)
+    }
+
+    #[test]
+    fn deletes_and_updates_are_sorted_before_creates() {
+        let mut store = Store {
+            operations: [
+                create_post("3", "2024-01-01T00:03:00Z"),
+                DeletePost(DeletePostOperation::test("a")),
+                create_post("1", "2024-01-01T00:01:00Z"),
+                UpdatePost(UpdatePostOperation::test("b", "edited")),
+                create_post("2", "2024-01-01T00:02:00Z"),
+                DeletePost(DeletePostOperation::test("c")),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        store.sort_operations();
+
+        let src_identifiers: Vec<_> = store
+            .operations
+            .iter()
+            .map(Operation::src_identifier)
+            .collect();
+        // NOTE: 削除と更新は積んだ順のまま
+        assert_eq!(src_identifiers, ["a", "b", "c", "1", "2", "3"]);
+    }
+
+    /** sensitive が無い、版の無い頃の Store */
+    fn v0_blob() -> Value {
+        json!({
+            "users": [],
+            "operations": [{
+                "operation": "createPost",
+                "srcOrigin": "https://src.example.com",
+                "srcAccountIdentifier": "src",
+                "dstOrigin": "https://dst.example.com",
+                "dstAccountIdentifier": "dst",
+                "srcIdentifier": "1",
+                "srcUri": "https://src.example.com/1",
+                "content": "hello",
+                "replySrcIdentifier": null,
+                "media": [{ "url": "https://src.example.com/1.png", "alt": "" }],
+                "createdAt": "2024-01-01T00:00:00Z"
+            }]
+        })
+    }
+
+    #[test]
+    fn v0_blob_is_migrated() {
+        // NOTE: migrate を通さなければ読めない
+        assert!(serde_json::from_value::<Store>(v0_blob()).is_err());
+
+        let mut store = migrate(v0_blob()).unwrap();
+
+        assert_eq!(store.version, CURRENT_VERSION);
+        let [CreatePost(operation)] = store.operations.make_contiguous() else {
+            panic!("unexpected operations");
+        };
+        assert!(!operation.status.media[0].sensitive);
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let mut value = v0_blob();
+        value["version"] = (CURRENT_VERSION + 1).into();
+
+        assert!(migrate(value).is_err());
+    }
 }
@@ -3,7 +3,10 @@ use std::ops::Range;
 use chrono::{DateTime, FixedOffset};
 use serde::{Deserialize, Serialize};
 
-use crate::{app::AccountKey, utils::format_rfc3339};
+use crate::{
+    app::AccountKey,
+    utils::{format_rfc3339, format_rfc3339_opt},
+};
 
 #[derive(Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -53,11 +56,32 @@ pub enum Facet {
     Link { byte_slice: Range<u32>, uri: String },
 }
 
+/** 切り抜かれても残したい位置。Mastodon と同じく中央が 0、左下が -1、右上が 1 */
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Focus {
+    pub x: f64,
+    pub y: f64,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Medium {
     pub url: String,
     pub alt: String,
+    /** v0 には無いので migrate で補う */
+    pub sensitive: bool,
+    /** 無い場合は中央 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub focus: Option<Focus>,
+    /**
+     * 取得元が教えてくれる content-type。種類しか分からない場合は image/ のような前方だけにする
+     *
+     * 無い場合は URL から推測する
+     */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub content_type: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -69,6 +93,28 @@ pub struct External {
     pub thumb_url: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Poll {
+    pub choices: Vec<String>,
+    pub multiple: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, with = "format_rfc3339_opt")]
+    pub expires_at: Option<DateTime<FixedOffset>>,
+}
+
+/** 引用した元の投稿 */
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quote {
+    pub src_identifier: String,
+    pub src_uri: String,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CreatePostOperationStatus {
@@ -84,6 +130,20 @@ pub struct CreatePostOperationStatus {
     pub media: Vec<Medium>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub external: Option<External>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub quote: Option<Quote>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub custom_emojis: Vec<String>,
+    /** 返信先がまだ送られていないために後回しにした回数 */
+    #[serde(skip_serializing_if = "is_zero")]
+    #[serde(default)]
+    pub reply_deferrals: u32,
     #[serde(with = "format_rfc3339")]
     pub created_at: DateTime<FixedOffset>,
 }
@@ -94,6 +154,49 @@ pub struct CreatePostOperation {
     pub account_pair: AccountPair,
     #[serde(flatten)]
     pub status: CreatePostOperationStatus,
+    /** 溜まっていたものを後から送る。日時は送信先の backdate に従う */
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub backfill: bool,
+}
+
+#[cfg(test)]
+impl AccountPair {
+    /** テスト用の src と dst の組 */
+    pub fn test() -> Self {
+        Self {
+            src_origin: "https://src.example.com".into(),
+            src_account_identifier: "src".into(),
+            dst_origin: "https://dst.example.com".into(),
+            dst_account_identifier: "dst".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl CreatePostOperation {
+    /** テスト用の本文だけの投稿の operation */
+    pub fn test(src_identifier: &str, content: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: CreatePostOperationStatus {
+                src_identifier: src_identifier.into(),
+                src_uri: format!("https://src.example.com/{}", src_identifier),
+                content: content.into(),
+                facets: Vec::new(),
+                reply_src_identifier: None,
+                media: Vec::new(),
+                external: None,
+                content_warning: None,
+                poll: None,
+                quote: None,
+                custom_emojis: Vec::new(),
+                reply_deferrals: 0,
+                created_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+            },
+            backfill: false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -102,6 +205,13 @@ pub struct CreateRepostOperationStatus {
     pub src_identifier: String,
     pub target_src_identifier: String,
     pub target_src_uri: String,
+    /** Bluesky の場合の対象の AT URI。cid と合わせて、送っていない投稿も直接リポストできる */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub target_src_at_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub target_src_cid: Option<String>,
     #[serde(with = "format_rfc3339")]
     pub created_at: DateTime<FixedOffset>,
 }
@@ -113,6 +223,69 @@ pub struct CreateRepostOperation {
     pub account_pair: AccountPair,
     #[serde(flatten)]
     pub status: CreateRepostOperationStatus,
+    /** 溜まっていたものを後から送る。日時は送信先の backdate に従う */
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub backfill: bool,
+}
+
+#[cfg(test)]
+impl CreateRepostOperation {
+    /** テスト用の、送っていない投稿のリポストの operation */
+    pub fn test(src_identifier: &str, target_src_uri: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: CreateRepostOperationStatus {
+                src_identifier: src_identifier.into(),
+                target_src_identifier: "target".into(),
+                target_src_uri: target_src_uri.into(),
+                target_src_at_uri: None,
+                target_src_cid: None,
+                created_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+            },
+            backfill: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateLikeOperationStatus {
+    pub src_identifier: String,
+    pub target_src_identifier: String,
+    pub target_src_uri: String,
+    #[serde(with = "format_rfc3339")]
+    pub created_at: DateTime<FixedOffset>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateLikeOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: CreateLikeOperationStatus,
+    /** 溜まっていたものを後から送る。日時は送信先の backdate に従う */
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub backfill: bool,
+}
+
+#[cfg(test)]
+impl CreateLikeOperation {
+    /** テスト用の、target へのいいねの operation */
+    pub fn test(src_identifier: &str, target_src_uri: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: CreateLikeOperationStatus {
+                src_identifier: src_identifier.into(),
+                target_src_identifier: "target".into(),
+                target_src_uri: target_src_uri.into(),
+                created_at: DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+            },
+            backfill: false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -122,6 +295,17 @@ pub struct UpdatePostOperationStatus {
     pub content: String,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub facets: Vec<Facet>,
+    /** 代替テキストが編集された場合の、メディアの順の代替テキスト */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_alts: Option<Vec<String>>,
+    /** テンプレートの {source_url} に使う。保存していなかった以前の operation は空 */
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub src_uri: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub custom_emojis: Vec<String>,
+    /** 引用できない送信先で、投稿と同じく引用元へのリンクを付けるために使う */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<Quote>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -133,6 +317,25 @@ pub struct UpdatePostOperation {
     pub status: UpdatePostOperationStatus,
 }
 
+#[cfg(test)]
+impl UpdatePostOperation {
+    /** テスト用の、本文だけを書き換える operation */
+    pub fn test(src_identifier: &str, content: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: UpdatePostOperationStatus {
+                src_identifier: src_identifier.into(),
+                content: content.into(),
+                facets: Vec::new(),
+                media_alts: None,
+                src_uri: format!("https://src.example.com/{}", src_identifier),
+                custom_emojis: Vec::new(),
+                quote: None,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeletePostOperationStatus {
@@ -148,6 +351,19 @@ pub struct DeletePostOperation {
     pub status: DeletePostOperationStatus,
 }
 
+#[cfg(test)]
+impl DeletePostOperation {
+    /** テスト用の投稿の削除の operation */
+    pub fn test(src_identifier: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: DeletePostOperationStatus {
+                src_identifier: src_identifier.into(),
+            },
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteRepostOperationStatus {
@@ -163,6 +379,35 @@ pub struct DeleteRepostOperation {
     pub status: DeleteRepostOperationStatus,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteLikeOperationStatus {
+    pub src_identifier: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteLikeOperation {
+    #[serde(flatten)]
+    pub account_pair: AccountPair,
+    #[serde(flatten)]
+    pub status: DeleteLikeOperationStatus,
+}
+
+#[cfg(test)]
+impl DeleteLikeOperation {
+    /** テスト用のいいねの取り消しの operation */
+    pub fn test(src_identifier: &str) -> Self {
+        Self {
+            account_pair: AccountPair::test(),
+            status: DeleteLikeOperationStatus {
+                src_identifier: src_identifier.into(),
+            },
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "operation")]
@@ -172,6 +417,8 @@ pub enum Operation {
     UpdatePost(UpdatePostOperation),
     DeletePost(DeletePostOperation),
     DeleteRepost(DeleteRepostOperation),
+    CreateLike(CreateLikeOperation),
+    DeleteLike(DeleteLikeOperation),
 }
 
 impl Operation {
@@ -181,7 +428,54 @@ impl Operation {
             | Operation::CreateRepost(CreateRepostOperation { account_pair, .. })
             | Operation::UpdatePost(UpdatePostOperation { account_pair, .. })
             | Operation::DeletePost(DeletePostOperation { account_pair, .. })
-            | Operation::DeleteRepost(DeleteRepostOperation { account_pair, .. }) => account_pair,
+            | Operation::DeleteRepost(DeleteRepostOperation { account_pair, .. })
+            | Operation::CreateLike(CreateLikeOperation { account_pair, .. })
+            | Operation::DeleteLike(DeleteLikeOperation { account_pair, .. }) => account_pair,
         }
     }
+
+    pub fn src_identifier(&self) -> &str {
+        match self {
+            Operation::CreatePost(operation) => &operation.status.src_identifier,
+            Operation::CreateRepost(operation) => &operation.status.src_identifier,
+            Operation::UpdatePost(operation) => &operation.status.src_identifier,
+            Operation::DeletePost(operation) => &operation.status.src_identifier,
+            Operation::DeleteRepost(operation) => &operation.status.src_identifier,
+            Operation::CreateLike(operation) => &operation.status.src_identifier,
+            Operation::DeleteLike(operation) => &operation.status.src_identifier,
+        }
+    }
+
+    /** 溜まっていたものとして、送信先の backdate に従った日時で送るようにする */
+    pub fn mark_backfill(&mut self) {
+        match self {
+            Operation::CreatePost(CreatePostOperation { backfill, .. })
+            | Operation::CreateRepost(CreateRepostOperation { backfill, .. })
+            | Operation::CreateLike(CreateLikeOperation { backfill, .. }) => *backfill = true,
+            Operation::UpdatePost(_)
+            | Operation::DeletePost(_)
+            | Operation::DeleteRepost(_)
+            | Operation::DeleteLike(_) => {}
+        }
+    }
+
+    /** ログやメトリクスに使う種類の名前 */
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Operation::CreatePost(_) => "create_post",
+            Operation::CreateRepost(_) => "create_repost",
+            Operation::UpdatePost(_) => "update_post",
+            Operation::DeletePost(_) => "delete_post",
+            Operation::DeleteRepost(_) => "delete_repost",
+            Operation::CreateLike(_) => "create_like",
+            Operation::DeleteLike(_) => "delete_like",
+        }
+    }
+
+    /** 同じ送信先の同じ status に対する、同じ種類の operation か */
+    pub fn is_equivalent(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+            && self.account_pair() == other.account_pair()
+            && self.src_identifier() == other.src_identifier()
+    }
 }
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+
+use crate::utils::format_rfc3339_opt;
+
+use super::Store;
+
+/** src の identifier はアクセストークンのことがあるので含めない。users は Store と同じ順に並ぶ */
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSummary {
+    pub src_origin: String,
+    /** operation の種類ごとの未消化の数 */
+    pub pending_operations: BTreeMap<&'static str, usize>,
+    pub src_statuses: usize,
+    pub dst_statuses: usize,
+    /** 保存している src の status のうち最新のものの日時 */
+    #[serde(with = "format_rfc3339_opt")]
+    pub last_seen_at: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    pub version: u32,
+    pub pending_operations: usize,
+    pub users: Vec<UserSummary>,
+}
+
+/**
+ * 監視用に Store の状態を集計する
+ *
+ * 未消化の operation が減らない場合は、上限や送信先の不調で詰まっている
+ */
+pub fn summarize(store: &Store) -> Summary {
+    let users = store
+        .users
+        .iter()
+        .map(|user| {
+            let mut pending_operations = BTreeMap::new();
+            store
+                .operations
+                .iter()
+                .filter(|operation| {
+                    let account_pair = operation.account_pair();
+                    account_pair.src_origin == user.src.origin
+                        && account_pair.src_account_identifier == user.src.identifier
+                })
+                .for_each(|operation| {
+                    *pending_operations.entry(operation.kind()).or_default() += 1
+                });
+            UserSummary {
+                src_origin: user.src.origin.clone(),
+                pending_operations,
+                src_statuses: user.src.statuses.len(),
+                dst_statuses: user.dsts.iter().map(|dst| dst.statuses.len()).sum(),
+                last_seen_at: user
+                    .src
+                    .statuses
+                    .iter()
+                    .map(|status| *status.created_at())
+                    .max(),
+            }
+        })
+        .collect();
+    Summary {
+        version: store.version,
+        pending_operations: store.operations.len(),
+        users,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::DateTime;
+    use serde_json::json;
+    use tokio_util::sync::CancellationToken;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        config,
+        database::InMemory,
+        operations::destination::post,
+        protocols::megalodon_client::{test_account, test_status},
+        store::{
+            operations::{AccountPair, CreatePostOperation, DeletePostOperation, Operation},
+            user::{
+                Destination, DestinationPost, DestinationStatus, Source, SourcePost, SourceStatus,
+                User,
+            },
+        },
+    };
+
+    use super::*;
+
+    fn src_post(identifier: &str, created_at: &str) -> SourceStatus {
+        SourceStatus::Post(SourcePost {
+            identifier: identifier.into(),
+            content: "hello".into(),
+            media_alts: None,
+            created_at: DateTime::parse_from_rfc3339(created_at).unwrap(),
+        })
+    }
+
+    fn user(account_pair: &AccountPair, src_statuses: Vec<SourceStatus>) -> User {
+        User {
+            src: Source {
+                origin: account_pair.src_origin.clone(),
+                identifier: account_pair.src_account_identifier.clone(),
+                session: None,
+                cursor: None,
+                statuses: src_statuses,
+            },
+            dsts: vec![Destination {
+                origin: account_pair.dst_origin.clone(),
+                identifier: account_pair.dst_account_identifier.clone(),
+                session: None,
+                statuses: vec![DestinationStatus::Post(DestinationPost {
+                    identifier: "10".into(),
+                    src_identifier: "1".into(),
+                    src_uri: "https://src.example.com/1".into(),
+                    follow_up_identifiers: Vec::new(),
+                })],
+            }],
+        }
+    }
+
+    fn delete_post(account_pair: &AccountPair, src_identifier: &str) -> Operation {
+        let mut operation = DeletePostOperation::test(src_identifier);
+        operation.account_pair = account_pair.clone();
+        Operation::DeletePost(operation)
+    }
+
+    fn create_post(account_pair: &AccountPair, src_identifier: &str) -> Operation {
+        let mut operation = CreatePostOperation::test(src_identifier, "hello");
+        operation.account_pair = account_pair.clone();
+        Operation::CreatePost(operation)
+    }
+
+    #[test]
+    fn summary_matches_store() {
+        let account_pair = AccountPair::test();
+        let other_account_pair = AccountPair {
+            src_account_identifier: "other".into(),
+            ..AccountPair::test()
+        };
+        let store = Store {
+            users: vec![
+                user(
+                    &account_pair,
+                    vec![
+                        src_post("1", "2024-01-01T00:01:00Z"),
+                        src_post("2", "2024-01-01T00:02:00Z"),
+                    ],
+                ),
+                user(&other_account_pair, Vec::new()),
+            ],
+            operations: [
+                create_post(&account_pair, "3"),
+                create_post(&account_pair, "4"),
+                delete_post(&account_pair, "1"),
+                create_post(&other_account_pair, "5"),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let summary = serde_json::to_value(summarize(&store)).unwrap();
+
+        assert_eq!(
+            summary,
+            json!({
+                "version": CURRENT_VERSION,
+                "pendingOperations": 4,
+                "users": [
+                    {
+                        "srcOrigin": "https://src.example.com",
+                        "pendingOperations": { "create_post": 2, "delete_post": 1 },
+                        "srcStatuses": 2,
+                        "dstStatuses": 1,
+                        "lastSeenAt": "2024-01-01T00:02:00.000Z",
+                    },
+                    {
+                        "srcOrigin": "https://src.example.com",
+                        "pendingOperations": { "create_post": 1 },
+                        "srcStatuses": 0,
+                        "dstStatuses": 1,
+                        "lastSeenAt": null,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn operations_left_by_batch_cap_are_pending() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/verify_credentials"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_account("1", "dst")))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(test_status("11")))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/statuses/10"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config: config::Config = serde_json::from_value(json!({
+            "users": [{
+                "src": {
+                    "protocol": "mastodon",
+                    "origin": "https://src.example.com",
+                    "accessToken": "src",
+                },
+                "dsts": [{
+                    "protocol": "mastodon",
+                    "origin": server.uri(),
+                    "accessToken": "dst",
+                }],
+            }],
+            "maxOperationsPerRun": 2,
+        }))
+        .unwrap();
+        let account_pair = AccountPair {
+            dst_origin: server.uri(),
+            ..AccountPair::test()
+        };
+        let mut store = Store {
+            users: vec![user(
+                &account_pair,
+                vec![src_post("1", "2024-01-01T00:01:00Z")],
+            )],
+            operations: [
+                create_post(&account_pair, "2"),
+                create_post(&account_pair, "3"),
+                create_post(&account_pair, "4"),
+                delete_post(&account_pair, "1"),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        post(
+            &CancellationToken::new(),
+            &mut store,
+            Arc::new(reqwest::Client::new()),
+            &config,
+            &InMemory::new(json!({}), Store::default()),
+        )
+        .await
+        .unwrap();
+
+        // NOTE: 削除が先に送られ、残りの投稿が上限で次回に持ち越される
+        let summary = summarize(&store);
+        assert_eq!(summary.pending_operations, 2);
+        let [user] = summary.users.as_slice() else {
+            panic!("unexpected users");
+        };
+        assert_eq!(
+            user.pending_operations,
+            BTreeMap::from([("create_post", 2)])
+        );
+        assert_eq!(user.dst_statuses, 1);
+    }
+}
@@ -8,6 +8,9 @@ use crate::{app::AccountKey, sources::source, utils::format_rfc3339};
 pub struct SourcePost {
     pub identifier: String,
     pub content: String,
+    /** 代替テキストの編集を検出するためのもの。保存していなかった以前の status は None */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_alts: Option<Vec<String>>,
     #[serde(with = "format_rfc3339")]
     pub created_at: DateTime<FixedOffset>,
 }
@@ -21,18 +24,38 @@ pub struct SourceRepost {
     pub created_at: DateTime<FixedOffset>,
 }
 
+/** 他の status と区別できるように、対象のフィールド名を repost と変えている */
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceLike {
+    pub identifier: String,
+    pub liked_identifier: String,
+    #[serde(with = "format_rfc3339")]
+    pub created_at: DateTime<FixedOffset>,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase", untagged)]
 pub enum SourceStatus {
     Post(SourcePost),
     Repost(SourceRepost),
+    Like(SourceLike),
 }
 
 impl SourceStatus {
+    pub fn identifier(&self) -> &str {
+        match self {
+            SourceStatus::Post(SourcePost { identifier, .. })
+            | SourceStatus::Repost(SourceRepost { identifier, .. })
+            | SourceStatus::Like(SourceLike { identifier, .. }) => identifier,
+        }
+    }
+
     pub fn created_at(&self) -> &DateTime<FixedOffset> {
         match self {
             SourceStatus::Post(SourcePost { created_at, .. })
-            | SourceStatus::Repost(SourceRepost { created_at, .. }) => created_at,
+            | SourceStatus::Repost(SourceRepost { created_at, .. })
+            | SourceStatus::Like(SourceLike { created_at, .. }) => created_at,
         }
     }
 }
@@ -42,6 +65,7 @@ impl From<super::operations::CreatePostOperationStatus> for SourceStatus {
         SourceStatus::Post(SourcePost {
             identifier: full.src_identifier,
             content: full.content,
+            media_alts: Some(full.media.into_iter().map(|medium| medium.alt).collect()),
             created_at: full.created_at,
         })
     }
@@ -53,6 +77,7 @@ impl From<source::LiveStatus> for SourceStatus {
             source::LiveStatus::Post(post) => SourceStatus::Post(SourcePost {
                 identifier: post.identifier,
                 content: post.content,
+                media_alts: Some(post.media.into_iter().map(|medium| medium.alt).collect()),
                 created_at: post.created_at,
             }),
             source::LiveStatus::Repost(repost) => SourceStatus::Repost(SourceRepost {
@@ -60,6 +85,11 @@ impl From<source::LiveStatus> for SourceStatus {
                 target_identifier: repost.target_src_identifier,
                 created_at: repost.created_at,
             }),
+            source::LiveStatus::Like(like) => SourceStatus::Like(SourceLike {
+                identifier: like.src_identifier,
+                liked_identifier: like.target_src_identifier,
+                created_at: like.created_at,
+            }),
         }
     }
 }
@@ -70,6 +100,9 @@ pub struct Source {
     pub origin: String,
     pub identifier: String,
     pub session: Option<String>,
+    /** 前回の取得で最新だった status の identifier。次回はここまで遡って取得する */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
     pub statuses: Vec<SourceStatus>,
 }
 
@@ -79,6 +112,9 @@ pub struct DestinationPost {
     pub identifier: String,
     pub src_identifier: String,
     pub src_uri: String,
+    /** メディアが多くて分けて送った場合の、返信としてつなげた投稿の identifier */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub follow_up_identifiers: Vec<String>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -88,11 +124,19 @@ pub struct DestinationRepost {
     pub src_identifier: String,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationLike {
+    pub identifier: String,
+    pub src_identifier: String,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum DestinationStatus {
     Post(DestinationPost),
     Repost(DestinationRepost),
+    Like(DestinationLike),
 }
 
 #[derive(Clone, Deserialize, Serialize)]
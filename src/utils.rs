@@ -16,3 +16,30 @@ pub mod format_rfc3339 {
         DateTime::parse_from_rfc3339(&String::deserialize(deserializer)?).map_err(de::Error::custom)
     }
 }
+
+pub mod format_rfc3339_opt {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(
+        date: &Option<DateTime<FixedOffset>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(date) => super::format_rfc3339::serialize(date, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<FixedOffset>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|date| DateTime::parse_from_rfc3339(&date).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}